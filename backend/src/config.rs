@@ -0,0 +1,564 @@
+use serde::Deserialize;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::OnceLock;
+
+use crate::inbox_client::pool;
+
+pub const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+pub const USAGE: &str = "Usage: mail_backend [OPTIONS]
+
+Options:
+  --config <FILE>           Configuration file [env: MAIL_CONFIG] [default: config.toml]
+  --listen <ADDR:PORT>      Loopback address to listen on [env: MAIL_LISTEN] [default: 127.0.0.1:9001]
+  --transport <KIND>        tcp, unix or both [env: MAIL_TRANSPORT] [default: tcp]
+  --socket <PATH>           Unix socket path [env: MAIL_SOCKET] [default: $XDG_RUNTIME_DIR/mail_backend/api.sock]
+  --data-dir <DIR>          Directory for the database, key and API token [env: MAIL_DATA_DIR] [default: .]
+  --log-level <LEVEL>       off, error, info or debug [env: MAIL_LOG_LEVEL] [default: error]
+  --sync-batch-size <N>     Messages fetched per IMAP request while syncing [env: MAIL_SYNC_BATCH_SIZE] [default: 20]
+  --prefetch <POLICY>       Mailboxes synced after connecting: none, inbox or all [env: MAIL_PREFETCH] [default: none]
+  --pool-size <N>           IMAP connections per account [env: MAIL_POOL_SIZE] [default: 4]
+  --smime-trust-store <FILE>
+                            PEM file of the CA certificates trusted for S/MIME [env: MAIL_SMIME_TRUST_STORE] [default: system certificates]
+  -h, --help                Print this help
+";
+
+const MAX_SYNC_BATCH_SIZE: u32 = 10_000;
+const MAX_POOL_SIZE: usize = 32;
+
+pub const SOCKET_DIR: &str = "mail_backend";
+pub const DEFAULT_SOCKET_FILE: &str = "api.sock";
+
+/// Longest path that fits in `sun_path` with its terminating NUL on Linux,
+/// other systems allow even less.
+const MAX_SOCKET_PATH_LEN: usize = 107;
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    pub fn from_str(log_level: &str) -> Option<LogLevel> {
+        match log_level.to_lowercase().as_str() {
+            "off" => Some(LogLevel::Off),
+            "error" => Some(LogLevel::Error),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None,
+        }
+    }
+}
+
+/// Which mailboxes are synced in the background once an account is connected,
+/// other mailboxes are synced when the app first updates them.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Prefetch {
+    None,
+    Inbox,
+    All,
+}
+
+impl Prefetch {
+    pub fn from_str(prefetch: &str) -> Option<Prefetch> {
+        match prefetch.to_lowercase().as_str() {
+            "none" => Some(Prefetch::None),
+            "inbox" => Some(Prefetch::Inbox),
+            "all" => Some(Prefetch::All),
+            _ => None,
+        }
+    }
+}
+
+/// Where the HTTP API is served, the Unix socket avoids exposing a port to
+/// other users of the machine.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Tcp,
+    Unix,
+    Both,
+}
+
+impl Transport {
+    pub fn from_str(transport: &str) -> Option<Transport> {
+        match transport.to_lowercase().as_str() {
+            "tcp" => Some(Transport::Tcp),
+            "unix" => Some(Transport::Unix),
+            "both" => Some(Transport::Both),
+            _ => None,
+        }
+    }
+
+    pub fn tcp(&self) -> bool {
+        return *self != Transport::Unix;
+    }
+
+    pub fn unix(&self) -> bool {
+        return *self != Transport::Tcp;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: String,
+    pub transport: Transport,
+    pub socket: Option<PathBuf>,
+    pub data_dir: PathBuf,
+    pub log_level: LogLevel,
+    pub sync_batch_size: u32,
+    pub prefetch: Prefetch,
+    pub pool_size: usize,
+    pub smime_trust_store: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        return Config {
+            listen: String::from("127.0.0.1:9001"),
+            transport: Transport::Tcp,
+            socket: None,
+            data_dir: PathBuf::from("."),
+            log_level: LogLevel::Error,
+            sync_batch_size: 20,
+            prefetch: Prefetch::None,
+            pool_size: pool::DEFAULT_POOL_SIZE,
+            smime_trust_store: None,
+        };
+    }
+}
+
+impl Config {
+    /// Paths of the files kept in the data directory.
+    pub fn data_path(&self, file: &str) -> PathBuf {
+        return self.data_dir.join(file);
+    }
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Error as u8);
+
+pub fn set_log_level(log_level: LogLevel) {
+    LOG_LEVEL.store(log_level as u8, Ordering::Relaxed);
+}
+
+pub fn log_enabled(log_level: LogLevel) -> bool {
+    return log_level as u8 <= LOG_LEVEL.load(Ordering::Relaxed);
+}
+
+static SMIME_TRUST_STORE: OnceLock<PathBuf> = OnceLock::new();
+
+/// Set once at startup, S/MIME signatures are checked against the system
+/// certificates until then.
+pub fn set_smime_trust_store(path: &Path) {
+    let _ = SMIME_TRUST_STORE.set(path.to_path_buf());
+}
+
+pub fn smime_trust_store() -> Option<&'static Path> {
+    return SMIME_TRUST_STORE.get().map(|path| path.as_path());
+}
+
+/// Builds the configuration from the defaults, the configuration file, the
+/// environment and the command line, later sources taking precedence. All
+/// problems are collected so they can be reported together at startup.
+pub fn load<F>(args: &[String], env: F) -> Result<Config, Vec<String>>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut errors: Vec<String> = Vec::new();
+
+    let (config_path, explicit) = match arg_value(args, "--config") {
+        Some(path) => (PathBuf::from(path), true),
+        None => match env("MAIL_CONFIG") {
+            Some(path) if !path.is_empty() => (PathBuf::from(path), true),
+            _ => (PathBuf::from(DEFAULT_CONFIG_FILE), false),
+        },
+    };
+
+    let mut config = match read_file(&config_path, explicit) {
+        Ok(config) => config,
+        Err(e) => {
+            errors.push(e);
+            Config::default()
+        }
+    };
+
+    for (key, variable) in [
+        ("listen", "MAIL_LISTEN"),
+        ("transport", "MAIL_TRANSPORT"),
+        ("socket", "MAIL_SOCKET"),
+        ("data-dir", "MAIL_DATA_DIR"),
+        ("log-level", "MAIL_LOG_LEVEL"),
+        ("sync-batch-size", "MAIL_SYNC_BATCH_SIZE"),
+        ("prefetch", "MAIL_PREFETCH"),
+        ("pool-size", "MAIL_POOL_SIZE"),
+        ("smime-trust-store", "MAIL_SMIME_TRUST_STORE"),
+    ] {
+        match env(variable) {
+            Some(value) if !value.is_empty() => match set(&mut config, key, &value) {
+                Ok(_) => {}
+                Err(e) => errors.push(format!("{}: {}", variable, e)),
+            },
+            _ => {}
+        }
+    }
+
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) => (flag, Some(value.to_string())),
+            None => (arg.as_str(), None),
+        };
+
+        let key = match flag.strip_prefix("--") {
+            Some(key) => key,
+            None => {
+                errors.push(format!("Unexpected argument {}", arg));
+                continue;
+            }
+        };
+
+        let value = match inline_value.or_else(|| args.next().cloned()) {
+            Some(value) => value,
+            None => {
+                errors.push(format!("--{} requires a value", key));
+                continue;
+            }
+        };
+
+        if key == "config" {
+            continue;
+        }
+
+        match set(&mut config, key, &value) {
+            Ok(_) => {}
+            Err(e) => errors.push(format!("--{}: {}", key, e)),
+        }
+    }
+
+    if config.transport.unix() && config.socket.is_none() {
+        config.socket = Some(default_socket_path(
+            env("XDG_RUNTIME_DIR"),
+            &config.data_dir,
+        ));
+    }
+
+    errors.extend(validate(&config));
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    return Ok(config);
+}
+
+fn arg_value(args: &[String], flag: &str) -> Option<String> {
+    let prefix = format!("{}=", flag);
+
+    for (i, arg) in args.iter().enumerate() {
+        if arg == flag {
+            return args.get(i + 1).cloned();
+        }
+
+        if let Some(value) = arg.strip_prefix(&prefix) {
+            return Some(value.to_string());
+        }
+    }
+
+    return None;
+}
+
+/// The socket lives in a directory of its own inside the user's runtime
+/// directory, systems without one fall back to the data directory.
+fn default_socket_path(runtime_dir: Option<String>, data_dir: &Path) -> PathBuf {
+    match runtime_dir {
+        Some(runtime_dir) if !runtime_dir.is_empty() => {
+            return PathBuf::from(runtime_dir)
+                .join(SOCKET_DIR)
+                .join(DEFAULT_SOCKET_FILE)
+        }
+        _ => return data_dir.join(DEFAULT_SOCKET_FILE),
+    }
+}
+
+/// A missing configuration file is only an error when it was asked for.
+fn read_file(path: &Path, explicit: bool) -> Result<Config, String> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => {
+            return Ok(Config::default());
+        }
+        Err(e) => return Err(format!("Error reading {}: {}", path.display(), e)),
+    };
+
+    match toml::from_str(&contents) {
+        Ok(config) => return Ok(config),
+        Err(e) => return Err(format!("Error parsing {}: {}", path.display(), e)),
+    }
+}
+
+fn set(config: &mut Config, key: &str, value: &str) -> Result<(), String> {
+    match key {
+        "listen" => config.listen = value.to_string(),
+        "transport" => match Transport::from_str(value) {
+            Some(transport) => config.transport = transport,
+            None => return Err(format!("Unknown transport {}", value)),
+        },
+        "socket" => config.socket = Some(PathBuf::from(value)),
+        "data-dir" => config.data_dir = PathBuf::from(value),
+        "log-level" => match LogLevel::from_str(value) {
+            Some(log_level) => config.log_level = log_level,
+            None => return Err(format!("Unknown log level {}", value)),
+        },
+        "sync-batch-size" => match value.parse::<u32>() {
+            Ok(sync_batch_size) => config.sync_batch_size = sync_batch_size,
+            Err(e) => return Err(format!("Invalid number {}: {}", value, e)),
+        },
+        "prefetch" => match Prefetch::from_str(value) {
+            Some(prefetch) => config.prefetch = prefetch,
+            None => return Err(format!("Unknown prefetch policy {}", value)),
+        },
+        "pool-size" => match value.parse::<usize>() {
+            Ok(pool_size) => config.pool_size = pool_size,
+            Err(e) => return Err(format!("Invalid number {}: {}", value, e)),
+        },
+        "smime-trust-store" => config.smime_trust_store = Some(PathBuf::from(value)),
+        _ => return Err(String::from("Unknown option")),
+    }
+
+    return Ok(());
+}
+
+fn validate(config: &Config) -> Vec<String> {
+    let mut errors: Vec<String> = Vec::new();
+
+    if config.transport.tcp() {
+        match config.listen.parse::<SocketAddr>() {
+            Ok(addr) if !addr.ip().is_loopback() => errors.push(format!(
+                "listen: {} is not a loopback address",
+                config.listen
+            )),
+            Ok(_) => {}
+            Err(_) => errors.push(format!(
+                "listen: {} is not an address and port",
+                config.listen
+            )),
+        }
+    }
+
+    if config.transport.unix() {
+        if !cfg!(unix) {
+            errors.push(String::from(
+                "transport: Unix sockets are not supported on this platform",
+            ));
+        }
+
+        match &config.socket {
+            Some(socket) if socket.as_os_str().is_empty() => {
+                errors.push(String::from("socket: must not be empty"))
+            }
+            Some(socket) if socket.as_os_str().len() > MAX_SOCKET_PATH_LEN => errors.push(format!(
+                "socket: {} is longer than {} bytes",
+                socket.display(),
+                MAX_SOCKET_PATH_LEN
+            )),
+            _ => {}
+        }
+    }
+
+    if config.data_dir.as_os_str().is_empty() {
+        errors.push(String::from("data_dir: must not be empty"));
+    } else if config.data_dir.exists() && !config.data_dir.is_dir() {
+        errors.push(format!(
+            "data_dir: {} is not a directory",
+            config.data_dir.display()
+        ));
+    }
+
+    if config.sync_batch_size == 0 || config.sync_batch_size > MAX_SYNC_BATCH_SIZE {
+        errors.push(format!(
+            "sync_batch_size: must be between 1 and {}",
+            MAX_SYNC_BATCH_SIZE
+        ));
+    }
+
+    if config.pool_size == 0 || config.pool_size > MAX_POOL_SIZE {
+        errors.push(format!(
+            "pool_size: must be between 1 and {}",
+            MAX_POOL_SIZE
+        ));
+    }
+
+    if let Some(trust_store) = &config.smime_trust_store {
+        if !trust_store.is_file() {
+            errors.push(format!(
+                "smime_trust_store: {} is not a file",
+                trust_store.display()
+            ));
+        }
+    }
+
+    return errors;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        return args.iter().map(|arg| arg.to_string()).collect();
+    }
+
+    fn write_config(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}.toml", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+
+        return path.to_str().unwrap().to_string();
+    }
+
+    #[test]
+    fn defaults() {
+        let config = load(&[], |_| None).unwrap();
+
+        assert_eq!(config, Config::default());
+    }
+
+    #[test]
+    fn precedence() {
+        let path = write_config(
+            "precedence",
+            "listen = \"127.0.0.1:9100\"\nsync_batch_size = 50\nprefetch = \"all\"\npool_size = 2\n",
+        );
+
+        let env: HashMap<&str, &str> =
+            HashMap::from([("MAIL_SYNC_BATCH_SIZE", "30"), ("MAIL_POOL_SIZE", "3")]);
+
+        let config = load(&args(&["--config", &path, "--pool-size=5"]), |key| {
+            env.get(key).map(|value| value.to_string())
+        })
+        .unwrap();
+
+        assert_eq!(config.listen, "127.0.0.1:9100");
+        assert_eq!(config.prefetch, Prefetch::All);
+        assert_eq!(config.sync_batch_size, 30);
+        assert_eq!(config.pool_size, 5);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn errors_collected() {
+        let errors = load(
+            &args(&[
+                "--listen",
+                "0.0.0.0:9001",
+                "--sync-batch-size",
+                "0",
+                "--prefetch",
+                "some",
+                "--verbose",
+            ]),
+            |key| match key {
+                "MAIL_POOL_SIZE" => Some(String::from("many")),
+                _ => None,
+            },
+        )
+        .unwrap_err();
+
+        assert_eq!(errors.len(), 5, "{:?}", errors);
+        assert!(errors.iter().any(|e| e.starts_with("MAIL_POOL_SIZE")));
+        assert!(errors.iter().any(|e| e.starts_with("--prefetch")));
+        assert!(errors.iter().any(|e| e.starts_with("--verbose")));
+        assert!(errors.iter().any(|e| e.contains("loopback")));
+        assert!(errors.iter().any(|e| e.starts_with("sync_batch_size")));
+    }
+
+    #[test]
+    fn smime_trust_store() {
+        let path = write_config("trust_store", "");
+
+        let config = load(&args(&["--smime-trust-store", &path]), |_| None).unwrap();
+        assert_eq!(config.smime_trust_store, Some(PathBuf::from(&path)));
+
+        let errors = load(&[], |key| match key {
+            "MAIL_SMIME_TRUST_STORE" => Some(format!("{}.missing", path)),
+            _ => None,
+        })
+        .unwrap_err();
+        assert!(errors[0].starts_with("smime_trust_store"));
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn invalid_file() {
+        let path = write_config("invalid", "listen = 9001\nunknown = true\n");
+
+        let errors = load(&args(&["--config", &path]), |_| None).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("Error parsing"));
+
+        fs::remove_file(&path).unwrap();
+
+        let errors = load(&args(&["--config", &path]), |_| None).unwrap_err();
+        assert!(errors[0].starts_with("Error reading"));
+    }
+
+    #[test]
+    fn socket_transport() {
+        let config = load(&args(&["--transport", "unix"]), |key| match key {
+            "XDG_RUNTIME_DIR" => Some(String::from("/run/user/1000")),
+            _ => None,
+        })
+        .unwrap();
+
+        assert!(!config.transport.tcp());
+        assert_eq!(
+            config.socket,
+            Some(PathBuf::from("/run/user/1000/mail_backend/api.sock"))
+        );
+
+        let config = load(&args(&["--transport=both", "--data-dir", "/tmp"]), |_| None).unwrap();
+
+        assert!(config.transport.tcp() && config.transport.unix());
+        assert_eq!(config.socket, Some(PathBuf::from("/tmp/api.sock")));
+
+        // The listen address is not used without the TCP transport.
+        let config = load(
+            &args(&["--listen", "0.0.0.0:9001", "--socket", "/tmp/mail.sock"]),
+            |key| match key {
+                "MAIL_TRANSPORT" => Some(String::from("unix")),
+                _ => None,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(config.socket, Some(PathBuf::from("/tmp/mail.sock")));
+
+        let errors = load(
+            &args(&["--transport", "unix", "--socket", &"a".repeat(200)]),
+            |_| None,
+        )
+        .unwrap_err();
+
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("socket"));
+    }
+
+    #[test]
+    fn log_levels() {
+        assert!(LogLevel::Error <= LogLevel::Debug);
+        assert_eq!(LogLevel::from_str("INFO"), Some(LogLevel::Info));
+        assert_eq!(LogLevel::from_str("verbose"), None);
+    }
+}
@@ -1,15 +1,57 @@
 use async_std::sync::{Arc, Mutex};
 use rusqlite::{backup, config::DbConfig, params, vtab, Connection, DatabaseName, OpenFlags};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
+use crate::mime_parser::{html_to_text, preview};
 use crate::my_error::MyError;
 
+pub mod autocrypt;
+pub mod changes;
 pub mod connections;
+pub mod credentials;
 pub mod mailbox;
 pub mod message;
 pub mod messages;
+pub mod openpgp_keys;
+pub mod remote_content;
+pub mod smime_identities;
 
-pub async fn new() -> Result<Connection, MyError> {
-    let database_path = "mail.db";
+pub const DEFAULT_DATABASE_FILE: &str = "mail.db";
+
+/// Sort keys of the message list, generated so messages stored by an older
+/// version are sortable without rewriting them. The sender is the display
+/// name of the first From address, or its address when it has no name.
+const SORT_SENDER_COLUMN: &str = "TEXT GENERATED ALWAYS AS (
+    CASE WHEN json_valid(from_) THEN lower(coalesce(
+        nullif(json_extract(from_, '$[0].name'), ''),
+        json_extract(from_, '$[0].mailbox') || '@' || json_extract(from_, '$[0].host'),
+        ''
+    )) ELSE '' END
+) VIRTUAL";
+const SORT_SUBJECT_COLUMN: &str = "TEXT GENERATED ALWAYS AS (lower(subject)) VIRTUAL";
+
+/// Indexes backing the sort orders of the message list, each ending in the
+/// uid used as tie breaker by keyset pagination.
+const MESSAGE_INDEXES: [(&str, &str); 5] = [
+    ("messages_received", "received"),
+    ("messages_date", "date_"),
+    ("messages_size", "size"),
+    ("messages_sender", "sort_sender"),
+    ("messages_subject", "sort_subject"),
+];
+
+/// The in-memory database is restored from and backed up to this file, set
+/// once at startup from the data directory.
+static DATABASE_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+fn database_path() -> &'static Path {
+    return DATABASE_PATH.get_or_init(|| PathBuf::from(DEFAULT_DATABASE_FILE));
+}
+
+pub async fn new(path: &Path) -> Result<Connection, MyError> {
+    let _ = DATABASE_PATH.set(path.to_path_buf());
+    let database_path = database_path();
 
     let mut conn = match Connection::open_in_memory_with_flags(
         OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_CREATE,
@@ -25,7 +67,7 @@ pub async fn new() -> Result<Connection, MyError> {
 
     match conn.restore(
         DatabaseName::Main,
-        &database_path,
+        database_path,
         None::<fn(backup::Progress)>,
     ) {
         Ok(_) => {}
@@ -59,12 +101,12 @@ pub async fn new() -> Result<Connection, MyError> {
 }
 
 pub async fn backup(conn: Arc<Mutex<Connection>>) -> Result<(), MyError> {
-    let database_path = "mail.db";
+    let database_path = database_path();
 
     let conn_locked = conn.lock().await;
     match conn_locked.backup(
         DatabaseName::Main,
-        &database_path,
+        database_path,
         None::<fn(backup::Progress)>,
     ) {
         Ok(_) => {}
@@ -86,6 +128,13 @@ pub async fn initialise(conn: &Connection) -> Result<(), MyError> {
                 password VARCHAR(500) NOT NULL,
                 address VARCHAR(500) NOT NULL,
                 port INTEGER NOT NULL,
+                security VARCHAR(20) NOT NULL DEFAULT 'tls',
+                cert_fingerprint VARCHAR(100),
+                auth_mechanism VARCHAR(20) NOT NULL DEFAULT 'password',
+                token_endpoint VARCHAR(500),
+                client_id VARCHAR(500),
+                client_secret VARCHAR(500),
+                refresh_token TEXT,
                 updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
                 PRIMARY KEY(username, address)
             )",
@@ -99,6 +148,59 @@ pub async fn initialise(conn: &Connection) -> Result<(), MyError> {
         }
     }
 
+    match add_column(
+        conn,
+        "connections",
+        "security",
+        "VARCHAR(20) NOT NULL DEFAULT 'tls'",
+    ) {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    }
+
+    match add_column(conn, "connections", "cert_fingerprint", "VARCHAR(100)") {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    }
+
+    match add_column(
+        conn,
+        "connections",
+        "auth_mechanism",
+        "VARCHAR(20) NOT NULL DEFAULT 'password'",
+    ) {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    }
+
+    for (column, definition) in [
+        ("token_endpoint", "VARCHAR(500)"),
+        ("client_id", "VARCHAR(500)"),
+        ("client_secret", "VARCHAR(500)"),
+        ("refresh_token", "TEXT"),
+    ] {
+        match add_column(conn, "connections", column, definition) {
+            Ok(_) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    match conn.execute(
+        "CREATE TABLE IF NOT EXISTS credential_store (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                salt TEXT NOT NULL,
+                verifier TEXT NOT NULL
+            )",
+        params![],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error creating credential_store table"));
+            err.log_error();
+            return Err(err);
+        }
+    }
+
     match conn.execute(
             "CREATE TABLE IF NOT EXISTS mailboxes (
                 c_username VARCHAR(500) NOT NULL,
@@ -119,8 +221,13 @@ pub async fn initialise(conn: &Connection) -> Result<(), MyError> {
             }
         }
 
+    match add_column(conn, "mailboxes", "special_use", "VARCHAR(20)") {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    }
+
     match conn.execute(
-            "CREATE TABLE IF NOT EXISTS messages (
+            &format!("CREATE TABLE IF NOT EXISTS messages (
                 message_uid INTEGER NOT NULL,
                 c_username VARCHAR(500) NOT NULL,
                 c_address VARCHAR(500) NOT NULL,
@@ -141,10 +248,16 @@ pub async fn initialise(conn: &Connection) -> Result<(), MyError> {
                 html TEXT NOT NULL,
                 text TEXT NOT NULL,
                 updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                size INTEGER NOT NULL DEFAULT 0,
+                sort_sender {},
+                sort_subject {},
+                preview TEXT,
+                has_attachments BOOLEAN NOT NULL DEFAULT FALSE,
+                thread_id VARCHAR(500) NOT NULL DEFAULT '',
                 PRIMARY KEY(c_username, c_address, m_path, message_uid),
                 FOREIGN KEY(c_username, c_address) REFERENCES connections(username, address) ON DELETE CASCADE,
                 FOREIGN KEY(c_username, c_address, m_path) REFERENCES mailboxes(c_username, c_address, path) ON DELETE CASCADE
-            )",
+            )", SORT_SENDER_COLUMN, SORT_SUBJECT_COLUMN),
             params![],
         ) {
             Ok(_) => {}
@@ -156,6 +269,63 @@ pub async fn initialise(conn: &Connection) -> Result<(), MyError> {
             }
         }
 
+    for (column, definition) in [
+        ("size", "INTEGER NOT NULL DEFAULT 0"),
+        ("sort_sender", SORT_SENDER_COLUMN),
+        ("sort_subject", SORT_SUBJECT_COLUMN),
+        ("preview", "TEXT"),
+        ("has_attachments", "BOOLEAN NOT NULL DEFAULT FALSE"),
+        ("thread_id", "VARCHAR(500) NOT NULL DEFAULT ''"),
+    ] {
+        match add_column(conn, "messages", column, definition) {
+            Ok(_) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    for (index, column) in MESSAGE_INDEXES {
+        match conn.execute(
+            &format!(
+                "CREATE INDEX IF NOT EXISTS {} ON messages (c_username, c_address, m_path, {}, message_uid)",
+                index, column
+            ),
+            params![],
+        ) {
+            Ok(_) => {}
+            Err(e) => {
+                let err = MyError::Sqlite(e, format!("Error creating {} index", index));
+                err.log_error();
+
+                return Err(err);
+            }
+        }
+    }
+
+    // Threads span the mailboxes of an account, a reply is found through the
+    // message id it refers to.
+    match conn.execute_batch(
+        "CREATE INDEX IF NOT EXISTS messages_thread ON messages (c_username, c_address, thread_id);
+        CREATE INDEX IF NOT EXISTS messages_message_id ON messages (c_username, c_address, message_id);",
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error creating thread indexes"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    match backfill_summaries(conn) {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    }
+
+    match backfill_text(conn) {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    }
+
     match conn.execute(
             "CREATE TABLE IF NOT EXISTS flags (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -179,5 +349,583 @@ pub async fn initialise(conn: &Connection) -> Result<(), MyError> {
             }
         }
 
+    // Parts referenced by Content-ID from the html of a message. They follow
+    // the message when it is moved, which changes its mailbox and uid.
+    match conn.execute(
+        "CREATE TABLE IF NOT EXISTS inline_parts (
+                message_uid INTEGER NOT NULL,
+                c_username VARCHAR(500) NOT NULL,
+                c_address VARCHAR(500) NOT NULL,
+                m_path VARCHAR(500) NOT NULL,
+                content_id VARCHAR(500) NOT NULL,
+                content_type VARCHAR(100) NOT NULL,
+                data TEXT NOT NULL,
+                PRIMARY KEY(c_username, c_address, m_path, message_uid, content_id),
+                FOREIGN KEY(c_username, c_address, m_path, message_uid) REFERENCES messages(c_username, c_address, m_path, message_uid) ON DELETE CASCADE ON UPDATE CASCADE
+            )",
+        params![],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error creating inline_parts table"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    // The text/calendar part of an invitation, parsed when the message is
+    // read.
+    match conn.execute(
+        "CREATE TABLE IF NOT EXISTS calendar_parts (
+                message_uid INTEGER NOT NULL,
+                c_username VARCHAR(500) NOT NULL,
+                c_address VARCHAR(500) NOT NULL,
+                m_path VARCHAR(500) NOT NULL,
+                data TEXT NOT NULL,
+                PRIMARY KEY(c_username, c_address, m_path, message_uid),
+                FOREIGN KEY(c_username, c_address, m_path, message_uid) REFERENCES messages(c_username, c_address, m_path, message_uid) ON DELETE CASCADE ON UPDATE CASCADE
+            )",
+        params![],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error creating calendar_parts table"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    // Headers of a message in the order of its source.
+    match conn.execute(
+        "CREATE TABLE IF NOT EXISTS message_headers (
+                message_uid INTEGER NOT NULL,
+                c_username VARCHAR(500) NOT NULL,
+                c_address VARCHAR(500) NOT NULL,
+                m_path VARCHAR(500) NOT NULL,
+                position INTEGER NOT NULL,
+                name VARCHAR(500) NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY(c_username, c_address, m_path, message_uid, position),
+                FOREIGN KEY(c_username, c_address, m_path, message_uid) REFERENCES messages(c_username, c_address, m_path, message_uid) ON DELETE CASCADE ON UPDATE CASCADE
+            )",
+        params![],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error creating message_headers table"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    // Sources of messages, kept once they have been requested.
+    match conn.execute(
+        "CREATE TABLE IF NOT EXISTS message_sources (
+                message_uid INTEGER NOT NULL,
+                c_username VARCHAR(500) NOT NULL,
+                c_address VARCHAR(500) NOT NULL,
+                m_path VARCHAR(500) NOT NULL,
+                source BLOB NOT NULL,
+                PRIMARY KEY(c_username, c_address, m_path, message_uid),
+                FOREIGN KEY(c_username, c_address, m_path, message_uid) REFERENCES messages(c_username, c_address, m_path, message_uid) ON DELETE CASCADE ON UPDATE CASCADE
+            )",
+        params![],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error creating message_sources table"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    // OpenPGP data found when a message is fetched, a PGP/MIME part or an
+    // inline block. It is opened when the message is read.
+    match conn.execute(
+        "CREATE TABLE IF NOT EXISTS openpgp_parts (
+                message_uid INTEGER NOT NULL,
+                c_username VARCHAR(500) NOT NULL,
+                c_address VARCHAR(500) NOT NULL,
+                m_path VARCHAR(500) NOT NULL,
+                kind VARCHAR(20) NOT NULL,
+                data TEXT NOT NULL,
+                signed TEXT NOT NULL,
+                PRIMARY KEY(c_username, c_address, m_path, message_uid),
+                FOREIGN KEY(c_username, c_address, m_path, message_uid) REFERENCES messages(c_username, c_address, m_path, message_uid) ON DELETE CASCADE ON UPDATE CASCADE
+            )",
+        params![],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error creating openpgp_parts table"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    // S/MIME entity found when a message is fetched, opened when it is read.
+    match conn.execute(
+        "CREATE TABLE IF NOT EXISTS smime_parts (
+                message_uid INTEGER NOT NULL,
+                c_username VARCHAR(500) NOT NULL,
+                c_address VARCHAR(500) NOT NULL,
+                m_path VARCHAR(500) NOT NULL,
+                kind VARCHAR(20) NOT NULL,
+                data TEXT NOT NULL,
+                PRIMARY KEY(c_username, c_address, m_path, message_uid),
+                FOREIGN KEY(c_username, c_address, m_path, message_uid) REFERENCES messages(c_username, c_address, m_path, message_uid) ON DELETE CASCADE ON UPDATE CASCADE
+            )",
+        params![],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error creating smime_parts table"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    // Result of decrypting a message and checking its signature, recorded
+    // each time the message is opened.
+    match conn.execute(
+        "CREATE TABLE IF NOT EXISTS message_security (
+                message_uid INTEGER NOT NULL,
+                c_username VARCHAR(500) NOT NULL,
+                c_address VARCHAR(500) NOT NULL,
+                m_path VARCHAR(500) NOT NULL,
+                protocol VARCHAR(20) NOT NULL,
+                encrypted BOOLEAN NOT NULL,
+                decrypted BOOLEAN NOT NULL,
+                signature VARCHAR(20) NOT NULL,
+                signer_fingerprint VARCHAR(100),
+                signer_identity VARCHAR(500),
+                signed_at INTEGER,
+                signer_expires INTEGER,
+                error TEXT,
+                checked_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                PRIMARY KEY(c_username, c_address, m_path, message_uid),
+                FOREIGN KEY(c_username, c_address, m_path, message_uid) REFERENCES messages(c_username, c_address, m_path, message_uid) ON DELETE CASCADE ON UPDATE CASCADE
+            )",
+        params![],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error creating message_security table"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    match add_column(conn, "message_security", "signer_expires", "INTEGER") {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    }
+
+    // Keywords were stored in their debug format by older versions, and the
+    // flags table had no unique key so a flag could be stored more than once.
+    match conn.execute_batch(
+        "UPDATE flags SET flag = substr(flag, 9, length(flag) - 10) WHERE flag LIKE 'Custom(\"%\")';
+        DELETE FROM flags WHERE id NOT IN (
+            SELECT min(id) FROM flags GROUP BY c_username, c_address, m_path, message_uid, flag
+        );
+        DROP INDEX IF EXISTS flags_message;",
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error removing duplicate flags"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    // Also used by the flagged and unread sort orders to look up a single flag.
+    match conn.execute(
+        "CREATE UNIQUE INDEX IF NOT EXISTS flags_message_flag
+            ON flags (c_username, c_address, m_path, message_uid, flag)",
+        params![],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error creating flags index"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    match conn.execute(
+        "CREATE TABLE IF NOT EXISTS mailbox_changes (
+                c_username VARCHAR(500) NOT NULL,
+                c_address VARCHAR(500) NOT NULL,
+                m_path VARCHAR(500) NOT NULL,
+                modseq INTEGER NOT NULL,
+                PRIMARY KEY(c_username, c_address, m_path)
+            )",
+        params![],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error creating mailbox_changes table"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    // One row per message holding its latest change, removed messages keep
+    // theirs so clients behind the counter learn about the removal.
+    match conn.execute(
+        "CREATE TABLE IF NOT EXISTS message_changes (
+                c_username VARCHAR(500) NOT NULL,
+                c_address VARCHAR(500) NOT NULL,
+                m_path VARCHAR(500) NOT NULL,
+                message_uid INTEGER NOT NULL,
+                modseq INTEGER NOT NULL,
+                removed BOOLEAN NOT NULL,
+                PRIMARY KEY(c_username, c_address, m_path, message_uid)
+            )",
+        params![],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error creating message_changes table"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    match conn.execute(
+        "CREATE INDEX IF NOT EXISTS message_changes_modseq
+            ON message_changes (c_username, c_address, m_path, modseq)",
+        params![],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error creating message_changes index"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    // Senders whose messages are shown with their remote images and styles.
+    match conn.execute(
+        "CREATE TABLE IF NOT EXISTS remote_content_senders (
+                c_username VARCHAR(500) NOT NULL,
+                c_address VARCHAR(500) NOT NULL,
+                sender VARCHAR(500) NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                PRIMARY KEY(c_username, c_address, sender),
+                FOREIGN KEY(c_username, c_address) REFERENCES connections(username, address) ON DELETE CASCADE
+            )",
+        params![],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(
+                e,
+                String::from("Error creating remote_content_senders table"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    // OpenPGP keys of an account, the secret key and its passphrase are
+    // encrypted by the credential store like the account passwords.
+    match conn.execute(
+        "CREATE TABLE IF NOT EXISTS openpgp_keys (
+                c_username VARCHAR(500) NOT NULL,
+                c_address VARCHAR(500) NOT NULL,
+                fingerprint VARCHAR(100) NOT NULL,
+                user_ids TEXT NOT NULL,
+                public_key TEXT NOT NULL,
+                secret_key TEXT,
+                passphrase TEXT,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                PRIMARY KEY(c_username, c_address, fingerprint),
+                FOREIGN KEY(c_username, c_address) REFERENCES connections(username, address) ON DELETE CASCADE
+            )",
+        params![],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error creating openpgp_keys table"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    // S/MIME identities of an account imported from PKCS#12 files, the
+    // private key is encrypted by the credential store.
+    match conn.execute(
+        "CREATE TABLE IF NOT EXISTS smime_identities (
+                c_username VARCHAR(500) NOT NULL,
+                c_address VARCHAR(500) NOT NULL,
+                fingerprint VARCHAR(100) NOT NULL,
+                certificates TEXT NOT NULL,
+                private_key TEXT NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                PRIMARY KEY(c_username, c_address, fingerprint),
+                FOREIGN KEY(c_username, c_address) REFERENCES connections(username, address) ON DELETE CASCADE
+            )",
+        params![],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error creating smime_identities table"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    // Autocrypt state of the peers an account got mail from, keys are base64
+    // encoded and timestamps in milliseconds (Autocrypt Level 1 section 2.3).
+    match conn.execute(
+        "CREATE TABLE IF NOT EXISTS autocrypt_peers (
+                c_username VARCHAR(500) NOT NULL,
+                c_address VARCHAR(500) NOT NULL,
+                peer VARCHAR(500) NOT NULL,
+                last_seen INTEGER NOT NULL DEFAULT 0,
+                autocrypt_timestamp INTEGER NOT NULL DEFAULT 0,
+                public_key TEXT,
+                prefer_encrypt VARCHAR(20) NOT NULL DEFAULT 'nopreference',
+                gossip_timestamp INTEGER NOT NULL DEFAULT 0,
+                gossip_key TEXT,
+                PRIMARY KEY(c_username, c_address, peer),
+                FOREIGN KEY(c_username, c_address) REFERENCES connections(username, address) ON DELETE CASCADE
+            )",
+        params![],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error creating autocrypt_peers table"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    // Autocrypt preference an account announces in its own header.
+    match conn.execute(
+        "CREATE TABLE IF NOT EXISTS autocrypt_accounts (
+                c_username VARCHAR(500) NOT NULL,
+                c_address VARCHAR(500) NOT NULL,
+                prefer_encrypt VARCHAR(20) NOT NULL,
+                PRIMARY KEY(c_username, c_address),
+                FOREIGN KEY(c_username, c_address) REFERENCES connections(username, address) ON DELETE CASCADE
+            )",
+        params![],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error creating autocrypt_accounts table"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    // Messages stored before changes were recorded count as the first change
+    // of their mailbox, so a client starting at cursor 0 sees them.
+    match conn.execute_batch(
+        "INSERT OR IGNORE INTO mailbox_changes (c_username, c_address, m_path, modseq)
+            SELECT DISTINCT c_username, c_address, m_path, 1 FROM messages;
+        INSERT OR IGNORE INTO message_changes (c_username, c_address, m_path, message_uid, modseq, removed)
+            SELECT c_username, c_address, m_path, message_uid, 1, FALSE FROM messages;",
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error recording existing messages"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    return Ok(());
+}
+
+/// Messages stored by an older version get a preview from their stored body
+/// and start a thread of their own. Whether they have attachments is not
+/// known without the raw message, so they show none until fetched again.
+fn backfill_summaries(conn: &Connection) -> Result<(), MyError> {
+    match conn.execute(
+        "UPDATE messages SET thread_id = CASE WHEN message_id != '' THEN message_id ELSE m_path || '/' || message_uid END WHERE thread_id = ''",
+        params![],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error filling in message threads"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    let mut stmt = match conn.prepare(
+        "SELECT c_username, c_address, m_path, message_uid, text, html FROM messages WHERE preview IS NULL",
+    ) {
+        Ok(stmt) => stmt,
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error preparing statement at previews"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    let rows: Vec<(String, String, String, u32, String)> = match stmt.query_map(params![], |row| {
+        let text: String = row.get(4)?;
+        let html: String = row.get(5)?;
+
+        Ok((
+            row.get(0)?,
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            preview::build(&text, &html),
+        ))
+    }) {
+        Ok(rows) => rows.filter_map(|row| row.ok()).collect(),
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error reading messages without preview"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    for (username, address, mailbox_path, message_uid, preview) in rows {
+        match conn.execute(
+            "UPDATE messages SET preview = ?1 WHERE c_username = ?2 AND c_address = ?3 AND m_path = ?4 AND message_uid = ?5",
+            params![preview, username, address, mailbox_path, message_uid],
+        ) {
+            Ok(_) => {}
+            Err(e) => {
+                let err = MyError::Sqlite(e, String::from("Error filling in message preview"));
+                err.log_error();
+
+                return Err(err);
+            }
+        }
+    }
+
+    return Ok(());
+}
+
+/// Messages stored by an older version without a text part get the text of
+/// their html.
+fn backfill_text(conn: &Connection) -> Result<(), MyError> {
+    let mut stmt = match conn.prepare(
+        "SELECT c_username, c_address, m_path, message_uid, html FROM messages WHERE text = '' AND html != ''",
+    ) {
+        Ok(stmt) => stmt,
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error preparing statement at message text"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    let rows: Vec<(String, String, String, u32, String)> = match stmt.query_map(params![], |row| {
+        let html: String = row.get(4)?;
+
+        Ok((
+            row.get(0)?,
+            row.get(1)?,
+            row.get(2)?,
+            row.get(3)?,
+            html_to_text::render(&html),
+        ))
+    }) {
+        Ok(rows) => rows.filter_map(|row| row.ok()).collect(),
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error reading messages without text"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    for (username, address, mailbox_path, message_uid, text) in rows {
+        match conn.execute(
+            "UPDATE messages SET text = ?1 WHERE c_username = ?2 AND c_address = ?3 AND m_path = ?4 AND message_uid = ?5",
+            params![text, username, address, mailbox_path, message_uid],
+        ) {
+            Ok(_) => {}
+            Err(e) => {
+                let err = MyError::Sqlite(e, String::from("Error filling in message text"));
+                err.log_error();
+
+                return Err(err);
+            }
+        }
+    }
+
+    return Ok(());
+}
+
+/// Adds a column to a table created by an older version, tables created by
+/// `initialise` already contain all columns.
+fn add_column(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), MyError> {
+    let mut stmt = match conn.prepare(&format!("PRAGMA table_xinfo({})", table)) {
+        Ok(stmt) => stmt,
+        Err(e) => {
+            let err = MyError::Sqlite(e, format!("Error reading columns of {} table", table));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    let columns: Vec<String> = match stmt.query_map(params![], |row| row.get(1)) {
+        Ok(rows) => rows.filter_map(|row| row.ok()).collect(),
+        Err(e) => {
+            let err = MyError::Sqlite(e, format!("Error reading columns of {} table", table));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    if columns.iter().any(|c| c == column) {
+        return Ok(());
+    }
+
+    match conn.execute(
+        &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+        params![],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(
+                e,
+                format!("Error adding {} column to {} table", column, table),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
     return Ok(());
 }
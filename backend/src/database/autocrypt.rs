@@ -0,0 +1,497 @@
+use async_std::sync::{Arc, Mutex};
+use async_std::task;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::database;
+use crate::mime_parser::autocrypt::{self, AutocryptHeader, Peer, PreferEncrypt};
+use crate::my_error::MyError;
+use crate::types::message::Header;
+
+/// Mailboxes without a special use that hold junk, deleted or the account's
+/// own mail, by the last part of their path.
+const SKIPPED_NAMES: [&str; 13] = [
+    "junk",
+    "junk e-mail",
+    "junk email",
+    "spam",
+    "bulk mail",
+    "trash",
+    "deleted items",
+    "deleted messages",
+    "sent",
+    "sent items",
+    "sent messages",
+    "sent mail",
+    "drafts",
+];
+
+/// Whether the messages of `mailbox_path` update the state of their senders.
+/// Junk and trash hold mail the user did not want, and sent mail and drafts
+/// are the account's own, Autocrypt Level 1 section 2.3.
+pub fn learns_from(
+    conn: &Connection,
+    username: &str,
+    address: &str,
+    mailbox_path: &str,
+) -> Result<bool, MyError> {
+    match database::mailbox::special_use(conn, username, address, mailbox_path) {
+        Ok(Some(special_use)) => {
+            return Ok(!["\\Junk", "\\Trash", "\\Sent", "\\Drafts"].contains(&special_use.as_str()))
+        }
+        Ok(None) => {
+            let name = mailbox_path
+                .rsplit(['/', '.'])
+                .next()
+                .unwrap_or(mailbox_path)
+                .to_lowercase();
+
+            return Ok(!SKIPPED_NAMES.contains(&name.as_str()));
+        }
+        Err(e) => return Err(e),
+    }
+}
+
+/// Updates the state of the sender of a message with its `headers` and
+/// `date` in milliseconds, part of the transaction that stores the message.
+/// Messages sent by the account itself, automated reports and messages
+/// without a single From address are left out.
+pub fn update_peer(
+    conn: &Connection,
+    username: &str,
+    address: &str,
+    headers: &[Header],
+    date: i64,
+    now: i64,
+) -> Result<(), MyError> {
+    let sender = match autocrypt::sender(headers) {
+        Some(sender) if sender != username.trim().to_lowercase() => sender,
+        _ => return Ok(()),
+    };
+
+    let content_type = headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case("Content-Type"))
+        .map(|header| header.value.as_str());
+    if !autocrypt::updates_peer(content_type) {
+        return Ok(());
+    }
+
+    let mut peer = match get_peer(conn, username, address, &sender) {
+        Ok(peer) => peer.unwrap_or_default(),
+        Err(e) => return Err(e),
+    };
+
+    let header = autocrypt::from_headers(headers, &sender);
+    if peer.update(header.as_ref(), effective_date(date, now)) {
+        return put_peer(conn, username, address, &sender, &peer);
+    }
+
+    return Ok(());
+}
+
+/// Updates the gossip keys of the peers with the `Autocrypt-Gossip` headers
+/// of a decrypted message sent at `date`, unless `mailbox_path` is left out
+/// by `learns_from`.
+pub async fn update_gossip(
+    conn: Arc<Mutex<Connection>>,
+    username: &str,
+    address: &str,
+    mailbox_path: &str,
+    headers: &[AutocryptHeader],
+    date: i64,
+    now: i64,
+) -> Result<(), MyError> {
+    let locked_conn = conn.lock().await;
+    let mut changed = false;
+
+    match learns_from(&locked_conn, username, address, mailbox_path) {
+        Ok(true) => {}
+        Ok(false) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    for header in headers {
+        let mut peer = match get_peer(&locked_conn, username, address, &header.addr) {
+            Ok(peer) => peer.unwrap_or_default(),
+            Err(e) => return Err(e),
+        };
+
+        if peer.update_gossip(header, effective_date(date, now)) {
+            match put_peer(&locked_conn, username, address, &header.addr, &peer) {
+                Ok(_) => changed = true,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    drop(locked_conn);
+    if changed {
+        task::spawn(async move {
+            match database::backup(conn).await {
+                Ok(_) => {}
+                Err(e) => e.log_error(),
+            }
+        });
+    }
+
+    return Ok(());
+}
+
+/// State of `peer`, None when no message from or about it was seen.
+pub async fn get(
+    conn: Arc<Mutex<Connection>>,
+    username: &str,
+    address: &str,
+    peer: &str,
+) -> Result<Option<Peer>, MyError> {
+    let locked_conn = conn.lock().await;
+
+    return get_peer(&locked_conn, username, address, &peer.to_lowercase());
+}
+
+/// Preference the account announces, no preference unless set.
+pub async fn get_preference(
+    conn: Arc<Mutex<Connection>>,
+    username: &str,
+    address: &str,
+) -> Result<PreferEncrypt, MyError> {
+    let locked_conn = conn.lock().await;
+
+    match locked_conn
+        .query_row(
+            "SELECT prefer_encrypt FROM autocrypt_accounts WHERE c_username = ?1 AND c_address = ?2",
+            params![username, address],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()
+    {
+        Ok(prefer_encrypt) => {
+            return Ok(prefer_encrypt
+                .and_then(|name| PreferEncrypt::from_name(&name))
+                .unwrap_or_default())
+        }
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error getting Autocrypt preference"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+pub async fn set_preference(
+    conn: Arc<Mutex<Connection>>,
+    username: &str,
+    address: &str,
+    prefer_encrypt: PreferEncrypt,
+) -> Result<(), MyError> {
+    let locked_conn = conn.lock().await;
+
+    match locked_conn.execute(
+        "INSERT OR REPLACE INTO autocrypt_accounts (c_username, c_address, prefer_encrypt) VALUES (?1, ?2, ?3)",
+        params![username, address, prefer_encrypt.name()],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error setting Autocrypt preference"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    drop(locked_conn);
+    task::spawn(async move {
+        match database::backup(conn).await {
+            Ok(_) => {}
+            Err(e) => e.log_error(),
+        }
+    });
+
+    return Ok(());
+}
+
+/// Messages dated in the future count as sent now, and so do messages
+/// without a date.
+fn effective_date(date: i64, now: i64) -> i64 {
+    match date {
+        date if date > 0 => return date.min(now),
+        _ => return now,
+    }
+}
+
+fn get_peer(
+    conn: &Connection,
+    username: &str,
+    address: &str,
+    peer: &str,
+) -> Result<Option<Peer>, MyError> {
+    let row = conn
+        .query_row(
+            "SELECT last_seen, autocrypt_timestamp, public_key, prefer_encrypt, gossip_timestamp, gossip_key
+FROM autocrypt_peers WHERE c_username = ?1 AND c_address = ?2 AND peer = ?3",
+            params![username, address, peer],
+            |row| {
+                let public_key: Option<String> = row.get(2)?;
+                let prefer_encrypt: String = row.get(3)?;
+                let gossip_key: Option<String> = row.get(5)?;
+
+                Ok(Peer {
+                    last_seen: row.get(0)?,
+                    autocrypt_timestamp: row.get(1)?,
+                    public_key: public_key.and_then(|key| BASE64_STANDARD.decode(key).ok()),
+                    prefer_encrypt: PreferEncrypt::from_name(&prefer_encrypt).unwrap_or_default(),
+                    gossip_timestamp: row.get(4)?,
+                    gossip_key: gossip_key.and_then(|key| BASE64_STANDARD.decode(key).ok()),
+                })
+            },
+        )
+        .optional();
+
+    match row {
+        Ok(peer) => return Ok(peer),
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error getting Autocrypt peer"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+fn put_peer(
+    conn: &Connection,
+    username: &str,
+    address: &str,
+    address_of_peer: &str,
+    peer: &Peer,
+) -> Result<(), MyError> {
+    match conn.execute(
+        "INSERT OR REPLACE INTO autocrypt_peers (
+c_username,
+c_address,
+peer,
+last_seen,
+autocrypt_timestamp,
+public_key,
+prefer_encrypt,
+gossip_timestamp,
+gossip_key
+) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            username,
+            address,
+            address_of_peer,
+            peer.last_seen,
+            peer.autocrypt_timestamp,
+            peer.public_key
+                .as_ref()
+                .map(|key| BASE64_STANDARD.encode(key)),
+            peer.prefer_encrypt.name(),
+            peer.gossip_timestamp,
+            peer.gossip_key
+                .as_ref()
+                .map(|key| BASE64_STANDARD.encode(key))
+        ],
+    ) {
+        Ok(_) => return Ok(()),
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error storing Autocrypt peer"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mime_parser::headers;
+    use crate::openpgp::key;
+
+    const BOB_PUBLIC: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/openpgp/bob_public.asc"
+    ));
+
+    const NOW: i64 = 1_900_000_000_000;
+
+    async fn get_conn() -> Arc<Mutex<Connection>> {
+        let conn = Connection::open_in_memory().unwrap();
+        database::initialise(&conn).await.unwrap();
+
+        conn.execute(
+            "INSERT INTO connections (username, password, address, port) VALUES ('u@example.com', 'p', 'a', 993)",
+            params![],
+        )
+        .unwrap();
+
+        return Arc::new(Mutex::new(conn));
+    }
+
+    fn bob() -> AutocryptHeader {
+        return AutocryptHeader {
+            addr: String::from("bob@example.com"),
+            prefer_encrypt: PreferEncrypt::Mutual,
+            keydata: key::read_armored(BOB_PUBLIC).unwrap()[0].public_bytes(),
+        };
+    }
+
+    #[async_std::test]
+    async fn peers() {
+        let conn = get_conn().await;
+
+        let source = autocrypt::add_headers(
+            "From: Bob <Bob@example.com>\r\nTo: u@example.com\r\n\r\nHi\r\n",
+            &autocrypt::format(autocrypt::HEADER, &bob()),
+        );
+        update_peer(
+            &*conn.lock().await,
+            "u@example.com",
+            "a",
+            &headers::parse(&source),
+            // Dated in the future.
+            NOW + 1000,
+            NOW,
+        )
+        .unwrap();
+
+        let peer = get(Arc::clone(&conn), "u@example.com", "a", "Bob@Example.com")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer.last_seen, NOW);
+        assert_eq!(peer.autocrypt_timestamp, NOW);
+        assert_eq!(peer.prefer_encrypt, PreferEncrypt::Mutual);
+        assert_eq!(peer.public_key, Some(bob().keydata));
+
+        // Own messages and reports do not make peers.
+        let own = headers::parse("From: u@example.com\r\n\r\n");
+        update_peer(&*conn.lock().await, "u@example.com", "a", &own, NOW, NOW).unwrap();
+        assert!(
+            get(Arc::clone(&conn), "u@example.com", "a", "u@example.com")
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        let report = headers::parse(
+            "From: mailer-daemon@example.com\r\nContent-Type: multipart/report; report-type=delivery-status\r\n\r\n",
+        );
+        update_peer(&*conn.lock().await, "u@example.com", "a", &report, NOW, NOW).unwrap();
+        assert!(get(
+            Arc::clone(&conn),
+            "u@example.com",
+            "a",
+            "mailer-daemon@example.com"
+        )
+        .await
+        .unwrap()
+        .is_none());
+
+        let mut carol = bob();
+        carol.addr = String::from("carol@example.com");
+        update_gossip(
+            Arc::clone(&conn),
+            "u@example.com",
+            "a",
+            "INBOX",
+            &[carol],
+            0,
+            NOW,
+        )
+        .await
+        .unwrap();
+
+        let mut dave = bob();
+        dave.addr = String::from("dave@example.com");
+        update_gossip(
+            Arc::clone(&conn),
+            "u@example.com",
+            "a",
+            "Junk",
+            &[dave],
+            0,
+            NOW,
+        )
+        .await
+        .unwrap();
+        assert!(
+            get(Arc::clone(&conn), "u@example.com", "a", "dave@example.com")
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        let peer = get(Arc::clone(&conn), "u@example.com", "a", "carol@example.com")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer.gossip_timestamp, NOW);
+        assert_eq!(peer.public_key, None);
+        assert!(peer.gossip_key.is_some());
+    }
+
+    #[async_std::test]
+    async fn skipped_mailboxes() {
+        let conn = get_conn().await;
+
+        database::mailbox::insert(
+            Arc::clone(&conn),
+            "u@example.com",
+            "a",
+            &[
+                (String::from("INBOX"), None),
+                (String::from("Bulk"), Some(String::from("\\Junk"))),
+                (String::from("Spam"), Some(String::from("\\Archive"))),
+                (String::from("[Gmail]/Trash"), None),
+                (String::from("INBOX.Sent Items"), None),
+            ],
+        )
+        .await
+        .unwrap();
+
+        let locked_conn = conn.lock().await;
+        let learns_from = |path: &str| -> bool {
+            return learns_from(&locked_conn, "u@example.com", "a", path).unwrap();
+        };
+
+        assert!(learns_from("INBOX"));
+        assert!(!learns_from("Bulk"));
+        // The special use goes before the name.
+        assert!(learns_from("Spam"));
+        assert!(!learns_from("[Gmail]/Trash"));
+        assert!(!learns_from("INBOX.Sent Items"));
+        assert!(!learns_from("Junk"));
+    }
+
+    #[async_std::test]
+    async fn preference() {
+        let conn = get_conn().await;
+
+        assert_eq!(
+            get_preference(Arc::clone(&conn), "u@example.com", "a")
+                .await
+                .unwrap(),
+            PreferEncrypt::NoPreference
+        );
+
+        set_preference(
+            Arc::clone(&conn),
+            "u@example.com",
+            "a",
+            PreferEncrypt::Mutual,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            get_preference(Arc::clone(&conn), "u@example.com", "a")
+                .await
+                .unwrap(),
+            PreferEncrypt::Mutual
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use async_std::sync::{Arc, Mutex};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::my_error::MyError;
+use crate::types::mailbox_changes::ChangesSince;
+
+/// Bumps the change counter of a mailbox and records the new value as the
+/// latest change of every message in `message_uids`. Called inside the
+/// transaction that modifies the messages, so a change is never visible
+/// without its counter.
+pub fn record(
+    conn: &Connection,
+    username: &str,
+    address: &str,
+    mailbox_path: &str,
+    message_uids: &[u32],
+    removed: bool,
+) -> Result<u32, MyError> {
+    if message_uids.is_empty() {
+        return Ok(0);
+    }
+
+    let modseq: u32 = match conn.query_row(
+        "INSERT INTO mailbox_changes (c_username, c_address, m_path, modseq) VALUES (?1, ?2, ?3, 1)
+ON CONFLICT (c_username, c_address, m_path) DO UPDATE SET modseq = modseq + 1
+RETURNING modseq",
+        params![username, address, mailbox_path],
+        |row| row.get(0),
+    ) {
+        Ok(modseq) => modseq,
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error updating mailbox change counter"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    let mut stmt = match conn.prepare_cached(
+        "INSERT OR REPLACE INTO message_changes (
+c_username,
+c_address,
+m_path,
+message_uid,
+modseq,
+removed
+) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    ) {
+        Ok(stmt) => stmt,
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error preparing statement at changes"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    for message_uid in message_uids {
+        match stmt.execute(params![
+            username,
+            address,
+            mailbox_path,
+            message_uid,
+            modseq,
+            removed
+        ]) {
+            Ok(_) => {}
+            Err(e) => {
+                let err = MyError::Sqlite(e, String::from("Error recording message change"));
+                err.log_error();
+
+                return Err(err);
+            }
+        }
+    }
+
+    return Ok(modseq);
+}
+
+/// Returns the messages changed and removed after `cursor`. A cursor ahead
+/// of the counter was handed out before the database was reset, so the
+/// client has to start over and gets every change since the beginning.
+pub async fn get_since(
+    conn: Arc<Mutex<Connection>>,
+    username: &str,
+    address: &str,
+    mailbox_path: &str,
+    cursor: u32,
+) -> Result<ChangesSince, MyError> {
+    let locked_conn = conn.lock().await;
+
+    let modseq: u32 = match locked_conn
+        .query_row(
+            "SELECT modseq FROM mailbox_changes WHERE c_username = ?1 AND c_address = ?2 AND m_path = ?3",
+            params![username, address, mailbox_path],
+            |row| row.get(0),
+        )
+        .optional()
+    {
+        Ok(modseq) => modseq.unwrap_or(0),
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error getting mailbox change counter"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    let reset = cursor > modseq;
+    let since = if reset { 0 } else { cursor };
+
+    let mut stmt = match locked_conn.prepare_cached(
+        "SELECT message_uid, removed FROM message_changes
+WHERE c_username = ?1 AND c_address = ?2 AND m_path = ?3 AND modseq > ?4
+ORDER BY modseq, message_uid",
+    ) {
+        Ok(stmt) => stmt,
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error preparing statement at changes"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    let rows: Vec<(u32, bool)> = match stmt
+        .query_map(params![username, address, mailbox_path, since], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        }) {
+        Ok(rows) => rows.filter_map(|row| row.ok()).collect(),
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error getting changes from database"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    let mut changes = ChangesSince {
+        cursor: modseq,
+        reset,
+        changed: vec![],
+        removed: vec![],
+    };
+
+    for (message_uid, removed) in rows {
+        if removed {
+            changes.removed.push(message_uid);
+        } else {
+            changes.changed.push(message_uid);
+        }
+    }
+
+    return Ok(changes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database;
+
+    async fn get_conn() -> Arc<Mutex<Connection>> {
+        let conn = Connection::open_in_memory().unwrap();
+        database::initialise(&conn).await.unwrap();
+
+        return Arc::new(Mutex::new(conn));
+    }
+
+    #[async_std::test]
+    async fn changes_since_cursor() {
+        let conn = get_conn().await;
+
+        {
+            let locked_conn = conn.lock().await;
+            assert_eq!(
+                record(&locked_conn, "u", "a", "INBOX", &[1, 2, 3], false).unwrap(),
+                1
+            );
+            assert_eq!(
+                record(&locked_conn, "u", "a", "INBOX", &[2], false).unwrap(),
+                2
+            );
+            assert_eq!(
+                record(&locked_conn, "u", "a", "INBOX", &[3], true).unwrap(),
+                3
+            );
+            assert_eq!(
+                record(&locked_conn, "u", "a", "Sent", &[9], false).unwrap(),
+                1
+            );
+            assert_eq!(
+                record(&locked_conn, "u", "a", "INBOX", &[], false).unwrap(),
+                0
+            );
+        }
+
+        let changes = get_since(Arc::clone(&conn), "u", "a", "INBOX", 0)
+            .await
+            .unwrap();
+        assert_eq!(changes.cursor, 3);
+        assert!(!changes.reset);
+        assert_eq!(changes.changed, vec![1, 2]);
+        assert_eq!(changes.removed, vec![3]);
+
+        let changes = get_since(Arc::clone(&conn), "u", "a", "INBOX", 1)
+            .await
+            .unwrap();
+        assert_eq!(changes.changed, vec![2]);
+        assert_eq!(changes.removed, vec![3]);
+
+        let changes = get_since(Arc::clone(&conn), "u", "a", "INBOX", 3)
+            .await
+            .unwrap();
+        assert!(changes.changed.is_empty() && changes.removed.is_empty());
+
+        let changes = get_since(Arc::clone(&conn), "u", "a", "INBOX", 7)
+            .await
+            .unwrap();
+        assert!(changes.reset);
+        assert_eq!(changes.changed, vec![1, 2]);
+
+        let changes = get_since(conn, "u", "a", "Drafts", 0).await.unwrap();
+        assert_eq!(changes.cursor, 0);
+        assert!(changes.changed.is_empty());
+    }
+}
@@ -3,24 +3,71 @@ use async_std::task;
 use rusqlite::{params, Connection};
 
 use crate::database;
+use crate::database::credentials::CredentialStore;
 use crate::my_error::MyError;
-use crate::types::session::Client;
+use crate::types::session::{AuthMechanism, Client, OAuth, Security};
+
+/// Stores a connection, the settings and secrets of a known one are
+/// replaced.
+pub async fn insert(
+    conn: Arc<Mutex<Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+) -> Result<(), MyError> {
+    let password = match credentials.encrypt(&client.password) {
+        Ok(password) => password,
+        Err(e) => return Err(e),
+    };
+    let client_secret = match credentials
+        .encrypt_optional(client.oauth.as_ref().and_then(|o| o.client_secret.as_ref()))
+    {
+        Ok(client_secret) => client_secret,
+        Err(e) => return Err(e),
+    };
+    let refresh_token =
+        match credentials.encrypt_optional(client.oauth.as_ref().map(|o| &o.refresh_token)) {
+            Ok(refresh_token) => refresh_token,
+            Err(e) => return Err(e),
+        };
 
-pub async fn insert(conn: Arc<Mutex<Connection>>, client: &Client) -> Result<(), MyError> {
     let locked_conn = conn.lock().await;
 
     match locked_conn.execute(
-        "INSERT OR IGNORE INTO connections (
+        "INSERT INTO connections (
               username,
               password,
               address,
-              port
-          ) VALUES (?1, ?2, ?3, ?4)",
+              port,
+              security,
+              cert_fingerprint,
+              auth_mechanism,
+              token_endpoint,
+              client_id,
+              client_secret,
+              refresh_token
+          ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
+          ON CONFLICT(username, address) DO UPDATE SET
+              password = excluded.password,
+              port = excluded.port,
+              security = excluded.security,
+              cert_fingerprint = excluded.cert_fingerprint,
+              auth_mechanism = excluded.auth_mechanism,
+              token_endpoint = excluded.token_endpoint,
+              client_id = excluded.client_id,
+              client_secret = excluded.client_secret,
+              refresh_token = excluded.refresh_token",
         params![
             client.username,
-            client.password,
+            password,
             client.address,
-            client.port
+            client.port,
+            client.security.as_str(),
+            client.cert_fingerprint,
+            client.auth_mechanism.as_str(),
+            client.oauth.as_ref().map(|o| &o.token_endpoint),
+            client.oauth.as_ref().map(|o| &o.client_id),
+            client_secret,
+            refresh_token
         ],
     ) {
         Ok(_) => (),
@@ -43,10 +90,17 @@ pub async fn insert(conn: Arc<Mutex<Connection>>, client: &Client) -> Result<(),
     return Ok(());
 }
 
-pub async fn get(conn: Arc<Mutex<Connection>>) -> Result<Vec<Client>, MyError> {
+/// Returns the stored connections with their secrets decrypted, for opening
+/// IMAP sessions only. Secrets are never sent to API clients.
+pub async fn get(
+    conn: Arc<Mutex<Connection>>,
+    credentials: &CredentialStore,
+) -> Result<Vec<Client>, MyError> {
     let locked_conn = conn.lock().await;
 
-    let mut stmt = match locked_conn.prepare_cached("SELECT * FROM connections") {
+    let mut stmt = match locked_conn.prepare_cached(
+        "SELECT username, password, address, port, security, cert_fingerprint, auth_mechanism, token_endpoint, client_id, client_secret, refresh_token FROM connections",
+    ) {
         Ok(stmt) => stmt,
         Err(e) => {
             let err = MyError::Sqlite(e, String::from("Error preparing statement at connections"));
@@ -57,17 +111,46 @@ pub async fn get(conn: Arc<Mutex<Connection>>) -> Result<Vec<Client>, MyError> {
     };
 
     match stmt.query_map(params![], |row| {
-        Ok(Client {
+        let password = match credentials.decrypt(&row.get::<_, String>(1)?) {
+            Ok(password) => password,
+            Err(e) => return Ok(Err(e)),
+        };
+        let client_secret = match credentials.decrypt_optional(row.get(9)?) {
+            Ok(client_secret) => client_secret,
+            Err(e) => return Ok(Err(e)),
+        };
+        let refresh_token = match credentials.decrypt_optional(row.get(10)?) {
+            Ok(refresh_token) => refresh_token,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        Ok(Ok(Client {
             username: row.get(0).unwrap(),
-            password: row.get(1).unwrap(),
+            password,
             address: row.get(2).unwrap(),
             port: row.get(3).unwrap(),
-        })
+            security: Security::from_str(&row.get::<_, String>(4).unwrap())
+                .unwrap_or(Security::Tls),
+            cert_fingerprint: row.get(5).unwrap(),
+            auth_mechanism: AuthMechanism::from_str(&row.get::<_, String>(6).unwrap())
+                .unwrap_or(AuthMechanism::Password),
+            oauth: match (row.get(7).unwrap(), row.get(8).unwrap(), refresh_token) {
+                (Some(token_endpoint), Some(client_id), Some(refresh_token)) => Some(OAuth {
+                    token_endpoint,
+                    client_id,
+                    client_secret,
+                    refresh_token,
+                }),
+                _ => None,
+            },
+        }))
     }) {
         Ok(rows) => {
             let connections: Vec<Client> = rows
                 .filter_map(|client| match client {
-                    Ok(client) => Some(client),
+                    Ok(Ok(client)) => Some(client),
+                    // Already logged, the account has to be logged in again.
+                    Ok(Err(_)) => None,
                     Err(e) => {
                         let err = MyError::Sqlite(
                             e,
@@ -117,3 +200,40 @@ pub async fn remove(conn: Arc<Mutex<Connection>>, client: &Client) -> Result<(),
 
     return Ok(());
 }
+
+pub async fn update_refresh_token(
+    conn: Arc<Mutex<Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+    refresh_token: &str,
+) -> Result<(), MyError> {
+    let refresh_token = match credentials.encrypt(refresh_token) {
+        Ok(refresh_token) => refresh_token,
+        Err(e) => return Err(e),
+    };
+
+    let locked_conn = conn.lock().await;
+
+    match locked_conn.execute(
+        "UPDATE connections SET refresh_token = ?1 WHERE username = ?2 AND address = ?3",
+        params![refresh_token, &client.username, &client.address],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error updating refresh token in database"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    drop(locked_conn);
+    task::spawn(async move {
+        match database::backup(conn).await {
+            Ok(_) => {}
+            Err(e) => e.log_error(),
+        }
+    });
+
+    return Ok(());
+}
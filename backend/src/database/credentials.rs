@@ -0,0 +1,632 @@
+use argon2::Argon2;
+use base64::{engine::general_purpose, Engine};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex as SyncMutex;
+
+use crate::my_error::MyError;
+use crate::types::error_code::ErrorCode;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const SALT_LEN: usize = 16;
+
+/// Prefix of every encrypted value, values without it are plaintext written by
+/// an older version and are encrypted when the store is first opened.
+const PREFIX: &str = "enc1:";
+
+/// Known plaintext encrypted with the master key, tells a wrong passphrase or
+/// key file apart from corrupted secrets.
+const VERIFIER: &str = "mail_backend credential store";
+
+const DEFAULT_KEY_FILE: &str = "mail.key";
+
+/// Tables and columns that hold secrets.
+const SECRET_COLUMNS: [(&str, &str); 6] = [
+    ("connections", "password"),
+    ("connections", "client_secret"),
+    ("connections", "refresh_token"),
+    ("openpgp_keys", "secret_key"),
+    ("openpgp_keys", "passphrase"),
+    ("smime_identities", "private_key"),
+];
+
+#[derive(Clone, PartialEq)]
+pub enum KeySource {
+    Passphrase(String),
+    KeyFile(String),
+}
+
+impl KeySource {
+    /// Uses `MAIL_MASTER_PASSPHRASE` when set, otherwise the key file at
+    /// `MAIL_KEY_FILE` or `mail.key`, which is generated on first use.
+    /// The key file defaults to `mail.key` in the data directory.
+    pub fn from_env(data_dir: &Path) -> KeySource {
+        if let Ok(passphrase) = std::env::var("MAIL_MASTER_PASSPHRASE") {
+            if !passphrase.is_empty() {
+                return KeySource::Passphrase(passphrase);
+            }
+        }
+
+        match std::env::var("MAIL_KEY_FILE") {
+            Ok(path) if !path.is_empty() => return KeySource::KeyFile(path),
+            _ => {
+                let path = data_dir.join(DEFAULT_KEY_FILE);
+
+                return KeySource::KeyFile(path.to_string_lossy().to_string());
+            }
+        }
+    }
+
+    fn secret(&self) -> Result<Vec<u8>, MyError> {
+        match self {
+            KeySource::Passphrase(passphrase) => {
+                if passphrase.is_empty() {
+                    let err = MyError::Code(
+                        ErrorCode::InvalidRequest,
+                        String::from("Empty passphrase"),
+                        String::from("Master passphrase must not be empty"),
+                    );
+                    err.log_error();
+
+                    return Err(err);
+                }
+
+                return Ok(passphrase.as_bytes().to_vec());
+            }
+            KeySource::KeyFile(path) => return read_or_create_key_file(path),
+        }
+    }
+}
+
+pub struct CredentialStore {
+    key: SyncMutex<[u8; KEY_LEN]>,
+}
+
+impl CredentialStore {
+    /// Derives the master key and checks it against the stored verifier. The
+    /// first time a database is opened the verifier is created and plaintext
+    /// secrets left by older versions are encrypted.
+    pub fn open(conn: &Connection, source: &KeySource) -> Result<CredentialStore, MyError> {
+        let stored: Option<(String, String)> = match conn
+            .query_row(
+                "SELECT salt, verifier FROM credential_store WHERE id = 0",
+                params![],
+                |row| match (row.get(0), row.get(1)) {
+                    (Ok(salt), Ok(verifier)) => return Ok((salt, verifier)),
+                    (Err(e), _) | (_, Err(e)) => return Err(e),
+                },
+            )
+            .optional()
+        {
+            Ok(stored) => stored,
+            Err(e) => {
+                let err = MyError::Sqlite(e, String::from("Error reading credential store"));
+                err.log_error();
+
+                return Err(err);
+            }
+        };
+
+        let (salt, verifier) = match stored {
+            Some((salt, verifier)) => match general_purpose::STANDARD.decode(salt) {
+                Ok(salt) => (salt, Some(verifier)),
+                Err(e) => {
+                    let err = MyError::Base64(e, String::from("Error decoding master key salt"));
+                    err.log_error();
+
+                    return Err(err);
+                }
+            },
+            None => (random_bytes(SALT_LEN), None),
+        };
+
+        let store = match CredentialStore::derive(source, &salt) {
+            Ok(store) => store,
+            Err(e) => return Err(e),
+        };
+
+        if let Some(verifier) = verifier {
+            match store.decrypt(&verifier) {
+                Ok(v) if v == VERIFIER => return Ok(store),
+                _ => {
+                    let err = MyError::Code(
+                        ErrorCode::WrongMasterKey,
+                        String::from("Verifier mismatch"),
+                        String::from("Wrong master passphrase or key file"),
+                    );
+                    err.log_error();
+
+                    return Err(err);
+                }
+            }
+        }
+
+        match store.reencrypt_secrets(conn, |value| return Ok(value.to_string())) {
+            Ok(_) => {}
+            Err(e) => return Err(e),
+        };
+
+        match store.save_verifier(conn, &salt) {
+            Ok(_) => return Ok(store),
+            Err(e) => return Err(e),
+        }
+    }
+
+    fn derive(source: &KeySource, salt: &[u8]) -> Result<CredentialStore, MyError> {
+        let secret = match source.secret() {
+            Ok(secret) => secret,
+            Err(e) => return Err(e),
+        };
+
+        let mut key = [0u8; KEY_LEN];
+
+        match Argon2::default().hash_password_into(&secret, salt, &mut key) {
+            Ok(_) => {}
+            Err(e) => {
+                let err = MyError::String(e.to_string(), String::from("Error deriving master key"));
+                err.log_error();
+
+                return Err(err);
+            }
+        }
+
+        return Ok(CredentialStore {
+            key: SyncMutex::new(key),
+        });
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> Result<String, MyError> {
+        let key = *self.key.lock().unwrap();
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+
+        let mut data = random_bytes(NONCE_LEN);
+        match cipher.encrypt(Nonce::from_slice(&data), plaintext.as_bytes()) {
+            Ok(ciphertext) => data.extend_from_slice(&ciphertext),
+            Err(e) => {
+                let err = MyError::String(e.to_string(), String::from("Error encrypting secret"));
+                err.log_error();
+
+                return Err(err);
+            }
+        };
+
+        return Ok(format!(
+            "{}{}",
+            PREFIX,
+            general_purpose::STANDARD.encode(data)
+        ));
+    }
+
+    pub fn decrypt(&self, value: &str) -> Result<String, MyError> {
+        let encoded = match value.strip_prefix(PREFIX) {
+            Some(encoded) => encoded,
+            None => {
+                let err = MyError::String(
+                    String::from("Missing prefix"),
+                    String::from("Secret is not encrypted"),
+                );
+                err.log_error();
+
+                return Err(err);
+            }
+        };
+
+        let data = match general_purpose::STANDARD.decode(encoded) {
+            Ok(data) if data.len() > NONCE_LEN => data,
+            Ok(_) => {
+                let err = MyError::String(
+                    String::from("Ciphertext too short"),
+                    String::from("Error decrypting secret"),
+                );
+                err.log_error();
+
+                return Err(err);
+            }
+            Err(e) => {
+                let err = MyError::Base64(e, String::from("Error decoding secret"));
+                err.log_error();
+
+                return Err(err);
+            }
+        };
+
+        let key = *self.key.lock().unwrap();
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        let plaintext = match cipher.decrypt(Nonce::from_slice(nonce), ciphertext) {
+            Ok(plaintext) => plaintext,
+            Err(e) => {
+                let err = MyError::String(e.to_string(), String::from("Error decrypting secret"));
+                err.log_error();
+
+                return Err(err);
+            }
+        };
+
+        match String::from_utf8(plaintext) {
+            Ok(plaintext) => return Ok(plaintext),
+            Err(e) => {
+                let err = MyError::FromUtf8(e, String::from("Error decrypting secret"));
+                err.log_error();
+
+                return Err(err);
+            }
+        }
+    }
+
+    pub fn encrypt_optional(&self, plaintext: Option<&String>) -> Result<Option<String>, MyError> {
+        match plaintext {
+            Some(plaintext) => return self.encrypt(plaintext).map(Some),
+            None => return Ok(None),
+        }
+    }
+
+    pub fn decrypt_optional(&self, value: Option<String>) -> Result<Option<String>, MyError> {
+        match value {
+            Some(value) => return self.decrypt(&value).map(Some),
+            None => return Ok(None),
+        }
+    }
+
+    /// Re-encrypts every stored secret under a key derived from `source`,
+    /// either all secrets are rewritten or none are.
+    pub fn change_key(&self, conn: &Connection, source: &KeySource) -> Result<(), MyError> {
+        let salt = random_bytes(SALT_LEN);
+
+        let new_store = match CredentialStore::derive(source, &salt) {
+            Ok(new_store) => new_store,
+            Err(e) => return Err(e),
+        };
+
+        match conn.execute_batch("BEGIN") {
+            Ok(_) => {}
+            Err(e) => {
+                let err = MyError::Sqlite(e, String::from("Error starting transaction"));
+                err.log_error();
+
+                return Err(err);
+            }
+        }
+
+        let result = match new_store.reencrypt_secrets(conn, |value| return self.decrypt(value)) {
+            Ok(_) => new_store.save_verifier(conn, &salt),
+            Err(e) => Err(e),
+        };
+
+        let end = match result {
+            Ok(_) => "COMMIT",
+            Err(_) => "ROLLBACK",
+        };
+
+        match conn.execute_batch(end) {
+            Ok(_) => {}
+            Err(e) => {
+                let err = MyError::Sqlite(e, String::from("Error ending transaction"));
+                err.log_error();
+
+                return Err(err);
+            }
+        }
+
+        match result {
+            Ok(_) => {}
+            Err(e) => return Err(e),
+        };
+
+        *self.key.lock().unwrap() = *new_store.key.lock().unwrap();
+
+        return Ok(());
+    }
+
+    fn save_verifier(&self, conn: &Connection, salt: &[u8]) -> Result<(), MyError> {
+        let verifier = match self.encrypt(VERIFIER) {
+            Ok(verifier) => verifier,
+            Err(e) => return Err(e),
+        };
+
+        match conn.execute(
+            "INSERT OR REPLACE INTO credential_store (id, salt, verifier) VALUES (0, ?1, ?2)",
+            params![general_purpose::STANDARD.encode(salt), verifier],
+        ) {
+            Ok(_) => {}
+            Err(e) => {
+                let err = MyError::Sqlite(e, String::from("Error saving credential store"));
+                err.log_error();
+
+                return Err(err);
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Rewrites every secret column, `to_plaintext` turns a stored value into
+    /// plaintext which is then encrypted with this store's key.
+    fn reencrypt_secrets<F>(&self, conn: &Connection, to_plaintext: F) -> Result<(), MyError>
+    where
+        F: Fn(&str) -> Result<String, MyError>,
+    {
+        for (table, column) in SECRET_COLUMNS {
+            let mut stmt = match conn.prepare(&format!(
+                "SELECT rowid, {} FROM {} WHERE {} IS NOT NULL",
+                column, table, column
+            )) {
+                Ok(stmt) => stmt,
+                Err(e) => {
+                    let err = MyError::Sqlite(e, String::from("Error reading stored secrets"));
+                    err.log_error();
+
+                    return Err(err);
+                }
+            };
+
+            let rows: Vec<(i64, String)> =
+                match stmt.query_map(params![], |row| match (row.get(0), row.get(1)) {
+                    (Ok(rowid), Ok(value)) => return Ok((rowid, value)),
+                    (Err(e), _) | (_, Err(e)) => return Err(e),
+                }) {
+                    Ok(rows) => rows.filter_map(|row| row.ok()).collect(),
+                    Err(e) => {
+                        let err = MyError::Sqlite(e, String::from("Error reading stored secrets"));
+                        err.log_error();
+
+                        return Err(err);
+                    }
+                };
+
+            for (rowid, value) in rows {
+                let plaintext = match to_plaintext(&value) {
+                    Ok(plaintext) => plaintext,
+                    Err(e) => return Err(e),
+                };
+
+                let encrypted = match self.encrypt(&plaintext) {
+                    Ok(encrypted) => encrypted,
+                    Err(e) => return Err(e),
+                };
+
+                match conn.execute(
+                    &format!("UPDATE {} SET {} = ?1 WHERE rowid = ?2", table, column),
+                    params![encrypted, rowid],
+                ) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        let err = MyError::Sqlite(e, String::from("Error writing stored secrets"));
+                        err.log_error();
+
+                        return Err(err);
+                    }
+                }
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    return bytes;
+}
+
+/// Reads the key file, or creates it with random contents readable only by
+/// the current user when it does not exist yet.
+fn read_or_create_key_file(path: &str) -> Result<Vec<u8>, MyError> {
+    match fs::read(path) {
+        Ok(contents) if !contents.is_empty() => return Ok(contents),
+        Ok(_) => {
+            let err = MyError::String(
+                format!("{} is empty", path),
+                String::from("Error reading key file"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            let err = MyError::Io(e, String::from("Error reading key file"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    let contents = general_purpose::STANDARD
+        .encode(random_bytes(KEY_LEN))
+        .into_bytes();
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    match options
+        .open(path)
+        .and_then(|mut file| file.write_all(&contents))
+    {
+        Ok(_) => return Ok(contents),
+        Err(e) => {
+            let err = MyError::Io(e, String::from("Error creating key file"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_conn() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+
+        conn.execute_batch(
+            "CREATE TABLE connections (
+                username VARCHAR(500) NOT NULL,
+                password VARCHAR(500) NOT NULL,
+                address VARCHAR(500) NOT NULL,
+                client_secret VARCHAR(500),
+                refresh_token TEXT,
+                PRIMARY KEY(username, address)
+            );
+            CREATE TABLE openpgp_keys (
+                c_username VARCHAR(500) NOT NULL,
+                c_address VARCHAR(500) NOT NULL,
+                fingerprint VARCHAR(100) NOT NULL,
+                secret_key TEXT,
+                passphrase TEXT,
+                PRIMARY KEY(c_username, c_address, fingerprint)
+            );
+            CREATE TABLE smime_identities (
+                c_username VARCHAR(500) NOT NULL,
+                c_address VARCHAR(500) NOT NULL,
+                fingerprint VARCHAR(100) NOT NULL,
+                private_key TEXT NOT NULL,
+                PRIMARY KEY(c_username, c_address, fingerprint)
+            );
+            CREATE TABLE credential_store (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                salt TEXT NOT NULL,
+                verifier TEXT NOT NULL
+            );",
+        )
+        .unwrap();
+
+        return conn;
+    }
+
+    fn get_column(conn: &Connection, column: &str) -> String {
+        return conn
+            .query_row(
+                &format!("SELECT {} FROM connections", column),
+                params![],
+                |row| row.get(0),
+            )
+            .unwrap();
+    }
+
+    fn passphrase(passphrase: &str) -> KeySource {
+        return KeySource::Passphrase(String::from(passphrase));
+    }
+
+    #[test]
+    fn encrypt_roundtrip() {
+        let conn = get_conn();
+        let store = CredentialStore::open(&conn, &passphrase("secret")).unwrap();
+
+        let encrypted = store.encrypt("hunter2").unwrap();
+
+        assert!(encrypted.starts_with(PREFIX));
+        assert!(!encrypted.contains("hunter2"));
+        assert_ne!(encrypted, store.encrypt("hunter2").unwrap());
+        assert_eq!(store.decrypt(&encrypted).unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn wrong_passphrase_rejected() {
+        let conn = get_conn();
+        CredentialStore::open(&conn, &passphrase("secret")).unwrap();
+
+        assert!(CredentialStore::open(&conn, &passphrase("wrong")).is_err());
+        assert!(CredentialStore::open(&conn, &passphrase("secret")).is_ok());
+    }
+
+    #[test]
+    fn plaintext_migrated() {
+        let conn = get_conn();
+        conn.execute(
+            "INSERT INTO connections (username, password, address) VALUES ('user', 'hunter2', 'imap.example.com')",
+            params![],
+        )
+        .unwrap();
+
+        let store = CredentialStore::open(&conn, &passphrase("secret")).unwrap();
+        let stored = get_column(&conn, "password");
+
+        assert!(stored.starts_with(PREFIX));
+        assert_eq!(store.decrypt(&stored).unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn change_key_reencrypts() {
+        let conn = get_conn();
+        let store = CredentialStore::open(&conn, &passphrase("old")).unwrap();
+
+        conn.execute(
+            "INSERT INTO connections (username, password, address, refresh_token) VALUES ('user', ?1, 'imap.example.com', ?2)",
+            params![store.encrypt("hunter2").unwrap(), store.encrypt("refresh").unwrap()],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO openpgp_keys (c_username, c_address, fingerprint, secret_key) VALUES ('user', 'imap.example.com', 'FPR', ?1)",
+            params![store.encrypt("secret key").unwrap()],
+        )
+        .unwrap();
+
+        store.change_key(&conn, &passphrase("new")).unwrap();
+
+        assert_eq!(
+            store.decrypt(&get_column(&conn, "password")).unwrap(),
+            "hunter2"
+        );
+        assert!(CredentialStore::open(&conn, &passphrase("old")).is_err());
+
+        let reopened = CredentialStore::open(&conn, &passphrase("new")).unwrap();
+
+        assert_eq!(
+            reopened.decrypt(&get_column(&conn, "password")).unwrap(),
+            "hunter2"
+        );
+        assert_eq!(
+            reopened
+                .decrypt(&get_column(&conn, "refresh_token"))
+                .unwrap(),
+            "refresh"
+        );
+
+        let secret_key: String = conn
+            .query_row("SELECT secret_key FROM openpgp_keys", params![], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(reopened.decrypt(&secret_key).unwrap(), "secret key");
+    }
+
+    #[test]
+    fn key_file_created() {
+        let path = std::env::temp_dir().join(format!("mail-key-{}", rand::random::<u64>()));
+        let path = path.to_str().unwrap().to_string();
+
+        let conn = get_conn();
+        let store = CredentialStore::open(&conn, &KeySource::KeyFile(path.clone())).unwrap();
+        let encrypted = store.encrypt("hunter2").unwrap();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let reopened = CredentialStore::open(&conn, &KeySource::KeyFile(path.clone())).unwrap();
+        assert_eq!(reopened.decrypt(&encrypted).unwrap(), "hunter2");
+
+        fs::remove_file(path).unwrap();
+    }
+}
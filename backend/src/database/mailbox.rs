@@ -1,15 +1,17 @@
 use async_std::sync::{Arc, Mutex};
 use async_std::task;
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, OptionalExtension};
 
 use crate::database;
 use crate::my_error::MyError;
 
+/// Stores `mailboxes` as path and special use, the special use of a known
+/// mailbox is updated.
 pub async fn insert(
     conn: Arc<Mutex<Connection>>,
     username: &str,
     address: &str,
-    mailbox_paths: &Vec<String>,
+    mailboxes: &[(String, Option<String>)],
 ) -> Result<(), MyError> {
     let mut locked_conn = conn.lock().await;
 
@@ -26,14 +28,16 @@ pub async fn insert(
         }
     };
 
-    for mailbox_path in mailbox_paths {
+    for (mailbox_path, special_use) in mailboxes {
         match tx.execute(
-            "INSERT OR IGNORE INTO mailboxes (
+            "INSERT INTO mailboxes (
                 c_username,
                 c_address,
-                path
-            ) VALUES (?1, ?2, ?3)",
-            params![username, address, mailbox_path],
+                path,
+                special_use
+            ) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT(c_username, c_address, path) DO UPDATE SET special_use = excluded.special_use",
+            params![username, address, mailbox_path, special_use],
         ) {
             Ok(_) => {}
             Err(e) => {
@@ -78,7 +82,7 @@ pub async fn get(
     let locked_conn = conn.lock().await;
 
     let mut stmt = match locked_conn
-        .prepare_cached("SELECT * FROM mailboxes WHERE c_username = ?1 AND c_address = ?2")
+        .prepare_cached("SELECT path FROM mailboxes WHERE c_username = ?1 AND c_address = ?2")
     {
         Ok(stmt) => stmt,
         Err(e) => {
@@ -91,7 +95,7 @@ pub async fn get(
 
     let mut mailboxes: Vec<String> = Vec::new();
 
-    match stmt.query_map(params![username, address], |row| row.get(2)) {
+    match stmt.query_map(params![username, address], |row| row.get(0)) {
         Ok(rows) => {
             for row in rows {
                 mailboxes.push(row.unwrap());
@@ -107,3 +111,29 @@ pub async fn get(
 
     return Ok(mailboxes);
 }
+
+/// Special use of a mailbox, e.g. `\\Junk`, None when the server lists it
+/// without one or the mailbox is not known.
+pub fn special_use(
+    conn: &Connection,
+    username: &str,
+    address: &str,
+    mailbox_path: &str,
+) -> Result<Option<String>, MyError> {
+    match conn
+        .query_row(
+            "SELECT special_use FROM mailboxes WHERE c_username = ?1 AND c_address = ?2 AND path = ?3",
+            params![username, address, mailbox_path],
+            |row| row.get::<_, Option<String>>(0),
+        )
+        .optional()
+    {
+        Ok(special_use) => return Ok(special_use.flatten()),
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error getting special use of mailbox"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
@@ -1,9 +1,12 @@
 use async_std::sync::{Arc, Mutex};
 use async_std::task;
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, OptionalExtension};
 
 use crate::database;
+use crate::mime_parser::pgp_mime::{self, PgpPart};
+use crate::mime_parser::smime::{self, SmimePart};
 use crate::my_error::MyError;
+use crate::types::message::{Header, InlinePart, MessageSecurity};
 use crate::types::session::Client;
 
 pub async fn update_flags(
@@ -58,6 +61,11 @@ WHERE message_uid = ?1 AND c_username = ?2 AND c_address = ?3 AND m_path = ?4 AN
         }
     }
 
+    match database::changes::record(&tx, username, address, mailbox_path, &[message_uid], false) {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    }
+
     match tx.commit() {
         Ok(_) => {}
         Err(e) => {
@@ -89,9 +97,22 @@ pub async fn change_mailbox(
     message_uid_new: u32,
     sequence_id_new: u32,
 ) -> Result<(), MyError> {
-    let locked_conn = conn.lock().await;
+    let mut locked_conn = conn.lock().await;
 
-    match locked_conn.execute(
+    let tx = match locked_conn.transaction() {
+        Ok(tx) => tx,
+        Err(e) => {
+            let err = MyError::Sqlite(
+                e,
+                String::from("Error starting transaction for moving message"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    match tx.execute(
         "UPDATE messages
 SET m_path = ?1, message_uid = ?2, sequence_id = ?3
 WHERE message_uid = ?4 AND c_username = ?5 AND c_address = ?6 AND m_path = ?7",
@@ -114,6 +135,36 @@ WHERE message_uid = ?4 AND c_username = ?5 AND c_address = ?6 AND m_path = ?7",
         }
     }
 
+    match database::changes::record(&tx, username, address, mailbox_path, &[message_uid], true) {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    }
+
+    match database::changes::record(
+        &tx,
+        username,
+        address,
+        mailbox_path_dest,
+        &[message_uid_new],
+        false,
+    ) {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    }
+
+    match tx.commit() {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(
+                e,
+                String::from("Error committing transaction for moving message"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
     drop(locked_conn);
     task::spawn(async move {
         match database::backup(conn).await {
@@ -175,9 +226,22 @@ pub async fn remove(
     mailbox_path: &str,
     message_uid: u32,
 ) -> Result<(), MyError> {
-    let locked_conn = conn.lock().await;
+    let mut locked_conn = conn.lock().await;
 
-    match locked_conn.execute(
+    let tx = match locked_conn.transaction() {
+        Ok(tx) => tx,
+        Err(e) => {
+            let err = MyError::Sqlite(
+                e,
+                String::from("Error starting transaction for deleting message"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    match tx.execute(
         "DELETE FROM messages WHERE message_uid = ?1 AND c_username = ?2 AND c_address = ?3 AND m_path = ?4",
         params![message_uid, &client.username, &client.address, mailbox_path],
     ) {
@@ -190,6 +254,500 @@ pub async fn remove(
         }
     }
 
+    match database::changes::record(
+        &tx,
+        &client.username,
+        &client.address,
+        mailbox_path,
+        &[message_uid],
+        true,
+    ) {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    }
+
+    match tx.commit() {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(
+                e,
+                String::from("Error committing transaction for deleting message"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    drop(locked_conn);
+    task::spawn(async move {
+        match database::backup(conn).await {
+            Ok(_) => {}
+            Err(e) => e.log_error(),
+        }
+    });
+
+    return Ok(());
+}
+
+pub async fn get_inline_parts(
+    conn: Arc<Mutex<Connection>>,
+    client: &Client,
+    mailbox_path: &str,
+    message_uid: u32,
+) -> Result<Vec<InlinePart>, MyError> {
+    let locked_conn = conn.lock().await;
+
+    let mut stmt = match locked_conn.prepare_cached(
+        "SELECT content_id, content_type, data FROM inline_parts
+WHERE message_uid = ?1 AND c_username = ?2 AND c_address = ?3 AND m_path = ?4",
+    ) {
+        Ok(stmt) => stmt,
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error preparing statement at inline parts"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    let rows = match stmt.query_map(
+        params![message_uid, &client.username, &client.address, mailbox_path],
+        |row| {
+            Ok(InlinePart {
+                content_id: row.get(0)?,
+                content_type: row.get(1)?,
+                data: row.get(2)?,
+            })
+        },
+    ) {
+        Ok(rows) => rows,
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error getting inline parts"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    match rows.collect::<Result<Vec<InlinePart>, rusqlite::Error>>() {
+        Ok(parts) => return Ok(parts),
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error reading inline parts"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+/// The text/calendar part of a message, `None` when it has none.
+pub async fn get_calendar(
+    conn: Arc<Mutex<Connection>>,
+    client: &Client,
+    mailbox_path: &str,
+    message_uid: u32,
+) -> Result<Option<String>, MyError> {
+    let locked_conn = conn.lock().await;
+
+    match locked_conn
+        .query_row(
+            "SELECT data FROM calendar_parts
+WHERE message_uid = ?1 AND c_username = ?2 AND c_address = ?3 AND m_path = ?4",
+            params![message_uid, &client.username, &client.address, mailbox_path],
+            |row| row.get(0),
+        )
+        .optional()
+    {
+        Ok(calendar) => return Ok(calendar),
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error getting calendar part"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+/// The OpenPGP data found when the message was fetched, `None` when it has
+/// none.
+pub async fn get_openpgp_part(
+    conn: Arc<Mutex<Connection>>,
+    client: &Client,
+    mailbox_path: &str,
+    message_uid: u32,
+) -> Result<Option<PgpPart>, MyError> {
+    let locked_conn = conn.lock().await;
+
+    let part = locked_conn
+        .query_row(
+            "SELECT kind, data, signed FROM openpgp_parts
+WHERE message_uid = ?1 AND c_username = ?2 AND c_address = ?3 AND m_path = ?4",
+            params![message_uid, &client.username, &client.address, mailbox_path],
+            |row| {
+                let kind: String = row.get(0)?;
+
+                return Ok(pgp_mime::Kind::from_name(&kind).map(|kind| PgpPart {
+                    kind,
+                    data: row.get(1).unwrap(),
+                    signed: row.get(2).unwrap(),
+                }));
+            },
+        )
+        .optional();
+
+    match part {
+        Ok(part) => return Ok(part.flatten()),
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error getting OpenPGP part"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+/// The S/MIME entity found when the message was fetched, `None` when it has
+/// none.
+pub async fn get_smime_part(
+    conn: Arc<Mutex<Connection>>,
+    client: &Client,
+    mailbox_path: &str,
+    message_uid: u32,
+) -> Result<Option<SmimePart>, MyError> {
+    let locked_conn = conn.lock().await;
+
+    let part = locked_conn
+        .query_row(
+            "SELECT kind, data FROM smime_parts
+WHERE message_uid = ?1 AND c_username = ?2 AND c_address = ?3 AND m_path = ?4",
+            params![message_uid, &client.username, &client.address, mailbox_path],
+            |row| {
+                let kind: String = row.get(0)?;
+
+                return Ok(smime::Kind::from_name(&kind).map(|kind| SmimePart {
+                    kind,
+                    data: row.get(1).unwrap(),
+                }));
+            },
+        )
+        .optional();
+
+    match part {
+        Ok(part) => return Ok(part.flatten()),
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error getting S/MIME part"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+/// Records the outcome of opening an encrypted or signed message.
+pub async fn set_security(
+    conn: Arc<Mutex<Connection>>,
+    client: &Client,
+    mailbox_path: &str,
+    message_uid: u32,
+    security: &MessageSecurity,
+) -> Result<(), MyError> {
+    let locked_conn = conn.lock().await;
+
+    match locked_conn.execute(
+        "INSERT OR REPLACE INTO message_security (
+message_uid,
+c_username,
+c_address,
+m_path,
+protocol,
+encrypted,
+decrypted,
+signature,
+signer_fingerprint,
+signer_identity,
+signed_at,
+signer_expires,
+error
+) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+        params![
+            message_uid,
+            &client.username,
+            &client.address,
+            mailbox_path,
+            security.protocol,
+            security.encrypted,
+            security.decrypted,
+            security.signature,
+            security.signer.as_ref().map(|signer| &signer.fingerprint),
+            security.signer.as_ref().map(|signer| &signer.identity),
+            security.signer.as_ref().map(|signer| signer.signed_at),
+            security.signer.as_ref().and_then(|signer| signer.expires),
+            security.error
+        ],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error storing message security"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    drop(locked_conn);
+    task::spawn(async move {
+        match database::backup(conn).await {
+            Ok(_) => {}
+            Err(e) => e.log_error(),
+        }
+    });
+
+    return Ok(());
+}
+
+/// Stores the headers of a message in their order. Called inside the
+/// transaction inserting the message, or on its own for messages stored
+/// without headers by an older version.
+pub fn insert_headers(
+    conn: &Connection,
+    username: &str,
+    address: &str,
+    mailbox_path: &str,
+    message_uid: u32,
+    headers: &[Header],
+) -> Result<(), MyError> {
+    let mut stmt = match conn.prepare_cached(
+        "INSERT OR IGNORE INTO message_headers (
+message_uid,
+c_username,
+c_address,
+m_path,
+position,
+name,
+value
+) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    ) {
+        Ok(stmt) => stmt,
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error preparing statement at headers"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    for (position, header) in headers.iter().enumerate() {
+        match stmt.execute(params![
+            message_uid,
+            username,
+            address,
+            mailbox_path,
+            position,
+            header.name,
+            header.value
+        ]) {
+            Ok(_) => {}
+            Err(e) => {
+                let err = MyError::Sqlite(e, String::from("Error inserting header into database"));
+                err.log_error();
+
+                return Err(err);
+            }
+        }
+    }
+
+    return Ok(());
+}
+
+pub async fn get_headers(
+    conn: Arc<Mutex<Connection>>,
+    client: &Client,
+    mailbox_path: &str,
+    message_uid: u32,
+) -> Result<Vec<Header>, MyError> {
+    let locked_conn = conn.lock().await;
+
+    let mut stmt = match locked_conn.prepare_cached(
+        "SELECT name, value FROM message_headers
+WHERE message_uid = ?1 AND c_username = ?2 AND c_address = ?3 AND m_path = ?4
+ORDER BY position",
+    ) {
+        Ok(stmt) => stmt,
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error preparing statement at headers"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    let rows = match stmt.query_map(
+        params![message_uid, &client.username, &client.address, mailbox_path],
+        |row| {
+            Ok(Header {
+                name: row.get(0)?,
+                value: row.get(1)?,
+            })
+        },
+    ) {
+        Ok(rows) => rows,
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error getting headers"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    match rows.collect::<Result<Vec<Header>, rusqlite::Error>>() {
+        Ok(headers) => return Ok(headers),
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error reading headers"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+/// Whether the message is stored, its headers and source are only kept for
+/// stored messages.
+pub async fn exists(
+    conn: Arc<Mutex<Connection>>,
+    client: &Client,
+    mailbox_path: &str,
+    message_uid: u32,
+) -> Result<bool, MyError> {
+    let locked_conn = conn.lock().await;
+
+    match locked_conn
+        .query_row(
+            "SELECT 1 FROM messages WHERE message_uid = ?1 AND c_username = ?2 AND c_address = ?3 AND m_path = ?4",
+            params![message_uid, &client.username, &client.address, mailbox_path],
+            |_| Ok(()),
+        )
+        .optional()
+    {
+        Ok(found) => return Ok(found.is_some()),
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error getting message"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+pub async fn get_source(
+    conn: Arc<Mutex<Connection>>,
+    client: &Client,
+    mailbox_path: &str,
+    message_uid: u32,
+) -> Result<Option<Vec<u8>>, MyError> {
+    let locked_conn = conn.lock().await;
+
+    match locked_conn
+        .query_row(
+            "SELECT source FROM message_sources WHERE message_uid = ?1 AND c_username = ?2 AND c_address = ?3 AND m_path = ?4",
+            params![message_uid, &client.username, &client.address, mailbox_path],
+            |row| row.get(0),
+        )
+        .optional()
+    {
+        Ok(source) => return Ok(source),
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error getting message source"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+/// Keeps the source of a stored message, and its headers when it was stored
+/// without them.
+pub async fn insert_source(
+    conn: Arc<Mutex<Connection>>,
+    client: &Client,
+    mailbox_path: &str,
+    message_uid: u32,
+    source: &[u8],
+    headers: &[Header],
+) -> Result<(), MyError> {
+    let mut locked_conn = conn.lock().await;
+
+    let tx = match locked_conn.transaction() {
+        Ok(tx) => tx,
+        Err(e) => {
+            let err = MyError::Sqlite(
+                e,
+                String::from("Error starting transaction for inserting message source"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    match tx.execute(
+        "INSERT OR REPLACE INTO message_sources (message_uid, c_username, c_address, m_path, source)
+VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![message_uid, &client.username, &client.address, mailbox_path, source],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error inserting message source"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    let has_headers = match tx
+        .query_row(
+            "SELECT 1 FROM message_headers WHERE message_uid = ?1 AND c_username = ?2 AND c_address = ?3 AND m_path = ?4",
+            params![message_uid, &client.username, &client.address, mailbox_path],
+            |_| Ok(()),
+        )
+        .optional()
+    {
+        Ok(found) => found.is_some(),
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error getting headers"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    if !has_headers {
+        match insert_headers(
+            &tx,
+            &client.username,
+            &client.address,
+            mailbox_path,
+            message_uid,
+            headers,
+        ) {
+            Ok(_) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    match tx.commit() {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(
+                e,
+                String::from("Error committing transaction for inserting message source"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
     drop(locked_conn);
     task::spawn(async move {
         match database::backup(conn).await {
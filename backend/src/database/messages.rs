@@ -1,12 +1,16 @@
 use async_std::sync::{Arc, Mutex};
 use async_std::task;
 use base64::{prelude::BASE64_STANDARD, Engine};
-use rusqlite::{params, types::Value, vtab, Connection};
+use chrono::Utc;
+use rusqlite::{params, types::Value, vtab, Connection, OptionalExtension};
 
 use crate::database;
 use crate::my_error::MyError;
-use crate::types::database_request::{DatabaseRequest, MessageIdType, MessageReturnData};
+use crate::types::database_request::{
+    DatabaseRequest, MessageIdType, MessageReturnData, Sort, SortOrder,
+};
 use crate::types::message::Message;
+use crate::types::page_cursor::PageCursor;
 
 pub async fn insert(
     conn: Arc<Mutex<Connection>>,
@@ -30,6 +34,12 @@ pub async fn insert(
         }
     };
 
+    let now = Utc::now().timestamp_millis();
+    let learns_from = match database::autocrypt::learns_from(&tx, username, address, mailbox_path) {
+        Ok(learns_from) => learns_from,
+        Err(e) => return Err(e),
+    };
+
     for message in messages {
         let html = match String::from_utf8(BASE64_STANDARD.decode(message.html.as_str()).unwrap()) {
             Ok(html) => html,
@@ -62,6 +72,11 @@ pub async fn insert(
             }
         };
 
+        let thread_id = match find_thread(&tx, username, address, mailbox_path, message) {
+            Ok(thread_id) => thread_id,
+            Err(e) => return Err(e),
+        };
+
         match tx.execute(
             "INSERT OR IGNORE INTO messages (
 message_uid,
@@ -82,8 +97,12 @@ delivered_to,
 date_,
 received,
 html,
-text
-) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19)",
+text,
+size,
+preview,
+has_attachments,
+thread_id
+) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23)",
             params![
                 message.message_uid,
                 username,
@@ -103,7 +122,11 @@ text
                 message.date,
                 message.received,
                 html,
-                text
+                text,
+                message.size,
+                message.preview,
+                message.has_attachments,
+                thread_id
             ],
         ) {
             Ok(_) => {}
@@ -115,6 +138,11 @@ text
             }
         };
 
+        match join_replies(&tx, username, address, &thread_id, &message.message_id) {
+            Ok(_) => {}
+            Err(e) => return Err(e),
+        }
+
         for flag in &message.flags {
             match tx.execute(
                 "INSERT OR IGNORE INTO flags (
@@ -136,6 +164,151 @@ flag
                 }
             }
         }
+
+        match database::message::insert_headers(
+            &tx,
+            username,
+            address,
+            mailbox_path,
+            message.message_uid,
+            &message.headers,
+        ) {
+            Ok(_) => {}
+            Err(e) => return Err(e),
+        }
+
+        if learns_from {
+            match database::autocrypt::update_peer(
+                &tx,
+                username,
+                address,
+                &message.headers,
+                message.date,
+                now,
+            ) {
+                Ok(_) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        for part in &message.inline_parts {
+            match tx.execute(
+                "INSERT OR IGNORE INTO inline_parts (
+message_uid,
+c_username,
+c_address,
+m_path,
+content_id,
+content_type,
+data
+) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    message.message_uid,
+                    username,
+                    address,
+                    mailbox_path,
+                    part.content_id,
+                    part.content_type,
+                    part.data
+                ],
+            ) {
+                Ok(_) => {}
+                Err(e) => {
+                    let err = MyError::Sqlite(
+                        e,
+                        String::from("Error inserting inline part into database"),
+                    );
+                    err.log_error();
+
+                    return Err(err);
+                }
+            }
+        }
+
+        if !message.calendar.is_empty() {
+            match tx.execute(
+                "INSERT OR REPLACE INTO calendar_parts (message_uid, c_username, c_address, m_path, data)
+VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    message.message_uid,
+                    username,
+                    address,
+                    mailbox_path,
+                    message.calendar
+                ],
+            ) {
+                Ok(_) => {}
+                Err(e) => {
+                    let err = MyError::Sqlite(
+                        e,
+                        String::from("Error inserting calendar part into database"),
+                    );
+                    err.log_error();
+
+                    return Err(err);
+                }
+            }
+        }
+
+        if let Some(part) = &message.openpgp {
+            match tx.execute(
+                "INSERT OR REPLACE INTO openpgp_parts (message_uid, c_username, c_address, m_path, kind, data, signed)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    message.message_uid,
+                    username,
+                    address,
+                    mailbox_path,
+                    part.kind.name(),
+                    part.data,
+                    part.signed
+                ],
+            ) {
+                Ok(_) => {}
+                Err(e) => {
+                    let err = MyError::Sqlite(
+                        e,
+                        String::from("Error inserting OpenPGP part into database"),
+                    );
+                    err.log_error();
+
+                    return Err(err);
+                }
+            }
+        }
+
+        if let Some(part) = &message.smime {
+            match tx.execute(
+                "INSERT OR REPLACE INTO smime_parts (message_uid, c_username, c_address, m_path, kind, data)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    message.message_uid,
+                    username,
+                    address,
+                    mailbox_path,
+                    part.kind.name(),
+                    part.data
+                ],
+            ) {
+                Ok(_) => {}
+                Err(e) => {
+                    let err = MyError::Sqlite(
+                        e,
+                        String::from("Error inserting S/MIME part into database"),
+                    );
+                    err.log_error();
+
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    let message_uids: Vec<u32> = messages.iter().map(|m| m.message_uid).collect();
+
+    match database::changes::record(&tx, username, address, mailbox_path, &message_uids, false) {
+        Ok(_) => {}
+        Err(e) => return Err(e),
     }
 
     match tx.commit() {
@@ -162,6 +335,68 @@ flag
     return Ok(());
 }
 
+/// Thread of the message it replies to, or of a copy of the message in
+/// another mailbox. Otherwise the message starts a thread.
+fn find_thread(
+    conn: &Connection,
+    username: &str,
+    address: &str,
+    mailbox_path: &str,
+    message: &Message,
+) -> Result<String, MyError> {
+    let thread_id: Option<String> = match conn
+        .query_row(
+            "SELECT thread_id FROM messages WHERE c_username = ?1 AND c_address = ?2 AND message_id != '' AND message_id IN (?3, ?4) AND thread_id != '' LIMIT 1",
+            params![username, address, message.in_reply_to, message.message_id],
+            |row| row.get(0),
+        )
+        .optional()
+    {
+        Ok(thread_id) => thread_id,
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error finding thread of message"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    match thread_id {
+        Some(thread_id) => return Ok(thread_id),
+        None if !message.message_id.is_empty() => return Ok(message.message_id.clone()),
+        None => return Ok(format!("{}/{}", mailbox_path, message.message_uid)),
+    }
+}
+
+/// Replies stored before the message they reply to started threads of
+/// their own, those threads are joined with the thread of the message.
+fn join_replies(
+    conn: &Connection,
+    username: &str,
+    address: &str,
+    thread_id: &str,
+    message_id: &str,
+) -> Result<(), MyError> {
+    if message_id.is_empty() {
+        return Ok(());
+    }
+
+    match conn.execute(
+        "UPDATE messages SET thread_id = ?3 WHERE c_username = ?1 AND c_address = ?2 AND thread_id IN (
+SELECT thread_id FROM messages WHERE c_username = ?1 AND c_address = ?2 AND in_reply_to = ?4 AND thread_id != ?3
+)",
+        params![username, address, thread_id, message_id],
+    ) {
+        Ok(_) => return Ok(()),
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error joining replies to thread"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
 pub async fn get(
     conn: Arc<Mutex<Connection>>,
     request: DatabaseRequest,
@@ -182,17 +417,22 @@ pub async fn get(
         ));
     }
 
-    let mut limit: Option<u32> = None;
+    let mut limit: Option<u32> = request.limit;
     if request.start.is_some() && request.end.is_some() {
         limit = Some(request.end.unwrap() - request.start.unwrap() + 1);
     }
 
+    let after: Vec<Value> = match &request.after {
+        Some(after) => after.sql_values(),
+        None => vec![],
+    };
+
     let username = &request.username.as_str();
     let address = &request.address.as_str();
     let mailbox_path = &request.mailbox_path.as_str();
     let list = &list.as_ref();
 
-    let iter: Vec<&dyn rusqlite::types::ToSql> = vec![
+    let mut iter: Vec<&dyn rusqlite::types::ToSql> = vec![
         username,
         address,
         mailbox_path,
@@ -201,6 +441,9 @@ pub async fn get(
         &limit,
         &request.start,
     ];
+    for value in &after {
+        iter.push(value);
+    }
     let iter = iter.get(0..highest_param).unwrap();
 
     let mut stmt = match locked_conn.prepare_cached(&query) {
@@ -239,95 +482,119 @@ pub async fn get(
     };
 }
 
-pub async fn get_flags(
+/// Cursor pointing after a message in a listing with the given sort.
+pub async fn get_page_cursor(
     conn: Arc<Mutex<Connection>>,
     username: &str,
     address: &str,
     mailbox_path: &str,
-) -> Result<Vec<(u32, String)>, MyError> {
-    let database_request = DatabaseRequest {
-        username: username.to_string(),
-        address: address.to_string(),
-        mailbox_path: mailbox_path.to_string(),
-        return_data: MessageReturnData::Flags,
-        id_type: MessageIdType::MessageUids,
-        sorted: true,
-        start: None,
-        end: None,
-        id_rarray: None,
-        flag: None,
-        not_flag: None,
+    sort: &Sort,
+    message_uid: u32,
+) -> Result<Option<PageCursor>, MyError> {
+    let locked_conn = conn.lock().await;
+
+    let columns = sort.field.key_columns();
+    let query = format!(
+        "SELECT {} FROM messages WHERE messages.c_username = ?1 AND messages.c_address = ?2 AND messages.m_path = ?3 AND messages.message_uid = ?4",
+        columns.join(", ")
+    );
+
+    let mut stmt = match locked_conn.prepare_cached(&query) {
+        Ok(stmt) => stmt,
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error preparing statement at messages"));
+            err.log_error();
+
+            return Err(err);
+        }
     };
 
-    let list = get(conn, database_request).await?;
+    let key = stmt
+        .query_row(
+            params![username, address, mailbox_path, message_uid],
+            |row| {
+                (0..columns.len())
+                    .map(|i| row.get::<_, Value>(i))
+                    .collect::<Result<Vec<Value>, _>>()
+            },
+        )
+        .optional();
+
+    match key {
+        Ok(Some(key)) => return Ok(Some(PageCursor::new(sort, key, message_uid))),
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error getting sort key from database"));
+            err.log_error();
 
-    let mut flags: Vec<(u32, String)> = Vec::new();
-    for message in list {
-        for flag in message.flags {
-            flags.push((message.message_uid, flag));
+            return Err(err);
         }
     }
-
-    return Ok(flags);
 }
 
-pub async fn get_flags_with_rarray(
+/// Complete flag set of every message in a mailbox, messages without flags
+/// included.
+pub async fn get_flags(
     conn: Arc<Mutex<Connection>>,
     username: &str,
     address: &str,
     mailbox_path: &str,
-    id_rarray: &Vec<u32>,
-    id_type: MessageIdType,
-) -> Result<Vec<(u32, String)>, MyError> {
+) -> Result<Vec<(u32, Vec<String>)>, MyError> {
     let database_request = DatabaseRequest {
         username: username.to_string(),
         address: address.to_string(),
         mailbox_path: mailbox_path.to_string(),
         return_data: MessageReturnData::Flags,
-        id_type: id_type.clone(),
-        sorted: match id_type {
-            MessageIdType::MessageUids => true,
-            MessageIdType::SequenceIds => false,
-        },
+        id_type: MessageIdType::MessageUids,
+        sort: Some(Sort::received_desc()),
         start: None,
         end: None,
-        id_rarray: Some(id_rarray.clone()),
+        limit: None,
+        after: None,
+        id_rarray: None,
         flag: None,
         not_flag: None,
     };
 
-    let messages = match get(conn, database_request).await {
-        Ok(m) => m,
-        Err(e) => return Err(e),
-    };
-
-    let mut flags: Vec<(u32, String)> = Vec::new();
-    for message in messages {
-        for flag in message.flags {
-            flags.push((message.message_uid, flag));
-        }
-    }
+    let list = get(conn, database_request).await?;
 
-    return Ok(flags);
+    return Ok(list
+        .into_iter()
+        .map(|message| (message.message_uid, message.flags))
+        .collect());
 }
 
+/// Columns of the message list, without the body. Copies of a message in
+/// several mailboxes count once in the size of its thread.
+const SUMMARY_COLUMNS: &str = "messages.message_uid, messages.sequence_id, messages.message_id, messages.subject, messages.from_, messages.sender, messages.to_, messages.cc, messages.bcc, messages.reply_to, messages.in_reply_to, messages.delivered_to, messages.date_, messages.received, messages.size, coalesce(messages.preview, '') AS preview, messages.has_attachments, (SELECT COUNT(DISTINCT t.message_id) FROM messages t WHERE t.c_username = messages.c_username AND t.c_address = messages.c_address AND t.thread_id = messages.thread_id) AS thread_size";
+
+const FLAG_OF_MESSAGE: &str = "f.message_uid = messages.message_uid AND f.c_username = messages.c_username AND f.c_address = messages.c_address AND f.m_path = messages.m_path";
+
+/// All flags of a message as a JSON array in a single column, so every
+/// message is one row and limits count messages rather than flags.
+const FLAGS_COLUMN: &str = "(SELECT json_group_array(f.flag ORDER BY f.id) FROM flags f WHERE f.message_uid = messages.message_uid AND f.c_username = messages.c_username AND f.c_address = messages.c_address AND f.m_path = messages.m_path) AS flags ";
+
 fn construct_sql_query(request: &DatabaseRequest) -> (String, usize) {
     let mut query = String::from("SELECT ");
 
     let mut highest_param = 3;
 
-    let mut get_flags = false;
     match request.return_data {
         MessageReturnData::All => {
             query.push_str("* ");
         }
         MessageReturnData::AllWithFlags => {
-            query.push_str("messages.*, flags.flag ");
-            get_flags = true;
+            query.push_str("messages.*, ");
+            query.push_str(FLAGS_COLUMN);
         }
         MessageReturnData::Flags => {
-            query.push_str("messages.message_uid, flags.flag ");
-            get_flags = true;
+            query.push_str("messages.message_uid, ");
+            query.push_str(FLAGS_COLUMN);
+        }
+        MessageReturnData::Summary => {
+            query.push_str(SUMMARY_COLUMNS);
+            query.push_str(", ");
+            query.push_str(FLAGS_COLUMN);
         }
         MessageReturnData::Uid => {
             query.push_str("messages.message_uid ");
@@ -335,14 +602,6 @@ fn construct_sql_query(request: &DatabaseRequest) -> (String, usize) {
     }
     query.push_str("FROM messages ");
 
-    if (request.flag.is_some() && request.not_flag.is_some()) || get_flags {
-        if request.not_flag.is_some() && request.not_flag.unwrap() {
-            query.push_str("LEFT JOIN flags ON messages.message_uid = flags.message_uid AND messages.c_username = flags.c_username AND messages.c_address = flags.c_address AND messages.m_path = flags.m_path ");
-        } else {
-            query.push_str("LEFT JOIN flags ON messages.message_uid = flags.message_uid AND messages.c_username = flags.c_username AND messages.c_address = flags.c_address AND messages.m_path = flags.m_path ");
-        }
-    }
-
     query.push_str(
         "WHERE messages.c_username = ?1 AND messages.c_address = ?2 AND messages.m_path = ?3 ",
     );
@@ -363,24 +622,67 @@ fn construct_sql_query(request: &DatabaseRequest) -> (String, usize) {
 
     if request.flag.is_some() && request.not_flag.is_some() {
         if !request.not_flag.unwrap() {
-            query.push_str("AND flags.flag = ?5 ");
+            query.push_str("AND EXISTS ");
         } else {
-            query.push_str(
-                "AND messages.message_uid NOT IN (SELECT message_uid FROM flags WHERE flag = ?5) ",
-            );
+            query.push_str("AND NOT EXISTS ");
         }
+        query.push_str(&format!(
+            "(SELECT 1 FROM flags f WHERE {} AND f.flag = ?5) ",
+            FLAG_OF_MESSAGE
+        ));
 
         highest_param = 5;
     }
 
-    if request.sorted {
-        query.push_str("ORDER BY messages.received DESC ");
+    // Keyset pagination, the row value comparison continues after the sort
+    // key and uid of the last message of the previous page.
+    match (&request.sort, &request.after) {
+        (Some(sort), Some(after)) if after.matches(sort) => {
+            let columns = sort.field.key_columns();
+            let comparison = match sort.order {
+                SortOrder::Asc => ">",
+                SortOrder::Desc => "<",
+            };
+            let placeholders: Vec<String> =
+                (0..=columns.len()).map(|i| format!("?{}", 8 + i)).collect();
+
+            query.push_str(&format!(
+                "AND ({}, messages.message_uid) {} ({}) ",
+                columns.join(", "),
+                comparison,
+                placeholders.join(", ")
+            ));
+
+            highest_param = 8 + columns.len();
+        }
+        _ => {}
+    }
+
+    if let Some(sort) = request.sort {
+        let order = sort.order.as_sql();
+
+        let columns: Vec<String> = sort
+            .field
+            .key_columns()
+            .iter()
+            .map(|column| format!("{} {}", column, order))
+            .collect();
+
+        query.push_str(&format!(
+            "ORDER BY {}, messages.message_uid {} ",
+            columns.join(", "),
+            order
+        ));
     }
 
     if request.start.is_some() && request.end.is_some() {
         query.push_str("LIMIT ?6 OFFSET ?7 ");
 
-        highest_param = 7;
+        highest_param = highest_param.max(7);
+    } else if request.limit.is_some() {
+        query.push_str("LIMIT ?6 ");
+
+        highest_param = highest_param.max(6);
     }
 
     return (query, highest_param);
@@ -389,6 +691,8 @@ fn construct_sql_query(request: &DatabaseRequest) -> (String, usize) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::types::database_request::SortField;
+    use crate::types::message::{Header, InlinePart};
 
     #[test]
     fn construct_sql_query_rarray_uids() {
@@ -398,9 +702,11 @@ mod tests {
             mailbox_path: "mailbox_path".to_string(),
             return_data: MessageReturnData::All,
             id_type: MessageIdType::MessageUids,
-            sorted: false,
+            sort: None,
             start: None,
             end: None,
+            limit: None,
+            after: None,
             id_rarray: Some(vec![1, 2, 3, 4, 5]),
             flag: None,
             not_flag: None,
@@ -419,9 +725,11 @@ mod tests {
             mailbox_path: "mailbox_path".to_string(),
             return_data: MessageReturnData::All,
             id_type: MessageIdType::SequenceIds,
-            sorted: false,
+            sort: None,
             start: None,
             end: None,
+            limit: None,
+            after: None,
             id_rarray: Some(vec![1, 2, 3, 4, 5]),
             flag: None,
             not_flag: None,
@@ -440,9 +748,11 @@ mod tests {
             mailbox_path: "mailbox_path".to_string(),
             return_data: MessageReturnData::All,
             id_type: MessageIdType::MessageUids,
-            sorted: false,
+            sort: None,
             start: Some(1),
             end: Some(5),
+            limit: None,
+            after: None,
             id_rarray: None,
             flag: None,
             not_flag: None,
@@ -461,16 +771,18 @@ mod tests {
             mailbox_path: "mailbox_path".to_string(),
             return_data: MessageReturnData::All,
             id_type: MessageIdType::MessageUids,
-            sorted: true,
+            sort: Some(Sort::received_desc()),
             start: Some(1),
             end: Some(5),
+            limit: None,
+            after: None,
             id_rarray: None,
             flag: None,
             not_flag: None,
         };
 
         let (query, highest_param) = construct_sql_query(&request);
-        assert_eq!(query, "SELECT * FROM messages WHERE messages.c_username = ?1 AND messages.c_address = ?2 AND messages.m_path = ?3 ORDER BY messages.received DESC LIMIT ?6 OFFSET ?7 ");
+        assert_eq!(query, "SELECT * FROM messages WHERE messages.c_username = ?1 AND messages.c_address = ?2 AND messages.m_path = ?3 ORDER BY messages.received DESC, messages.message_uid DESC LIMIT ?6 OFFSET ?7 ");
         assert_eq!(highest_param, 7);
     }
 
@@ -482,16 +794,18 @@ mod tests {
             mailbox_path: "mailbox_path".to_string(),
             return_data: MessageReturnData::Flags,
             id_type: MessageIdType::MessageUids,
-            sorted: false,
+            sort: None,
             start: None,
             end: None,
+            limit: None,
+            after: None,
             id_rarray: None,
             flag: Some("flag".to_string()),
             not_flag: Some(false),
         };
 
         let (query, highest_param) = construct_sql_query(&request);
-        assert_eq!(query, "SELECT messages.message_uid, flags.flag FROM messages INNER JOIN flags ON messages.message_uid = flags.message_uid AND messages.c_username = flags.c_username AND messages.c_address = flags.c_address AND messages.m_path = flags.m_path WHERE messages.c_username = ?1 AND messages.c_address = ?2 AND messages.m_path = ?3 AND flags.flag = ?5 ");
+        assert_eq!(query, format!("SELECT messages.message_uid, {}FROM messages WHERE messages.c_username = ?1 AND messages.c_address = ?2 AND messages.m_path = ?3 AND EXISTS (SELECT 1 FROM flags f WHERE {} AND f.flag = ?5) ", FLAGS_COLUMN, FLAG_OF_MESSAGE));
         assert_eq!(highest_param, 5);
     }
 
@@ -503,16 +817,18 @@ mod tests {
             mailbox_path: "mailbox_path".to_string(),
             return_data: MessageReturnData::Flags,
             id_type: MessageIdType::MessageUids,
-            sorted: false,
+            sort: None,
             start: None,
             end: None,
+            limit: None,
+            after: None,
             id_rarray: None,
             flag: Some("flag".to_string()),
             not_flag: Some(true),
         };
 
         let (query, highest_param) = construct_sql_query(&request);
-        assert_eq!(query, "SELECT messages.message_uid, flags.flag FROM messages LEFT JOIN flags ON messages.message_uid = flags.message_uid AND messages.c_username = flags.c_username AND messages.c_address = flags.c_address AND messages.m_path = flags.m_path WHERE messages.c_username = ?1 AND messages.c_address = ?2 AND messages.m_path = ?3 AND messages.message_uid NOT IN (SELECT message_uid FROM flags WHERE flag = ?5) ");
+        assert_eq!(query, format!("SELECT messages.message_uid, {}FROM messages WHERE messages.c_username = ?1 AND messages.c_address = ?2 AND messages.m_path = ?3 AND NOT EXISTS (SELECT 1 FROM flags f WHERE {} AND f.flag = ?5) ", FLAGS_COLUMN, FLAG_OF_MESSAGE));
         assert_eq!(highest_param, 5);
     }
 
@@ -524,16 +840,18 @@ mod tests {
             mailbox_path: "mailbox_path".to_string(),
             return_data: MessageReturnData::Flags,
             id_type: MessageIdType::MessageUids,
-            sorted: false,
+            sort: None,
             start: None,
             end: None,
+            limit: None,
+            after: None,
             id_rarray: None,
             flag: None,
             not_flag: None,
         };
 
         let (query, highest_param) = construct_sql_query(&request);
-        assert_eq!(query, "SELECT messages.message_uid, flags.flag FROM messages INNER JOIN flags ON messages.message_uid = flags.message_uid AND messages.c_username = flags.c_username AND messages.c_address = flags.c_address AND messages.m_path = flags.m_path WHERE messages.c_username = ?1 AND messages.c_address = ?2 AND messages.m_path = ?3 ");
+        assert_eq!(query, format!("SELECT messages.message_uid, {}FROM messages WHERE messages.c_username = ?1 AND messages.c_address = ?2 AND messages.m_path = ?3 ", FLAGS_COLUMN));
         assert_eq!(highest_param, 3);
     }
 
@@ -545,16 +863,460 @@ mod tests {
             mailbox_path: "mailbox_path".to_string(),
             return_data: MessageReturnData::AllWithFlags,
             id_type: MessageIdType::MessageUids,
-            sorted: false,
+            sort: None,
             start: None,
             end: None,
+            limit: None,
+            after: None,
             id_rarray: None,
             flag: None,
             not_flag: None,
         };
 
         let (query, highest_param) = construct_sql_query(&request);
-        assert_eq!(query, "SELECT messages.*, flags.flag FROM messages INNER JOIN flags ON messages.message_uid = flags.message_uid AND messages.c_username = flags.c_username AND messages.c_address = flags.c_address AND messages.m_path = flags.m_path WHERE messages.c_username = ?1 AND messages.c_address = ?2 AND messages.m_path = ?3 ");
+        assert_eq!(query, format!("SELECT messages.*, {}FROM messages WHERE messages.c_username = ?1 AND messages.c_address = ?2 AND messages.m_path = ?3 ", FLAGS_COLUMN));
         assert_eq!(highest_param, 3);
     }
+
+    #[test]
+    fn construct_sql_query_keyset() {
+        let sort = Sort {
+            field: SortField::Flagged,
+            order: SortOrder::Asc,
+        };
+        let request = DatabaseRequest {
+            username: "username".to_string(),
+            address: "address".to_string(),
+            mailbox_path: "mailbox_path".to_string(),
+            return_data: MessageReturnData::Uid,
+            id_type: MessageIdType::MessageUids,
+            sort: Some(sort),
+            start: None,
+            end: None,
+            limit: Some(50),
+            after: Some(PageCursor::new(
+                &sort,
+                vec![Value::Integer(0), Value::Integer(10)],
+                7,
+            )),
+            id_rarray: None,
+            flag: None,
+            not_flag: None,
+        };
+
+        let flagged = "EXISTS (SELECT 1 FROM flags f WHERE f.message_uid = messages.message_uid AND f.c_username = messages.c_username AND f.c_address = messages.c_address AND f.m_path = messages.m_path AND f.flag = 'Flagged')";
+
+        let (query, highest_param) = construct_sql_query(&request);
+        assert_eq!(query, format!("SELECT messages.message_uid FROM messages WHERE messages.c_username = ?1 AND messages.c_address = ?2 AND messages.m_path = ?3 AND ({}, messages.received, messages.message_uid) > (?8, ?9, ?10) ORDER BY {} ASC, messages.received ASC, messages.message_uid ASC LIMIT ?6 ", flagged, flagged));
+        assert_eq!(highest_param, 10);
+    }
+
+    fn insert_message(conn: &Connection, uid: u32, subject: &str, from: &str, received: i64) {
+        conn.execute(
+            "INSERT INTO messages (message_uid, c_username, c_address, m_path, sequence_id, message_id, subject, from_, sender, to_, cc, bcc, reply_to, in_reply_to, delivered_to, date_, received, html, text, size)
+VALUES (?1, 'user', 'address', 'INBOX', ?1, '', ?2, ?3, '[]', '[]', '[]', '[]', '[]', '', '', ?4, ?4, '', '', ?1)",
+            params![uid, subject, from, received],
+        )
+        .unwrap();
+    }
+
+    async fn get_page(
+        conn: &Arc<Mutex<Connection>>,
+        sort: Sort,
+        after: Option<PageCursor>,
+    ) -> (Vec<u32>, Option<PageCursor>) {
+        let request = DatabaseRequest {
+            username: "user".to_string(),
+            address: "address".to_string(),
+            mailbox_path: "INBOX".to_string(),
+            return_data: MessageReturnData::Uid,
+            id_type: MessageIdType::MessageUids,
+            sort: Some(sort),
+            start: None,
+            end: None,
+            limit: Some(2),
+            after,
+            id_rarray: None,
+            flag: None,
+            not_flag: None,
+        };
+
+        let uids: Vec<u32> = get(Arc::clone(conn), request)
+            .await
+            .unwrap()
+            .iter()
+            .map(|message| message.message_uid)
+            .collect();
+
+        let cursor = get_page_cursor(
+            Arc::clone(conn),
+            "user",
+            "address",
+            "INBOX",
+            &sort,
+            *uids.last().unwrap(),
+        )
+        .await
+        .unwrap();
+
+        return (uids, cursor);
+    }
+
+    #[async_std::test]
+    async fn keyset_pagination() {
+        let conn = Connection::open_in_memory().unwrap();
+        vtab::array::load_module(&conn).unwrap();
+        database::initialise(&conn).await.unwrap();
+
+        conn.execute_batch(
+            "INSERT INTO connections (username, password, address, port) VALUES ('user', 'password', 'address', 993);
+            INSERT INTO mailboxes (c_username, c_address, path) VALUES ('user', 'address', 'INBOX');",
+        )
+        .unwrap();
+
+        insert_message(
+            &conn,
+            1,
+            "b",
+            r#"[{"host":"example.com","mailbox":"zed","name":""}]"#,
+            100,
+        );
+        insert_message(
+            &conn,
+            2,
+            "A",
+            r#"[{"host":"example.com","mailbox":"x","name":"Yann"}]"#,
+            300,
+        );
+        insert_message(
+            &conn,
+            3,
+            "c",
+            r#"[{"host":"example.com","mailbox":"x","name":"alice"}]"#,
+            300,
+        );
+        insert_message(&conn, 4, "a", "not json", 200);
+
+        let conn = Arc::new(Mutex::new(conn));
+
+        let sort = Sort::received_desc();
+        let (uids, cursor) = get_page(&conn, sort, None).await;
+        assert_eq!(uids, vec![3, 2]);
+
+        // Mail arriving between two pages does not shift the next page.
+        insert_message(&*conn.lock().await, 5, "d", "[]", 400);
+
+        let (uids, _) = get_page(&conn, sort, cursor).await;
+        assert_eq!(uids, vec![4, 1]);
+
+        let sort = Sort {
+            field: SortField::Subject,
+            order: SortOrder::Asc,
+        };
+        let (uids, cursor) = get_page(&conn, sort, None).await;
+        assert_eq!(uids, vec![2, 4]);
+        let (uids, _) = get_page(&conn, sort, cursor).await;
+        assert_eq!(uids, vec![1, 3]);
+
+        let sort = Sort {
+            field: SortField::Sender,
+            order: SortOrder::Asc,
+        };
+        let (uids, cursor) = get_page(&conn, sort, None).await;
+        assert_eq!(uids, vec![4, 5]);
+        let (uids, cursor) = get_page(&conn, sort, cursor).await;
+        assert_eq!(uids, vec![3, 2]);
+        let (uids, _) = get_page(&conn, sort, cursor).await;
+        assert_eq!(uids, vec![1]);
+    }
+
+    fn insert_threaded(
+        conn: &Connection,
+        mailbox_path: &str,
+        uid: u32,
+        message_id: &str,
+        in_reply_to: &str,
+    ) {
+        let mut message = parser_message(uid);
+        message.message_id = message_id.to_string();
+        message.in_reply_to = in_reply_to.to_string();
+
+        let thread_id = find_thread(conn, "user", "address", mailbox_path, &message).unwrap();
+
+        conn.execute(
+            "INSERT INTO messages (message_uid, c_username, c_address, m_path, sequence_id, message_id, subject, from_, sender, to_, cc, bcc, reply_to, in_reply_to, delivered_to, date_, received, html, text, preview, has_attachments, thread_id)
+VALUES (?1, 'user', 'address', ?2, ?1, ?3, '', '[]', '[]', '[]', '[]', '[]', '[]', ?4, '', ?1, ?1, '<p>body</p>', 'body', 'preview', TRUE, ?5)",
+            params![uid, mailbox_path, message_id, in_reply_to, thread_id],
+        )
+        .unwrap();
+
+        join_replies(conn, "user", "address", &thread_id, message_id).unwrap();
+    }
+
+    fn parser_message(uid: u32) -> Message {
+        return Message {
+            message_uid: uid,
+            sequence_id: uid,
+            message_id: String::new(),
+            subject: String::new(),
+            from: String::from("[]"),
+            sender: String::from("[]"),
+            to: String::from("[]"),
+            cc: String::from("[]"),
+            bcc: String::from("[]"),
+            reply_to: String::from("[]"),
+            in_reply_to: String::new(),
+            delivered_to: String::new(),
+            date: 0,
+            received: 0,
+            size: 0,
+            preview: String::new(),
+            has_attachments: false,
+            inline_parts: vec![],
+            calendar: String::new(),
+            openpgp: None,
+            smime: None,
+            security: None,
+            headers: vec![],
+            thread_size: 0,
+            flags: vec![],
+            text: String::new(),
+            html: String::new(),
+        };
+    }
+
+    #[async_std::test]
+    async fn insert_headers_and_parts() {
+        let conn = Connection::open_in_memory().unwrap();
+        vtab::array::load_module(&conn).unwrap();
+        database::initialise(&conn).await.unwrap();
+
+        conn.execute_batch(
+            "INSERT INTO connections (username, password, address, port) VALUES ('user', 'password', 'address', 993);
+            INSERT INTO mailboxes (c_username, c_address, path) VALUES ('user', 'address', 'INBOX');",
+        )
+        .unwrap();
+
+        let mut message = parser_message(1);
+        message.headers = vec![
+            Header {
+                name: String::from("Received"),
+                value: String::from("from b"),
+            },
+            Header {
+                name: String::from("Received"),
+                value: String::from("from a"),
+            },
+        ];
+        message.inline_parts = vec![InlinePart {
+            content_id: String::from("logo@x"),
+            content_type: String::from("image/png"),
+            data: String::from("iVBORw0KGgo="),
+        }];
+
+        let conn = Arc::new(Mutex::new(conn));
+        insert(
+            Arc::clone(&conn),
+            "user",
+            "address",
+            "INBOX",
+            &vec![message],
+        )
+        .await
+        .unwrap();
+
+        let locked_conn = conn.lock().await;
+        let headers: Vec<String> = locked_conn
+            .prepare("SELECT value FROM message_headers WHERE message_uid = 1 ORDER BY position")
+            .unwrap()
+            .query_map(params![], |row| row.get(0))
+            .unwrap()
+            .map(|value| value.unwrap())
+            .collect();
+        let parts: u32 = locked_conn
+            .query_row("SELECT COUNT(*) FROM inline_parts", params![], |row| {
+                row.get(0)
+            })
+            .unwrap();
+
+        assert_eq!(headers, vec!["from b", "from a"]);
+        assert_eq!(parts, 1);
+    }
+
+    #[async_std::test]
+    async fn summary_threads() {
+        let conn = Connection::open_in_memory().unwrap();
+        vtab::array::load_module(&conn).unwrap();
+        database::initialise(&conn).await.unwrap();
+
+        conn.execute_batch(
+            "INSERT INTO connections (username, password, address, port) VALUES ('user', 'password', 'address', 993);
+            INSERT INTO mailboxes (c_username, c_address, path) VALUES ('user', 'address', 'INBOX');
+            INSERT INTO mailboxes (c_username, c_address, path) VALUES ('user', 'address', 'Sent');",
+        )
+        .unwrap();
+
+        // The reply arrives before the message it replies to.
+        insert_threaded(&conn, "INBOX", 1, "<b@x>", "<a@x>");
+        insert_threaded(&conn, "Sent", 1, "<a@x>", "");
+        insert_threaded(&conn, "INBOX", 2, "<c@x>", "<b@x>");
+        // A copy of a message in another mailbox is not counted twice.
+        insert_threaded(&conn, "Sent", 2, "<c@x>", "<b@x>");
+        insert_threaded(&conn, "INBOX", 3, "", "");
+        insert_threaded(&conn, "INBOX", 4, "", "");
+
+        let request = DatabaseRequest {
+            username: "user".to_string(),
+            address: "address".to_string(),
+            mailbox_path: "INBOX".to_string(),
+            return_data: MessageReturnData::Summary,
+            id_type: MessageIdType::MessageUids,
+            sort: Some(Sort::received_desc()),
+            start: None,
+            end: None,
+            limit: None,
+            after: None,
+            id_rarray: None,
+            flag: None,
+            not_flag: None,
+        };
+
+        let messages = get(Arc::new(Mutex::new(conn)), request).await.unwrap();
+
+        let summaries: Vec<(u32, u32)> = messages
+            .iter()
+            .map(|message| (message.message_uid, message.thread_size))
+            .collect();
+        assert_eq!(summaries, vec![(4, 1), (3, 1), (2, 3), (1, 3)]);
+
+        assert_eq!(messages[0].preview, "preview");
+        assert!(messages[0].has_attachments);
+        assert!(messages[0].html.is_empty() && messages[0].text.is_empty());
+    }
+
+    fn insert_flags(conn: &Connection, mailbox_path: &str, uid: u32, flags: &[&str]) {
+        for flag in flags {
+            conn.execute(
+                "INSERT OR IGNORE INTO flags (message_uid, c_username, c_address, m_path, flag) VALUES (?1, 'user', 'address', ?2, ?3)",
+                params![uid, mailbox_path, flag],
+            )
+            .unwrap();
+        }
+    }
+
+    fn flags_request(return_data: MessageReturnData) -> DatabaseRequest {
+        return DatabaseRequest {
+            username: "user".to_string(),
+            address: "address".to_string(),
+            mailbox_path: "INBOX".to_string(),
+            return_data,
+            id_type: MessageIdType::MessageUids,
+            sort: Some(Sort::received_desc()),
+            start: None,
+            end: None,
+            limit: None,
+            after: None,
+            id_rarray: None,
+            flag: None,
+            not_flag: None,
+        };
+    }
+
+    #[async_std::test]
+    async fn messages_with_flags() {
+        let conn = Connection::open_in_memory().unwrap();
+        vtab::array::load_module(&conn).unwrap();
+        database::initialise(&conn).await.unwrap();
+
+        conn.execute_batch(
+            "INSERT INTO connections (username, password, address, port) VALUES ('user', 'password', 'address', 993);
+            INSERT INTO mailboxes (c_username, c_address, path) VALUES ('user', 'address', 'INBOX');
+            INSERT INTO mailboxes (c_username, c_address, path) VALUES ('user', 'address', 'Archive');",
+        )
+        .unwrap();
+
+        insert_message(&conn, 1, "none", "[]", 300);
+        insert_message(&conn, 2, "one", "[]", 200);
+        insert_message(&conn, 3, "many", "[]", 100);
+        insert_flags(&conn, "INBOX", 2, &["Seen"]);
+        insert_flags(
+            &conn,
+            "INBOX",
+            3,
+            &["Seen", "Flagged", "$Forwarded", "Seen"],
+        );
+        // The same uid in another mailbox does not affect the flags in INBOX.
+        conn.execute(
+            "INSERT INTO messages (message_uid, c_username, c_address, m_path, sequence_id, message_id, subject, from_, sender, to_, cc, bcc, reply_to, in_reply_to, delivered_to, date_, received, html, text)
+VALUES (1, 'user', 'address', 'Archive', 1, '', '', '[]', '[]', '[]', '[]', '[]', '[]', '', '', 0, 0, '', '')",
+            params![],
+        )
+        .unwrap();
+        insert_flags(&conn, "Archive", 1, &["Seen"]);
+
+        let conn = Arc::new(Mutex::new(conn));
+
+        let expected = vec![
+            (1, vec![]),
+            (2, vec![String::from("Seen")]),
+            (
+                3,
+                vec![
+                    String::from("Seen"),
+                    String::from("Flagged"),
+                    String::from("$Forwarded"),
+                ],
+            ),
+        ];
+
+        for return_data in [
+            MessageReturnData::AllWithFlags,
+            MessageReturnData::Flags,
+            MessageReturnData::Summary,
+        ] {
+            let messages = get(Arc::clone(&conn), flags_request(return_data))
+                .await
+                .unwrap();
+            let flags: Vec<(u32, Vec<String>)> = messages
+                .into_iter()
+                .map(|message| (message.message_uid, message.flags))
+                .collect();
+
+            assert_eq!(flags, expected);
+        }
+
+        // Pages count messages, not flags.
+        let mut request = flags_request(MessageReturnData::AllWithFlags);
+        request.start = Some(1);
+        request.end = Some(2);
+        let uids: Vec<u32> = get(Arc::clone(&conn), request)
+            .await
+            .unwrap()
+            .iter()
+            .map(|message| message.message_uid)
+            .collect();
+        assert_eq!(uids, vec![2, 3]);
+
+        let mut request = flags_request(MessageReturnData::Summary);
+        request.flag = Some(String::from("Seen"));
+        request.not_flag = Some(false);
+        let uids: Vec<u32> = get(Arc::clone(&conn), request)
+            .await
+            .unwrap()
+            .iter()
+            .map(|message| message.message_uid)
+            .collect();
+        assert_eq!(uids, vec![2, 3]);
+
+        let mut request = flags_request(MessageReturnData::Summary);
+        request.flag = Some(String::from("Seen"));
+        request.not_flag = Some(true);
+        let messages = get(Arc::clone(&conn), request).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].message_uid, 1);
+        assert!(messages[0].flags.is_empty());
+
+        let flags = get_flags(conn, "user", "address", "INBOX").await.unwrap();
+        assert_eq!(flags, expected);
+    }
 }
@@ -0,0 +1,261 @@
+use async_std::sync::{Arc, Mutex};
+use async_std::task;
+use rusqlite::{params, Connection};
+
+use crate::database;
+use crate::database::credentials::CredentialStore;
+use crate::my_error::MyError;
+use crate::types::session::Client;
+
+/// Key of the keyring with its secrets decrypted, for use by the backend
+/// only. Secret keys are never sent to API clients.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredKey {
+    pub fingerprint: String,
+    /// Armored public key.
+    pub public_key: String,
+    /// Armored secret key as imported, `None` for keys of other people.
+    pub secret_key: Option<String>,
+    pub passphrase: Option<String>,
+}
+
+/// Adds a key to the keyring of an account or replaces it. Importing only the
+/// public part of a key keeps its stored secret key.
+pub async fn insert(
+    conn: Arc<Mutex<Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+    key: &StoredKey,
+    user_ids: &[String],
+) -> Result<(), MyError> {
+    let secret_key = match credentials.encrypt_optional(key.secret_key.as_ref()) {
+        Ok(secret_key) => secret_key,
+        Err(e) => return Err(e),
+    };
+    let passphrase = match credentials.encrypt_optional(key.passphrase.as_ref()) {
+        Ok(passphrase) => passphrase,
+        Err(e) => return Err(e),
+    };
+    let user_ids = match serde_json::to_string(user_ids) {
+        Ok(user_ids) => user_ids,
+        Err(e) => {
+            let err = MyError::String(e.to_string(), String::from("Error serialising user IDs"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    let locked_conn = conn.lock().await;
+
+    match locked_conn.execute(
+        "INSERT INTO openpgp_keys (c_username, c_address, fingerprint, user_ids, public_key, secret_key, passphrase)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+ON CONFLICT(c_username, c_address, fingerprint) DO UPDATE SET
+    user_ids = excluded.user_ids,
+    public_key = excluded.public_key,
+    secret_key = COALESCE(excluded.secret_key, secret_key),
+    passphrase = CASE WHEN excluded.secret_key IS NULL THEN passphrase ELSE excluded.passphrase END",
+        params![
+            client.username,
+            client.address,
+            key.fingerprint,
+            user_ids,
+            key.public_key,
+            secret_key,
+            passphrase
+        ],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error inserting OpenPGP key"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    drop(locked_conn);
+    task::spawn(async move {
+        match database::backup(conn).await {
+            Ok(_) => {}
+            Err(e) => e.log_error(),
+        }
+    });
+
+    return Ok(());
+}
+
+/// Keys of an account in the order they were imported.
+pub async fn get(
+    conn: Arc<Mutex<Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+) -> Result<Vec<StoredKey>, MyError> {
+    let locked_conn = conn.lock().await;
+
+    let mut stmt = match locked_conn.prepare_cached(
+        "SELECT fingerprint, public_key, secret_key, passphrase FROM openpgp_keys
+WHERE c_username = ?1 AND c_address = ?2 ORDER BY rowid",
+    ) {
+        Ok(stmt) => stmt,
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error preparing statement at openpgp_keys"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    let rows = stmt.query_map(params![client.username, client.address], |row| {
+        let secret_key = match credentials.decrypt_optional(row.get(2)?) {
+            Ok(secret_key) => secret_key,
+            Err(e) => return Ok(Err(e)),
+        };
+        let passphrase = match credentials.decrypt_optional(row.get(3)?) {
+            Ok(passphrase) => passphrase,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        Ok(Ok(StoredKey {
+            fingerprint: row.get(0)?,
+            public_key: row.get(1)?,
+            secret_key,
+            passphrase,
+        }))
+    });
+
+    match rows {
+        Ok(rows) => {
+            let mut keys: Vec<StoredKey> = vec![];
+
+            for row in rows {
+                match row {
+                    Ok(Ok(key)) => keys.push(key),
+                    Ok(Err(e)) => return Err(e),
+                    Err(e) => {
+                        let err = MyError::Sqlite(e, String::from("Error reading OpenPGP key"));
+                        err.log_error();
+
+                        return Err(err);
+                    }
+                }
+            }
+
+            return Ok(keys);
+        }
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error getting OpenPGP keys"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+/// Removes a key from the keyring of an account, returns whether it was
+/// there.
+pub async fn remove(
+    conn: Arc<Mutex<Connection>>,
+    client: &Client,
+    fingerprint: &str,
+) -> Result<bool, MyError> {
+    let locked_conn = conn.lock().await;
+
+    let removed = match locked_conn.execute(
+        "DELETE FROM openpgp_keys WHERE c_username = ?1 AND c_address = ?2 AND fingerprint = ?3",
+        params![client.username, client.address, fingerprint],
+    ) {
+        Ok(removed) => removed,
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error removing OpenPGP key"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    drop(locked_conn);
+    task::spawn(async move {
+        match database::backup(conn).await {
+            Ok(_) => {}
+            Err(e) => e.log_error(),
+        }
+    });
+
+    return Ok(removed > 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::credentials::KeySource;
+    use crate::types::session::{AuthMechanism, Security};
+
+    #[async_std::test]
+    async fn keyring() {
+        let conn = Connection::open_in_memory().unwrap();
+        database::initialise(&conn).await.unwrap();
+        conn.execute(
+            "INSERT INTO connections (username, password, address, port) VALUES ('user', 'password', 'imap.example.com', 993)",
+            params![],
+        )
+        .unwrap();
+
+        let credentials =
+            CredentialStore::open(&conn, &KeySource::Passphrase(String::from("test"))).unwrap();
+        let conn = Arc::new(Mutex::new(conn));
+        let client = Client {
+            username: String::from("user"),
+            password: String::from("password"),
+            address: String::from("imap.example.com"),
+            port: 993,
+            security: Security::Tls,
+            cert_fingerprint: None,
+            auth_mechanism: AuthMechanism::Password,
+            oauth: None,
+        };
+
+        let key = StoredKey {
+            fingerprint: String::from("FPR"),
+            public_key: String::from("public"),
+            secret_key: Some(String::from("secret")),
+            passphrase: Some(String::from("passphrase")),
+        };
+        insert(Arc::clone(&conn), &credentials, &client, &key, &[])
+            .await
+            .unwrap();
+
+        let stored: String = conn
+            .lock()
+            .await
+            .query_row("SELECT secret_key FROM openpgp_keys", params![], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert!(!stored.contains("secret"));
+
+        // A public key import keeps the secret key.
+        let public = StoredKey {
+            public_key: String::from("public 2"),
+            secret_key: None,
+            passphrase: None,
+            ..key.clone()
+        };
+        insert(Arc::clone(&conn), &credentials, &client, &public, &[])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            get(Arc::clone(&conn), &credentials, &client).await.unwrap(),
+            vec![StoredKey {
+                public_key: String::from("public 2"),
+                ..key
+            }]
+        );
+
+        assert!(remove(Arc::clone(&conn), &client, "FPR").await.unwrap());
+        assert!(!remove(Arc::clone(&conn), &client, "FPR").await.unwrap());
+        assert!(get(conn, &credentials, &client).await.unwrap().is_empty());
+    }
+}
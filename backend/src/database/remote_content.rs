@@ -0,0 +1,122 @@
+use async_std::sync::{Arc, Mutex};
+use async_std::task;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::database;
+use crate::my_error::MyError;
+
+/// Whether remote images and styles are loaded for messages from `sender`.
+pub async fn is_allowed(
+    conn: Arc<Mutex<Connection>>,
+    username: &str,
+    address: &str,
+    sender: &str,
+) -> Result<bool, MyError> {
+    let locked_conn = conn.lock().await;
+
+    match locked_conn
+        .query_row(
+            "SELECT 1 FROM remote_content_senders WHERE c_username = ?1 AND c_address = ?2 AND sender = ?3",
+            params![username, address, sender.to_lowercase()],
+            |_| Ok(()),
+        )
+        .optional()
+    {
+        Ok(allowed) => return Ok(allowed.is_some()),
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error getting remote content sender"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+/// Adds `sender` to the senders whose remote content is loaded, or removes it
+/// when `allow` is not set.
+pub async fn set_allowed(
+    conn: Arc<Mutex<Connection>>,
+    username: &str,
+    address: &str,
+    sender: &str,
+    allow: bool,
+) -> Result<(), MyError> {
+    let locked_conn = conn.lock().await;
+
+    let query = if allow {
+        "INSERT OR IGNORE INTO remote_content_senders (c_username, c_address, sender) VALUES (?1, ?2, ?3)"
+    } else {
+        "DELETE FROM remote_content_senders WHERE c_username = ?1 AND c_address = ?2 AND sender = ?3"
+    };
+
+    match locked_conn.execute(query, params![username, address, sender.to_lowercase()]) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error updating remote content sender"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    drop(locked_conn);
+    task::spawn(async move {
+        match database::backup(conn).await {
+            Ok(_) => {}
+            Err(e) => e.log_error(),
+        }
+    });
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get_conn() -> Arc<Mutex<Connection>> {
+        let conn = Connection::open_in_memory().unwrap();
+        database::initialise(&conn).await.unwrap();
+
+        conn.execute(
+            "INSERT INTO connections (username, password, address, port) VALUES ('u', 'p', 'a', 993)",
+            params![],
+        )
+        .unwrap();
+
+        return Arc::new(Mutex::new(conn));
+    }
+
+    #[async_std::test]
+    async fn allowed_senders() {
+        let conn = get_conn().await;
+
+        assert!(!is_allowed(Arc::clone(&conn), "u", "a", "news@example.com")
+            .await
+            .unwrap());
+
+        set_allowed(Arc::clone(&conn), "u", "a", "News@Example.com", true)
+            .await
+            .unwrap();
+        set_allowed(Arc::clone(&conn), "u", "a", "news@example.com", true)
+            .await
+            .unwrap();
+
+        assert!(is_allowed(Arc::clone(&conn), "u", "a", "news@example.com")
+            .await
+            .unwrap());
+        assert!(
+            !is_allowed(Arc::clone(&conn), "u", "other", "news@example.com")
+                .await
+                .unwrap()
+        );
+
+        set_allowed(Arc::clone(&conn), "u", "a", "news@example.com", false)
+            .await
+            .unwrap();
+
+        assert!(!is_allowed(Arc::clone(&conn), "u", "a", "news@example.com")
+            .await
+            .unwrap());
+    }
+}
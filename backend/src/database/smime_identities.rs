@@ -0,0 +1,163 @@
+use async_std::sync::{Arc, Mutex};
+use async_std::task;
+use rusqlite::{params, Connection};
+
+use crate::database;
+use crate::database::credentials::CredentialStore;
+use crate::my_error::MyError;
+use crate::types::session::Client;
+
+/// S/MIME identity with its private key decrypted, for use by the backend
+/// only. Private keys are never sent to API clients.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredIdentity {
+    /// SHA-256 fingerprint of the certificate.
+    pub fingerprint: String,
+    /// PEM certificate followed by the certificates that issued it.
+    pub certificates: String,
+    /// PEM private key.
+    pub private_key: String,
+}
+
+/// Adds an identity to an account or replaces it.
+pub async fn insert(
+    conn: Arc<Mutex<Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+    identity: &StoredIdentity,
+) -> Result<(), MyError> {
+    let private_key = match credentials.encrypt(&identity.private_key) {
+        Ok(private_key) => private_key,
+        Err(e) => return Err(e),
+    };
+
+    let locked_conn = conn.lock().await;
+
+    match locked_conn.execute(
+        "INSERT OR REPLACE INTO smime_identities (c_username, c_address, fingerprint, certificates, private_key)
+VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            client.username,
+            client.address,
+            identity.fingerprint,
+            identity.certificates,
+            private_key
+        ],
+    ) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error inserting S/MIME identity"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    drop(locked_conn);
+    task::spawn(async move {
+        match database::backup(conn).await {
+            Ok(_) => {}
+            Err(e) => e.log_error(),
+        }
+    });
+
+    return Ok(());
+}
+
+/// Identities of an account in the order they were imported.
+pub async fn get(
+    conn: Arc<Mutex<Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+) -> Result<Vec<StoredIdentity>, MyError> {
+    let locked_conn = conn.lock().await;
+
+    let mut stmt = match locked_conn.prepare_cached(
+        "SELECT fingerprint, certificates, private_key FROM smime_identities
+WHERE c_username = ?1 AND c_address = ?2 ORDER BY rowid",
+    ) {
+        Ok(stmt) => stmt,
+        Err(e) => {
+            let err = MyError::Sqlite(
+                e,
+                String::from("Error preparing statement at smime_identities"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    let rows = stmt.query_map(params![client.username, client.address], |row| {
+        let private_key: String = row.get(2)?;
+        let private_key = match credentials.decrypt(&private_key) {
+            Ok(private_key) => private_key,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        Ok(Ok(StoredIdentity {
+            fingerprint: row.get(0)?,
+            certificates: row.get(1)?,
+            private_key,
+        }))
+    });
+
+    match rows {
+        Ok(rows) => {
+            let mut identities: Vec<StoredIdentity> = vec![];
+
+            for row in rows {
+                match row {
+                    Ok(Ok(identity)) => identities.push(identity),
+                    Ok(Err(e)) => return Err(e),
+                    Err(e) => {
+                        let err = MyError::Sqlite(e, String::from("Error reading S/MIME identity"));
+                        err.log_error();
+
+                        return Err(err);
+                    }
+                }
+            }
+
+            return Ok(identities);
+        }
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error getting S/MIME identities"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+/// Removes an identity from an account, returns whether it was there.
+pub async fn remove(
+    conn: Arc<Mutex<Connection>>,
+    client: &Client,
+    fingerprint: &str,
+) -> Result<bool, MyError> {
+    let locked_conn = conn.lock().await;
+
+    let removed = match locked_conn.execute(
+        "DELETE FROM smime_identities WHERE c_username = ?1 AND c_address = ?2 AND fingerprint = ?3",
+        params![client.username, client.address, fingerprint],
+    ) {
+        Ok(removed) => removed,
+        Err(e) => {
+            let err = MyError::Sqlite(e, String::from("Error removing S/MIME identity"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    drop(locked_conn);
+    task::spawn(async move {
+        match database::backup(conn).await {
+            Ok(_) => {}
+            Err(e) => e.log_error(),
+        }
+    });
+
+    return Ok(removed > 0);
+}
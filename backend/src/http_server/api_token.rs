@@ -0,0 +1,311 @@
+use rand::RngCore;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use crate::http_server::request::{HttpError, Request};
+use crate::my_error::MyError;
+
+pub const DEFAULT_TOKEN_FILE: &str = "api.token";
+const TOKEN_LEN: usize = 32;
+
+/// Hosts the server is reached by, requests naming any other host come from
+/// a page that rebound its own domain to the loopback address.
+const ALLOWED_HOSTS: [&str; 3] = ["localhost", "127.0.0.1", "[::1]"];
+
+pub fn path_from_env(data_dir: &Path) -> String {
+    match std::env::var("MAIL_API_TOKEN_FILE") {
+        Ok(path) if !path.is_empty() => return path,
+        _ => {
+            return data_dir
+                .join(DEFAULT_TOKEN_FILE)
+                .to_string_lossy()
+                .to_string()
+        }
+    }
+}
+
+/// Reads the API token, or creates the token file readable only by the
+/// current user on the first start. An existing file other users can read is
+/// restricted to the current user before the token is read from it.
+pub fn read_or_create(path: &str) -> Result<String, MyError> {
+    match fs::File::open(path) {
+        Ok(mut file) => {
+            match restrict(&file, path) {
+                Ok(_) => {}
+                Err(e) => return Err(e),
+            };
+
+            let mut token = String::new();
+            match file.read_to_string(&mut token) {
+                Ok(_) if !token.trim().is_empty() => return Ok(token.trim().to_string()),
+                Ok(_) => {}
+                Err(e) => {
+                    let err = MyError::Io(e, String::from("Error reading API token file"));
+                    err.log_error();
+
+                    return Err(err);
+                }
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            let err = MyError::Io(e, String::from("Error reading API token file"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    let mut bytes = [0u8; TOKEN_LEN];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    match options
+        .open(path)
+        .and_then(|mut file| file.write_all(token.as_bytes()))
+    {
+        Ok(_) => return Ok(token),
+        Err(e) => {
+            let err = MyError::Io(e, String::from("Error creating API token file"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+/// Takes the permissions of group and others from the opened token file, the
+/// token grants access to every account.
+#[cfg(unix)]
+fn restrict(file: &fs::File, path: &str) -> Result<(), MyError> {
+    use crate::config::{self, LogLevel};
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = match file.metadata() {
+        Ok(metadata) => metadata.permissions().mode(),
+        Err(e) => {
+            let err = MyError::Io(e, String::from("Error reading API token file"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    if mode & 0o077 == 0 {
+        return Ok(());
+    }
+
+    if config::log_enabled(LogLevel::Error) {
+        eprintln!(
+            "API token file {} was readable by other users, restricting it to mode 600",
+            path
+        );
+    }
+
+    match file.set_permissions(fs::Permissions::from_mode(0o600)) {
+        Ok(_) => return Ok(()),
+        Err(e) => {
+            let err = MyError::Io(e, String::from("Error restricting API token file"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn restrict(_file: &fs::File, _path: &str) -> Result<(), MyError> {
+    return Ok(());
+}
+
+/// Checks the Host and Origin headers and the bearer token of a request.
+pub fn authorize(request: &Request, token: &str) -> Result<(), HttpError> {
+    match request.header("Host") {
+        Some(host) if allowed_host(host) => {}
+        Some(_) => return Err(HttpError::new(403, "Host not allowed")),
+        None => return Err(HttpError::new(400, "Missing Host header")),
+    }
+
+    match request.header("Origin") {
+        Some(origin) if !allowed_origin(origin) => {
+            return Err(HttpError::new(403, "Origin not allowed"));
+        }
+        _ => {}
+    }
+
+    let provided = match request.header("Authorization") {
+        Some(authorization) => match authorization.split_once(' ') {
+            Some((scheme, value)) if scheme.eq_ignore_ascii_case("Bearer") => value.trim(),
+            _ => "",
+        },
+        None => "",
+    };
+
+    if provided.is_empty() || !constant_time_eq(provided.as_bytes(), token.as_bytes()) {
+        return Err(HttpError::new(401, "Missing or invalid API token"));
+    }
+
+    return Ok(());
+}
+
+fn allowed_host(host: &str) -> bool {
+    let host = host.trim().to_lowercase();
+
+    // Strip the port, IPv6 addresses keep their brackets.
+    let name = match host.rfind(':') {
+        Some(idx) if !host[idx..].contains(']') => &host[..idx],
+        _ => host.as_str(),
+    };
+
+    return ALLOWED_HOSTS.contains(&name);
+}
+
+fn allowed_origin(origin: &str) -> bool {
+    let origin = origin.trim().to_lowercase();
+
+    let host = match origin
+        .strip_prefix("http://")
+        .or_else(|| origin.strip_prefix("https://"))
+    {
+        Some(host) => host,
+        None => return false,
+    };
+
+    return !host.contains('/') && allowed_host(host);
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    return a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_request(headers: &[(&str, &str)]) -> Request {
+        return Request {
+            method: String::from("POST"),
+            path: String::from("/get_sessions"),
+            query: String::new(),
+            version: String::from("HTTP/1.1"),
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            body: vec![],
+        };
+    }
+
+    #[test]
+    fn valid_request() {
+        for host in [
+            "localhost:9001",
+            "127.0.0.1:9001",
+            "[::1]:9001",
+            "LOCALHOST",
+        ] {
+            let request = get_request(&[("Host", host), ("Authorization", "Bearer secret")]);
+
+            assert_eq!(authorize(&request, "secret"), Ok(()));
+        }
+
+        let request = get_request(&[
+            ("Host", "localhost:9001"),
+            ("Origin", "http://localhost:3000"),
+            ("Authorization", "bearer secret"),
+        ]);
+        assert_eq!(authorize(&request, "secret"), Ok(()));
+    }
+
+    #[test]
+    fn wrong_token() {
+        for authorization in ["Bearer wrong", "Bearer ", "Basic secret", "secret"] {
+            let request = get_request(&[("Host", "localhost"), ("Authorization", authorization)]);
+
+            assert_eq!(authorize(&request, "secret").unwrap_err().status, 401);
+        }
+
+        let request = get_request(&[("Host", "localhost")]);
+        assert_eq!(authorize(&request, "secret").unwrap_err().status, 401);
+    }
+
+    #[test]
+    fn rebinding_rejected() {
+        let request = get_request(&[
+            ("Host", "attacker.example:9001"),
+            ("Authorization", "Bearer secret"),
+        ]);
+        assert_eq!(authorize(&request, "secret").unwrap_err().status, 403);
+
+        for origin in [
+            "http://attacker.example",
+            "null",
+            "http://localhost.attacker.example",
+            "http://localhost:9001/path",
+        ] {
+            let request = get_request(&[
+                ("Host", "localhost:9001"),
+                ("Origin", origin),
+                ("Authorization", "Bearer secret"),
+            ]);
+
+            assert_eq!(authorize(&request, "secret").unwrap_err().status, 403);
+        }
+
+        let request = get_request(&[("Authorization", "Bearer secret")]);
+        assert_eq!(authorize(&request, "secret").unwrap_err().status, 400);
+    }
+
+    #[test]
+    fn token_file_created() {
+        let path = std::env::temp_dir().join(format!("api-token-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let token = read_or_create(path).unwrap();
+        assert_eq!(token.len(), TOKEN_LEN * 2);
+        assert_eq!(read_or_create(path).unwrap(), token);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn token_file_restricted() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("api-token-open-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        fs::write(path, "secret\n").unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        assert_eq!(read_or_create(path).unwrap(), "secret");
+
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        fs::remove_file(path).unwrap();
+    }
+}
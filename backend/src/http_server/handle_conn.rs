@@ -1,661 +1,2407 @@
 use async_std::sync::{Arc, Mutex};
-
-use crate::http_server::{params, to_display};
+use async_std::task;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::database;
+use crate::database::credentials::{CredentialStore, KeySource};
+use crate::http_server::params;
+use crate::http_server::request::Request;
+use crate::http_server::response::Response;
+use crate::http_server::to_display::{self, MessageBodyDisplay, MessageSummaryDisplay};
 use crate::inbox_client;
-use crate::types::session::{Client, Session};
+use crate::inbox_client::pool::SessionPool;
+use crate::mime_parser::itip::PartStat;
+use crate::my_error::MyError;
+use crate::types::account_state::AccountState;
+use crate::types::database_request::{Sort, SortField, SortOrder};
+use crate::types::error_code::ErrorCode;
+use crate::types::page_cursor::PageCursor;
+use crate::types::session::{AuthMechanism, Client, OAuth, Security};
+
+/// Largest page of messages returned by one keyset paginated request.
+const MAX_PAGE_SIZE: u32 = 500;
+
+#[derive(Debug, Serialize)]
+struct ApiResponse<T: Serialize> {
+    success: bool,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<T>,
+}
+
+fn success<T: Serialize>(message: &str, data: Option<T>) -> Response {
+    let response = ApiResponse {
+        success: true,
+        message: message.to_string(),
+        data,
+    };
+
+    match serde_json::to_string(&response) {
+        Ok(body) => return Response::json(200, body),
+        Err(e) => {
+            eprintln!("Error serialising response: {}", e);
+            return Response::error(500, "Error serialising response");
+        }
+    }
+}
+
+fn invalid_request(message: &str) -> Response {
+    let err = MyError::Code(
+        ErrorCode::InvalidRequest,
+        message.to_string(),
+        String::from("Invalid request parameters"),
+    );
+
+    return Response::from_error(&err);
+}
+
+fn invalid_session() -> Response {
+    let err = MyError::Code(
+        ErrorCode::InvalidSession,
+        String::from("No session with this session_id"),
+        String::from("Invalid session_id"),
+    );
+
+    return Response::from_error(&err);
+}
+
+async fn get_client(clients: &Arc<Mutex<Vec<Client>>>, session_id: usize) -> Option<Client> {
+    let locked_clients = clients.lock().await;
+
+    return locked_clients.get(session_id).cloned();
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: Option<String>,
+    pub address: String,
+    #[serde(deserialize_with = "params::u16_param")]
+    pub port: u16,
+    pub security: Option<String>,
+    pub cert_fingerprint: Option<String>,
+    pub auth: Option<String>,
+    pub token_endpoint: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoginData {
+    pub session_id: usize,
+}
 
 pub async fn login(
-    uri: &str,
-    sessions: Arc<Mutex<Vec<Session>>>,
+    request: &Request,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: Arc<CredentialStore>,
     clients: Arc<Mutex<Vec<Client>>>,
-) -> String {
-    let uri_params = params::parse_params(String::from(uri));
-
-    let username = uri_params.get("username");
-    let password = uri_params.get("password");
-    let address = uri_params.get("address");
-    let port = match params::get_u16(uri_params.get("port")) {
-        Ok(port) => port,
-        Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
-        }
+    config: Arc<Config>,
+) -> Response {
+    let request: LoginRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
     };
 
-    if username.is_none() || password.is_none() || address.is_none() || port.is_none() {
-        eprintln!(
-            "Provide username, password, address, and port GET parameters: {}",
-            uri
-        );
-        return String::from("{\"success\": false, \"message\": \"Provide all GET parameters\"}");
-    }
+    let auth_mechanism = match &request.auth {
+        Some(auth) => match AuthMechanism::from_str(auth) {
+            Some(auth_mechanism) => auth_mechanism,
+            None => {
+                return invalid_request("Auth must be password, xoauth2 or oauthbearer");
+            }
+        },
+        None => AuthMechanism::Password,
+    };
 
-    let username = username.unwrap();
-    let password = password.unwrap();
-    let address = address.unwrap();
-    let port = port.unwrap();
+    let oauth = match auth_mechanism {
+        AuthMechanism::Password => None,
+        _ => match (
+            &request.token_endpoint,
+            &request.client_id,
+            &request.refresh_token,
+        ) {
+            (Some(token_endpoint), Some(client_id), Some(refresh_token)) => Some(OAuth {
+                token_endpoint: token_endpoint.to_string(),
+                client_id: client_id.to_string(),
+                client_secret: request.client_secret.clone(),
+                refresh_token: refresh_token.to_string(),
+            }),
+            _ => {
+                return invalid_request(
+                    "Provide token_endpoint, client_id, and refresh_token parameters",
+                );
+            }
+        },
+    };
 
-    let locked_clients = clients.lock().await;
-    match locked_clients
-        .iter()
-        .position(|x| x.username == username.to_string() && x.address == address.to_string())
-    {
-        Some(idx) => {
-            return format!("{{\"success\": true, \"message\": \"Allready connected to IMAP server\", \"data\": {{ \"session_id\": {}}}}}", idx);
+    let password = match (&request.password, &oauth) {
+        (Some(password), _) => password.to_string(),
+        (None, Some(_)) => String::new(),
+        (None, None) => {
+            return invalid_request("Provide username, password, address, and port parameters");
         }
-        None => {}
     };
 
-    drop(locked_clients);
+    let security = match &request.security {
+        Some(security) => match Security::from_str(security) {
+            Some(security) => security,
+            None => {
+                return invalid_request("Security must be tls or starttls");
+            }
+        },
+        None => Security::default_for_port(request.port),
+    };
 
     let client_add = Client {
-        username: username.to_string(),
-        password: password.to_string(),
-        address: address.to_string(),
-        port,
+        username: request.username,
+        password,
+        address: request.address,
+        port: request.port,
+        security,
+        cert_fingerprint: request.cert_fingerprint,
+        auth_mechanism,
+        oauth,
     };
 
     let clients_2 = Arc::clone(&clients);
-    match inbox_client::connect::connect(sessions, database_conn, clients_2, &client_add).await {
+    match inbox_client::connect::connect(
+        Arc::clone(&sessions),
+        Arc::clone(&database_conn),
+        credentials,
+        clients_2,
+        &client_add,
+        config.pool_size,
+    )
+    .await
+    {
         Ok(idx) => {
-            return format!("{{\"success\": true, \"message\": \"Connected to IMAP server\", \"data\": {{ \"session_id\": {}}}}}", idx);
+            task::spawn(async move {
+                match inbox_client::prefetch::prefetch(
+                    sessions,
+                    database_conn,
+                    idx,
+                    &client_add,
+                    config.prefetch,
+                    config.sync_batch_size,
+                )
+                .await
+                {
+                    Ok(_) => {}
+                    Err(e) => e.log_error(),
+                }
+            });
+
+            return success(
+                "Connected to IMAP server",
+                Some(LoginData { session_id: idx }),
+            );
         }
         Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
+            return Response::from_error(&e);
         }
     }
 }
 
+#[derive(Debug, Deserialize)]
+pub struct SessionRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+}
+
 pub async fn logout(
-    uri: &str,
-    sessions: Arc<Mutex<Vec<Session>>>,
+    request: &Request,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
     clients: Arc<Mutex<Vec<Client>>>,
-) -> String {
-    let uri_params = params::parse_params(String::from(uri));
-
-    let session_id = match params::get_usize(uri_params.get("session_id")) {
-        Ok(session_id) => session_id,
-        Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
-        }
+) -> Response {
+    let request: SessionRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
     };
 
-    if session_id.is_none() {
-        eprintln!("Provide session_id GET parameter: {}", uri);
-        return String::from(
-            "{\"success\": false, \"message\": \"Provide session_id GET parameter\"}",
-        );
-    }
-
-    let session_id = session_id.unwrap();
-
-    let locked_clients = clients.lock().await;
-
-    if session_id + 1 > locked_clients.len() {
-        return String::from("{\"success\": false, \"message\": \"Invalid session_id\"}");
-    }
-
-    let client = &locked_clients[session_id].clone();
-    drop(locked_clients);
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
+    };
 
-    match inbox_client::logout::logout(sessions, database_conn, client, session_id).await {
+    match inbox_client::logout::logout(
+        sessions,
+        database_conn,
+        clients,
+        &client,
+        request.session_id,
+    )
+    .await
+    {
         Ok(_) => {
-            return format!(
-                "{{\"success\": true, \"message\": \"Logged out\", \"data\": {}}}",
-                session_id
-            );
+            return success("Logged out", Some(request.session_id));
         }
         Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
+            return Response::from_error(&e);
         }
     }
 }
 
-pub async fn get_sessions(clients: Arc<Mutex<Vec<Client>>>) -> String {
-    let mut response =
-        String::from("{\"success\": true, \"message\": \"Sessions retrieved\", \"data\": [");
+#[derive(Debug, Serialize)]
+pub struct SessionData<'a> {
+    pub session_id: usize,
+    pub username: &'a str,
+    pub address: &'a str,
+    pub port: u16,
+}
 
+pub async fn get_sessions(clients: Arc<Mutex<Vec<Client>>>) -> Response {
     let locked_clients = clients.lock().await;
 
-    for (i, client) in locked_clients.iter().enumerate() {
-        response.push_str(&format!(
-            "{{\"session_id\": {}, \"username\": \"{}\", \"address\": \"{}\", \"port\": {}}}",
-            i, client.username, client.address, client.port
-        ));
+    let sessions: Vec<SessionData> = locked_clients
+        .iter()
+        .enumerate()
+        .map(|(i, client)| SessionData {
+            session_id: i,
+            username: &client.username,
+            address: &client.address,
+            port: client.port,
+        })
+        .collect();
 
-        if i < locked_clients.len() - 1 {
-            response.push_str(",");
-        }
-    }
-    response.push_str("]}");
+    return success("Sessions retrieved", Some(sessions));
+}
 
-    return response;
+#[derive(Debug, Serialize)]
+pub struct AccountStatusData {
+    pub session_id: usize,
+    pub username: String,
+    pub address: String,
+    pub state: String,
+    pub retry_at: Option<i64>,
+    pub attempts: u32,
 }
 
-pub async fn get_mailboxes(
-    uri: &str,
+pub async fn get_account_status(sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>) -> Response {
+    let pools: Vec<Arc<SessionPool>> = sessions.lock().await.iter().cloned().collect();
+
+    let statuses: Vec<AccountStatusData> = pools
+        .iter()
+        .enumerate()
+        .map(|(i, pool)| {
+            let client = pool.client();
+            let health = pool.health().lock().unwrap().clone();
+
+            let retry_at = match health.state {
+                AccountState::Backoff(until) => Some(until),
+                _ => None,
+            };
+
+            AccountStatusData {
+                session_id: i,
+                username: client.username.clone(),
+                address: client.address.clone(),
+                state: health.state.name().to_string(),
+                retry_at,
+                attempts: health.attempts,
+            }
+        })
+        .collect();
+
+    return success("Account status retrieved", Some(statuses));
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangeMasterKeyRequest {
+    pub passphrase: Option<String>,
+    pub key_file: Option<String>,
+}
+
+pub async fn change_master_key(
+    request: &Request,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
-    clients: Arc<Mutex<Vec<Client>>>,
-) -> String {
-    let uri_params = params::parse_params(String::from(uri));
+    credentials: Arc<CredentialStore>,
+) -> Response {
+    let request: ChangeMasterKeyRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
 
-    let session_id = match params::get_usize(uri_params.get("session_id")) {
-        Ok(session_id) => session_id,
-        Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
+    let source = match (request.passphrase, request.key_file) {
+        (Some(passphrase), None) => KeySource::Passphrase(passphrase),
+        (None, Some(key_file)) => KeySource::KeyFile(key_file),
+        _ => {
+            return invalid_request("Provide either passphrase or key_file parameter");
         }
     };
 
-    if session_id.is_none() {
-        eprintln!("Provide session_id GET parameter: {}", uri);
-        return String::from(
-            "{\"success\": false, \"message\": \"Provide session_id GET parameter\"}",
-        );
-    }
+    let locked_conn = database_conn.lock().await;
+    let result = credentials.change_key(&locked_conn, &source);
+    drop(locked_conn);
 
-    let session_id = session_id.unwrap();
-
-    let locked_clients = clients.lock().await;
-
-    if session_id + 1 > locked_clients.len() {
-        return String::from("{\"success\": false, \"message\": \"Invalid session_id\"}");
+    match result {
+        Ok(_) => {
+            task::spawn(async move {
+                match database::backup(database_conn).await {
+                    Ok(_) => {}
+                    Err(e) => e.log_error(),
+                }
+            });
+
+            return success::<()>("Master key changed", None);
+        }
+        Err(e) => {
+            return Response::from_error(&e);
+        }
     }
+}
 
-    let client = &locked_clients[session_id].clone();
-    drop(locked_clients);
+pub async fn get_mailboxes(
+    request: &Request,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    clients: Arc<Mutex<Vec<Client>>>,
+) -> Response {
+    let request: SessionRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
 
-    match inbox_client::mailboxes::get_database(database_conn, client).await {
-        Ok(mailboxes) => {
-            let mailboxes_str = to_display::string_vec_to_display(&mailboxes);
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
+    };
 
-            return format!(
-                "{{\"success\": true, \"message\": \"Mailboxes retrieved\", \"data\": {}}}",
-                mailboxes_str
-            );
+    match inbox_client::mailboxes::get_database(database_conn, &client).await {
+        Ok(mailboxes) => {
+            return success("Mailboxes retrieved", Some(mailboxes));
         }
         Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
+            return Response::from_error(&e);
         }
     }
 }
 
 pub async fn update_mailboxes(
-    uri: &str,
-    sessions: Arc<Mutex<Vec<Session>>>,
+    request: &Request,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
     clients: Arc<Mutex<Vec<Client>>>,
-) -> String {
-    let uri_params = params::parse_params(String::from(uri));
+) -> Response {
+    let request: SessionRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
 
-    let session_id = match params::get_usize(uri_params.get("session_id")) {
-        Ok(session_id) => session_id,
-        Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
-        }
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
     };
 
-    if session_id.is_none() {
-        eprintln!("Provide session_id GET parameter: {}", uri);
-        return String::from(
-            "{\"success\": false, \"message\": \"Provide session_id GET parameter\"}",
-        );
+    match inbox_client::mailboxes::update(sessions, database_conn, request.session_id, &client)
+        .await
+    {
+        Ok(mailboxes) => {
+            return success("Mailboxes updated", Some(mailboxes));
+        }
+        Err(e) => {
+            return Response::from_error(&e);
+        }
     }
+}
 
-    let session_id = session_id.unwrap();
-
-    let locked_clients = clients.lock().await;
+#[derive(Debug, Deserialize)]
+pub struct GetMessagesWithUidsRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+    pub mailbox_path: String,
+    #[serde(deserialize_with = "params::list_param")]
+    pub message_uids: Vec<u32>,
+    #[serde(default, deserialize_with = "params::bool_param")]
+    pub load_remote: bool,
+}
 
-    if session_id + 1 > locked_clients.len() {
-        return String::from("{\"success\": false, \"message\": \"Invalid session_id\"}");
-    }
+pub async fn get_messages_with_uids(
+    request: &Request,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: Arc<CredentialStore>,
+    clients: Arc<Mutex<Vec<Client>>>,
+) -> Response {
+    let request: GetMessagesWithUidsRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
 
-    let client = &locked_clients[session_id].clone();
-    drop(locked_clients);
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
+    };
 
-    match inbox_client::mailboxes::update(sessions, database_conn, session_id, client).await {
-        Ok(mailboxes) => {
-            let mailboxes_str = to_display::string_vec_to_display(&mailboxes);
+    match inbox_client::messages::get_database_bodies(
+        database_conn,
+        &credentials,
+        &client,
+        &request.mailbox_path,
+        &request.message_uids,
+        request.load_remote,
+    )
+    .await
+    {
+        Ok(messages) => {
+            let messages: Vec<MessageBodyDisplay> =
+                to_display::message_vec_to_body_display(&messages);
 
-            return format!(
-                "{{\"success\": true, \"message\": \"Mailboxes updated\", \"data\": {}}}",
-                mailboxes_str
-            );
+            return success("Messages retrieved", Some(messages));
         }
         Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
+            return Response::from_error(&e);
         }
     }
 }
 
-pub async fn get_messages_with_uids(
-    uri: &str,
+#[derive(Debug, Deserialize)]
+pub struct GetMessagesSortedRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+    pub mailbox_path: String,
+    #[serde(default, deserialize_with = "params::u32_option_param")]
+    pub start: Option<u32>,
+    #[serde(default, deserialize_with = "params::u32_option_param")]
+    pub end: Option<u32>,
+    #[serde(default, deserialize_with = "params::u32_option_param")]
+    pub limit: Option<u32>,
+    pub cursor: Option<String>,
+    pub sort: Option<String>,
+    pub order: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessagePageData<'a> {
+    pub messages: Vec<MessageSummaryDisplay<'a>>,
+    pub next_cursor: Option<String>,
+}
+
+pub async fn get_messages_sorted(
+    request: &Request,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
     clients: Arc<Mutex<Vec<Client>>>,
-) -> String {
-    let uri_params = params::parse_params(String::from(uri));
+) -> Response {
+    let request: GetMessagesSortedRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
 
-    let session_id = match params::get_usize(uri_params.get("session_id")) {
-        Ok(session_id) => session_id,
-        Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
-        }
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
     };
-    let mailbox_path = uri_params.get("mailbox_path");
-    let message_uids = uri_params.get("message_uids");
 
-    if session_id.is_none() || mailbox_path.is_none() || message_uids.is_none() {
-        eprintln!(
-            "Provide session_id, mailbox_path, and message_uids GET parameters: {}",
-            uri
-        );
-        return String::from("{\"success\": false, \"message\": \"Provide session_id, mailbox_path and message_uid GET parameters\"}");
-    }
+    let field = match &request.sort {
+        Some(sort) => match SortField::from_str(sort) {
+            Some(field) => field,
+            None => {
+                return invalid_request(
+                    "Sort must be date, received, sender, subject, size, flagged or unread",
+                );
+            }
+        },
+        None => SortField::Received,
+    };
 
-    let session_id = session_id.unwrap();
-    let mailbox_path = mailbox_path.unwrap();
-    let message_uids = message_uids.unwrap();
+    let order = match &request.order {
+        Some(order) => match SortOrder::from_str(order) {
+            Some(order) => order,
+            None => return invalid_request("Order must be asc or desc"),
+        },
+        None => SortOrder::Desc,
+    };
 
-    let message_uids: Vec<u32> = message_uids
-        .split(",")
-        .map(|x| x.parse::<u32>().unwrap())
-        .collect();
+    let sort = Sort { field, order };
+
+    match (request.start, request.end, request.limit) {
+        (Some(start), Some(end), None) => {
+            if end < start {
+                return invalid_request("End must not be before start");
+            }
+
+            match inbox_client::messages::get_database_sorted(
+                database_conn,
+                &client,
+                &request.mailbox_path,
+                sort,
+                start,
+                end,
+            )
+            .await
+            {
+                Ok(messages) => {
+                    let messages: Vec<MessageSummaryDisplay> =
+                        to_display::message_vec_to_summary_display(&messages);
+
+                    return success("Messages retrieved", Some(messages));
+                }
+                Err(e) => {
+                    return Response::from_error(&e);
+                }
+            }
+        }
+        (None, None, Some(limit)) => {
+            if limit == 0 || limit > MAX_PAGE_SIZE {
+                return invalid_request(&format!("Limit must be between 1 and {}", MAX_PAGE_SIZE));
+            }
+
+            let after = match &request.cursor {
+                Some(cursor) if !cursor.is_empty() => match PageCursor::decode(cursor) {
+                    Some(after) if after.matches(&sort) => Some(after),
+                    _ => return invalid_request("Cursor does not belong to this sort order"),
+                },
+                _ => None,
+            };
+
+            match inbox_client::messages::get_database_page(
+                database_conn,
+                &client,
+                &request.mailbox_path,
+                sort,
+                after,
+                limit,
+            )
+            .await
+            {
+                Ok((messages, next_cursor)) => {
+                    let data = MessagePageData {
+                        messages: to_display::message_vec_to_summary_display(&messages),
+                        next_cursor: next_cursor.map(|cursor| cursor.encode()),
+                    };
+
+                    return success("Messages retrieved", Some(data));
+                }
+                Err(e) => {
+                    return Response::from_error(&e);
+                }
+            }
+        }
+        _ => return invalid_request("Provide start and end, or limit and an optional cursor"),
+    }
+}
 
-    let locked_clients = clients.lock().await;
+#[derive(Debug, Deserialize)]
+pub struct GetMessagesWithFlagRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+    pub mailbox_path: String,
+    pub flag: String,
+    #[serde(deserialize_with = "params::bool_param")]
+    pub not_flag: bool,
+}
 
-    if session_id + 1 > locked_clients.len() {
-        return String::from("{\"success\": false, \"message\": \"Invalid session_id\"}");
-    }
+pub async fn get_messages_with_flag(
+    request: &Request,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    clients: Arc<Mutex<Vec<Client>>>,
+) -> Response {
+    let request: GetMessagesWithFlagRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
 
-    let client = &locked_clients[session_id].clone();
-    drop(locked_clients);
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
+    };
 
-    match inbox_client::messages::get_database_with_uids(
+    match inbox_client::messages::get_database_with_flag(
         database_conn,
-        client,
-        mailbox_path,
-        &message_uids,
+        &client,
+        &request.mailbox_path,
+        &request.flag,
+        request.not_flag,
     )
     .await
     {
         Ok(messages) => {
-            let messages_str = to_display::message_vec_to_display(&messages);
+            let messages: Vec<MessageSummaryDisplay> =
+                to_display::message_vec_to_summary_display(&messages);
 
-            return format!(
-                "{{\"success\": true, \"message\": \"Messages retrieved\", \"data\": {}}}",
-                messages_str
-            );
+            return success("Messages retrieved", Some(messages));
         }
         Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
+            return Response::from_error(&e);
         }
     }
 }
 
-pub async fn get_messages_sorted(
-    uri: &str,
+#[derive(Debug, Deserialize)]
+pub struct ChangesSinceRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+    pub mailbox_path: String,
+    #[serde(default, deserialize_with = "params::u32_param")]
+    pub cursor: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChangesSinceData<'a> {
+    pub cursor: u32,
+    pub reset: bool,
+    pub changed: Vec<MessageSummaryDisplay<'a>>,
+    pub removed: Vec<u32>,
+}
+
+pub async fn changes_since(
+    request: &Request,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
     clients: Arc<Mutex<Vec<Client>>>,
-) -> String {
-    let uri_params = params::parse_params(String::from(uri));
+) -> Response {
+    let request: ChangesSinceRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
 
-    let session_id = match params::get_usize(uri_params.get("session_id")) {
-        Ok(session_id) => session_id,
-        Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
-        }
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
     };
-    let mailbox_path = uri_params.get("mailbox_path");
 
-    let start = match params::get_u32(uri_params.get("start")) {
-        Ok(start) => start,
-        Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
+    match inbox_client::messages::get_database_changes_since(
+        database_conn,
+        &client,
+        &request.mailbox_path,
+        request.cursor,
+    )
+    .await
+    {
+        Ok((changes, messages)) => {
+            let data = ChangesSinceData {
+                cursor: changes.cursor,
+                reset: changes.reset,
+                changed: to_display::message_vec_to_summary_display(&messages),
+                removed: changes.removed,
+            };
+
+            return success("Changes retrieved", Some(data));
         }
-    };
-    let end = match params::get_u32(uri_params.get("end")) {
-        Ok(end) => end,
         Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
+            return Response::from_error(&e);
         }
-    };
-
-    if session_id.is_none() || mailbox_path.is_none() || start.is_none() || end.is_none() {
-        eprintln!(
-            "Provide session_id, mailbox_path, start, and end GET parameters: {}",
-            uri
-        );
-        return String::from(
-            "{\"success\": false, \"message\": \"Provide session_id, mailbox_path, start, and end GET parameters\"}",
-        );
     }
+}
 
-    let session_id = session_id.unwrap();
-    let mailbox_path = mailbox_path.unwrap();
-    let start = start.unwrap();
-    let end = end.unwrap();
+#[derive(Debug, Deserialize)]
+pub struct UpdateMailboxRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+    pub mailbox_path: String,
+    #[serde(default, deserialize_with = "params::bool_param")]
+    pub quick: bool,
+}
 
-    let locked_clients = clients.lock().await;
+#[derive(Debug, Serialize)]
+pub struct UpdateMailboxData {
+    pub new_uids: Vec<u32>,
+    pub removed_uids: Vec<u32>,
+    pub changed_uids: Vec<u32>,
+}
 
-    if session_id + 1 > locked_clients.len() {
-        return String::from("{\"success\": false, \"message\": \"Invalid session_id\"}");
-    }
+pub async fn update_mailbox(
+    request: &Request,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    clients: Arc<Mutex<Vec<Client>>>,
+    config: Arc<Config>,
+) -> Response {
+    let request: UpdateMailboxRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
 
-    let client = &locked_clients[session_id].clone();
-    drop(locked_clients);
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
+    };
 
-    match inbox_client::messages::get_database_sorted(
+    match inbox_client::update_mailbox::update_mailbox(
+        sessions,
         database_conn,
-        client,
-        mailbox_path,
-        start,
-        end,
+        request.session_id,
+        &client,
+        &request.mailbox_path,
+        request.quick,
+        config.sync_batch_size,
     )
     .await
     {
-        Ok(messages) => {
-            let messages_str = to_display::message_vec_to_display(&messages);
-            
-            return format!(
-                "{{\"success\": true, \"message\": \"Messages retrieved\", \"data\": {}}}",
-                messages_str
-            );
+        Ok(updated) => {
+            let data = UpdateMailboxData {
+                new_uids: updated.new,
+                removed_uids: updated.removed,
+                changed_uids: updated.changed,
+            };
+
+            return success("Mailbox updated", Some(data));
         }
         Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
+            return Response::from_error(&e);
         }
     }
 }
 
-pub async fn get_messages_with_flag(
-    uri: &str,
+#[derive(Debug, Deserialize)]
+pub struct ModifyFlagsRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+    pub mailbox_path: String,
+    #[serde(deserialize_with = "params::u32_param")]
+    pub message_uid: u32,
+    #[serde(deserialize_with = "params::list_param")]
+    pub flags: Vec<String>,
+    #[serde(deserialize_with = "params::bool_param")]
+    pub add: bool,
+}
+
+pub async fn modify_flags(
+    request: &Request,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
     clients: Arc<Mutex<Vec<Client>>>,
-) -> String {
-    let uri_params = params::parse_params(String::from(uri));
+) -> Response {
+    let request: ModifyFlagsRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
 
-    let session_id = match params::get_usize(uri_params.get("session_id")) {
-        Ok(session_id) => session_id,
-        Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
-        }
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
     };
-    let mailbox_path = uri_params.get("mailbox_path");
-    let flag = uri_params.get("flag");
-    let not_flag = match params::get_bool(uri_params.get("not_flag")) {
-        Ok(not_flag) => not_flag,
+
+    match inbox_client::message_flags::modify(
+        database_conn,
+        sessions,
+        request.session_id,
+        &client,
+        &request.mailbox_path,
+        request.message_uid,
+        &request.flags,
+        request.add,
+    )
+    .await
+    {
+        Ok(_) => {
+            return success("Flags successfully updated", Some(request.flags));
+        }
         Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
+            return Response::from_error(&e);
         }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MoveMessageRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+    pub mailbox_path: String,
+    #[serde(deserialize_with = "params::u32_param")]
+    pub message_uid: u32,
+    pub mailbox_path_dest: String,
+}
+
+pub async fn move_message(
+    request: &Request,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    clients: Arc<Mutex<Vec<Client>>>,
+) -> Response {
+    let request: MoveMessageRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
     };
 
-    if session_id.is_none() || mailbox_path.is_none() || flag.is_none() || not_flag.is_none() {
-        eprintln!(
-            "Provide session_id, mailbox_path, flag, and not_flag GET parameters: {}",
-            uri
-        );
-        return String::from("{\"success\": false, \"message\": \"Provide session_id, mailbox_path and message_uid GET parameters\"}");
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
+    };
+
+    match inbox_client::message::mv(
+        sessions,
+        database_conn,
+        request.session_id,
+        &client,
+        &request.mailbox_path,
+        request.message_uid,
+        &request.mailbox_path_dest,
+    )
+    .await
+    {
+        Ok(_) => {
+            return success::<()>("Message successfully moved", None);
+        }
+        Err(e) => {
+            return Response::from_error(&e);
+        }
     }
+}
 
-    let session_id = session_id.unwrap();
-    let mailbox_path = mailbox_path.unwrap();
-    let flag = flag.unwrap();
-    let not_flag = not_flag.unwrap();
+#[derive(Debug, Deserialize)]
+pub struct MessageSourceRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+    pub mailbox_path: String,
+    #[serde(deserialize_with = "params::u32_param")]
+    pub message_uid: u32,
+}
 
-    let locked_clients = clients.lock().await;
+pub async fn get_headers(
+    request: &Request,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    clients: Arc<Mutex<Vec<Client>>>,
+) -> Response {
+    let request: MessageSourceRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
+
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
+    };
 
-    if session_id + 1 > locked_clients.len() {
-        return String::from("{\"success\": false, \"message\": \"Invalid session_id\"}");
+    match inbox_client::message::get_headers(
+        sessions,
+        database_conn,
+        request.session_id,
+        &client,
+        &request.mailbox_path,
+        request.message_uid,
+    )
+    .await
+    {
+        Ok(headers) => {
+            return success("Headers retrieved", Some(headers));
+        }
+        Err(e) => {
+            return Response::from_error(&e);
+        }
     }
+}
 
-    let client = &locked_clients[session_id].clone();
-    drop(locked_clients);
+pub async fn get_raw_message(
+    request: &Request,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    clients: Arc<Mutex<Vec<Client>>>,
+) -> Response {
+    let request: MessageSourceRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
 
-    match inbox_client::messages::get_database_with_flag(
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
+    };
+
+    match inbox_client::message::get_source(
+        sessions,
         database_conn,
-        client,
-        mailbox_path,
-        &flag,
-        not_flag,
+        request.session_id,
+        &client,
+        &request.mailbox_path,
+        request.message_uid,
     )
     .await
     {
-        Ok(messages) => {
-            let messages_str = to_display::message_vec_to_display(&messages);
-
-            return format!(
-                "{{\"success\": true, \"message\": \"Messages retrieved\", \"data\": {}}}",
-                messages_str
+        Ok(source) => {
+            return success(
+                "Message source retrieved",
+                Some(BASE64_STANDARD.encode(source)),
             );
         }
         Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
+            return Response::from_error(&e);
         }
     }
 }
 
-pub async fn update_mailbox(
-    uri: &str,
-    sessions: Arc<Mutex<Vec<Session>>>,
+#[derive(Debug, Deserialize)]
+pub struct RespondInvitationRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+    pub mailbox_path: String,
+    #[serde(deserialize_with = "params::u32_param")]
+    pub message_uid: u32,
+    pub response: String,
+}
+
+pub async fn respond_invitation(
+    request: &Request,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: Arc<CredentialStore>,
     clients: Arc<Mutex<Vec<Client>>>,
-) -> String {
-    let uri_params = params::parse_params(String::from(uri));
+) -> Response {
+    let request: RespondInvitationRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
 
-    let session_id = match params::get_usize(uri_params.get("session_id")) {
-        Ok(session_id) => session_id,
-        Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
-        }
+    let partstat = match PartStat::parse(&request.response) {
+        Some(partstat) => partstat,
+        None => return invalid_request("Response must be accepted, tentative or declined"),
     };
-    let mailbox_path = uri_params.get("mailbox_path");
-    let mut quick = match params::get_bool(uri_params.get("quick")) {
-        Ok(quick) => quick,
-        Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
-        }
+
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
     };
 
-    if session_id.is_none() || mailbox_path.is_none() {
-        eprintln!(
-            "Provide session_id and mailbox_path GET parameters: {}",
-            uri
-        );
-        return String::from("{\"success\": false, \"message\": \"Provide session_id and mailbox_path GET parameters\"}");
+    match inbox_client::message::respond_invitation(
+        database_conn,
+        &credentials,
+        &client,
+        &request.mailbox_path,
+        request.message_uid,
+        partstat,
+    )
+    .await
+    {
+        Ok(reply) => {
+            return success("Invitation reply created", Some(reply));
+        }
+        Err(e) => {
+            return Response::from_error(&e);
+        }
     }
+}
 
-    if quick.is_none() {
-        quick = Some(false);
-    }
+#[derive(Debug, Deserialize)]
+pub struct AllowRemoteContentRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+    pub sender: String,
+    #[serde(deserialize_with = "params::bool_param")]
+    pub allow: bool,
+}
 
-    let session_id = session_id.unwrap();
-    let mailbox_path = mailbox_path.unwrap();
+pub async fn allow_remote_content(
+    request: &Request,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    clients: Arc<Mutex<Vec<Client>>>,
+) -> Response {
+    let request: AllowRemoteContentRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
 
-    let locked_clients = clients.lock().await;
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
+    };
 
-    if session_id + 1 > locked_clients.len() {
-        return String::from("{\"success\": false, \"message\": \"Invalid session_id\"}");
+    let sender = request.sender.trim();
+    if sender.is_empty() {
+        return invalid_request("Sender is required");
     }
 
-    let client = &locked_clients[session_id].clone();
-    drop(locked_clients);
-
-    match inbox_client::update_mailbox::update_mailbox(
-        sessions,
+    match database::remote_content::set_allowed(
         database_conn,
-        session_id,
-        client,
-        mailbox_path,
-        quick.unwrap(),
+        &client.username,
+        &client.address,
+        sender,
+        request.allow,
     )
     .await
     {
-        Ok(updated) => {
-            let removed_uids_str = to_display::u32_vec_to_display(&updated.removed);
-            let new_uids_str = to_display::u32_vec_to_display(&updated.new);
-            let changed_uids_str = to_display::u32_vec_to_display(&updated.changed);
-
-            return format!(
-                "{{\"success\": true, \"message\": \"Mailbox updated\", \"data\": {{\"new_uids\": {}, \"removed_uids\": {}, \"changed_uids\": {}}}}}",
-                new_uids_str, removed_uids_str, changed_uids_str
-            );
+        Ok(_) => {
+            return success::<()>("Remote content setting updated", None);
         }
         Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
+            return Response::from_error(&e);
         }
     }
 }
 
-pub async fn modify_flags(
-    uri: &str,
-    sessions: Arc<Mutex<Vec<Session>>>,
+#[derive(Debug, Deserialize)]
+pub struct ImportOpenPgpKeyRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+    pub key: String,
+    pub passphrase: Option<String>,
+}
+
+pub async fn import_openpgp_key(
+    request: &Request,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: Arc<CredentialStore>,
     clients: Arc<Mutex<Vec<Client>>>,
-) -> String {
-    let uri_params = params::parse_params(String::from(uri));
+) -> Response {
+    let request: ImportOpenPgpKeyRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
 
-    let session_id = match params::get_usize(uri_params.get("session_id")) {
-        Ok(session_id) => session_id,
-        Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
-        }
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
     };
-    let mailbox_path = uri_params.get("mailbox_path");
-    let message_uid = match params::get_u32(uri_params.get("message_uid")) {
-        Ok(message_uid) => message_uid,
-        Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
+
+    match inbox_client::openpgp::import_keys(
+        database_conn,
+        &credentials,
+        &client,
+        &request.key,
+        request.passphrase.as_deref(),
+    )
+    .await
+    {
+        Ok(keys) => {
+            return success("Keys imported", Some(keys));
         }
-    };
-    let flags = uri_params.get("flags");
-    let add = match params::get_bool(uri_params.get("add")) {
-        Ok(add) => add,
         Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
+            return Response::from_error(&e);
         }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GetOpenPgpKeysRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+}
+
+pub async fn get_openpgp_keys(
+    request: &Request,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: Arc<CredentialStore>,
+    clients: Arc<Mutex<Vec<Client>>>,
+) -> Response {
+    let request: GetOpenPgpKeysRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
     };
 
-    if session_id.is_none()
-        || mailbox_path.is_none()
-        || message_uid.is_none()
-        || flags.is_none()
-        || add.is_none()
-    {
-        eprintln!(
-            "Provide session_id, mailbox_path, message_uid, flags, and add GET parameters: {}",
-            uri
-        );
-        return String::from("{\"success\": false, \"message\": \"Provide session_id, mailbox_path, message_uid, flags, and add GET parameters\"}");
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
+    };
+
+    match inbox_client::openpgp::get_keys(database_conn, &credentials, &client).await {
+        Ok(keys) => {
+            return success("Keys retrieved", Some(keys));
+        }
+        Err(e) => {
+            return Response::from_error(&e);
+        }
     }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteOpenPgpKeyRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+    pub fingerprint: String,
+}
 
-    let session_id = session_id.unwrap();
-    let mailbox_path = mailbox_path.unwrap();
-    let message_uid = message_uid.unwrap();
-    let flags = flags.unwrap();
-    let add = add.unwrap();
+pub async fn delete_openpgp_key(
+    request: &Request,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    clients: Arc<Mutex<Vec<Client>>>,
+) -> Response {
+    let request: DeleteOpenPgpKeyRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
 
-    let locked_clients = clients.lock().await;
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
+    };
 
-    if session_id + 1 > locked_clients.len() {
-        return String::from("{\"success\": false, \"message\": \"Invalid session_id\"}");
+    match inbox_client::openpgp::delete_key(database_conn, &client, &request.fingerprint).await {
+        Ok(_) => {
+            return success::<()>("Key deleted", None);
+        }
+        Err(e) => {
+            return Response::from_error(&e);
+        }
     }
+}
 
-    let client = &locked_clients[session_id].clone();
-    drop(locked_clients);
+#[derive(Debug, Deserialize)]
+pub struct ImportSmimeIdentityRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+    /// Base64 encoded PKCS#12 file.
+    pub identity: String,
+    #[serde(default)]
+    pub password: String,
+}
 
-    let flags: Vec<String> = flags.split(",").map(|f| f.to_string()).collect();
+pub async fn import_smime_identity(
+    request: &Request,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: Arc<CredentialStore>,
+    clients: Arc<Mutex<Vec<Client>>>,
+) -> Response {
+    let request: ImportSmimeIdentityRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
 
-    match inbox_client::message_flags::modify(
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
+    };
+
+    match inbox_client::smime::import_identity(
         database_conn,
-        sessions,
-        session_id,
-        client,
-        mailbox_path,
-        message_uid,
-        &flags,
-        add,
+        &credentials,
+        &client,
+        &request.identity,
+        &request.password,
     )
     .await
     {
-        Ok(_) => {
-            let flag_str = to_display::string_vec_to_display(&flags);
-
-            return format!(
-                "{{\"success\": true, \"message\": \"Flags successfully updated\", \"data\": {}}}",
-                flag_str
-            );
+        Ok(identity) => {
+            return success("Identity imported", Some(identity));
         }
         Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
+            return Response::from_error(&e);
         }
     }
 }
 
-pub async fn move_message(
-    uri: &str,
-    sessions: Arc<Mutex<Vec<Session>>>,
+#[derive(Debug, Deserialize)]
+pub struct GetSmimeIdentitiesRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+}
+
+pub async fn get_smime_identities(
+    request: &Request,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: Arc<CredentialStore>,
     clients: Arc<Mutex<Vec<Client>>>,
-) -> String {
-    let uri_params = params::parse_params(String::from(uri));
+) -> Response {
+    let request: GetSmimeIdentitiesRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
+
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
+    };
 
-    let session_id = match params::get_usize(uri_params.get("session_id")) {
-        Ok(session_id) => session_id,
+    match inbox_client::smime::get_identities(database_conn, &credentials, &client).await {
+        Ok(identities) => {
+            return success("Identities retrieved", Some(identities));
+        }
         Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
+            return Response::from_error(&e);
         }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteSmimeIdentityRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+    pub fingerprint: String,
+}
+
+pub async fn delete_smime_identity(
+    request: &Request,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    clients: Arc<Mutex<Vec<Client>>>,
+) -> Response {
+    let request: DeleteSmimeIdentityRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
+
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
     };
-    let mailbox_path = uri_params.get("mailbox_path");
-    let message_uid = match params::get_u32(uri_params.get("message_uid")) {
-        Ok(message_uid) => message_uid,
+
+    match inbox_client::smime::delete_identity(database_conn, &client, &request.fingerprint).await {
+        Ok(_) => {
+            return success::<()>("Identity deleted", None);
+        }
         Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
+            return Response::from_error(&e);
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProtectMessageRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+    /// RFC 5322 message to protect.
+    pub message: String,
+    #[serde(default, deserialize_with = "params::bool_param")]
+    pub sign: bool,
+    #[serde(default, deserialize_with = "params::bool_param")]
+    pub encrypt: bool,
+    /// `openpgp` or `smime`, OpenPGP when left out.
+    pub protocol: Option<String>,
+}
+
+pub async fn protect_message(
+    request: &Request,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: Arc<CredentialStore>,
+    clients: Arc<Mutex<Vec<Client>>>,
+) -> Response {
+    let request: ProtectMessageRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
+
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
+    };
+
+    let result = match request.protocol.as_deref() {
+        None | Some("openpgp") => {
+            inbox_client::openpgp::protect_message(
+                database_conn,
+                &credentials,
+                &client,
+                &request.message,
+                request.sign,
+                request.encrypt,
+            )
+            .await
+        }
+        Some("smime") => {
+            inbox_client::smime::protect_message(
+                database_conn,
+                &credentials,
+                &client,
+                &request.message,
+                request.sign,
+                request.encrypt,
+            )
+            .await
         }
+        Some(protocol) => return invalid_request(&format!("Unknown protocol {}", protocol)),
     };
-    let mailbox_path_dest = uri_params.get("mailbox_path_dest");
 
-    if session_id.is_none()
-        || mailbox_path.is_none()
-        || message_uid.is_none()
-        || mailbox_path_dest.is_none()
-    {
-        eprintln!(
-            "Provide session_id, mailbox_path, message_uid, and mailbox_path_dest GET parameters: {}",
-            uri
-        );
-        return String::from("{\"success\": false, \"message\": \"Provide session_id, mailbox_path, message_uid, and mailbox_path_dest GET parameters\"}");
+    match result {
+        Ok(message) => {
+            return success("Message protected", Some(message));
+        }
+        Err(e) => {
+            return Response::from_error(&e);
+        }
     }
+}
 
-    let session_id = session_id.unwrap();
-    let mailbox_path = mailbox_path.unwrap();
-    let message_uid = message_uid.unwrap();
-    let mailbox_path_dest = mailbox_path_dest.unwrap();
+#[derive(Debug, Deserialize)]
+pub struct RecommendEncryptionRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+    /// Sender address, the username of the account when left out.
+    pub from: Option<String>,
+    #[serde(deserialize_with = "params::list_param")]
+    pub recipients: Vec<String>,
+    #[serde(default, deserialize_with = "params::bool_param")]
+    pub reply_to_encrypted: bool,
+}
 
-    let locked_clients = clients.lock().await;
+pub async fn recommend_encryption(
+    request: &Request,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: Arc<CredentialStore>,
+    clients: Arc<Mutex<Vec<Client>>>,
+) -> Response {
+    let request: RecommendEncryptionRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
 
-    if session_id + 1 > locked_clients.len() {
-        return String::from("{\"success\": false, \"message\": \"Invalid session_id\"}");
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
+    };
+
+    let recipients: Vec<String> = request
+        .recipients
+        .iter()
+        .map(|recipient| recipient.trim().to_string())
+        .filter(|recipient| !recipient.is_empty())
+        .collect();
+    if recipients.is_empty() {
+        return invalid_request("At least one recipient is required");
     }
 
-    let client = &locked_clients[session_id].clone();
-    drop(locked_clients);
+    let from = request.from.unwrap_or(client.username.clone());
 
-    match inbox_client::message::mv(
-        sessions,
+    match inbox_client::autocrypt::recommend(
         database_conn,
-        session_id,
-        client,
-        mailbox_path,
-        message_uid,
-        mailbox_path_dest,
+        &credentials,
+        &client,
+        &from,
+        &recipients,
+        request.reply_to_encrypted,
     )
     .await
+    {
+        Ok(recommendation) => {
+            return success("Encryption recommended", Some(recommendation));
+        }
+        Err(e) => {
+            return Response::from_error(&e);
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetAutocryptPreferenceRequest {
+    #[serde(deserialize_with = "params::usize_param")]
+    pub session_id: usize,
+    pub prefer_encrypt: String,
+}
+
+pub async fn set_autocrypt_preference(
+    request: &Request,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    clients: Arc<Mutex<Vec<Client>>>,
+) -> Response {
+    let request: SetAutocryptPreferenceRequest = match params::parse_request(request) {
+        Ok(request) => request,
+        Err(e) => return Response::from_error(&e),
+    };
+
+    let client = match get_client(&clients, request.session_id).await {
+        Some(client) => client,
+        None => return invalid_session(),
+    };
+
+    match inbox_client::autocrypt::set_preference(database_conn, &client, &request.prefer_encrypt)
+        .await
     {
         Ok(_) => {
-            return String::from("{\"success\": true, \"message\": \"Message successfully moved\"}")
+            return success::<()>("Autocrypt preference set", None);
         }
         Err(e) => {
-            return format!("{{\"success\": false, \"message\": \"{}\"}}", e);
+            return Response::from_error(&e);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(path: &str, body: &str) -> Request {
+        return Request {
+            method: String::from("POST"),
+            path: path.to_string(),
+            query: String::new(),
+            version: String::from("HTTP/1.1"),
+            headers: vec![],
+            body: body.as_bytes().to_vec(),
+        };
+    }
+
+    fn open_credentials(conn: &rusqlite::Connection) -> Arc<CredentialStore> {
+        let source = KeySource::Passphrase(String::from("test"));
+
+        return Arc::new(CredentialStore::open(conn, &source).unwrap());
+    }
+
+    fn get(path: &str, query: &str) -> Request {
+        return Request {
+            method: String::from("GET"),
+            path: path.to_string(),
+            query: query.to_string(),
+            version: String::from("HTTP/1.1"),
+            headers: vec![],
+            body: vec![],
+        };
+    }
+
+    #[test]
+    fn parse_json_body() {
+        let request = post(
+            "/modify_flags",
+            r#"{"session_id": 0, "mailbox_path": "INBOX \"quoted\"", "message_uid": 7, "flags": ["\\Seen", "\\Flagged"], "add": true}"#,
+        );
+
+        let parsed: ModifyFlagsRequest = params::parse_request(&request).unwrap();
+
+        assert_eq!(parsed.session_id, 0);
+        assert_eq!(parsed.mailbox_path, "INBOX \"quoted\"");
+        assert_eq!(parsed.message_uid, 7);
+        assert_eq!(parsed.flags, vec!["\\Seen", "\\Flagged"]);
+        assert!(parsed.add);
+    }
+
+    #[test]
+    fn parse_query_string() {
+        let request = get(
+            "/modify_flags",
+            "session_id=0&mailbox_path=INBOX&message_uid=7&flags=%5CSeen,%5CFlagged&add=false",
+        );
+
+        let parsed: ModifyFlagsRequest = params::parse_request(&request).unwrap();
+
+        assert_eq!(parsed.message_uid, 7);
+        assert_eq!(parsed.flags, vec!["\\Seen", "\\Flagged"]);
+        assert!(!parsed.add);
+    }
+
+    #[test]
+    fn parse_optional_defaults() {
+        let request = post(
+            "/update_mailbox",
+            r#"{"session_id": "1", "mailbox_path": "INBOX"}"#,
+        );
+
+        let parsed: UpdateMailboxRequest = params::parse_request(&request).unwrap();
+
+        assert_eq!(parsed.session_id, 1);
+        assert!(!parsed.quick);
+    }
+
+    #[test]
+    fn parse_missing_and_invalid() {
+        let missing = post("/get_messages_sorted", r#"{"session_id": 0}"#);
+        assert!(params::parse_request::<GetMessagesSortedRequest>(&missing).is_err());
+
+        let invalid = post("/get_messages_sorted", r#"{"session_id": "#);
+        assert!(params::parse_request::<GetMessagesSortedRequest>(&invalid).is_err());
+
+        let negative = get("/logout", "session_id=-1");
+        assert!(params::parse_request::<SessionRequest>(&negative).is_err());
+    }
+
+    #[async_std::test]
+    async fn unknown_session() {
+        let clients = Arc::new(Mutex::new(vec![]));
+        let request = post("/get_mailboxes", r#"{"session_id": 0}"#);
+        let database_conn = Arc::new(Mutex::new(rusqlite::Connection::open_in_memory().unwrap()));
+
+        let response = get_mailboxes(&request, database_conn, clients).await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+
+        assert_eq!(response.status, 404);
+        assert_eq!(parsed["success"], false);
+        assert_eq!(parsed["error"]["code"], "INVALID_SESSION");
+        assert!(parsed.get("data").is_none());
+    }
+
+    #[async_std::test]
+    async fn changes_since_cursor() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        rusqlite::vtab::array::load_module(&conn).unwrap();
+        database::initialise(&conn).await.unwrap();
+
+        conn.execute_batch(
+            "INSERT INTO connections (username, password, address, port) VALUES ('user', 'password', 'imap.example.com', 993);
+            INSERT INTO mailboxes (c_username, c_address, path) VALUES ('user', 'imap.example.com', 'INBOX');
+            INSERT INTO messages (message_uid, c_username, c_address, m_path, sequence_id, message_id, subject, from_, sender, to_, cc, bcc, reply_to, in_reply_to, delivered_to, date_, received, html, text, preview)
+                VALUES (1, 'user', 'imap.example.com', 'INBOX', 1, 'id', 'subject', '[]', '[]', '[]', '[]', '[]', '[]', '', '', 0, 0, '<p>body</p>', 'body', 'body');
+            INSERT INTO flags (message_uid, c_username, c_address, m_path, flag) VALUES (1, 'user', 'imap.example.com', 'INBOX', 'Seen');",
+        )
+        .unwrap();
+        database::changes::record(&conn, "user", "imap.example.com", "INBOX", &[1], false).unwrap();
+        database::changes::record(&conn, "user", "imap.example.com", "INBOX", &[2], true).unwrap();
+
+        let client = Client {
+            username: String::from("user"),
+            password: String::from("password"),
+            address: String::from("imap.example.com"),
+            port: 993,
+            security: Security::Tls,
+            cert_fingerprint: None,
+            auth_mechanism: AuthMechanism::Password,
+            oauth: None,
+        };
+        let clients = Arc::new(Mutex::new(vec![client]));
+        let database_conn = Arc::new(Mutex::new(conn));
+
+        let request = post(
+            "/changes_since",
+            r#"{"session_id": 0, "mailbox_path": "INBOX", "cursor": 0}"#,
+        );
+        let response =
+            changes_since(&request, Arc::clone(&database_conn), Arc::clone(&clients)).await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+
+        assert_eq!(response.status, 200);
+        assert_eq!(parsed["data"]["cursor"], 2);
+        assert_eq!(parsed["data"]["reset"], false);
+        assert_eq!(parsed["data"]["changed"][0]["uid"], 1);
+        assert_eq!(parsed["data"]["changed"][0]["flags"][0], "Seen");
+        assert_eq!(parsed["data"]["changed"][0]["preview"], "body");
+        assert!(parsed["data"]["changed"][0].get("html").is_none());
+        assert_eq!(parsed["data"]["removed"], serde_json::json!([2]));
+
+        let request = post(
+            "/changes_since",
+            r#"{"session_id": 0, "mailbox_path": "INBOX", "cursor": "2"}"#,
+        );
+        let response = changes_since(&request, database_conn, clients).await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+
+        assert_eq!(parsed["data"]["changed"], serde_json::json!([]));
+        assert_eq!(parsed["data"]["removed"], serde_json::json!([]));
+    }
+
+    #[async_std::test]
+    async fn remote_content_allowlist() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        rusqlite::vtab::array::load_module(&conn).unwrap();
+        database::initialise(&conn).await.unwrap();
+
+        conn.execute_batch(
+            r#"INSERT INTO connections (username, password, address, port) VALUES ('user', 'password', 'imap.example.com', 993);
+            INSERT INTO mailboxes (c_username, c_address, path) VALUES ('user', 'imap.example.com', 'INBOX');
+            INSERT INTO messages (message_uid, c_username, c_address, m_path, sequence_id, message_id, subject, from_, sender, to_, cc, bcc, reply_to, in_reply_to, delivered_to, date_, received, html, text, preview)
+                VALUES (1, 'user', 'imap.example.com', 'INBOX', 1, 'id', 'subject', '[{"name":"News","mailbox":"News","host":"Example.com"}]', '[]', '[]', '[]', '[]', '[]', '', '', 0, 0, '<p onclick="x()">Hi</p><img src="https://cdn.example.com/a.png">', '', 'Hi');"#,
+        )
+        .unwrap();
+
+        let client = Client {
+            username: String::from("user"),
+            password: String::from("password"),
+            address: String::from("imap.example.com"),
+            port: 993,
+            security: Security::Tls,
+            cert_fingerprint: None,
+            auth_mechanism: AuthMechanism::Password,
+            oauth: None,
+        };
+        let clients = Arc::new(Mutex::new(vec![client]));
+        let credentials = open_credentials(&conn);
+        let database_conn = Arc::new(Mutex::new(conn));
+
+        let request = post(
+            "/get_messages_with_uids",
+            r#"{"session_id": 0, "mailbox_path": "INBOX", "message_uids": [1]}"#,
+        );
+        let response = get_messages_with_uids(
+            &request,
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+
+        assert_eq!(response.status, 200);
+        assert_eq!(parsed["data"][0]["html"], "PHA+SGk8L3A+PGltZz4=");
+        assert_eq!(parsed["data"][0]["remote_content"]["images"], 1);
+        assert_eq!(
+            parsed["data"][0]["remote_content"]["hosts"],
+            serde_json::json!(["cdn.example.com"])
+        );
+
+        let request = post(
+            "/allow_remote_content",
+            r#"{"session_id": 0, "sender": "news@example.com", "allow": true}"#,
+        );
+        let response =
+            allow_remote_content(&request, Arc::clone(&database_conn), Arc::clone(&clients)).await;
+
+        assert_eq!(response.status, 200);
+
+        let request = post(
+            "/get_messages_with_uids",
+            r#"{"session_id": 0, "mailbox_path": "INBOX", "message_uids": [1]}"#,
+        );
+        let response = get_messages_with_uids(&request, database_conn, credentials, clients).await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+
+        assert_eq!(parsed["data"][0]["remote_content"]["images"], 0);
+    }
+
+    #[async_std::test]
+    async fn inline_images() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        rusqlite::vtab::array::load_module(&conn).unwrap();
+        database::initialise(&conn).await.unwrap();
+
+        conn.execute_batch(
+            r#"INSERT INTO connections (username, password, address, port) VALUES ('user', 'password', 'imap.example.com', 993);
+            INSERT INTO mailboxes (c_username, c_address, path) VALUES ('user', 'imap.example.com', 'INBOX');
+            INSERT INTO mailboxes (c_username, c_address, path) VALUES ('user', 'imap.example.com', 'Archive');
+            INSERT INTO messages (message_uid, c_username, c_address, m_path, sequence_id, message_id, subject, from_, sender, to_, cc, bcc, reply_to, in_reply_to, delivered_to, date_, received, html, text, preview)
+                VALUES (1, 'user', 'imap.example.com', 'INBOX', 1, 'id', 'subject', '[]', '[]', '[]', '[]', '[]', '[]', '', '', 0, 0, '<img src="cid:logo@example.com">', '', '');
+            INSERT INTO inline_parts (message_uid, c_username, c_address, m_path, content_id, content_type, data)
+                VALUES (1, 'user', 'imap.example.com', 'INBOX', 'logo@example.com', 'image/png', 'iVBORw0KGgo=');
+            UPDATE messages SET m_path = 'Archive', message_uid = 7 WHERE message_uid = 1;"#,
+        )
+        .unwrap();
+
+        let client = Client {
+            username: String::from("user"),
+            password: String::from("password"),
+            address: String::from("imap.example.com"),
+            port: 993,
+            security: Security::Tls,
+            cert_fingerprint: None,
+            auth_mechanism: AuthMechanism::Password,
+            oauth: None,
+        };
+        let clients = Arc::new(Mutex::new(vec![client]));
+        let credentials = open_credentials(&conn);
+        let database_conn = Arc::new(Mutex::new(conn));
+
+        let request = post(
+            "/get_messages_with_uids",
+            r#"{"session_id": 0, "mailbox_path": "Archive", "message_uids": [7]}"#,
+        );
+        let response = get_messages_with_uids(&request, database_conn, credentials, clients).await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+
+        let html = BASE64_STANDARD
+            .decode(parsed["data"][0]["html"].as_str().unwrap())
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(html).unwrap(),
+            r#"<img src="data:image/png;base64,iVBORw0KGgo=">"#
+        );
+    }
+
+    #[async_std::test]
+    async fn invitation_reply() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        rusqlite::vtab::array::load_module(&conn).unwrap();
+        database::initialise(&conn).await.unwrap();
+
+        conn.execute_batch(
+            r#"INSERT INTO connections (username, password, address, port) VALUES ('user', 'password', 'imap.example.com', 993);
+            INSERT INTO mailboxes (c_username, c_address, path) VALUES ('user', 'imap.example.com', 'INBOX');
+            INSERT INTO messages (message_uid, c_username, c_address, m_path, sequence_id, message_id, subject, from_, sender, to_, cc, bcc, reply_to, in_reply_to, delivered_to, date_, received, html, text, preview)
+                VALUES (1, 'user', 'imap.example.com', 'INBOX', 1, 'id', 'subject', '[]', '[]', '[{"name":"Bob","mailbox":"bob","host":"example.com"}]', '[]', '[]', '[]', '', '', 0, 0, '', 'invite', 'invite');
+            INSERT INTO messages (message_uid, c_username, c_address, m_path, sequence_id, message_id, subject, from_, sender, to_, cc, bcc, reply_to, in_reply_to, delivered_to, date_, received, html, text, preview)
+                VALUES (2, 'user', 'imap.example.com', 'INBOX', 2, 'id2', 'subject', '[]', '[]', '[]', '[]', '[]', '[]', '', '', 0, 0, '', 'plain', 'plain');"#,
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO calendar_parts (message_uid, c_username, c_address, m_path, data) VALUES (1, 'user', 'imap.example.com', 'INBOX', ?1)",
+            [include_str!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/fixtures/calendar/google_request.ics"
+            ))],
+        )
+        .unwrap();
+
+        let client = Client {
+            username: String::from("user"),
+            password: String::from("password"),
+            address: String::from("imap.example.com"),
+            port: 993,
+            security: Security::Tls,
+            cert_fingerprint: None,
+            auth_mechanism: AuthMechanism::Password,
+            oauth: None,
+        };
+        let clients = Arc::new(Mutex::new(vec![client]));
+        let credentials = open_credentials(&conn);
+        let database_conn = Arc::new(Mutex::new(conn));
+
+        let request = post(
+            "/get_messages_with_uids",
+            r#"{"session_id": 0, "mailbox_path": "INBOX", "message_uids": [1, 2]}"#,
+        );
+        let response = get_messages_with_uids(
+            &request,
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+
+        let messages = parsed["data"].as_array().unwrap();
+        let invite = messages.iter().find(|m| m["uid"] == 1).unwrap();
+        let plain = messages.iter().find(|m| m["uid"] == 2).unwrap();
+
+        let invitation = &invite["invitation"];
+        assert_eq!(invitation["method"], "REQUEST");
+        assert_eq!(invitation["events"][0]["summary"], "Weekly sync; planning");
+        assert_eq!(
+            invitation["events"][0]["start"]["timestamp"],
+            1704726000000i64
+        );
+        assert!(plain.get("invitation").is_none());
+
+        let request = post(
+            "/respond_invitation",
+            r#"{"session_id": 0, "mailbox_path": "INBOX", "message_uid": 1, "response": "accepted"}"#,
+        );
+        let response = respond_invitation(
+            &request,
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+
+        assert_eq!(parsed["success"], true);
+        assert_eq!(parsed["data"]["to"], "Alice Doe <alice@example.com>");
+        assert_eq!(parsed["data"]["subject"], "Accepted: Weekly sync; planning");
+        assert!(parsed["data"]["source"]
+            .as_str()
+            .unwrap()
+            .contains("ATTENDEE;PARTSTAT=ACCEPTED;CN=\"Doe, Bob\":mailto:bob@example.com"));
+
+        let request = post(
+            "/respond_invitation",
+            r#"{"session_id": 0, "mailbox_path": "INBOX", "message_uid": 1, "response": "maybe"}"#,
+        );
+        let response = respond_invitation(
+            &request,
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        assert_eq!(response.status, 400);
+
+        let request = post(
+            "/respond_invitation",
+            r#"{"session_id": 0, "mailbox_path": "INBOX", "message_uid": 2, "response": "declined"}"#,
+        );
+        let response = respond_invitation(&request, database_conn, credentials, clients).await;
+        assert_eq!(response.status, 404);
+    }
+
+    #[async_std::test]
+    async fn message_source() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        rusqlite::vtab::array::load_module(&conn).unwrap();
+        database::initialise(&conn).await.unwrap();
+
+        conn.execute_batch(
+            "INSERT INTO connections (username, password, address, port) VALUES ('user', 'password', 'imap.example.com', 993);
+            INSERT INTO mailboxes (c_username, c_address, path) VALUES ('user', 'imap.example.com', 'INBOX');
+            INSERT INTO messages (message_uid, c_username, c_address, m_path, sequence_id, message_id, subject, from_, sender, to_, cc, bcc, reply_to, in_reply_to, delivered_to, date_, received, html, text, preview)
+                VALUES (1, 'user', 'imap.example.com', 'INBOX', 1, 'id', 'subject', '[]', '[]', '[]', '[]', '[]', '[]', '', '', 0, 0, '', 'body', 'body');",
+        )
+        .unwrap();
+
+        let client = Client {
+            username: String::from("user"),
+            password: String::from("password"),
+            address: String::from("imap.example.com"),
+            port: 993,
+            security: Security::Tls,
+            cert_fingerprint: None,
+            auth_mechanism: AuthMechanism::Password,
+            oauth: None,
+        };
+        let sessions = Arc::new(Mutex::new(vec![]));
+        let clients = Arc::new(Mutex::new(vec![client.clone()]));
+        let database_conn = Arc::new(Mutex::new(conn));
+
+        // Stored without headers by an older version, they come from the
+        // source once it is known.
+        let source = b"Received: from b\r\nReceived: from a\r\nSubject: Hi\r\n\r\nbody\r\n";
+        database::message::insert_source(
+            Arc::clone(&database_conn),
+            &client,
+            "INBOX",
+            1,
+            source,
+            &crate::mime_parser::headers::parse(&String::from_utf8_lossy(source)),
+        )
+        .await
+        .unwrap();
+
+        let request = post(
+            "/get_headers",
+            r#"{"session_id": 0, "mailbox_path": "INBOX", "message_uid": 1}"#,
+        );
+        let response = get_headers(
+            &request,
+            Arc::clone(&sessions),
+            Arc::clone(&database_conn),
+            Arc::clone(&clients),
+        )
+        .await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            parsed["data"],
+            serde_json::json!([
+                {"name": "Received", "value": "from b"},
+                {"name": "Received", "value": "from a"},
+                {"name": "Subject", "value": "Hi"}
+            ])
+        );
+
+        let request = post(
+            "/get_raw_message",
+            r#"{"session_id": 0, "mailbox_path": "INBOX", "message_uid": 1}"#,
+        );
+        let response = get_raw_message(
+            &request,
+            Arc::clone(&sessions),
+            Arc::clone(&database_conn),
+            Arc::clone(&clients),
+        )
+        .await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+
+        assert_eq!(parsed["data"], BASE64_STANDARD.encode(source));
+
+        let request = post(
+            "/get_raw_message",
+            r#"{"session_id": 0, "mailbox_path": "INBOX", "message_uid": 2}"#,
+        );
+        let response = get_raw_message(&request, sessions, database_conn, clients).await;
+
+        assert_eq!(response.status, 404);
+    }
+
+    #[async_std::test]
+    async fn openpgp() {
+        let fixture = |name: &str| {
+            return std::fs::read_to_string(format!(
+                "{}/tests/fixtures/openpgp/{}",
+                env!("CARGO_MANIFEST_DIR"),
+                name
+            ))
+            .unwrap();
+        };
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        rusqlite::vtab::array::load_module(&conn).unwrap();
+        database::initialise(&conn).await.unwrap();
+
+        conn.execute_batch(
+            r#"INSERT INTO connections (username, password, address, port) VALUES ('user', 'password', 'imap.example.com', 993);
+            INSERT INTO mailboxes (c_username, c_address, path) VALUES ('user', 'imap.example.com', 'INBOX');
+            INSERT INTO messages (message_uid, c_username, c_address, m_path, sequence_id, message_id, subject, from_, sender, to_, cc, bcc, reply_to, in_reply_to, delivered_to, date_, received, html, text, preview)
+                VALUES (1, 'user', 'imap.example.com', 'INBOX', 1, 'id', '...', '[{"name":"Bob","mailbox":"bob","host":"example.com"}]', '[]', '[]', '[]', '[]', '[]', '', '', 0, 0, '', '', '');"#,
+        )
+        .unwrap();
+
+        let part =
+            crate::mime_parser::pgp_mime::detect(&fixture("pgp_mime_encrypted.eml")).unwrap();
+        conn.execute(
+            "INSERT INTO openpgp_parts (message_uid, c_username, c_address, m_path, kind, data, signed)
+                VALUES (1, 'user', 'imap.example.com', 'INBOX', ?1, ?2, ?3)",
+            rusqlite::params![part.kind.name(), part.data, part.signed],
+        )
+        .unwrap();
+
+        let client = Client {
+            username: String::from("user"),
+            password: String::from("password"),
+            address: String::from("imap.example.com"),
+            port: 993,
+            security: Security::Tls,
+            cert_fingerprint: None,
+            auth_mechanism: AuthMechanism::Password,
+            oauth: None,
+        };
+        let clients = Arc::new(Mutex::new(vec![client]));
+        let credentials = open_credentials(&conn);
+        let database_conn = Arc::new(Mutex::new(conn));
+
+        let import = |key: &str, passphrase: &str| {
+            let body =
+                serde_json::json!({"session_id": 0, "key": fixture(key), "passphrase": passphrase});
+
+            return post("/import_openpgp_key", &body.to_string());
+        };
+
+        let response = import_openpgp_key(
+            &import("alice_secret.asc", "wrong"),
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        assert_eq!(response.status, 400);
+
+        let response = import_openpgp_key(
+            &import("alice_secret.asc", "alice passphrase"),
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(
+            parsed["data"][0]["fingerprint"],
+            "4FC52BAA2FDB1BBBE6571F1FC25A344C65AB30A0"
+        );
+        assert_eq!(parsed["data"][0]["can_sign"], true);
+
+        let response = import_openpgp_key(
+            &import("bob_public.asc", ""),
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        assert_eq!(response.status, 200);
+
+        let request = post("/get_openpgp_keys", r#"{"session_id": 0}"#);
+        let response = get_openpgp_keys(
+            &request,
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(
+            parsed["data"][1]["user_ids"],
+            serde_json::json!(["Bob <bob@example.com>"])
+        );
+        assert_eq!(parsed["data"][1]["has_secret"], false);
+
+        // The result is stored with the message when it is fetched, reading
+        // it leaves the database alone.
+        inbox_client::messages::store_security(
+            Arc::clone(&database_conn),
+            &credentials,
+            &clients.lock().await[0],
+            "INBOX",
+            &vec![1],
+        )
+        .await
+        .unwrap();
+
+        let signature: String = database_conn
+            .lock()
+            .await
+            .query_row("SELECT signature FROM message_security", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(signature, "good");
+
+        database_conn
+            .lock()
+            .await
+            .execute("DELETE FROM message_security", [])
+            .unwrap();
+
+        let request = post(
+            "/get_messages_with_uids",
+            r#"{"session_id": 0, "mailbox_path": "INBOX", "message_uids": [1]}"#,
+        );
+        let response = get_messages_with_uids(
+            &request,
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        let text = BASE64_STANDARD
+            .decode(parsed["data"][0]["text"].as_str().unwrap())
+            .unwrap();
+
+        assert!(String::from_utf8(text)
+            .unwrap()
+            .contains("The launch moves to Thursday"));
+        assert_eq!(parsed["data"][0]["security"]["decrypted"], true);
+        assert_eq!(parsed["data"][0]["security"]["signature"], "good");
+        assert_eq!(
+            parsed["data"][0]["security"]["signer"]["identity"],
+            "Bob <bob@example.com>"
+        );
+
+        let stored: i64 = database_conn
+            .lock()
+            .await
+            .query_row("SELECT COUNT(*) FROM message_security", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(stored, 0);
+
+        let message =
+            "From: alice@example.com\r\nTo: Bob <bob@example.com>\r\nSubject: Hi\r\n\r\nHi Bob\r\n";
+        let body =
+            serde_json::json!({"session_id": 0, "message": message, "sign": true, "encrypt": true});
+        let response = protect_message(
+            &post("/protect_message", &body.to_string()),
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(response.status, 200);
+        assert!(parsed["data"]["source"]
+            .as_str()
+            .unwrap()
+            .contains("Content-Type: multipart/encrypted;"));
+
+        let body = serde_json::json!({"session_id": 0, "message": message.replace("bob@example.com", "carol@example.org"), "encrypt": true});
+        let response = protect_message(
+            &post("/protect_message", &body.to_string()),
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        assert_eq!(response.status, 400);
+
+        let request = post(
+            "/delete_openpgp_key",
+            r#"{"session_id": 0, "fingerprint": "C174 2C35 F16F A02F 5406 57BF 4FF6 F779 2802 1E86"}"#,
+        );
+        let response =
+            delete_openpgp_key(&request, Arc::clone(&database_conn), Arc::clone(&clients)).await;
+        assert_eq!(response.status, 200);
+
+        let response = delete_openpgp_key(&request, database_conn, clients).await;
+        assert_eq!(response.status, 404);
+    }
+
+    #[async_std::test]
+    async fn autocrypt() {
+        let fixture = |name: &str| {
+            return std::fs::read_to_string(format!(
+                "{}/tests/fixtures/openpgp/{}",
+                env!("CARGO_MANIFEST_DIR"),
+                name
+            ))
+            .unwrap();
+        };
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        rusqlite::vtab::array::load_module(&conn).unwrap();
+        database::initialise(&conn).await.unwrap();
+        conn.execute(
+            "INSERT INTO connections (username, password, address, port) VALUES ('alice@example.com', 'password', 'imap.example.com', 993)",
+            [],
+        )
+        .unwrap();
+
+        // Bob wrote with his Autocrypt header and prefers encryption.
+        let bob = crate::openpgp::key::read_armored(&fixture("bob_public.asc")).unwrap();
+        let header = crate::mime_parser::autocrypt::AutocryptHeader {
+            addr: String::from("bob@example.com"),
+            prefer_encrypt: crate::mime_parser::autocrypt::PreferEncrypt::Mutual,
+            keydata: bob[0].public_bytes(),
+        };
+        let source = crate::mime_parser::autocrypt::add_headers(
+            "From: Bob <bob@example.com>\r\nTo: alice@example.com\r\n\r\nHi\r\n",
+            &crate::mime_parser::autocrypt::format("Autocrypt", &header),
+        );
+        database::autocrypt::update_peer(
+            &conn,
+            "alice@example.com",
+            "imap.example.com",
+            &crate::mime_parser::headers::parse(&source),
+            chrono::Utc::now().timestamp_millis(),
+            chrono::Utc::now().timestamp_millis(),
+        )
+        .unwrap();
+
+        let client = Client {
+            username: String::from("alice@example.com"),
+            password: String::from("password"),
+            address: String::from("imap.example.com"),
+            port: 993,
+            security: Security::Tls,
+            cert_fingerprint: None,
+            auth_mechanism: AuthMechanism::Password,
+            oauth: None,
+        };
+        let clients = Arc::new(Mutex::new(vec![client]));
+        let credentials = open_credentials(&conn);
+        let database_conn = Arc::new(Mutex::new(conn));
+
+        let body = serde_json::json!({"session_id": 0, "key": fixture("alice_secret.asc"), "passphrase": "alice passphrase"});
+        let response = import_openpgp_key(
+            &post("/import_openpgp_key", &body.to_string()),
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        assert_eq!(response.status, 200);
+
+        let request = post(
+            "/recommend_encryption",
+            r#"{"session_id": 0, "recipients": "bob@example.com"}"#,
+        );
+        let response = recommend_encryption(
+            &request,
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(parsed["data"]["recommendation"], "available");
+        assert_eq!(parsed["data"]["recipients"][0]["key_source"], "autocrypt");
+        assert!(parsed["data"]["autocrypt_header"]
+            .as_str()
+            .unwrap()
+            .starts_with("Autocrypt: addr=alice@example.com; keydata=\r\n"));
+
+        let request = post(
+            "/set_autocrypt_preference",
+            r#"{"session_id": 0, "prefer_encrypt": "always"}"#,
+        );
+        let response =
+            set_autocrypt_preference(&request, Arc::clone(&database_conn), Arc::clone(&clients))
+                .await;
+        assert_eq!(response.status, 400);
+
+        let request = post(
+            "/set_autocrypt_preference",
+            r#"{"session_id": 0, "prefer_encrypt": "mutual"}"#,
+        );
+        let response =
+            set_autocrypt_preference(&request, Arc::clone(&database_conn), Arc::clone(&clients))
+                .await;
+        assert_eq!(response.status, 200);
+
+        let request = post(
+            "/recommend_encryption",
+            r#"{"session_id": 0, "recipients": ["bob@example.com", "carol@example.org"]}"#,
+        );
+        let response = recommend_encryption(
+            &request,
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(parsed["data"]["recommendation"], "disable");
+        assert_eq!(parsed["data"]["recipients"][0]["recommendation"], "encrypt");
+        assert_eq!(parsed["data"]["recipients"][1]["recommendation"], "disable");
+        assert!(parsed["data"]["recipients"][1]["key_source"].is_null());
+        assert!(parsed["data"]["autocrypt_header"]
+            .as_str()
+            .unwrap()
+            .contains("prefer-encrypt=mutual;"));
+
+        // Bob's key only comes from Autocrypt, the keys of both recipients
+        // are gossiped inside the encryption.
+        let message = "From: alice@example.com\r\nTo: Bob <bob@example.com>, alice@example.com\r\nSubject: Hi\r\n\r\nHi Bob\r\n";
+        let body = serde_json::json!({"session_id": 0, "message": message, "encrypt": true});
+        let response = protect_message(
+            &post("/protect_message", &body.to_string()),
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(response.status, 200);
+
+        let source = parsed["data"]["source"].as_str().unwrap();
+        assert!(source.contains("\r\nAutocrypt: addr=alice@example.com; prefer-encrypt=mutual;"));
+        assert!(!source.contains("Autocrypt-Gossip"));
+
+        let bob_secret = crate::openpgp::key::read_armored(&fixture("bob_secret.asc")).unwrap();
+        let part = crate::mime_parser::pgp_mime::detect(source).unwrap();
+        let opened = crate::mime_parser::pgp_mime::open(&part, &bob_secret, &[]);
+        let entity = opened.entity.unwrap();
+        assert!(entity.contains("Autocrypt-Gossip: addr=bob@example.com; keydata="));
+        assert!(entity.contains("Autocrypt-Gossip: addr=alice@example.com; keydata="));
+
+        let request = post(
+            "/recommend_encryption",
+            r#"{"session_id": 0, "recipients": ""}"#,
+        );
+        let response = recommend_encryption(&request, database_conn, credentials, clients).await;
+        assert_eq!(response.status, 400);
+    }
+
+    #[async_std::test]
+    async fn smime() {
+        let fixture = |name: &str| {
+            return std::fs::read(format!(
+                "{}/tests/fixtures/smime/{}",
+                env!("CARGO_MANIFEST_DIR"),
+                name
+            ))
+            .unwrap();
+        };
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        rusqlite::vtab::array::load_module(&conn).unwrap();
+        database::initialise(&conn).await.unwrap();
+
+        conn.execute_batch(
+            r#"INSERT INTO connections (username, password, address, port) VALUES ('user', 'password', 'imap.example.com', 993);
+            INSERT INTO mailboxes (c_username, c_address, path) VALUES ('user', 'imap.example.com', 'INBOX');
+            INSERT INTO messages (message_uid, c_username, c_address, m_path, sequence_id, message_id, subject, from_, sender, to_, cc, bcc, reply_to, in_reply_to, delivered_to, date_, received, html, text, preview)
+                VALUES (1, 'user', 'imap.example.com', 'INBOX', 1, 'id', 'Budget', '[{"name":"Alice","mailbox":"alice","host":"example.com"}]', '[]', '[]', '[]', '[]', '[]', '', '', 0, 0, '', '', '');"#,
+        )
+        .unwrap();
+
+        let part = crate::mime_parser::smime::detect(
+            &String::from_utf8(fixture("smime_encrypted.eml")).unwrap(),
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO smime_parts (message_uid, c_username, c_address, m_path, kind, data)
+                VALUES (1, 'user', 'imap.example.com', 'INBOX', ?1, ?2)",
+            rusqlite::params![part.kind.name(), part.data],
+        )
+        .unwrap();
+
+        let client = Client {
+            username: String::from("user"),
+            password: String::from("password"),
+            address: String::from("imap.example.com"),
+            port: 993,
+            security: Security::Tls,
+            cert_fingerprint: None,
+            auth_mechanism: AuthMechanism::Password,
+            oauth: None,
+        };
+        let clients = Arc::new(Mutex::new(vec![client]));
+        let credentials = open_credentials(&conn);
+        let database_conn = Arc::new(Mutex::new(conn));
+
+        let import = |password: &str| {
+            let body = serde_json::json!({
+                "session_id": 0,
+                "identity": BASE64_STANDARD.encode(fixture("alice.p12")),
+                "password": password
+            });
+
+            return post("/import_smime_identity", &body.to_string());
+        };
+
+        let response = import_smime_identity(
+            &import("wrong"),
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        assert_eq!(response.status, 400);
+
+        let response = import_smime_identity(
+            &import("alice password"),
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(parsed["data"]["subject"], "Alice <alice@example.com>");
+        assert_eq!(parsed["data"]["issuer"], "Example Mail CA");
+
+        let request = post("/get_smime_identities", r#"{"session_id": 0}"#);
+        let response = get_smime_identities(
+            &request,
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(
+            parsed["data"][0]["addresses"],
+            serde_json::json!(["alice@example.com"])
+        );
+
+        let request = post(
+            "/get_messages_with_uids",
+            r#"{"session_id": 0, "mailbox_path": "INBOX", "message_uids": [1]}"#,
+        );
+        let response = get_messages_with_uids(
+            &request,
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        let text = BASE64_STANDARD
+            .decode(parsed["data"][0]["text"].as_str().unwrap())
+            .unwrap();
+
+        assert!(String::from_utf8(text)
+            .unwrap()
+            .contains("The budget is approved"));
+        assert_eq!(parsed["data"][0]["security"]["protocol"], "smime");
+        assert_eq!(parsed["data"][0]["security"]["decrypted"], true);
+        // The test CA is not among the system certificates.
+        assert_eq!(parsed["data"][0]["security"]["signature"], "untrusted");
+        assert_eq!(
+            parsed["data"][0]["security"]["signer"]["identity"],
+            "Alice <alice@example.com>"
+        );
+
+        let message =
+            "From: alice@example.com\r\nTo: Bob <bob@example.com>\r\nSubject: Hi\r\n\r\nHi Bob\r\n";
+        let body = serde_json::json!({"session_id": 0, "message": message, "sign": true, "protocol": "smime"});
+        let response = protect_message(
+            &post("/protect_message", &body.to_string()),
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(response.status, 200);
+        assert!(parsed["data"]["source"]
+            .as_str()
+            .unwrap()
+            .contains("application/pkcs7-signature"));
+
+        let body = serde_json::json!({"session_id": 0, "message": message, "encrypt": true, "protocol": "smime"});
+        let response = protect_message(
+            &post("/protect_message", &body.to_string()),
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+        )
+        .await;
+        assert_eq!(response.status, 400);
+
+        let request = post(
+            "/delete_smime_identity",
+            r#"{"session_id": 0, "fingerprint": "f7:9f:7d:7b:bb:df:ca:e4:24:30:8e:b9:9a:b8:9e:f5:e5:ca:c4:92:43:bd:da:f9:c1:93:40:b8:bb:9c:f7:fb"}"#,
+        );
+        let response =
+            delete_smime_identity(&request, Arc::clone(&database_conn), Arc::clone(&clients)).await;
+        assert_eq!(response.status, 200);
+
+        let response = delete_smime_identity(&request, database_conn, clients).await;
+        assert_eq!(response.status, 404);
+    }
+
+    #[async_std::test]
+    async fn get_sessions_escaped() {
+        let client = Client {
+            username: String::from("user\"name"),
+            password: String::from("password"),
+            address: String::from("imap.example.com"),
+            port: 993,
+            security: Security::Tls,
+            cert_fingerprint: None,
+            auth_mechanism: AuthMechanism::Password,
+            oauth: None,
+        };
+        let clients = Arc::new(Mutex::new(vec![client]));
+
+        let body = get_sessions(clients).await.body;
+        let parsed: serde_json::Value = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(parsed["data"][0]["username"], "user\"name");
+        assert_eq!(parsed["data"][0]["port"], 993);
+        assert!(!body.contains("password"));
+    }
+}
@@ -1,116 +1,672 @@
-use async_std::net::TcpStream;
+use async_std::future;
+use async_std::io::{BufReader, Read, Write};
+use async_std::net::TcpListener;
 use async_std::sync::{Arc, Mutex};
-use async_std::{net::TcpListener, prelude::*};
-use futures::stream::StreamExt;
+use futures::stream::{Stream, StreamExt};
+use futures::FutureExt;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
 
-use crate::http_server::handle_conn;
-use crate::types::session::{Client, Session};
+use crate::config::{self, Config, LogLevel};
+use crate::database::credentials::CredentialStore;
+use crate::http_server::request::{self, Request};
+use crate::http_server::response::Response;
+#[cfg(unix)]
+use crate::http_server::unix_socket;
+use crate::http_server::{api_token, handle_conn};
+use crate::inbox_client::pool::SessionPool;
+use crate::my_error::MyError;
+use crate::types::session::Client;
+
+/// Idle keep-alive connections and clients that stall while sending a request
+/// are closed after this long.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+const ROUTES: [&str; 27] = [
+    "/login",
+    "/logout",
+    "/get_sessions",
+    "/account_status",
+    "/change_master_key",
+    "/get_mailboxes",
+    "/get_messages_with_uids",
+    "/get_messages_sorted",
+    "/get_messages_with_flag",
+    "/changes_since",
+    "/update_mailbox",
+    "/update_mailboxes",
+    "/modify_flags",
+    "/move_message",
+    "/get_headers",
+    "/get_raw_message",
+    "/allow_remote_content",
+    "/respond_invitation",
+    "/import_openpgp_key",
+    "/get_openpgp_keys",
+    "/delete_openpgp_key",
+    "/protect_message",
+    "/import_smime_identity",
+    "/get_smime_identities",
+    "/delete_smime_identity",
+    "/recommend_encryption",
+    "/set_autocrypt_preference",
+];
+
+/// Routes carrying passwords, passphrases or secret keys. They only take a
+/// POST body, so credentials never end up in a URL or a log of one.
+const CREDENTIAL_ROUTES: [&str; 4] = [
+    "/login",
+    "/change_master_key",
+    "/import_openpgp_key",
+    "/import_smime_identity",
+];
 
 pub async fn create_server(
-    sessions: Arc<Mutex<Vec<Session>>>,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: Arc<CredentialStore>,
     clients: Arc<Mutex<Vec<Client>>>,
-) {
-    let listener = TcpListener::bind("127.0.0.1:9001").await.unwrap();
+    api_token: Arc<String>,
+    config: Arc<Config>,
+) -> Result<(), MyError> {
+    // Both listeners are bound before serving, so a transport that cannot
+    // start stops the server instead of leaving it half available.
+    let tcp_listener = match config.transport.tcp() {
+        true => match TcpListener::bind(&config.listen).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                let err = MyError::Io(e, format!("Error listening on {}", config.listen));
+                err.log_error();
+
+                return Err(err);
+            }
+        },
+        false => None,
+    };
+
+    #[cfg(unix)]
+    let unix_listener = match (config.transport.unix(), &config.socket) {
+        (true, Some(socket)) => match unix_socket::bind(socket).await {
+            Ok(listener) => Some(listener),
+            Err(e) => return Err(e),
+        },
+        _ => None,
+    };
+
+    let tcp = async {
+        let listener = match &tcp_listener {
+            Some(listener) => listener,
+            None => return,
+        };
 
-    let _ = listener
-        .incoming()
-        .for_each_concurrent(/* limit */ None, |tcpstream| {
+        if config::log_enabled(LogLevel::Info) {
+            eprintln!("Listening on {}", config.listen);
+        }
+
+        serve(
+            listener.incoming(),
+            Arc::clone(&sessions),
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+            Arc::clone(&api_token),
+            Arc::clone(&config),
+        )
+        .await;
+    };
+
+    #[cfg(unix)]
+    let unix = async {
+        let listener = match &unix_listener {
+            Some(listener) => listener,
+            None => return,
+        };
+
+        if config::log_enabled(LogLevel::Info) {
+            if let Some(socket) = &config.socket {
+                eprintln!("Listening on {}", socket.display());
+            }
+        }
+
+        serve(
+            listener.incoming(),
+            Arc::clone(&sessions),
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+            Arc::clone(&api_token),
+            Arc::clone(&config),
+        )
+        .await;
+    };
+
+    #[cfg(not(unix))]
+    let unix = async {};
+
+    futures::join!(tcp, unix);
+
+    return Ok(());
+}
+
+/// Handles the connections of one listener, the same API is served on every
+/// transport.
+async fn serve<I, S>(
+    incoming: I,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: Arc<CredentialStore>,
+    clients: Arc<Mutex<Vec<Client>>>,
+    api_token: Arc<String>,
+    config: Arc<Config>,
+) where
+    I: Stream<Item = std::io::Result<S>>,
+    S: Read + Write + Clone + Unpin,
+{
+    incoming
+        .for_each_concurrent(/* limit */ None, |stream| {
             let sessions = Arc::clone(&sessions);
             let database_conn = Arc::clone(&database_conn);
+            let credentials = Arc::clone(&credentials);
             let clients = Arc::clone(&clients);
+            let api_token = Arc::clone(&api_token);
+            let config = Arc::clone(&config);
 
             async move {
-                handle_connection(tcpstream.unwrap(), sessions, database_conn, clients).await;
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        eprintln!("Error accepting connection: {}", e);
+                        return;
+                    }
+                };
+
+                handle_connection(
+                    stream,
+                    sessions,
+                    database_conn,
+                    credentials,
+                    clients,
+                    api_token,
+                    config,
+                )
+                .await;
             }
         })
         .await;
 }
 
-async fn handle_connection(
-    mut stream: TcpStream,
-    sessions: Arc<Mutex<Vec<Session>>>,
+async fn handle_connection<S>(
+    stream: S,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: Arc<CredentialStore>,
+    clients: Arc<Mutex<Vec<Client>>>,
+    api_token: Arc<String>,
+    config: Arc<Config>,
+) where
+    S: Read + Write + Clone + Unpin,
+{
+    let mut writer = stream.clone();
+    let mut reader = BufReader::new(stream);
+
+    // Requests are answered in the order they arrive, so pipelined requests
+    // are handled by reading the next one from the same buffered stream.
+    loop {
+        let head = match future::timeout(REQUEST_TIMEOUT, request::read_head(&mut reader)).await {
+            Ok(head) => head,
+            Err(_) => break,
+        };
+
+        let mut request = match head {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(e) => {
+                let _ = Response::error(e.status, &e.message)
+                    .write(&mut writer, false)
+                    .await;
+                break;
+            }
+        };
+
+        // Checked before the body is read, the connection is closed so an
+        // unauthorised client cannot make the server buffer a large body.
+        match api_token::authorize(&request, &api_token) {
+            Ok(_) => {}
+            Err(e) => {
+                let mut response = Response::error(e.status, &e.message);
+
+                if e.status == 401 {
+                    response = response.with_header("WWW-Authenticate", "Bearer");
+                }
+
+                let _ = response.write(&mut writer, false).await;
+                break;
+            }
+        }
+
+        if request.expects_continue() {
+            let continue_response = "HTTP/1.1 100 Continue\r\n\r\n";
+
+            match async_std::io::WriteExt::write_all(&mut writer, continue_response.as_bytes())
+                .await
+            {
+                Ok(_) => {}
+                Err(_) => break,
+            }
+        }
+
+        let body = future::timeout(
+            REQUEST_TIMEOUT,
+            request::read_body(&mut reader, &mut request),
+        )
+        .await;
+
+        match body {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                let _ = Response::error(e.status, &e.message)
+                    .write(&mut writer, false)
+                    .await;
+                break;
+            }
+            Err(_) => {
+                let _ = Response::error(408, "Request timed out")
+                    .write(&mut writer, false)
+                    .await;
+                break;
+            }
+        }
+
+        let keep_alive = request.keep_alive();
+
+        // A panicking handler answers its own request with a 500 instead of
+        // taking the connection or the listener down.
+        let response = match AssertUnwindSafe(route(
+            &request,
+            Arc::clone(&sessions),
+            Arc::clone(&database_conn),
+            Arc::clone(&credentials),
+            Arc::clone(&clients),
+            Arc::clone(&config),
+        ))
+        .catch_unwind()
+        .await
+        {
+            Ok(response) => response,
+            Err(_) => {
+                eprintln!("Handler for {} panicked", request.path);
+                Response::error(500, "Internal Server Error")
+            }
+        };
+
+        if config::log_enabled(LogLevel::Debug) {
+            eprintln!("{} {} {}", request.method, request.path, response.status);
+        }
+
+        match response.write(&mut writer, keep_alive).await {
+            Ok(_) => {}
+            Err(_) => break,
+        }
+
+        if !keep_alive {
+            break;
+        }
+    }
+}
+
+async fn route(
+    request: &Request,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: Arc<CredentialStore>,
     clients: Arc<Mutex<Vec<Client>>>,
-) {
-    let mut buffer = [0; 1024];
-    stream.read(&mut buffer).await.unwrap();
+    config: Arc<Config>,
+) -> Response {
+    let path = request.path.as_str();
+
+    if !ROUTES.contains(&path) {
+        return Response::error(404, "Not Found");
+    }
+
+    if request.method != "GET" && request.method != "POST" {
+        return Response::error(405, "Method Not Allowed").with_header("Allow", "GET, POST");
+    }
+
+    if CREDENTIAL_ROUTES.contains(&path) {
+        if request.method != "POST" {
+            return Response::error(405, "Method Not Allowed").with_header("Allow", "POST");
+        }
 
-    let request = String::from_utf8_lossy(&buffer);
-    let (path, params) = extract_params_from_request(&request);
+        if !request.query.is_empty() {
+            return Response::error(400, "Credentials must be sent in the request body");
+        }
+    }
 
-    let data = match path {
-        "/login" => handle_conn::login(params, sessions, database_conn, clients).await,
-        "/logout" => handle_conn::logout(params, sessions, database_conn, clients).await,
+    let response = match path {
+        "/login" => {
+            handle_conn::login(
+                request,
+                sessions,
+                database_conn,
+                credentials,
+                clients,
+                config,
+            )
+            .await
+        }
+        "/logout" => handle_conn::logout(request, sessions, database_conn, clients).await,
         "/get_sessions" => handle_conn::get_sessions(clients).await,
-        "/get_mailboxes" => handle_conn::get_mailboxes(params, database_conn, clients).await,
+        "/account_status" => handle_conn::get_account_status(sessions).await,
+        "/change_master_key" => {
+            handle_conn::change_master_key(request, database_conn, credentials).await
+        }
+        "/get_mailboxes" => handle_conn::get_mailboxes(request, database_conn, clients).await,
         "/get_messages_with_uids" => {
-            handle_conn::get_messages_with_uids(params, database_conn, clients).await
+            handle_conn::get_messages_with_uids(request, database_conn, credentials, clients).await
         }
         "/get_messages_sorted" => {
-            handle_conn::get_messages_sorted(params, database_conn, clients).await
+            handle_conn::get_messages_sorted(request, database_conn, clients).await
         }
         "/get_messages_with_flag" => {
-            handle_conn::get_messages_with_flag(params, database_conn, clients).await
+            handle_conn::get_messages_with_flag(request, database_conn, clients).await
         }
+        "/changes_since" => handle_conn::changes_since(request, database_conn, clients).await,
         "/update_mailbox" => {
-            handle_conn::update_mailbox(params, sessions, database_conn, clients).await
+            handle_conn::update_mailbox(request, sessions, database_conn, clients, config).await
         }
         "/update_mailboxes" => {
-            handle_conn::update_mailboxes(params, sessions, database_conn, clients).await
+            handle_conn::update_mailboxes(request, sessions, database_conn, clients).await
         }
 
         "/modify_flags" => {
-            handle_conn::modify_flags(params, sessions, database_conn, clients).await
+            handle_conn::modify_flags(request, sessions, database_conn, clients).await
         }
         "/move_message" => {
-            handle_conn::move_message(params, sessions, database_conn, clients).await
+            handle_conn::move_message(request, sessions, database_conn, clients).await
+        }
+        "/get_headers" => handle_conn::get_headers(request, sessions, database_conn, clients).await,
+        "/get_raw_message" => {
+            handle_conn::get_raw_message(request, sessions, database_conn, clients).await
+        }
+        "/allow_remote_content" => {
+            handle_conn::allow_remote_content(request, database_conn, clients).await
+        }
+        "/respond_invitation" => {
+            handle_conn::respond_invitation(request, database_conn, credentials, clients).await
+        }
+        "/import_openpgp_key" => {
+            handle_conn::import_openpgp_key(request, database_conn, credentials, clients).await
+        }
+        "/get_openpgp_keys" => {
+            handle_conn::get_openpgp_keys(request, database_conn, credentials, clients).await
         }
-        _ => String::from("{\"success\": false, \"message\": \"Not Found\"}"),
+        "/delete_openpgp_key" => {
+            handle_conn::delete_openpgp_key(request, database_conn, clients).await
+        }
+        "/protect_message" => {
+            handle_conn::protect_message(request, database_conn, credentials, clients).await
+        }
+        "/import_smime_identity" => {
+            handle_conn::import_smime_identity(request, database_conn, credentials, clients).await
+        }
+        "/get_smime_identities" => {
+            handle_conn::get_smime_identities(request, database_conn, credentials, clients).await
+        }
+        "/delete_smime_identity" => {
+            handle_conn::delete_smime_identity(request, database_conn, clients).await
+        }
+        "/recommend_encryption" => {
+            handle_conn::recommend_encryption(request, database_conn, credentials, clients).await
+        }
+        "/set_autocrypt_preference" => {
+            handle_conn::set_autocrypt_preference(request, database_conn, clients).await
+        }
+        _ => return Response::error(404, "Not Found"),
     };
 
-    let response = format!(
-        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
-        data.len(),
-        data
-    );
-
-    stream.write(response.as_bytes()).await.unwrap();
-    stream.flush().await.unwrap();
+    return response;
 }
 
-fn extract_params_from_request(request: &str) -> (&str, &str) {
-    let header = request.split("\r\n").next().unwrap_or("");
-    let header_parts: Vec<&str> = header.split(" ").collect();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::{ReadExt, WriteExt};
+    use async_std::net::TcpStream;
 
-    let uri = header_parts.get(1).unwrap_or(&"");
-    let uri_parts: Vec<&str> = uri.split("?").collect();
+    use crate::database;
+    use crate::database::credentials::KeySource;
 
-    let path: &str = uri_parts.get(0).unwrap_or(&"");
-    let params: &str = uri_parts.get(1).unwrap_or(&"");
+    async fn read_request(data: &[u8]) -> Request {
+        let mut reader = BufReader::new(data);
 
-    return (path, params);
-}
+        return request::read_head(&mut reader).await.unwrap().unwrap();
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[async_std::test]
+    async fn test_extract_params_from_request() {
+        let request =
+            read_request(b"GET /login?username=test&password=test HTTP/1.1\r\n\r\n").await;
+
+        assert_eq!(request.path, "/login");
+        assert_eq!(request.query, "username=test&password=test");
+    }
+
+    #[async_std::test]
+    async fn test_extract_params_from_request_no_params() {
+        let request = read_request(b"GET /login HTTP/1.1\r\n\r\n").await;
+
+        assert_eq!(request.path, "/login");
+        assert_eq!(request.query, "");
+    }
+
+    async fn get_state() -> (
+        Arc<Mutex<Vec<Arc<SessionPool>>>>,
+        Arc<Mutex<rusqlite::Connection>>,
+        Arc<CredentialStore>,
+        Arc<Mutex<Vec<Client>>>,
+    ) {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        database::initialise(&conn).await.unwrap();
+
+        let credentials =
+            match CredentialStore::open(&conn, &KeySource::Passphrase(String::from("test"))) {
+                Ok(credentials) => credentials,
+                Err(e) => panic!("{}", e),
+            };
+
+        return (
+            Arc::new(Mutex::new(Vec::new())),
+            Arc::new(Mutex::new(conn)),
+            Arc::new(credentials),
+            Arc::new(Mutex::new(Vec::new())),
+        );
+    }
+
+    #[async_std::test]
+    async fn test_route_status_codes() {
+        let (sessions, database_conn, credentials, clients) = get_state().await;
+
+        for (data, status) in [
+            (&b"GET /get_sessions HTTP/1.1\r\n\r\n"[..], 200),
+            (b"GET /unknown HTTP/1.1\r\n\r\n", 404),
+            (b"DELETE /get_sessions HTTP/1.1\r\n\r\n", 405),
+            (b"POST /get_sessions HTTP/1.1\r\n\r\n", 200),
+            (b"GET /login?username=u&password=p HTTP/1.1\r\n\r\n", 405),
+            (b"GET /change_master_key HTTP/1.1\r\n\r\n", 405),
+            (b"POST /login?password=p HTTP/1.1\r\n\r\n", 400),
+            (
+                b"POST /import_smime_identity?password=p HTTP/1.1\r\n\r\n",
+                400,
+            ),
+        ] {
+            let request = read_request(data).await;
+            let response = route(
+                &request,
+                Arc::clone(&sessions),
+                Arc::clone(&database_conn),
+                Arc::clone(&credentials),
+                Arc::clone(&clients),
+                Arc::new(Config::default()),
+            )
+            .await;
+
+            assert_eq!(response.status, status);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_route_json_body() {
+        let (sessions, database_conn, credentials, clients) = get_state().await;
+
+        let mut reader = BufReader::new(
+            &b"POST /logout HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: 17\r\n\r\n{\"session_id\": 3}"[..],
+        );
+        let mut request = request::read_head(&mut reader).await.unwrap().unwrap();
+        request::read_body(&mut reader, &mut request).await.unwrap();
+
+        let response = route(
+            &request,
+            sessions,
+            database_conn,
+            credentials,
+            clients,
+            Arc::new(Config::default()),
+        )
+        .await;
+
+        assert_eq!(response.status, 404);
+        assert!(response.body.contains("\"code\":\"INVALID_SESSION\""));
+    }
+
+    #[async_std::test]
+    async fn test_connection_pipelining_and_malformed() {
+        let (sessions, database_conn, credentials, clients) = get_state().await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = async_std::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_connection(
+                stream,
+                sessions,
+                database_conn,
+                credentials,
+                clients,
+                Arc::new(String::from("token")),
+                Arc::new(Config::default()),
+            )
+            .await;
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /get_sessions HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer token\r\n\r\nPOST /nope HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer token\r\nContent-Length: 2\r\n\r\n{}GARBAGE\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).await.unwrap();
+        server.await;
 
-    #[test]
-    fn test_extract_params_from_request() {
-        let request = "GET /login?username=test&password=test HTTP/1.1\r\n";
-        let (path, params) = extract_params_from_request(request);
+        // Bodies are not terminated by a line break, so the next status line
+        // follows directly after the previous body.
+        let status_lines: Vec<&str> = responses
+            .split("\r\n")
+            .filter_map(|line| line.find("HTTP/1.1").map(|i| &line[i..]))
+            .collect();
 
-        assert_eq!(path, "/login");
-        assert_eq!(params, "username=test&password=test");
+        assert_eq!(
+            status_lines,
+            vec![
+                "HTTP/1.1 200 OK",
+                "HTTP/1.1 404 Not Found",
+                "HTTP/1.1 400 Bad Request"
+            ]
+        );
+        assert!(responses.contains("Content-Type: application/json"));
     }
 
-    #[test]
-    fn test_extract_params_from_request_no_params() {
-        let request = "GET /login HTTP/1.1\r\n";
-        let (path, params) = extract_params_from_request(request);
+    #[async_std::test]
+    async fn test_connection_unauthorised() {
+        let (sessions, database_conn, credentials, clients) = get_state().await;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = async_std::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            handle_connection(
+                stream,
+                sessions,
+                database_conn,
+                credentials,
+                clients,
+                Arc::new(String::from("token")),
+                Arc::new(Config::default()),
+            )
+            .await;
+        });
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /get_sessions HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer wrong\r\n\r\nGET /get_sessions HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer token\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).await.unwrap();
+        server.await;
+
+        assert!(responses.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(responses.contains("WWW-Authenticate: Bearer\r\n"));
+        assert!(responses.contains("\"code\":\"UNAUTHORIZED\""));
+        assert_eq!(responses.matches("HTTP/1.1").count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn test_unix_socket_transport() {
+        use crate::config::Transport;
+        use async_std::os::unix::net::UnixStream;
+
+        let (sessions, database_conn, credentials, clients) = get_state().await;
+
+        let socket = std::env::temp_dir().join(format!("http-server-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&socket);
+
+        let config = Config {
+            transport: Transport::Unix,
+            socket: Some(socket.clone()),
+            ..Config::default()
+        };
+
+        async_std::task::spawn(create_server(
+            sessions,
+            database_conn,
+            credentials,
+            clients,
+            Arc::new(String::from("token")),
+            Arc::new(config),
+        ));
+
+        let mut client = loop {
+            match UnixStream::connect(&socket).await {
+                Ok(client) => break client,
+                Err(_) => async_std::task::sleep(Duration::from_millis(10)).await,
+            }
+        };
+
+        client
+            .write_all(b"POST /get_sessions HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer token\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
 
-        assert_eq!(path, "/login");
-        assert_eq!(params, "");
+        std::fs::remove_file(&socket).unwrap();
     }
 }
@@ -1,7 +1,124 @@
+use serde::de::{self, DeserializeOwned, Deserializer};
+use serde::Deserialize;
 use std::collections::HashMap;
+use std::str::FromStr;
 use url_escape::decode;
 
+use crate::http_server::request::Request;
 use crate::my_error::MyError;
+use crate::types::error_code::ErrorCode;
+
+/// A parameter as it arrives, either typed in a JSON body or as a string from
+/// a query string or a client that sends every value as a string.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Param<T> {
+    Value(T),
+    String(String),
+}
+
+/// Deserialises the parameters of a request into `T`, from the JSON body of a
+/// POST request or from the query string of a GET request.
+pub fn parse_request<T: DeserializeOwned>(request: &Request) -> Result<T, MyError> {
+    let value = if request.method == "POST" && !request.body.is_empty() {
+        match serde_json::from_slice(&request.body) {
+            Ok(value) => value,
+            Err(e) => {
+                let err = MyError::Code(
+                    ErrorCode::InvalidRequest,
+                    e.to_string(),
+                    String::from("Invalid JSON body"),
+                );
+                err.log_error();
+
+                return Err(err);
+            }
+        }
+    } else {
+        let params = parse_params(request.query.clone());
+
+        serde_json::Value::Object(
+            params
+                .into_iter()
+                .map(|(key, value)| (key, serde_json::Value::String(value)))
+                .collect(),
+        )
+    };
+
+    match serde_json::from_value(value) {
+        Ok(parsed) => return Ok(parsed),
+        Err(e) => {
+            let err = MyError::Code(
+                ErrorCode::InvalidRequest,
+                e.to_string(),
+                String::from("Invalid request parameters"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+fn from_param<'de, D, T, F>(deserializer: D, parse: F) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+    F: Fn(Option<&String>) -> Result<Option<T>, MyError>,
+{
+    match Param::<T>::deserialize(deserializer) {
+        Ok(Param::Value(value)) => return Ok(value),
+        Ok(Param::String(string)) => match parse(Some(&string)) {
+            Ok(Some(value)) => return Ok(value),
+            Ok(None) => return Err(de::Error::custom("missing value")),
+            Err(e) => return Err(de::Error::custom(e)),
+        },
+        Err(e) => return Err(e),
+    }
+}
+
+pub fn usize_param<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+    return from_param(deserializer, get_usize);
+}
+
+pub fn u16_param<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u16, D::Error> {
+    return from_param(deserializer, get_u16);
+}
+
+pub fn u32_param<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    return from_param(deserializer, get_u32);
+}
+
+/// An optional number, missing when the field has `#[serde(default)]`.
+pub fn u32_option_param<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u32>, D::Error> {
+    return from_param(deserializer, get_u32).map(Some);
+}
+
+pub fn bool_param<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    return from_param(deserializer, get_bool);
+}
+
+/// A list given as an array or as a comma separated string.
+pub fn list_param<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + FromStr,
+    T::Err: std::fmt::Display,
+{
+    match Param::<Vec<T>>::deserialize(deserializer) {
+        Ok(Param::Value(values)) => return Ok(values),
+        Ok(Param::String(string)) if string.is_empty() => return Ok(vec![]),
+        Ok(Param::String(string)) => {
+            return string
+                .split(',')
+                .map(|value| value.parse::<T>().map_err(de::Error::custom))
+                .collect();
+        }
+        Err(e) => return Err(e),
+    }
+}
 
 pub fn get_usize(uri_param: Option<&String>) -> Result<Option<usize>, MyError> {
     match uri_param {
@@ -0,0 +1,432 @@
+use async_std::io::{BufRead, BufReadExt, ReadExt};
+
+/// Limit for the request line and headers together, long `message_uids`
+/// query strings fit comfortably.
+pub const MAX_HEAD_SIZE: usize = 64 * 1024;
+pub const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        return self
+            .headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str());
+    }
+
+    /// HTTP/1.1 connections stay open unless the client asks to close them,
+    /// HTTP/1.0 connections only when the client asks to keep them open.
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("Connection").unwrap_or("").to_lowercase();
+        let has_token = |token: &str| connection.split(',').any(|t| t.trim() == token);
+
+        if self.version == "HTTP/1.0" {
+            return has_token("keep-alive");
+        }
+
+        return !has_token("close");
+    }
+
+    pub fn expects_continue(&self) -> bool {
+        match self.header("Expect") {
+            Some(expect) => return expect.eq_ignore_ascii_case("100-continue"),
+            None => return false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpError {
+    pub status: u16,
+    pub message: String,
+}
+
+impl HttpError {
+    pub fn new(status: u16, message: &str) -> HttpError {
+        return HttpError {
+            status,
+            message: String::from(message),
+        };
+    }
+
+    fn bad_request(message: &str) -> HttpError {
+        return HttpError::new(400, message);
+    }
+}
+
+/// Reads the request line and headers of the next request. Returns `None`
+/// when the client closed the connection before sending another request.
+pub async fn read_head<R>(reader: &mut R) -> Result<Option<Request>, HttpError>
+where
+    R: BufRead + Unpin,
+{
+    let mut head_size = 0;
+
+    // Empty lines in front of a request are ignored, RFC 9112 section 2.2.
+    let request_line = loop {
+        match read_line(reader, &mut head_size).await {
+            Ok(Some(line)) if line.is_empty() => continue,
+            Ok(Some(line)) => break line,
+            Ok(None) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    };
+
+    let parts: Vec<&str> = request_line.split(' ').collect();
+    if parts.len() != 3 || parts.iter().any(|p| p.is_empty()) {
+        return Err(HttpError::bad_request("Malformed request line"));
+    }
+
+    let method = parts[0];
+    let target = parts[1];
+    let version = parts[2];
+
+    if !method.bytes().all(|b| b.is_ascii_uppercase()) {
+        return Err(HttpError::bad_request("Malformed request method"));
+    }
+
+    if version != "HTTP/1.1" && version != "HTTP/1.0" {
+        return Err(HttpError::new(505, "HTTP version not supported"));
+    }
+
+    if !target.starts_with('/') {
+        return Err(HttpError::bad_request("Malformed request target"));
+    }
+
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (target, ""),
+    };
+
+    let mut headers = Vec::new();
+
+    loop {
+        let line = match read_line(reader, &mut head_size).await {
+            Ok(Some(line)) => line,
+            Ok(None) => return Err(HttpError::bad_request("Connection closed inside headers")),
+            Err(e) => return Err(e),
+        };
+
+        if line.is_empty() {
+            break;
+        }
+
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) if !name.is_empty() && !name.ends_with([' ', '\t']) => {
+                (name, value)
+            }
+            _ => return Err(HttpError::bad_request("Malformed header")),
+        };
+
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+
+    return Ok(Some(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        version: version.to_string(),
+        headers,
+        body: Vec::new(),
+    }));
+}
+
+/// Reads the body announced by the headers of `request`, either with a
+/// `Content-Length` or with chunked transfer encoding.
+pub async fn read_body<R>(reader: &mut R, request: &mut Request) -> Result<(), HttpError>
+where
+    R: BufRead + Unpin,
+{
+    if let Some(encoding) = request.header("Transfer-Encoding") {
+        if !encoding.eq_ignore_ascii_case("chunked") {
+            return Err(HttpError::new(501, "Transfer encoding not supported"));
+        }
+
+        match read_chunked(reader).await {
+            Ok(body) => request.body = body,
+            Err(e) => return Err(e),
+        }
+
+        return Ok(());
+    }
+
+    let lengths: Vec<&str> = request
+        .headers
+        .iter()
+        .filter(|(key, _)| key.eq_ignore_ascii_case("Content-Length"))
+        .map(|(_, value)| value.as_str())
+        .collect();
+
+    let content_length = match lengths.first() {
+        Some(length) => {
+            if lengths.iter().any(|l| l != length) {
+                return Err(HttpError::bad_request("Conflicting Content-Length headers"));
+            }
+
+            match length.parse::<usize>() {
+                Ok(length) => length,
+                Err(_) => return Err(HttpError::bad_request("Invalid Content-Length")),
+            }
+        }
+        None => 0,
+    };
+
+    if content_length > MAX_BODY_SIZE {
+        return Err(HttpError::new(413, "Request body too large"));
+    }
+
+    let mut body = vec![0; content_length];
+    match reader.read_exact(&mut body).await {
+        Ok(_) => {}
+        Err(_) => return Err(HttpError::bad_request("Connection closed inside body")),
+    }
+
+    request.body = body;
+
+    return Ok(());
+}
+
+async fn read_chunked<R>(reader: &mut R) -> Result<Vec<u8>, HttpError>
+where
+    R: BufRead + Unpin,
+{
+    let mut body = Vec::new();
+
+    loop {
+        let line = match read_line(reader, &mut 0).await {
+            Ok(Some(line)) => line,
+            Ok(None) => return Err(HttpError::bad_request("Connection closed inside body")),
+            Err(e) => return Err(e),
+        };
+
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = match usize::from_str_radix(size, 16) {
+            Ok(size) => size,
+            Err(_) => return Err(HttpError::bad_request("Invalid chunk size")),
+        };
+
+        if size > MAX_BODY_SIZE - body.len() {
+            return Err(HttpError::new(413, "Request body too large"));
+        }
+
+        if size == 0 {
+            break;
+        }
+
+        let start = body.len();
+        body.resize(start + size, 0);
+
+        match reader.read_exact(&mut body[start..]).await {
+            Ok(_) => {}
+            Err(_) => return Err(HttpError::bad_request("Connection closed inside body")),
+        }
+
+        match read_line(reader, &mut 0).await {
+            Ok(Some(line)) if line.is_empty() => {}
+            Ok(_) => return Err(HttpError::bad_request("Malformed chunk")),
+            Err(e) => return Err(e),
+        }
+    }
+
+    // Trailer fields are read and dropped.
+    let mut trailer_size = 0;
+    loop {
+        match read_line(reader, &mut trailer_size).await {
+            Ok(Some(line)) if line.is_empty() => break,
+            Ok(Some(_)) => continue,
+            Ok(None) => return Err(HttpError::bad_request("Connection closed inside body")),
+            Err(e) => return Err(e),
+        }
+    }
+
+    return Ok(body);
+}
+
+/// Reads one line without its line ending, `read_size` keeps track of the
+/// bytes read so far so oversized heads are rejected without buffering them.
+async fn read_line<R>(reader: &mut R, read_size: &mut usize) -> Result<Option<String>, HttpError>
+where
+    R: BufRead + Unpin,
+{
+    let mut line = Vec::new();
+    let limit = MAX_HEAD_SIZE.saturating_sub(*read_size) as u64 + 1;
+
+    let read = match (&mut *reader)
+        .take(limit)
+        .read_until(b'\n', &mut line)
+        .await
+    {
+        Ok(read) => read,
+        Err(_) => return Err(HttpError::bad_request("Error reading request")),
+    };
+
+    if read == 0 {
+        return Ok(None);
+    }
+
+    *read_size += read;
+
+    if *read_size > MAX_HEAD_SIZE {
+        return Err(HttpError::new(413, "Request head too large"));
+    }
+
+    if line.last() != Some(&b'\n') {
+        return Err(HttpError::bad_request("Connection closed inside request"));
+    }
+
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    match String::from_utf8(line) {
+        Ok(line) => return Ok(Some(line)),
+        Err(_) => return Err(HttpError::bad_request("Request is not valid UTF-8")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::BufReader;
+
+    async fn read(data: &[u8]) -> Result<Option<Request>, HttpError> {
+        let mut reader = BufReader::new(data);
+
+        let mut request = match read_head(&mut reader).await {
+            Ok(Some(request)) => request,
+            other => return other,
+        };
+
+        match read_body(&mut reader, &mut request).await {
+            Ok(_) => return Ok(Some(request)),
+            Err(e) => return Err(e),
+        }
+    }
+
+    #[async_std::test]
+    async fn get_request() {
+        let request = read(b"GET /login?username=test HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/login");
+        assert_eq!(request.query, "username=test");
+        assert_eq!(request.header("host"), Some("localhost"));
+        assert!(request.body.is_empty());
+        assert!(request.keep_alive());
+    }
+
+    #[async_std::test]
+    async fn content_length_body() {
+        let request = read(b"POST /login HTTP/1.1\r\nContent-Length: 4\r\n\r\n{}{}")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(request.body, b"{}{}");
+    }
+
+    #[async_std::test]
+    async fn chunked_body() {
+        let request = read(
+            b"POST /login HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3;ext=1\r\nabc\r\n2\r\nde\r\n0\r\nX-Trailer: 1\r\n\r\n",
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(request.body, b"abcde");
+    }
+
+    #[async_std::test]
+    async fn pipelined_requests() {
+        let data = b"GET /a HTTP/1.1\r\n\r\nPOST /b HTTP/1.1\r\nContent-Length: 2\r\n\r\nhiGET /c HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let mut reader = BufReader::new(&data[..]);
+
+        let mut paths = Vec::new();
+        while let Some(mut request) = read_head(&mut reader).await.unwrap() {
+            read_body(&mut reader, &mut request).await.unwrap();
+            paths.push((request.path.clone(), request.keep_alive()));
+        }
+
+        assert_eq!(
+            paths,
+            vec![
+                (String::from("/a"), true),
+                (String::from("/b"), true),
+                (String::from("/c"), false)
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn http_10_closes() {
+        let request = read(b"GET / HTTP/1.0\r\n\r\n").await.unwrap().unwrap();
+        assert!(!request.keep_alive());
+
+        let request = read(b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(request.keep_alive());
+    }
+
+    #[async_std::test]
+    async fn closed_connection() {
+        assert_eq!(read(b"").await, Ok(None));
+        assert_eq!(read(b"\r\n").await, Ok(None));
+    }
+
+    #[async_std::test]
+    async fn malformed_requests() {
+        for data in [
+            &b"GET\r\n\r\n"[..],
+            b"GET /login\r\n\r\n",
+            b"GET login HTTP/1.1\r\n\r\n",
+            b"get / HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/1.1\r\nNo colon\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost : localhost\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: localhost\r\n",
+            b"GET / HTTP/1.1\r\nContent-Length: abc\r\n\r\n",
+            b"GET / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab",
+            b"GET / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort",
+            b"GET / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n",
+            b"GET /\xff HTTP/1.1\r\n\r\n",
+        ] {
+            assert_eq!(read(data).await.unwrap_err().status, 400, "{:?}", data);
+        }
+    }
+
+    #[async_std::test]
+    async fn unsupported_version() {
+        assert_eq!(
+            read(b"GET / HTTP/2.0\r\n\r\n").await.unwrap_err().status,
+            505
+        );
+    }
+
+    #[async_std::test]
+    async fn oversized_requests() {
+        let long_query = format!("GET /?{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_HEAD_SIZE));
+        assert_eq!(read(long_query.as_bytes()).await.unwrap_err().status, 413);
+
+        let long_body = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        assert_eq!(read(long_body.as_bytes()).await.unwrap_err().status, 413);
+    }
+}
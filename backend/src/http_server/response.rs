@@ -0,0 +1,193 @@
+use async_std::io::{Write, WriteExt};
+use serde::Serialize;
+
+use crate::my_error::MyError;
+use crate::types::error_code::ErrorCode;
+
+#[derive(Debug, Serialize)]
+struct ErrorBody<'a> {
+    success: bool,
+    message: &'a str,
+    error: ErrorDetail,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorDetail {
+    code: ErrorCode,
+    retryable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_at: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json(status: u16, body: String) -> Response {
+        return Response {
+            status,
+            headers: Vec::new(),
+            body,
+        };
+    }
+
+    /// A response in the same shape the handlers use for failures.
+    pub fn error(status: u16, message: &str) -> Response {
+        return Response::error_body(status, ErrorCode::from_status(status), message, None);
+    }
+
+    /// Failure response with the status and error code derived from `err`,
+    /// errors with a known retry time also get a Retry-After header.
+    pub fn from_error(err: &MyError) -> Response {
+        let code = err.code();
+        let retry_at = err.retry_at();
+
+        let response = Response::error_body(code.status(), code, &err.to_string(), retry_at);
+
+        match retry_at {
+            Some(retry_at) => {
+                let now = chrono::Utc::now().timestamp_millis();
+                let seconds = ((retry_at - now).max(0) + 999) / 1000;
+
+                return response.with_header("Retry-After", &seconds.to_string());
+            }
+            None => return response,
+        }
+    }
+
+    fn error_body(status: u16, code: ErrorCode, message: &str, retry_at: Option<i64>) -> Response {
+        let body = ErrorBody {
+            success: false,
+            message,
+            error: ErrorDetail {
+                code,
+                retryable: code.retryable(),
+                retry_at,
+            },
+        };
+
+        return Response::json(status, serde_json::to_string(&body).unwrap_or_default());
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+
+        return self;
+    }
+
+    pub fn to_bytes(&self, keep_alive: bool) -> Vec<u8> {
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: {}\r\n",
+            self.status,
+            reason_phrase(self.status),
+            self.body.len(),
+            if keep_alive { "keep-alive" } else { "close" }
+        );
+
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        head.push_str("\r\n");
+
+        let mut bytes = head.into_bytes();
+        bytes.extend_from_slice(self.body.as_bytes());
+
+        return bytes;
+    }
+
+    pub async fn write<W>(&self, writer: &mut W, keep_alive: bool) -> std::io::Result<()>
+    where
+        W: Write + Unpin,
+    {
+        match writer.write_all(&self.to_bytes(keep_alive)).await {
+            Ok(_) => {}
+            Err(e) => return Err(e),
+        }
+
+        return writer.flush().await;
+    }
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        507 => "Insufficient Storage",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_imap::error::Error as ImapError;
+
+    #[test]
+    fn serialise_response() {
+        let response =
+            Response::error(405, "Method \"PUT\" not allowed").with_header("Allow", "GET");
+        let bytes = String::from_utf8(response.to_bytes(false)).unwrap();
+
+        assert_eq!(
+            bytes,
+            "HTTP/1.1 405 Method Not Allowed\r\nContent-Type: application/json\r\nContent-Length: 112\r\nConnection: close\r\nAllow: GET\r\n\r\n{\"success\":false,\"message\":\"Method \\\"PUT\\\" not allowed\",\"error\":{\"code\":\"METHOD_NOT_ALLOWED\",\"retryable\":false}}"
+        );
+    }
+
+    #[test]
+    fn imap_error_response() {
+        let err = MyError::Imap(
+            ImapError::No(String::from("[NONEXISTENT] Unknown \"Mailbox\"")),
+            String::from("Error selecting mailbox"),
+        );
+        let response = Response::from_error(&err);
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+
+        assert_eq!(response.status, 404);
+        assert_eq!(parsed["error"]["code"], "NONEXISTENT");
+        assert_eq!(parsed["error"]["retryable"], false);
+        assert!(parsed["error"].get("retry_at").is_none());
+        assert_eq!(parsed["message"], err.to_string());
+    }
+
+    #[test]
+    fn backoff_response() {
+        let retry_at = chrono::Utc::now().timestamp_millis() + 30_000;
+        let err = MyError::Backoff(
+            retry_at,
+            String::from("Next attempt in 30000 ms"),
+            String::from("Server unreachable, waiting before reconnecting"),
+        );
+        let response = Response::from_error(&err);
+        let parsed: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+
+        assert_eq!(response.status, 503);
+        assert_eq!(parsed["error"]["code"], "BACKOFF");
+        assert_eq!(parsed["error"]["retryable"], true);
+        assert_eq!(parsed["error"]["retry_at"], retry_at);
+        assert_eq!(
+            response.headers,
+            vec![(String::from("Retry-After"), String::from("30"))]
+        );
+    }
+}
@@ -1,75 +1,140 @@
-use crate::types::message::Message;
-
-pub fn message_to_display(message: &Message) -> String {
-    let result = String::from("{")
-        + &format!("\"uid\":{},", message.message_uid)
-        + &format!("\"sequence_id\":{},", message.sequence_id)
-        + &format!("\"message_id\":\"{}\",", message.message_id)
-        + &format!("\"subject\":\"{}\",", message.subject)
-        + &format!("\"from\":{},", message.from)
-        + &format!("\"sender\":{},", message.sender)
-        + &format!("\"to\":{},", message.to)
-        + &format!("\"cc\":{},", message.cc)
-        + &format!("\"bcc\":{},", message.bcc)
-        + &format!("\"reply_to\":{},", message.reply_to)
-        + &format!("\"in_reply_to\":\"{}\",", message.in_reply_to)
-        + &format!("\"delivered_to\":\"{}\",", message.delivered_to)
-        + &format!("\"date\":{},", message.date)
-        + &format!("\"received\":{},", message.received)
-        + &format!("\"flags\":{},", string_vec_to_display(&message.flags))
-        + &format!("\"html\":\"{}\",", message.html)
-        + &format!("\"text\":\"{}\"", message.text)
-        + "}";
-
-    return result;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::mime_parser::icalendar::{self, Invitation};
+use crate::mime_parser::sanitize::RemoteContent;
+use crate::types::message::{Message, MessageSecurity};
+
+#[derive(Debug, Serialize)]
+pub struct MessageDisplay<'a> {
+    pub uid: u32,
+    pub sequence_id: u32,
+    pub message_id: &'a str,
+    pub subject: &'a str,
+    pub from: Value,
+    pub sender: Value,
+    pub to: Value,
+    pub cc: Value,
+    pub bcc: Value,
+    pub reply_to: Value,
+    pub in_reply_to: &'a str,
+    pub delivered_to: &'a str,
+    pub date: i64,
+    pub received: i64,
+    pub flags: &'a [String],
+    pub html: &'a str,
+    pub text: &'a str,
 }
 
-pub fn message_vec_to_display(messages: &Vec<Message>) -> String {
-    let mut result = String::from("[");
-
-    for (i, message) in messages.iter().enumerate() {
-        result.push_str(&message_to_display(message));
-
-        if i < messages.len() - 1 {
-            result.push_str(",");
-        }
-    }
-
-    result.push_str("]");
-
-    return result;
+pub fn message_to_display(message: &Message) -> MessageDisplay<'_> {
+    return MessageDisplay {
+        uid: message.message_uid,
+        sequence_id: message.sequence_id,
+        message_id: &message.message_id,
+        subject: &message.subject,
+        from: address_to_display(&message.from),
+        sender: address_to_display(&message.sender),
+        to: address_to_display(&message.to),
+        cc: address_to_display(&message.cc),
+        bcc: address_to_display(&message.bcc),
+        reply_to: address_to_display(&message.reply_to),
+        in_reply_to: &message.in_reply_to,
+        delivered_to: &message.delivered_to,
+        date: message.date,
+        received: message.received,
+        flags: &message.flags,
+        html: &message.html,
+        text: &message.text,
+    };
 }
 
-pub fn string_vec_to_display(strings: &Vec<String>) -> String {
-    let mut result = String::from("[");
-
-    for (i, string) in strings.iter().enumerate() {
-        result.push_str(&format!("\"{}\"", string));
-
-        if i < strings.len() - 1 {
-            result.push_str(",");
-        }
-    }
+/// Message opened for reading, with the remote content left out of its html,
+/// the events of an invitation and whether it was encrypted or signed.
+#[derive(Debug, Serialize)]
+pub struct MessageBodyDisplay<'a> {
+    #[serde(flatten)]
+    pub message: MessageDisplay<'a>,
+    pub remote_content: &'a RemoteContent,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub invitation: Option<Invitation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub security: Option<&'a MessageSecurity>,
+}
 
-    result.push_str("]");
+pub fn message_vec_to_body_display(
+    bodies: &[(Message, RemoteContent)],
+) -> Vec<MessageBodyDisplay<'_>> {
+    return bodies
+        .iter()
+        .map(|(message, remote_content)| MessageBodyDisplay {
+            message: message_to_display(message),
+            remote_content,
+            invitation: icalendar::parse(&message.calendar),
+            security: message.security.as_ref(),
+        })
+        .collect();
+}
 
-    return result;
+/// Message as shown in the message list, the body is left out and replaced
+/// by a preview. The body is retrieved with the uid of the message.
+#[derive(Debug, Serialize)]
+pub struct MessageSummaryDisplay<'a> {
+    pub uid: u32,
+    pub sequence_id: u32,
+    pub message_id: &'a str,
+    pub subject: &'a str,
+    pub from: Value,
+    pub sender: Value,
+    pub to: Value,
+    pub cc: Value,
+    pub bcc: Value,
+    pub reply_to: Value,
+    pub in_reply_to: &'a str,
+    pub delivered_to: &'a str,
+    pub date: i64,
+    pub received: i64,
+    pub size: u32,
+    pub flags: &'a [String],
+    pub has_attachments: bool,
+    pub thread_size: u32,
+    pub preview: &'a str,
 }
 
-pub fn u32_vec_to_display(u32s: &Vec<u32>) -> String {
-    let mut result = String::from("[");
+pub fn message_to_summary_display(message: &Message) -> MessageSummaryDisplay<'_> {
+    return MessageSummaryDisplay {
+        uid: message.message_uid,
+        sequence_id: message.sequence_id,
+        message_id: &message.message_id,
+        subject: &message.subject,
+        from: address_to_display(&message.from),
+        sender: address_to_display(&message.sender),
+        to: address_to_display(&message.to),
+        cc: address_to_display(&message.cc),
+        bcc: address_to_display(&message.bcc),
+        reply_to: address_to_display(&message.reply_to),
+        in_reply_to: &message.in_reply_to,
+        delivered_to: &message.delivered_to,
+        date: message.date,
+        received: message.received,
+        size: message.size,
+        flags: &message.flags,
+        has_attachments: message.has_attachments,
+        thread_size: message.thread_size,
+        preview: &message.preview,
+    };
+}
 
-    for (i, u32) in u32s.iter().enumerate() {
-        result.push_str(&u32.to_string());
+pub fn message_vec_to_summary_display(messages: &[Message]) -> Vec<MessageSummaryDisplay<'_>> {
+    return messages.iter().map(message_to_summary_display).collect();
+}
 
-        if i < u32s.len() - 1 {
-            result.push_str(",");
-        }
+/// Addresses are stored as JSON arrays, rows written by older versions could
+/// hold invalid JSON and are returned as an empty list.
+fn address_to_display(address: &str) -> Value {
+    match serde_json::from_str(address) {
+        Ok(value) => return value,
+        Err(_) => return Value::Array(vec![]),
     }
-
-    result.push_str("]");
-
-    return result;
 }
 
 #[cfg(test)]
@@ -82,16 +147,26 @@ mod tests {
             sequence_id: 2,
             message_id: String::from("message_id"),
             subject: String::from("subject"),
-            from: String::from("from"),
-            sender: String::from("sender"),
-            to: String::from("to"),
-            cc: String::from("cc"),
-            bcc: String::from("bcc"),
-            reply_to: String::from("reply_to"),
+            from: String::from(r#"[{"host":"example.com","mailbox":"from","name":"from"}]"#),
+            sender: String::from("[]"),
+            to: String::from("[]"),
+            cc: String::from("[]"),
+            bcc: String::from("[]"),
+            reply_to: String::from("[]"),
             in_reply_to: String::from("in_reply_to"),
             delivered_to: String::from("delivered_to"),
             date: 3,
             received: 4,
+            size: 5,
+            preview: String::from("preview"),
+            has_attachments: true,
+            inline_parts: vec![],
+            calendar: String::new(),
+            openpgp: None,
+            smime: None,
+            security: None,
+            headers: vec![],
+            thread_size: 2,
             flags: vec![String::from("seen"), String::from("flagged")],
             text: String::from("text"),
             html: String::from("html"),
@@ -102,54 +177,69 @@ mod tests {
     fn message() {
         let message = get_message();
 
-        let expected = r#"{"uid":1,"sequence_id":2,"message_id":"message_id","subject":"subject","from":from,"sender":sender,"to":to,"cc":cc,"bcc":bcc,"reply_to":reply_to,"in_reply_to":"in_reply_to","delivered_to":"delivered_to","date":3,"received":4,"flags":["seen","flagged"],"html":"html","text":"text"}"#;
+        let expected = r#"{"uid":1,"sequence_id":2,"message_id":"message_id","subject":"subject","from":[{"host":"example.com","mailbox":"from","name":"from"}],"sender":[],"to":[],"cc":[],"bcc":[],"reply_to":[],"in_reply_to":"in_reply_to","delivered_to":"delivered_to","date":3,"received":4,"flags":["seen","flagged"],"html":"html","text":"text"}"#;
 
-        assert_eq!(message_to_display(&message), expected);
+        assert_eq!(
+            serde_json::to_string(&message_to_display(&message)).unwrap(),
+            expected
+        );
     }
 
     #[test]
     fn messages() {
-        let messages = vec![get_message(), get_message()];
-
-        let expected = r#"[{"uid":1,"sequence_id":2,"message_id":"message_id","subject":"subject","from":from,"sender":sender,"to":to,"cc":cc,"bcc":bcc,"reply_to":reply_to,"in_reply_to":"in_reply_to","delivered_to":"delivered_to","date":3,"received":4,"flags":["seen","flagged"],"html":"html","text":"text"},{"uid":1,"sequence_id":2,"message_id":"message_id","subject":"subject","from":from,"sender":sender,"to":to,"cc":cc,"bcc":bcc,"reply_to":reply_to,"in_reply_to":"in_reply_to","delivered_to":"delivered_to","date":3,"received":4,"flags":["seen","flagged"],"html":"html","text":"text"}]"#;
-
-        assert_eq!(message_vec_to_display(&messages), expected);
+        let remote_content = RemoteContent {
+            images: 1,
+            styles: 0,
+            tracking_pixels: 2,
+            hosts: vec![String::from("cdn.example.com")],
+        };
+        let bodies = vec![
+            (get_message(), remote_content),
+            (get_message(), RemoteContent::default()),
+        ];
+
+        let expected = r#"[{"uid":1,"sequence_id":2,"message_id":"message_id","subject":"subject","from":[{"host":"example.com","mailbox":"from","name":"from"}],"sender":[],"to":[],"cc":[],"bcc":[],"reply_to":[],"in_reply_to":"in_reply_to","delivered_to":"delivered_to","date":3,"received":4,"flags":["seen","flagged"],"html":"html","text":"text","remote_content":{"images":1,"styles":0,"tracking_pixels":2,"hosts":["cdn.example.com"]}},{"uid":1,"sequence_id":2,"message_id":"message_id","subject":"subject","from":[{"host":"example.com","mailbox":"from","name":"from"}],"sender":[],"to":[],"cc":[],"bcc":[],"reply_to":[],"in_reply_to":"in_reply_to","delivered_to":"delivered_to","date":3,"received":4,"flags":["seen","flagged"],"html":"html","text":"text","remote_content":{"images":0,"styles":0,"tracking_pixels":0,"hosts":[]}}]"#;
+
+        assert_eq!(
+            serde_json::to_string(&message_vec_to_body_display(&bodies)).unwrap(),
+            expected
+        );
     }
 
     #[test]
-    fn string_vec() {
-        let strings = vec![String::from("one"), String::from("two")];
-
-        let expected = r#"["one","two"]"#;
-
-        assert_eq!(string_vec_to_display(&strings), expected);
-    }
-
-    #[test]
-    fn u32_vec() {
-        let u32s = vec![1, 2];
+    fn summary() {
+        let message = get_message();
 
-        let expected = r#"[1,2]"#;
+        let expected = r#"{"uid":1,"sequence_id":2,"message_id":"message_id","subject":"subject","from":[{"host":"example.com","mailbox":"from","name":"from"}],"sender":[],"to":[],"cc":[],"bcc":[],"reply_to":[],"in_reply_to":"in_reply_to","delivered_to":"delivered_to","date":3,"received":4,"size":5,"flags":["seen","flagged"],"has_attachments":true,"thread_size":2,"preview":"preview"}"#;
 
-        assert_eq!(u32_vec_to_display(&u32s), expected);
+        assert_eq!(
+            serde_json::to_string(&message_to_summary_display(&message)).unwrap(),
+            expected
+        );
     }
 
     #[test]
-    fn empty_string_vec() {
-        let strings = vec![];
+    fn special_characters_round_trip() {
+        let mut message = get_message();
+        message.subject =
+            String::from("\"Quoted\" \\ back\\slash\n\ttab \u{0}\u{1f} \u{2028} émoji 📧");
+        message.message_id = String::from("<\"id\"@example.com>");
 
-        let expected = r#"[]"#;
+        let json = serde_json::to_string(&message_to_display(&message)).unwrap();
+        let parsed: Value = serde_json::from_str(&json).unwrap();
 
-        assert_eq!(string_vec_to_display(&strings), expected);
+        assert_eq!(parsed["subject"], message.subject.as_str());
+        assert_eq!(parsed["message_id"], message.message_id.as_str());
     }
 
     #[test]
-    fn empty_u32_vec() {
-        let u32s = vec![];
+    fn invalid_address_json() {
+        let mut message = get_message();
+        message.from = String::from(r#"[{"name": "un"closed"}]"#);
 
-        let expected = r#"[]"#;
+        let display = message_to_display(&message);
 
-        assert_eq!(u32_vec_to_display(&u32s), expected);
+        assert_eq!(display.from, Value::Array(vec![]));
     }
 
     #[test]
@@ -158,6 +248,9 @@ mod tests {
 
         let expected = r#"[]"#;
 
-        assert_eq!(message_vec_to_display(&messages), expected);
+        assert_eq!(
+            serde_json::to_string(&message_vec_to_body_display(&messages)).unwrap(),
+            expected
+        );
     }
 }
@@ -0,0 +1,161 @@
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use std::fs;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::my_error::MyError;
+
+/// Binds the socket readable and writable only by the current user. A socket
+/// left behind by a previous run is replaced, one that still accepts
+/// connections belongs to a running backend and is left alone.
+pub async fn bind(path: &Path) -> Result<UnixListener, MyError> {
+    match create_dir(path) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Io(e, String::from("Error creating socket directory"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    match remove_stale(path).await {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Io(e, format!("Error binding {}", path.display()));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    // The socket is created with the permissions of the umask, so it is bound
+    // inside a directory only the current user can enter and moved into place
+    // once its own permissions are set.
+    let tmp_dir = tmp_dir(path);
+    let _ = fs::remove_dir_all(&tmp_dir);
+
+    match fs::DirBuilder::new().mode(0o700).create(&tmp_dir) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = MyError::Io(e, String::from("Error creating socket directory"));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+
+    let tmp_path = tmp_dir.join(path.file_name().unwrap_or_default());
+
+    let result = match UnixListener::bind(&tmp_path).await {
+        Ok(listener) => fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600))
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .map(|_| listener),
+        Err(e) => Err(e),
+    };
+
+    let _ = fs::remove_dir_all(&tmp_dir);
+
+    match result {
+        Ok(listener) => return Ok(listener),
+        Err(e) => {
+            let err = MyError::Io(e, format!("Error binding {}", path.display()));
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+/// Only a directory created here is made private, an existing one such as
+/// `/tmp` is left as it is.
+fn create_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => return Ok(()),
+    };
+
+    if dir.exists() {
+        return Ok(());
+    }
+
+    return fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir);
+}
+
+async fn remove_stale(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "path exists and is not a socket",
+        ));
+    }
+
+    if UnixStream::connect(path).await.is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "another backend is listening on the socket",
+        ));
+    }
+
+    return fs::remove_file(path);
+}
+
+fn tmp_dir(path: &Path) -> PathBuf {
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => String::new(),
+    };
+
+    return path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn bind_private_socket() {
+        let dir = std::env::temp_dir().join(format!("unix-socket-{}", std::process::id()));
+        let path = dir.join("sub").join("api.sock");
+        let _ = fs::remove_dir_all(&dir);
+
+        let listener = bind(&path).await.unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mode = fs::metadata(path.parent().unwrap())
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o700);
+
+        // The private directory it was bound in is gone.
+        let entries = fs::read_dir(path.parent().unwrap()).unwrap().count();
+        assert_eq!(entries, 1);
+
+        UnixStream::connect(&path).await.unwrap();
+
+        // Still in use by the listener above.
+        assert!(bind(&path).await.is_err());
+
+        drop(listener);
+        bind(&path).await.unwrap();
+
+        fs::remove_file(&path).unwrap();
+        fs::write(&path, "not a socket").unwrap();
+        assert!(bind(&path).await.is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
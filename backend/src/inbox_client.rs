@@ -1,7 +1,14 @@
+pub mod autocrypt;
 pub mod connect;
 pub mod logout;
 pub mod mailboxes;
 pub mod message;
 pub mod message_flags;
 pub mod messages;
+pub mod oauth;
+pub mod openpgp;
+pub mod pool;
+pub mod prefetch;
+pub mod smime;
+pub mod supervisor;
 pub mod update_mailbox;
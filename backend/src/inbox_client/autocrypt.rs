@@ -0,0 +1,213 @@
+use async_std::sync::{Arc, Mutex};
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::database;
+use crate::database::credentials::CredentialStore;
+use crate::inbox_client;
+use crate::mime_parser::autocrypt::{self, AutocryptHeader, PreferEncrypt, Recommendation};
+use crate::mime_parser::{headers, parse_address};
+use crate::my_error::MyError;
+use crate::openpgp::key::Cert;
+use crate::types::error_code::ErrorCode;
+use crate::types::session::Client;
+
+/// Whether to encrypt a message being composed, Autocrypt Level 1 section 2.4.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EncryptionRecommendation {
+    /// For the whole message, the weakest of its recipients: `disable`,
+    /// `discourage`, `available` or `encrypt`.
+    pub recommendation: String,
+    pub recipients: Vec<RecipientRecommendation>,
+    /// Autocrypt header of the sender to add to the message, None without a
+    /// secret key for the From address.
+    pub autocrypt_header: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RecipientRecommendation {
+    pub address: String,
+    pub recommendation: String,
+    /// `keyring` for an imported key, `autocrypt` for one of the recipient's
+    /// own header and `gossip` for one sent by another sender. None without
+    /// a usable key.
+    pub key_source: Option<String>,
+}
+
+/// Recommendation for a message from `from` to `recipients`. A key of the
+/// keyring makes encryption available even without Autocrypt state.
+pub async fn recommend(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+    from: &str,
+    recipients: &[String],
+    reply_to_encrypted: bool,
+) -> Result<EncryptionRecommendation, MyError> {
+    let certs =
+        match inbox_client::openpgp::keyring(Arc::clone(&database_conn), credentials, client).await
+        {
+            Ok(certs) => certs,
+            Err(e) => return Err(e),
+        };
+
+    let own = match database::autocrypt::get_preference(
+        Arc::clone(&database_conn),
+        &client.username,
+        &client.address,
+    )
+    .await
+    {
+        Ok(own) => own,
+        Err(e) => return Err(e),
+    };
+
+    let now = Utc::now().timestamp_millis();
+    let mut recommendations: Vec<RecipientRecommendation> = vec![];
+    let mut overall: Vec<Recommendation> = vec![];
+
+    for address in recipients {
+        let address = address.trim().to_lowercase();
+
+        let peer = match database::autocrypt::get(
+            Arc::clone(&database_conn),
+            &client.username,
+            &client.address,
+            &address,
+        )
+        .await
+        {
+            Ok(peer) => peer.unwrap_or_default(),
+            Err(e) => return Err(e),
+        };
+
+        let mut recommendation = autocrypt::recommend(&peer, own, reply_to_encrypted, now);
+        let mut key_source = match recommendation {
+            Recommendation::Disable => None,
+            _ if peer.public_key.is_some() => Some(String::from("autocrypt")),
+            _ => Some(String::from("gossip")),
+        };
+
+        if inbox_client::openpgp::encryption_cert(&certs, &address, now / 1000).is_some() {
+            let keyring = match reply_to_encrypted {
+                true => Recommendation::Encrypt,
+                false => Recommendation::Available,
+            };
+
+            recommendation = recommendation.max(keyring);
+            key_source = Some(String::from("keyring"));
+        }
+
+        overall.push(recommendation);
+        recommendations.push(RecipientRecommendation {
+            address,
+            recommendation: recommendation.name().to_string(),
+            key_source,
+        });
+    }
+
+    let autocrypt_header = own_header(&certs, from, own);
+
+    return Ok(EncryptionRecommendation {
+        recommendation: autocrypt::combine(&overall).name().to_string(),
+        recipients: recommendations,
+        autocrypt_header,
+    });
+}
+
+/// Sets the preference announced in the account's Autocrypt header,
+/// `mutual` or `nopreference`.
+pub async fn set_preference(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    client: &Client,
+    prefer_encrypt: &str,
+) -> Result<(), MyError> {
+    let prefer_encrypt = match PreferEncrypt::from_name(&prefer_encrypt.to_lowercase()) {
+        Some(prefer_encrypt) => prefer_encrypt,
+        None => {
+            let err = MyError::Code(
+                ErrorCode::InvalidRequest,
+                String::from("prefer_encrypt must be mutual or nopreference"),
+                String::from("Error setting Autocrypt preference"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    return database::autocrypt::set_preference(
+        database_conn,
+        &client.username,
+        &client.address,
+        prefer_encrypt,
+    )
+    .await;
+}
+
+/// `source` with the Autocrypt header of its From address added, unchanged
+/// when it has one or there is no secret key for the address.
+pub async fn add_own_header(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+    source: &str,
+) -> Result<String, MyError> {
+    let message_headers = headers::parse(source);
+    if headers::first(&message_headers, autocrypt::HEADER).is_some() {
+        return Ok(source.to_string());
+    }
+
+    let from = match headers::first(&message_headers, "From")
+        .map(parse_address::parse_list)
+        .unwrap_or_default()
+        .first()
+    {
+        Some(from) => from.address(),
+        None => return Ok(source.to_string()),
+    };
+
+    let certs =
+        match inbox_client::openpgp::keyring(Arc::clone(&database_conn), credentials, client).await
+        {
+            Ok(certs) => certs,
+            Err(e) => return Err(e),
+        };
+
+    let own =
+        match database::autocrypt::get_preference(database_conn, &client.username, &client.address)
+            .await
+        {
+            Ok(own) => own,
+            Err(e) => return Err(e),
+        };
+
+    match own_header(&certs, &from, own) {
+        Some(header) => return Ok(autocrypt::add_headers(source, &header)),
+        None => return Ok(source.to_string()),
+    }
+}
+
+/// Autocrypt header for mail from `from` with the newest key of the keyring
+/// that has its secret key and can encrypt.
+pub fn own_header(certs: &[Cert], from: &str, prefer_encrypt: PreferEncrypt) -> Option<String> {
+    let now = Utc::now().timestamp();
+
+    let cert = certs
+        .iter()
+        .filter(|cert| {
+            return cert.has_address(from)
+                && cert.has_secret()
+                && !cert.encryption_keys(now).is_empty();
+        })
+        .max_by_key(|cert| cert.primary.public.created)?;
+
+    return Some(autocrypt::format(
+        autocrypt::HEADER,
+        &AutocryptHeader {
+            addr: from.trim().to_lowercase(),
+            prefer_encrypt,
+            keydata: cert.public_bytes(),
+        },
+    ));
+}
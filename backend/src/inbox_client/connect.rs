@@ -1,157 +1,570 @@
 use async_imap;
 use async_imap::error::Error as ImapError;
-use async_native_tls::TlsConnector;
+use async_native_tls::{TlsConnector, TlsStream};
 use async_std::net::TcpStream;
+use async_std::prelude::*;
 use async_std::sync::{Arc, Mutex};
+use async_std::task;
+use sha2::{Digest, Sha256};
 
 use crate::database;
+use crate::database::credentials::CredentialStore;
+use crate::inbox_client::oauth::{self, OAuthAuthenticator};
+use crate::inbox_client::pool::SessionPool;
 use crate::my_error::MyError;
-use crate::types::session::{Client, Session};
+use crate::types::error_code::ErrorCode;
+use crate::types::session::{Client, Security, Session};
 
+/// Logs in with `client_add` and stores the account once the server accepted
+/// it. Logging in again to a known account replaces its credentials and its
+/// pool, which also clears a failed authentication.
 pub async fn connect(
-    sessions: Arc<Mutex<Vec<Session>>>,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: Arc<CredentialStore>,
     clients: Arc<Mutex<Vec<Client>>>,
     client_add: &Client,
+    pool_size: usize,
 ) -> Result<usize, MyError> {
-    let idx_db = match database(database_conn, clients, client_add).await {
-        Ok(idx) => idx,
-        Err(e) => return Err(e),
-    };
+    let pool = Arc::new(SessionPool::new(
+        client_add,
+        Arc::clone(&database_conn),
+        Arc::clone(&credentials),
+        pool_size,
+    ));
 
-    let idx_imap = match imap(sessions, &client_add).await {
-        Ok(_) => idx_db,
+    // Open the first connection straight away so login errors are reported to the caller.
+    match pool.acquire().await {
+        Ok(_) => {}
         Err(e) => return Err(e),
     };
 
-    assert!(idx_db == idx_imap);
-
-    return Ok(idx_db);
-}
+    match database::connections::insert(database_conn, &credentials, client_add).await {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    }
 
-async fn database(
-    database_conn: Arc<Mutex<rusqlite::Connection>>,
-    clients: Arc<Mutex<Vec<Client>>>,
-    client_add: &Client,
-) -> Result<usize, MyError> {
+    let mut locked_sessions = sessions.lock().await;
     let mut locked_clients = clients.lock().await;
 
     let pos = locked_clients
         .iter()
         .position(|x| x.username == client_add.username && x.address == client_add.address);
 
-    if locked_clients.len() > pos.unwrap_or(0) {
-        return Ok(pos.unwrap());
-    }
+    let idx = match pos {
+        Some(pos) => {
+            locked_clients[pos] = client_add.clone();
+            let replaced = std::mem::replace(&mut locked_sessions[pos], pool);
 
-    locked_clients.push(client_add.clone());
-    let idx = locked_clients.len() - 1;
+            task::spawn(async move {
+                match replaced.close().await {
+                    Ok(_) => {}
+                    Err(e) => e.log_error(),
+                }
+            });
 
-    drop(locked_clients);
+            pos
+        }
+        None => {
+            locked_clients.push(client_add.clone());
+            locked_sessions.push(pool);
 
-    match database::connections::insert(database_conn, client_add).await {
-        Ok(_) => {}
-        Err(e) => {
-            let mut locked_clients = clients.lock().await;
-            locked_clients.remove(idx);
-            drop(locked_clients);
+            assert!(locked_clients.len() == locked_sessions.len());
 
-            return Err(e);
+            locked_sessions.len() - 1
         }
-    }
+    };
 
     return Ok(idx);
 }
 
-pub async fn handle_disconnect(
-    sessions: Arc<Mutex<Vec<Session>>>,
-    session_id: usize,
+/// Opens the pool of an account stored in the database, `client` is already
+/// in the client list at the position of the new pool. A failed login is
+/// left to the supervisor to retry.
+pub async fn reconnect(
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: Arc<CredentialStore>,
     client: &Client,
-    e: ImapError,
+    pool_size: usize,
 ) -> Result<(), MyError> {
+    let pool = Arc::new(SessionPool::new(
+        client,
+        database_conn,
+        credentials,
+        pool_size,
+    ));
+
     let mut locked_sessions = sessions.lock().await;
+    locked_sessions.push(Arc::clone(&pool));
+    drop(locked_sessions);
 
-    match locked_sessions[session_id].close().await {
-        Ok(_) => {}
+    match pool.acquire().await {
+        Ok(_) => return Ok(()),
+        Err(e) => return Err(e),
+    }
+}
+
+pub async fn imap(client: &Client, access_token: Option<&str>) -> Result<Session, MyError> {
+    let address = &client.address;
+    let port = client.port;
+    let username = &client.username;
+    let password = &client.password;
+
+    let tcp_stream = match TcpStream::connect((address.as_str(), port)).await {
+        Ok(s) => s,
         Err(e) => {
-            let err = MyError::Imap(e, String::from("Error closing session"));
+            let err = MyError::Io(e, String::from("Error connecting to IMAP server"));
             err.log_error();
 
             return Err(err);
         }
+    };
+
+    let tcp_stream = match client.security {
+        Security::Tls => tcp_stream,
+        Security::StartTls => match starttls(tcp_stream).await {
+            Ok(s) => s,
+            Err(e) => return Err(e),
+        },
+    };
+
+    let tls_stream = match tls(client, tcp_stream).await {
+        Ok(s) => s,
+        Err(e) => return Err(e),
+    };
+
+    let mut imap_client = async_imap::Client::new(tls_stream);
+
+    // With STARTTLS the greeting was already read before upgrading the connection.
+    if client.security == Security::Tls {
+        let _greeting = imap_client.read_response().await;
     }
-    drop(locked_sessions);
 
-    match e {
-        ImapError::ConnectionLost => {
-            match imap(sessions, client).await {
-                Ok(_) => {}
-                Err(e) => return Err(e),
-            }
+    let result = match access_token {
+        Some(access_token) => {
+            let authenticator = OAuthAuthenticator::new(client, access_token);
 
-            return Ok(());
+            imap_client
+                .authenticate(oauth::mechanism_name(&client.auth_mechanism), authenticator)
+                .await
         }
-        ImapError::Io(_) => {
-            match imap(sessions, client).await {
-                Ok(_) => {}
-                Err(e) => return Err(e),
-            }
+        None => imap_client.login(username, password).await,
+    };
 
-            return Ok(());
+    match result {
+        Ok(session) => return Ok(session),
+        Err(e) => {
+            let err = match e.0 {
+                // Servers without RFC 5530 response codes reject a login
+                // with a plain NO.
+                ImapError::No(text) if ErrorCode::from_imap_response(&text).is_none() => {
+                    MyError::Code(
+                        ErrorCode::AuthenticationFailed,
+                        text,
+                        String::from("Error logging in"),
+                    )
+                }
+                e => MyError::Imap(e, String::from("Error logging in")),
+            };
+            err.log_error();
+
+            return Err(err);
         }
-        _ => {}
     }
-
-    let err = MyError::Imap(e, String::from("Error handling disconnect"));
-    err.log_error();
-
-    return Err(err);
 }
 
-async fn imap(sessions: Arc<Mutex<Vec<Session>>>, client: &Client) -> Result<(), MyError> {
+async fn tls(client: &Client, tcp_stream: TcpStream) -> Result<TlsStream<TcpStream>, MyError> {
     let address = &client.address;
-    let port = client.port;
-    let username = &client.username;
-    let password = &client.password;
+    let pinned_fingerprint = client
+        .cert_fingerprint
+        .as_deref()
+        .map(normalise_fingerprint);
 
-    let tcp_stream = match TcpStream::connect((address.as_str(), port)).await {
+    // A pinned certificate replaces the chain and hostname checks, the fingerprint is
+    // verified once the handshake is done.
+    let tls = TlsConnector::new()
+        .danger_accept_invalid_hostnames(pinned_fingerprint.is_some())
+        .danger_accept_invalid_certs(pinned_fingerprint.is_some());
+
+    let tls_stream = match tls.connect(address, tcp_stream).await {
         Ok(s) => s,
         Err(e) => {
-            let err = MyError::Io(e, String::from("Error connecting to IMAP server"));
+            let context = if e.to_string().to_lowercase().contains("certificate") {
+                format!(
+                    "Certificate of {} could not be verified, pin its SHA-256 fingerprint to trust a self-signed certificate",
+                    address
+                )
+            } else {
+                String::from("Error connecting to IMAP server")
+            };
+
+            let err = MyError::Tls(e, context);
             err.log_error();
 
             return Err(err);
         }
     };
 
-    let tls = TlsConnector::new()
-        .danger_accept_invalid_hostnames(true)
-        .danger_accept_invalid_certs(true);
-    let tls_stream = match tls.connect(address, tcp_stream).await {
-        Ok(s) => s,
+    let pinned_fingerprint = match pinned_fingerprint {
+        Some(f) => f,
+        None => return Ok(tls_stream),
+    };
+
+    let certificate = match tls_stream.peer_certificate() {
+        Ok(Some(c)) => c,
+        Ok(None) => {
+            let err = MyError::Code(
+                ErrorCode::TlsError,
+                String::from("Server did not present a certificate"),
+                String::from("Error verifying pinned certificate"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
         Err(e) => {
-            let err = MyError::Tls(e, String::from("Error connecting to IMAP server"));
+            let err = MyError::Tls(e, String::from("Error verifying pinned certificate"));
             err.log_error();
 
             return Err(err);
         }
     };
 
-    let mut client = async_imap::Client::new(tls_stream);
-    let _greeting = client.read_response().await;
-
-    match client.login(username, password).await {
-        Ok(session) => {
-            let mut locked_sessions = sessions.lock().await;
-            locked_sessions.push(session);
+    let der = match certificate.to_der() {
+        Ok(der) => der,
+        Err(e) => {
+            let err = MyError::Tls(e, String::from("Error verifying pinned certificate"));
+            err.log_error();
 
-            return Ok(());
+            return Err(err);
         }
+    };
+
+    let fingerprint = certificate_fingerprint(&der);
+
+    if fingerprint != pinned_fingerprint {
+        let err = MyError::Code(
+            ErrorCode::TlsError,
+            format!("expected {}, got {}", pinned_fingerprint, fingerprint),
+            format!("Certificate fingerprint of {} does not match", address),
+        );
+        err.log_error();
+
+        return Err(err);
+    }
+
+    return Ok(tls_stream);
+}
+
+async fn starttls(mut tcp_stream: TcpStream) -> Result<TcpStream, MyError> {
+    let _greeting = match read_line(&mut tcp_stream).await {
+        Ok(line) => line,
+        Err(e) => return Err(e),
+    };
+
+    match tcp_stream.write_all(b"a0 STARTTLS\r\n").await {
+        Ok(_) => {}
         Err(e) => {
-            let err = MyError::Imap(e.0, String::from("Error logging in"));
+            let err = MyError::Io(e, String::from("Error sending STARTTLS command"));
             err.log_error();
 
             return Err(err);
         }
     }
+
+    loop {
+        let line = match read_line(&mut tcp_stream).await {
+            Ok(line) => line,
+            Err(e) => return Err(e),
+        };
+
+        if !line.starts_with("a0 ") {
+            continue;
+        }
+
+        if line.to_uppercase().starts_with("A0 OK") {
+            return Ok(tcp_stream);
+        }
+
+        let err = MyError::Code(
+            ErrorCode::TlsError,
+            line.trim_end().to_string(),
+            String::from("Server refused STARTTLS"),
+        );
+        err.log_error();
+
+        return Err(err);
+    }
+}
+
+/// Reads a single CRLF terminated line without buffering past it, the remaining
+/// bytes of the stream belong to the TLS handshake.
+async fn read_line(tcp_stream: &mut TcpStream) -> Result<String, MyError> {
+    let mut line: Vec<u8> = Vec::new();
+    let mut byte = [0; 1];
+
+    while !line.ends_with(b"\n") {
+        match tcp_stream.read(&mut byte).await {
+            Ok(0) => {
+                let err = MyError::Code(
+                    ErrorCode::ServerUnreachable,
+                    String::from("Connection closed by server"),
+                    String::from("Error reading response before STARTTLS"),
+                );
+                err.log_error();
+
+                return Err(err);
+            }
+            Ok(_) => line.push(byte[0]),
+            Err(e) => {
+                let err = MyError::Io(e, String::from("Error reading response before STARTTLS"));
+                err.log_error();
+
+                return Err(err);
+            }
+        }
+    }
+
+    return Ok(String::from_utf8_lossy(&line).to_string());
+}
+
+pub fn certificate_fingerprint(der: &[u8]) -> String {
+    return Sha256::digest(der)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+}
+
+fn normalise_fingerprint(fingerprint: &str) -> String {
+    return fingerprint
+        .chars()
+        .filter(|c| c.is_ascii_hexdigit())
+        .collect::<String>()
+        .to_lowercase();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_native_tls::{Identity, TlsAcceptor};
+    use async_std::io::{BufReader, Read, Write};
+    use async_std::net::TcpListener;
+
+    use crate::database::credentials::KeySource;
+    use crate::types::account_state::AccountState;
+    use crate::types::session::AuthMechanism;
+
+    struct StandIn {
+        port: u16,
+        fingerprint: String,
+    }
+
+    /// Accepts a login with the password `password`.
+    async fn reply_to_login<S: Read + Write + Unpin>(stream: S) {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+
+        if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+            return;
+        }
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let tag = words.first().unwrap_or(&"").to_string();
+        let response = match words.get(3).map(|word| word.trim_matches('"')) {
+            Some("password") => format!("{} OK LOGIN completed\r\n", tag),
+            _ => format!("{} NO [AUTHENTICATIONFAILED] Invalid credentials\r\n", tag),
+        };
+
+        let stream = reader.get_mut();
+        let _ = stream.write_all(response.as_bytes()).await;
+        let _ = stream.flush().await;
+    }
+
+    async fn stand_in(security: Security) -> StandIn {
+        let certified_key =
+            rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let identity = Identity::from_pkcs8(
+            certified_key.cert.pem().as_bytes(),
+            certified_key.key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+        let acceptor = TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        task::spawn(async move {
+            loop {
+                let (tcp_stream, _) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(_) => return,
+                };
+
+                task::spawn(accept(acceptor.clone(), tcp_stream, security.clone()));
+            }
+        });
+
+        return StandIn {
+            port,
+            fingerprint: certificate_fingerprint(certified_key.cert.der()),
+        };
+    }
+
+    async fn accept(acceptor: TlsAcceptor, mut tcp_stream: TcpStream, security: Security) {
+        if security == Security::StartTls {
+            tcp_stream
+                .write_all(b"* OK [CAPABILITY IMAP4rev1 STARTTLS] ready\r\n")
+                .await
+                .unwrap();
+
+            let mut command = [0; 13];
+            tcp_stream.read_exact(&mut command).await.unwrap();
+            assert_eq!(&command, b"a0 STARTTLS\r\n");
+
+            tcp_stream
+                .write_all(b"a0 OK Begin TLS negotiation\r\n")
+                .await
+                .unwrap();
+        }
+
+        let mut tls_stream = match acceptor.accept(tcp_stream).await {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+
+        if security == Security::Tls {
+            tls_stream.write_all(b"* OK ready\r\n").await.unwrap();
+        }
+
+        reply_to_login(tls_stream).await;
+    }
+
+    fn get_client(port: u16, security: Security, cert_fingerprint: Option<String>) -> Client {
+        Client {
+            address: String::from("localhost"),
+            port,
+            username: String::from("username"),
+            password: String::from("password"),
+            security,
+            cert_fingerprint,
+            auth_mechanism: AuthMechanism::Password,
+            oauth: None,
+        }
+    }
+
+    #[async_std::test]
+    async fn self_signed_rejected_by_default() {
+        let stand_in = stand_in(Security::Tls).await;
+        let client = get_client(stand_in.port, Security::Tls, None);
+
+        match imap(&client, None).await {
+            Ok(_) => panic!("self-signed certificate accepted"),
+            Err(e) => assert!(e.to_string().contains("could not be verified")),
+        }
+    }
+
+    #[async_std::test]
+    async fn pinned_fingerprint_accepted() {
+        let stand_in = stand_in(Security::Tls).await;
+        let fingerprint = stand_in.fingerprint.to_uppercase();
+        let client = get_client(stand_in.port, Security::Tls, Some(fingerprint));
+
+        assert!(imap(&client, None).await.is_ok());
+    }
+
+    #[async_std::test]
+    async fn pinned_fingerprint_mismatch() {
+        let stand_in = stand_in(Security::Tls).await;
+        let client = get_client(stand_in.port, Security::Tls, Some("00".repeat(32)));
+
+        match imap(&client, None).await {
+            Ok(_) => panic!("certificate with wrong fingerprint accepted"),
+            Err(e) => assert!(e.to_string().contains("does not match")),
+        }
+    }
+
+    #[async_std::test]
+    async fn starttls_with_pinned_fingerprint() {
+        let stand_in = stand_in(Security::StartTls).await;
+        let client = get_client(
+            stand_in.port,
+            Security::StartTls,
+            Some(stand_in.fingerprint),
+        );
+
+        assert!(imap(&client, None).await.is_ok());
+    }
+
+    #[async_std::test]
+    async fn login_stored_once_accepted() {
+        let stand_in = stand_in(Security::Tls).await;
+
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        database::initialise(&conn).await.unwrap();
+        let source = KeySource::Passphrase(String::from("test"));
+        let credentials = Arc::new(CredentialStore::open(&conn, &source).unwrap());
+        let database_conn = Arc::new(Mutex::new(conn));
+        let sessions: Arc<Mutex<Vec<Arc<SessionPool>>>> = Arc::new(Mutex::new(vec![]));
+        let clients: Arc<Mutex<Vec<Client>>> = Arc::new(Mutex::new(vec![]));
+
+        let mut client = get_client(
+            stand_in.port,
+            Security::Tls,
+            Some(stand_in.fingerprint.clone()),
+        );
+        let login = |client: Client| {
+            let sessions = Arc::clone(&sessions);
+            let database_conn = Arc::clone(&database_conn);
+            let credentials = Arc::clone(&credentials);
+            let clients = Arc::clone(&clients);
+
+            return async move {
+                return connect(sessions, database_conn, credentials, clients, &client, 1).await;
+            };
+        };
+        let stored = || async {
+            return database::connections::get(Arc::clone(&database_conn), &credentials)
+                .await
+                .unwrap();
+        };
+
+        // A rejected password leaves nothing behind.
+        client.password = String::from("wrong");
+        assert!(login(client.clone()).await.is_err());
+        assert!(sessions.lock().await.is_empty());
+        assert!(clients.lock().await.is_empty());
+        assert!(stored().await.is_empty());
+
+        client.password = String::from("password");
+        assert_eq!(login(client.clone()).await.unwrap(), 0);
+        assert_eq!(stored().await[0].password, "password");
+
+        // Logging in again after the credentials were rejected replaces the
+        // pool and its health, a wrong password keeps the stored account.
+        let pool = Arc::clone(&sessions.lock().await[0]);
+        pool.health().lock().unwrap().state = AccountState::AuthFailed;
+
+        client.password = String::from("wrong");
+        assert!(login(client.clone()).await.is_err());
+        assert_eq!(
+            sessions.lock().await[0].health().lock().unwrap().state,
+            AccountState::AuthFailed
+        );
+
+        client.password = String::from("password");
+        assert_eq!(login(client.clone()).await.unwrap(), 0);
+        assert_eq!(sessions.lock().await.len(), 1);
+        assert_eq!(clients.lock().await.len(), 1);
+        assert_eq!(stored().await.len(), 1);
+        assert_eq!(
+            sessions.lock().await[0].health().lock().unwrap().state,
+            AccountState::Connected
+        );
+    }
+
+    #[test]
+    fn fingerprint_normalised() {
+        assert_eq!(normalise_fingerprint("AB:cd:0F"), "abcd0f");
+    }
 }
@@ -2,16 +2,19 @@ use async_std::sync::{Arc, Mutex};
 use rusqlite::Connection;
 
 use crate::database;
+use crate::inbox_client::pool::SessionPool;
 use crate::my_error::MyError;
-use crate::types::session::{Client, Session};
+use crate::types::error_code::ErrorCode;
+use crate::types::session::Client;
 
 pub async fn logout(
-    sessions: Arc<Mutex<Vec<Session>>>,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
+    clients: Arc<Mutex<Vec<Client>>>,
     client: &Client,
     session_id: usize,
 ) -> Result<(), MyError> {
-    match imap(Arc::clone(&sessions), session_id).await {
+    match imap(Arc::clone(&sessions), clients, session_id).await {
         Ok(_) => (),
         Err(e) => return Err(e),
     };
@@ -24,11 +27,17 @@ pub async fn logout(
     return Ok(());
 }
 
-async fn imap(sessions: Arc<Mutex<Vec<Session>>>, session_id: usize) -> Result<(), MyError> {
+async fn imap(
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
+    clients: Arc<Mutex<Vec<Client>>>,
+    session_id: usize,
+) -> Result<(), MyError> {
     let mut locked_sessions = sessions.lock().await;
-    
-    if session_id >= locked_sessions.len() {
-        let err = MyError::String(
+    let mut locked_clients = clients.lock().await;
+
+    if session_id >= locked_sessions.len() || session_id >= locked_clients.len() {
+        let err = MyError::Code(
+            ErrorCode::InvalidSession,
             String::from("Session ID out of bounds"),
             String::from("Session not found"),
         );
@@ -37,22 +46,13 @@ async fn imap(sessions: Arc<Mutex<Vec<Session>>>, session_id: usize) -> Result<(
         return Err(err);
     }
 
-    let session = &mut locked_sessions[session_id];
+    let pool = locked_sessions.remove(session_id);
+    locked_clients.remove(session_id);
 
-    match session.logout().await {
-        Ok(_) => {
-            let mut locked_sessions = sessions.lock().await;
-                        locked_sessions.remove(session_id);
+    drop(locked_clients);
+    drop(locked_sessions);
 
-            return Ok(());
-        }
-        Err(e) => {
-            let err = MyError::Imap(e, String::from("Error logging out"));
-            err.log_error();
-
-            return Err(err);
-        }
-    }
+    return pool.close().await;
 }
 
 async fn database(database_conn: Arc<Mutex<Connection>>, client: &Client) -> Result<(), MyError> {
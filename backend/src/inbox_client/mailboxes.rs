@@ -1,18 +1,19 @@
+use async_imap::types::NameAttribute;
 use async_std::stream::StreamExt;
 use async_std::sync::{Arc, Mutex};
 
 use crate::database::{self, mailbox};
-use crate::inbox_client;
+use crate::inbox_client::pool::{self, SessionPool};
 use crate::my_error::MyError;
-use crate::types::session::{Client, Session};
+use crate::types::session::Client;
 
 pub async fn update(
-    sessions: Arc<Mutex<Vec<Session>>>,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
     session_id: usize,
     client: &Client,
 ) -> Result<Vec<String>, MyError> {
-    let mailboxes = match get_imap(sessions, session_id, client).await {
+    let mailboxes = match get_imap(sessions, session_id).await {
         Ok(m) => m,
         Err(e) => return Err(e),
     };
@@ -22,7 +23,7 @@ pub async fn update(
         Err(e) => return Err(e),
     }
 
-    return Ok(mailboxes);
+    return Ok(mailboxes.into_iter().map(|(path, _)| path).collect());
 }
 
 pub async fn get_database(
@@ -40,76 +41,68 @@ pub async fn get_database(
     return Ok(mailboxes);
 }
 
+/// Paths of the mailboxes with their special use (RFC 6154).
 async fn get_imap(
-    sessions: Arc<Mutex<Vec<Session>>>,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     session_id: usize,
-    client: &Client,
-) -> Result<Vec<String>, MyError> {
-    let sessions_2 = Arc::clone(&sessions);
-    let sessions_3 = Arc::clone(&sessions);
-
-    let mut sessions_lock = sessions.lock().await;
-
-    if session_id + 1 > sessions_lock.len() {
-        let err = MyError::String(
-            String::from("Out of bounds array access"),
-            String::from("Invalid session ID"),
-        );
-        err.log_error();
-
-        return Err(err);
-    }
+) -> Result<Vec<(String, Option<String>)>, MyError> {
+    let mut session = match pool::acquire(&sessions, session_id).await {
+        Ok(s) => s,
+        Err(e) => return Err(e),
+    };
 
-    let session = &mut sessions_lock[session_id];
+    let mailboxes = match session.session.list(Some(""), Some("*")).await {
+        Ok(m) => Ok(m.collect::<Vec<_>>().await),
+        Err(e) => Err(e),
+    };
 
-    let mailboxes: Vec<_> = match session.list(Some(""), Some("*")).await {
-        Ok(m) => m.collect().await,
-        Err(e) => match inbox_client::connect::handle_disconnect(sessions_3, session_id, client, e)
-            .await
-        {
-            Ok(_) => {
-                return Box::pin(get_imap(sessions_2, session_id, client)).await;
-            }
-            Err(e) => return Err(e),
-        },
+    let mailboxes = match mailboxes {
+        Ok(m) => m,
+        Err(e) => return Err(session.command_failed(e, "Error listing mailboxes")),
     };
 
-    drop(sessions_lock);
+    session.record_success();
+    drop(session);
 
-    let mailboxes: Vec<String> = mailboxes
+    let mailboxes: Vec<(String, Option<String>)> = mailboxes
         .iter()
         .map(|mailbox| {
             let mailbox = match mailbox {
-                Ok(m) => m.name(),
+                Ok(m) => m,
                 Err(_) => {
-                    return "".to_string();
+                    return ("".to_string(), None);
                 }
             };
 
-            mailbox.to_string()
+            (
+                mailbox.name().to_string(),
+                special_use(mailbox.attributes()),
+            )
         })
         .collect();
 
     return Ok(mailboxes);
 }
 
+fn special_use(attributes: &[NameAttribute]) -> Option<String> {
+    return attributes.iter().find_map(|attribute| match attribute {
+        NameAttribute::All => Some(String::from("\\All")),
+        NameAttribute::Archive => Some(String::from("\\Archive")),
+        NameAttribute::Drafts => Some(String::from("\\Drafts")),
+        NameAttribute::Flagged => Some(String::from("\\Flagged")),
+        NameAttribute::Junk => Some(String::from("\\Junk")),
+        NameAttribute::Sent => Some(String::from("\\Sent")),
+        NameAttribute::Trash => Some(String::from("\\Trash")),
+        _ => None,
+    });
+}
+
 async fn store_database(
     database_conn: Arc<Mutex<rusqlite::Connection>>,
     client: &Client,
-    mailboxes: &Vec<String>,
+    mailboxes: &[(String, Option<String>)],
 ) -> Result<(), MyError> {
-    // let mut tasks = vec![];
-
-    let mailbox_paths: Vec<String> = mailboxes.iter().map(|m| m.to_string()).collect();
-
-    match mailbox::insert(
-        database_conn,
-        &client.username,
-        &client.address,
-        &mailbox_paths,
-    )
-    .await
-    {
+    match mailbox::insert(database_conn, &client.username, &client.address, mailboxes).await {
         Ok(_) => {}
         Err(e) => return Err(e),
     }
@@ -1,15 +1,25 @@
+use async_imap::error::Error as ImapError;
+use async_imap::types::Fetch;
+use async_std::stream::StreamExt;
 use async_std::sync::{Arc, Mutex};
+use chrono::Utc;
 use std::u32;
 
 use crate::database;
+use crate::database::credentials::CredentialStore;
 use crate::inbox_client;
+use crate::inbox_client::pool::{self, SessionPool};
+use crate::mime_parser::itip::{PartStat, ReplyMessage};
+use crate::mime_parser::{headers, itip, parse_address};
 use crate::my_error::MyError;
-use crate::types::fetch_mode::FetchMode;
+use crate::types::error_code::ErrorCode;
+use crate::types::fetch_mode::{self, FetchMode};
+use crate::types::message::Header;
 use crate::types::sequence_set::{SequenceSet, StartEnd};
-use crate::types::session::{Client, Session};
+use crate::types::session::Client;
 
 pub async fn mv(
-    sessions: Arc<Mutex<Vec<Session>>>,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
     session_id: usize,
     client: &Client,
@@ -22,7 +32,6 @@ pub async fn mv(
     match mv_imap(
         sessions,
         session_id,
-        client,
         mailbox_path,
         message_uid,
         mailbox_path_dest,
@@ -45,7 +54,6 @@ pub async fn mv(
     let messages = match inbox_client::messages::get_imap_with_seq(
         sessions_2,
         session_id,
-        client,
         mailbox_path_dest,
         &sequence_set,
         FetchMode::ALL,
@@ -59,7 +67,8 @@ pub async fn mv(
     let message = match messages.first() {
         Some(m) => m,
         None => {
-            let err = MyError::String(
+            let err = MyError::Code(
+                ErrorCode::Nonexistent,
                 String::from("No message found"),
                 String::from("Error fetching message"),
             );
@@ -91,54 +100,28 @@ pub async fn mv(
 }
 
 async fn mv_imap(
-    sessions: Arc<Mutex<Vec<Session>>>,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     session_id: usize,
-    client: &Client,
     mailbox_path: &str,
     message_uid: u32,
     mailbox_path_dest: &str,
 ) -> Result<(), MyError> {
-    let sessions_2 = Arc::clone(&sessions);
-
-    let mut locked_sessions = sessions.lock().await;
-
-    let session = &mut locked_sessions[session_id];
-
-    match session.select(mailbox_path).await {
-        Ok(_) => {}
-        Err(e) => {
-            drop(locked_sessions);
-
-            match inbox_client::connect::handle_disconnect(sessions, session_id, client, e).await {
-                Ok(_) => {
-                    return Box::pin(mv_imap(
-                        sessions_2,
-                        session_id,
-                        client,
-                        mailbox_path,
-                        message_uid,
-                        mailbox_path_dest,
-                    ))
-                    .await;
-                }
-                Err(e) => return Err(e),
-            }
-        }
+    let mut session = match pool::acquire_mailbox(&sessions, session_id, mailbox_path).await {
+        Ok(s) => s,
+        Err(e) => return Err(e),
     };
 
     match session
+        .session
         .uid_mv(message_uid.to_string(), mailbox_path_dest)
         .await
     {
         Ok(e) => e,
-        Err(e) => {
-            let err = MyError::Imap(e, String::from("Error moving message"));
-            err.log_error();
-
-            return Err(err);
-        }
+        Err(e) => return Err(session.command_failed(e, "Error moving message")),
     };
 
+    session.record_success();
+
     return Ok(());
 }
 
@@ -167,3 +150,239 @@ async fn mv_database(
         Err(e) => return Err(e),
     };
 }
+
+/// Source of a stored message, fetched from the server the first time and
+/// kept in the database after.
+pub async fn get_source(
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    session_id: usize,
+    client: &Client,
+    mailbox_path: &str,
+    message_uid: u32,
+) -> Result<Vec<u8>, MyError> {
+    match database::message::get_source(
+        Arc::clone(&database_conn),
+        client,
+        mailbox_path,
+        message_uid,
+    )
+    .await
+    {
+        Ok(Some(source)) => return Ok(source),
+        Ok(None) => {}
+        Err(e) => return Err(e),
+    }
+
+    match database::message::exists(
+        Arc::clone(&database_conn),
+        client,
+        mailbox_path,
+        message_uid,
+    )
+    .await
+    {
+        Ok(true) => {}
+        Ok(false) => return Err(nonexistent()),
+        Err(e) => return Err(e),
+    }
+
+    let source = match get_source_imap(sessions, session_id, mailbox_path, message_uid).await {
+        Ok(source) => source,
+        Err(e) => return Err(e),
+    };
+
+    let headers = headers::parse(&String::from_utf8_lossy(&source));
+
+    match database::message::insert_source(
+        database_conn,
+        client,
+        mailbox_path,
+        message_uid,
+        &source,
+        &headers,
+    )
+    .await
+    {
+        Ok(_) => return Ok(source),
+        Err(e) => return Err(e),
+    }
+}
+
+/// Headers of a stored message. Messages stored by an older version have
+/// none, their headers are taken from the source.
+pub async fn get_headers(
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    session_id: usize,
+    client: &Client,
+    mailbox_path: &str,
+    message_uid: u32,
+) -> Result<Vec<Header>, MyError> {
+    match database::message::get_headers(
+        Arc::clone(&database_conn),
+        client,
+        mailbox_path,
+        message_uid,
+    )
+    .await
+    {
+        Ok(headers) if !headers.is_empty() => return Ok(headers),
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    }
+
+    match get_source(
+        sessions,
+        database_conn,
+        session_id,
+        client,
+        mailbox_path,
+        message_uid,
+    )
+    .await
+    {
+        Ok(source) => return Ok(headers::parse(&String::from_utf8_lossy(&source))),
+        Err(e) => return Err(e),
+    }
+}
+
+async fn get_source_imap(
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
+    session_id: usize,
+    mailbox_path: &str,
+    message_uid: u32,
+) -> Result<Vec<u8>, MyError> {
+    let mut session = match pool::acquire_mailbox(&sessions, session_id, mailbox_path).await {
+        Ok(s) => s,
+        Err(e) => return Err(e),
+    };
+
+    let fetches = match session
+        .session
+        .uid_fetch(
+            message_uid.to_string(),
+            fetch_mode::string(FetchMode::SOURCE),
+        )
+        .await
+    {
+        Ok(e) => Ok(e.collect::<Vec<_>>().await),
+        Err(e) => Err(e),
+    };
+
+    let fetches: Vec<Result<Fetch, ImapError>> = match fetches {
+        Ok(f) => f,
+        Err(e) => return Err(session.command_failed(e, "Error fetching message source")),
+    };
+
+    session.record_success();
+    drop(session);
+
+    let source = fetches.iter().find_map(|fetch| match fetch {
+        Ok(f) if f.uid == Some(message_uid) => f.body().map(|body| body.to_vec()),
+        _ => None,
+    });
+
+    match source {
+        Some(source) => return Ok(source),
+        None => return Err(nonexistent()),
+    }
+}
+
+/// Reply to the invitation of a message, answered as the attendee named by
+/// the username of the account or an address the message was sent to, with
+/// the Autocrypt header of the attendee.
+pub async fn respond_invitation(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+    mailbox_path: &str,
+    message_uid: u32,
+    partstat: PartStat,
+) -> Result<ReplyMessage, MyError> {
+    let calendar = match database::message::get_calendar(
+        Arc::clone(&database_conn),
+        client,
+        mailbox_path,
+        message_uid,
+    )
+    .await
+    {
+        Ok(Some(calendar)) => calendar,
+        Ok(None) => {
+            let err = MyError::Code(
+                ErrorCode::Nonexistent,
+                String::from("No invitation found"),
+                String::from("Error responding to invitation"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+        Err(e) => return Err(e),
+    };
+
+    let messages = match inbox_client::messages::get_database_with_uids(
+        Arc::clone(&database_conn),
+        client,
+        mailbox_path,
+        &vec![message_uid],
+    )
+    .await
+    {
+        Ok(messages) => messages,
+        Err(e) => return Err(e),
+    };
+
+    let mut attendees: Vec<String> = vec![client.username.clone()];
+    if let Some(message) = messages.first() {
+        attendees.push(message.delivered_to.clone());
+
+        for addresses in [&message.to, &message.cc, &message.bcc] {
+            attendees.extend(
+                parse_address::from_json(addresses)
+                    .iter()
+                    .map(|address| address.address()),
+            );
+        }
+    }
+
+    let now = Utc::now();
+    for attendee in attendees.iter().filter(|attendee| !attendee.is_empty()) {
+        if let Some(mut reply) = itip::reply_message(&calendar, attendee, partstat, now) {
+            match inbox_client::autocrypt::add_own_header(
+                database_conn,
+                credentials,
+                client,
+                &reply.source,
+            )
+            .await
+            {
+                Ok(source) => reply.source = source,
+                Err(e) => return Err(e),
+            }
+
+            return Ok(reply);
+        }
+    }
+
+    let err = MyError::Code(
+        ErrorCode::InvalidRequest,
+        String::from("Not an attendee of a request in this invitation"),
+        String::from("Error responding to invitation"),
+    );
+    err.log_error();
+
+    return Err(err);
+}
+
+fn nonexistent() -> MyError {
+    let err = MyError::Code(
+        ErrorCode::Nonexistent,
+        String::from("No message found"),
+        String::from("Error fetching message source"),
+    );
+    err.log_error();
+
+    return err;
+}
@@ -4,13 +4,13 @@ use async_std::stream::StreamExt;
 use async_std::sync::{Arc, Mutex};
 
 use crate::database;
-use crate::inbox_client;
+use crate::inbox_client::pool::{self, SessionPool};
 use crate::my_error::MyError;
-use crate::types::session::{Client, Session};
+use crate::types::session::Client;
 
 pub async fn modify(
     database_conn: Arc<Mutex<rusqlite::Connection>>,
-    sessions: Arc<Mutex<Vec<Session>>>,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     session_id: usize,
     client: &Client,
     mailbox_path: &str,
@@ -21,7 +21,6 @@ pub async fn modify(
     match modify_imap(
         Arc::clone(&sessions),
         session_id,
-        client,
         mailbox_path,
         message_uid,
         &flags,
@@ -50,60 +49,38 @@ pub async fn modify(
 }
 
 async fn modify_imap(
-    sessions: Arc<Mutex<Vec<Session>>>,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     session_id: usize,
-    client: &Client,
     mailbox_path: &str,
     message_uid: u32,
     flags: &Vec<String>,
     add: bool,
 ) -> Result<(), MyError> {
-    let sessions_2 = Arc::clone(&sessions);
+    let mut session = match pool::acquire_mailbox(&sessions, session_id, mailbox_path).await {
+        Ok(s) => s,
+        Err(e) => return Err(e),
+    };
 
-    let mut locked_sessions = sessions.lock().await;
+    let query = flags_query(flags, add);
 
-    let session = &mut locked_sessions[session_id];
+    let fetches = match session
+        .session
+        .uid_store(message_uid.to_string(), query)
+        .await
+    {
+        Ok(e) => Ok(e.collect::<Vec<_>>().await),
+        Err(e) => Err(e),
+    };
 
-    match session.select(mailbox_path).await {
-        Ok(_) => {}
+    let fetches: Vec<Result<Fetch, ImapError>> = match fetches {
+        Ok(f) => f,
         Err(e) => {
-            drop(locked_sessions);
-
-            match inbox_client::connect::handle_disconnect(sessions, session_id, client, e).await {
-                Ok(_) => {
-                    return Box::pin(modify_imap(
-                        sessions_2,
-                        session_id,
-                        client,
-                        mailbox_path,
-                        message_uid,
-                        flags,
-                        add,
-                    ))
-                    .await;
-                }
-                Err(e) => return Err(e),
-            }
+            return Err(session.command_failed(e, "Error retrieving message while updating flags"))
         }
     };
 
-    let query = flags_query(flags, add);
-
-    let fetches: Vec<Result<Fetch, ImapError>> =
-        match session.uid_store(message_uid.to_string(), query).await {
-            Ok(e) => e.collect().await,
-            Err(e) => {
-                let err = MyError::Imap(
-                    e,
-                    String::from("Error retrieving message while updating flags"),
-                );
-                err.log_error();
-
-                return Err(err);
-            }
-        };
-
-    drop(locked_sessions);
+    session.record_success();
+    drop(session);
 
     let fetch = if let Some(m) = fetches.first() {
         m
@@ -128,13 +105,19 @@ async fn modify_imap(
     };
 }
 
+/// Flags sent with a backslash, any other flag is a keyword such as
+/// `$Forwarded`.
+const SYSTEM_FLAGS: [&str; 5] = ["Seen", "Answered", "Flagged", "Deleted", "Draft"];
+
 fn flags_query(flags: &Vec<String>, add: bool) -> String {
     let mut query = if add { "+" } else { "-" }.to_string();
 
     query.push_str("FLAGS (");
 
     for (i, flag) in flags.iter().enumerate() {
-        query.push_str("\\");
+        if SYSTEM_FLAGS.contains(&flag.as_str()) {
+            query.push_str("\\");
+        }
         query.push_str(&flag);
 
         if i < flags.len() - 1 {
@@ -146,3 +129,16 @@ fn flags_query(flags: &Vec<String>, add: bool) -> String {
 
     return query;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_query_keywords() {
+        let flags = vec![String::from("Seen"), String::from("$Forwarded")];
+
+        assert_eq!(flags_query(&flags, true), "+FLAGS (\\Seen $Forwarded)");
+        assert_eq!(flags_query(&flags[..1].to_vec(), false), "-FLAGS (\\Seen)");
+    }
+}
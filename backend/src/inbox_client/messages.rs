@@ -2,20 +2,31 @@ use async_imap::error::Error as ImapError;
 use async_imap::types::Fetch;
 use async_std::stream::StreamExt;
 use async_std::sync::{Arc, Mutex};
-
-use crate::mime_parser::parser;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::Utc;
+use openssl::x509::store::X509Store;
+
+use crate::database::credentials::CredentialStore;
+use crate::inbox_client::pool::{self, SessionPool};
+use crate::mime_parser::sanitize::{self, RemoteContent};
+use crate::mime_parser::smime::{self, Identity};
+use crate::mime_parser::{autocrypt, headers, inline_parts, parse_address, parser, pgp_mime};
 use crate::my_error::MyError;
-use crate::types::database_request::{DatabaseRequest, MessageIdType, MessageReturnData};
+use crate::openpgp::key::Cert;
+use crate::types::database_request::{DatabaseRequest, MessageIdType, MessageReturnData, Sort};
 use crate::types::fetch_mode;
-use crate::types::message::Message;
+use crate::types::mailbox_changes::ChangesSince;
+use crate::types::message::{Message, MessageSecurity};
+use crate::types::page_cursor::PageCursor;
 use crate::types::sequence_set::SequenceSet;
-use crate::types::session::{Client, Session};
+use crate::types::session::Client;
 use crate::{database, inbox_client};
 
 pub async fn get_database_sorted(
     database_conn: Arc<Mutex<rusqlite::Connection>>,
     client: &Client,
     mailbox_path: &str,
+    sort: Sort,
     start: u32,
     end: u32,
 ) -> Result<Vec<Message>, MyError> {
@@ -23,11 +34,13 @@ pub async fn get_database_sorted(
         username: client.username.clone(),
         address: client.address.clone(),
         mailbox_path: mailbox_path.to_string(),
-        return_data: MessageReturnData::AllWithFlags,
+        return_data: MessageReturnData::Summary,
         id_type: MessageIdType::MessageUids,
-        sorted: true,
+        sort: Some(sort),
         start: Some(start),
         end: Some(end),
+        limit: None,
+        after: None,
         id_rarray: None,
         flag: None,
         not_flag: None,
@@ -36,6 +49,58 @@ pub async fn get_database_sorted(
     return database::messages::get(database_conn, database_request).await;
 }
 
+/// A page of at most `limit` messages after `after`, and the cursor of the
+/// next page when this one is full.
+pub async fn get_database_page(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    client: &Client,
+    mailbox_path: &str,
+    sort: Sort,
+    after: Option<PageCursor>,
+    limit: u32,
+) -> Result<(Vec<Message>, Option<PageCursor>), MyError> {
+    let database_request = DatabaseRequest {
+        username: client.username.clone(),
+        address: client.address.clone(),
+        mailbox_path: mailbox_path.to_string(),
+        return_data: MessageReturnData::Summary,
+        id_type: MessageIdType::MessageUids,
+        sort: Some(sort),
+        start: None,
+        end: None,
+        limit: Some(limit),
+        after,
+        id_rarray: None,
+        flag: None,
+        not_flag: None,
+    };
+
+    let messages = match database::messages::get(Arc::clone(&database_conn), database_request).await
+    {
+        Ok(m) => m,
+        Err(e) => return Err(e),
+    };
+
+    let last_uid = match messages.last() {
+        Some(message) if messages.len() as u32 == limit => message.message_uid,
+        _ => return Ok((messages, None)),
+    };
+
+    match database::messages::get_page_cursor(
+        database_conn,
+        &client.username,
+        &client.address,
+        mailbox_path,
+        &sort,
+        last_uid,
+    )
+    .await
+    {
+        Ok(cursor) => return Ok((messages, cursor)),
+        Err(e) => return Err(e),
+    }
+}
+
 pub async fn get_database_with_uids(
     database_conn: Arc<Mutex<rusqlite::Connection>>,
     client: &Client,
@@ -46,11 +111,13 @@ pub async fn get_database_with_uids(
         username: client.username.clone(),
         address: client.address.clone(),
         mailbox_path: mailbox_path.to_string(),
-        return_data: MessageReturnData::All,
+        return_data: MessageReturnData::AllWithFlags,
         id_type: MessageIdType::MessageUids,
-        sorted: true,
+        sort: Some(Sort::received_desc()),
         start: None,
         end: None,
+        limit: None,
+        after: None,
         id_rarray: Some(message_uids.clone()),
         flag: None,
         not_flag: None,
@@ -59,6 +126,335 @@ pub async fn get_database_with_uids(
     return database::messages::get(database_conn, database_request).await;
 }
 
+/// Messages with their html sanitised for display, inline images referenced
+/// by Content-ID are embedded and the calendar part of invitations is
+/// loaded. Remote content is only kept when `load_remote` is set or the
+/// sender is on the allowlist, what was removed is returned with each
+/// message. OpenPGP and S/MIME messages are decrypted and verified with the
+/// keys and identities of the account, nothing is written to the database.
+pub async fn get_database_bodies(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+    mailbox_path: &str,
+    message_uids: &Vec<u32>,
+    load_remote: bool,
+) -> Result<Vec<(Message, RemoteContent)>, MyError> {
+    let messages = match get_database_with_uids(
+        Arc::clone(&database_conn),
+        client,
+        mailbox_path,
+        message_uids,
+    )
+    .await
+    {
+        Ok(messages) => messages,
+        Err(e) => return Err(e),
+    };
+
+    // Loaded for the first OpenPGP or S/MIME message only.
+    let mut keyring: Option<Vec<Cert>> = None;
+    let mut smime_keys: Option<(Vec<Identity>, X509Store)> = None;
+
+    let mut bodies = Vec::with_capacity(messages.len());
+    for mut message in messages {
+        let allow_remote = if load_remote {
+            true
+        } else {
+            match sender_address(&message.from) {
+                Some(sender) => match database::remote_content::is_allowed(
+                    Arc::clone(&database_conn),
+                    &client.username,
+                    &client.address,
+                    &sender,
+                )
+                .await
+                {
+                    Ok(allowed) => allowed,
+                    Err(e) => return Err(e),
+                },
+                None => false,
+            }
+        };
+
+        let mut html = match BASE64_STANDARD.decode(message.html.as_bytes()) {
+            Ok(html) => String::from_utf8_lossy(&html).to_string(),
+            Err(_) => String::new(),
+        };
+
+        let mut parts = match database::message::get_inline_parts(
+            Arc::clone(&database_conn),
+            client,
+            mailbox_path,
+            message.message_uid,
+        )
+        .await
+        {
+            Ok(parts) => parts,
+            Err(e) => return Err(e),
+        };
+
+        match database::message::get_calendar(
+            Arc::clone(&database_conn),
+            client,
+            mailbox_path,
+            message.message_uid,
+        )
+        .await
+        {
+            Ok(calendar) => message.calendar = calendar.unwrap_or_default(),
+            Err(e) => return Err(e),
+        }
+
+        let opened = match open_protected(
+            Arc::clone(&database_conn),
+            credentials,
+            client,
+            mailbox_path,
+            &message,
+            &mut keyring,
+            &mut smime_keys,
+        )
+        .await
+        {
+            Ok(opened) => opened,
+            Err(e) => return Err(e),
+        };
+
+        if let Some((entity, text, security)) = opened {
+            if let Some(entity) = entity {
+                let content = parser::parse_entity(&entity);
+
+                message.text = content.text;
+                html = match BASE64_STANDARD.decode(content.html.as_bytes()) {
+                    Ok(html) => String::from_utf8_lossy(&html).to_string(),
+                    Err(_) => String::new(),
+                };
+                parts = content.inline_parts;
+                if !content.calendar.is_empty() {
+                    message.calendar = content.calendar;
+                }
+            }
+
+            // The html of an inline OpenPGP message shows the armored block.
+            if let Some(text) = text {
+                message.text = BASE64_STANDARD.encode(text.as_bytes());
+                html = String::new();
+            }
+
+            message.security = Some(security);
+        }
+
+        let html = inline_parts::resolve(&html, &parts);
+        let (html, remote_content) = sanitize::sanitize(&html, allow_remote);
+        message.html = BASE64_STANDARD.encode(html.as_bytes());
+
+        bodies.push((message, remote_content));
+    }
+
+    return Ok(bodies);
+}
+
+/// Decrypts and verifies the OpenPGP and S/MIME messages among
+/// `message_uids` once they are stored and keeps the result with each
+/// message. Keys gossiped inside an encrypted message update the Autocrypt
+/// state of its other recipients.
+pub async fn store_security(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+    mailbox_path: &str,
+    message_uids: &Vec<u32>,
+) -> Result<(), MyError> {
+    let messages = match get_database_with_uids(
+        Arc::clone(&database_conn),
+        client,
+        mailbox_path,
+        message_uids,
+    )
+    .await
+    {
+        Ok(messages) => messages,
+        Err(e) => return Err(e),
+    };
+
+    let mut keyring: Option<Vec<Cert>> = None;
+    let mut smime_keys: Option<(Vec<Identity>, X509Store)> = None;
+
+    for message in messages {
+        let (entity, _, security) = match open_protected(
+            Arc::clone(&database_conn),
+            credentials,
+            client,
+            mailbox_path,
+            &message,
+            &mut keyring,
+            &mut smime_keys,
+        )
+        .await
+        {
+            Ok(Some(opened)) => opened,
+            Ok(None) => continue,
+            Err(e) => return Err(e),
+        };
+
+        // Keys gossiped to the other recipients, only trusted inside the
+        // encryption.
+        if let Some(entity) = entity
+            .as_ref()
+            .filter(|_| security.protocol == "openpgp" && security.decrypted)
+        {
+            let recipients: Vec<String> = [&message.to, &message.cc]
+                .iter()
+                .flat_map(|addresses| parse_address::from_json(addresses))
+                .map(|address| address.address().to_lowercase())
+                .filter(|address| *address != client.username.to_lowercase())
+                .collect();
+            let gossip = autocrypt::gossip(&headers::parse(entity), &recipients);
+
+            if !gossip.is_empty() {
+                match database::autocrypt::update_gossip(
+                    Arc::clone(&database_conn),
+                    &client.username,
+                    &client.address,
+                    mailbox_path,
+                    &gossip,
+                    message.date,
+                    Utc::now().timestamp_millis(),
+                )
+                .await
+                {
+                    Ok(_) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        match database::message::set_security(
+            Arc::clone(&database_conn),
+            client,
+            mailbox_path,
+            message.message_uid,
+            &security,
+        )
+        .await
+        {
+            Ok(_) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    return Ok(());
+}
+
+/// The entity, inline text and security of an OpenPGP or S/MIME message, or
+/// `None` for a message that is neither. The keys are loaded into `keyring`
+/// and `smime_keys` for the first message that needs them.
+async fn open_protected(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+    mailbox_path: &str,
+    message: &Message,
+    keyring: &mut Option<Vec<Cert>>,
+    smime_keys: &mut Option<(Vec<Identity>, X509Store)>,
+) -> Result<Option<(Option<String>, Option<String>, MessageSecurity)>, MyError> {
+    let openpgp = match database::message::get_openpgp_part(
+        Arc::clone(&database_conn),
+        client,
+        mailbox_path,
+        message.message_uid,
+    )
+    .await
+    {
+        Ok(openpgp) => openpgp,
+        Err(e) => return Err(e),
+    };
+
+    let smime_part = match openpgp {
+        Some(_) => None,
+        None => match database::message::get_smime_part(
+            Arc::clone(&database_conn),
+            client,
+            mailbox_path,
+            message.message_uid,
+        )
+        .await
+        {
+            Ok(smime_part) => smime_part,
+            Err(e) => return Err(e),
+        },
+    };
+
+    // A signature only stands for the message when made by the sender.
+    let senders: Vec<String> = [&message.from, &message.sender]
+        .iter()
+        .flat_map(|addresses| parse_address::from_json(addresses))
+        .map(|address| address.address())
+        .collect();
+
+    match (openpgp, smime_part) {
+        (Some(part), _) => {
+            if keyring.is_none() {
+                *keyring = match inbox_client::openpgp::keyring(
+                    Arc::clone(&database_conn),
+                    credentials,
+                    client,
+                )
+                .await
+                {
+                    Ok(certs) => Some(certs),
+                    Err(e) => return Err(e),
+                };
+            }
+
+            let opened = pgp_mime::open(&part, keyring.as_deref().unwrap_or_default(), &senders);
+
+            return Ok(Some((opened.entity, opened.text, opened.security)));
+        }
+        (None, Some(part)) => {
+            if smime_keys.is_none() {
+                let identities = match inbox_client::smime::identities(
+                    Arc::clone(&database_conn),
+                    credentials,
+                    client,
+                )
+                .await
+                {
+                    Ok(identities) => identities,
+                    Err(e) => return Err(e),
+                };
+
+                *smime_keys = match inbox_client::smime::trust_store() {
+                    Ok(trust_store) => Some((identities, trust_store)),
+                    Err(e) => return Err(e),
+                };
+            }
+
+            let opened = match smime_keys {
+                Some((identities, trust_store)) => {
+                    smime::open(&part, identities, trust_store, &senders)
+                }
+                None => return Ok(None),
+            };
+
+            return Ok(Some((opened.entity, None, opened.security)));
+        }
+        (None, None) => return Ok(None),
+    }
+}
+
+/// Address of the first From address of a message, as stored in the remote
+/// content allowlist.
+pub fn sender_address(from: &str) -> Option<String> {
+    match parse_address::from_json(from).first() {
+        Some(address) if !address.mailbox.is_empty() && !address.host.is_empty() => {
+            return Some(address.address().to_lowercase())
+        }
+        _ => return None,
+    }
+}
+
 pub async fn get_database_with_flag(
     database_conn: Arc<Mutex<rusqlite::Connection>>,
     client: &Client,
@@ -70,92 +466,78 @@ pub async fn get_database_with_flag(
         username: client.username.clone(),
         address: client.address.clone(),
         mailbox_path: mailbox_path.to_string(),
-        return_data: MessageReturnData::AllWithFlags,
+        return_data: MessageReturnData::Summary,
         id_type: MessageIdType::MessageUids,
-        sorted: true,
+        sort: Some(Sort::received_desc()),
         start: None,
         end: None,
+        limit: None,
+        after: None,
         id_rarray: None,
         flag: Some(flag.to_string()),
         not_flag: Some(not_flag),
     };
 
-    let database_conn_2 = Arc::clone(&database_conn);
-    let mut messages = match database::messages::get(database_conn_2, database_request).await {
-        Ok(m) => m,
-        Err(e) => return Err(e),
-    };
-
-    let list: Vec<u32> = messages.iter().map(|message| message.message_uid).collect();
+    return database::messages::get(database_conn, database_request).await;
+}
 
-    let flags = match database::messages::get_flags_with_rarray(
-        database_conn,
+/// Summaries of the messages changed after `cursor` with their flags, and
+/// the uids of the messages removed since.
+pub async fn get_database_changes_since(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    client: &Client,
+    mailbox_path: &str,
+    cursor: u32,
+) -> Result<(ChangesSince, Vec<Message>), MyError> {
+    let changes = match database::changes::get_since(
+        Arc::clone(&database_conn),
         &client.username,
         &client.address,
         mailbox_path,
-        &list,
-        MessageIdType::MessageUids,
+        cursor,
     )
     .await
     {
-        Ok(f) => f,
+        Ok(changes) => changes,
         Err(e) => return Err(e),
     };
 
-    let _ = messages.iter_mut().map(|message| {
-        let flags = flags
-            .iter()
-            .filter_map(|flag| {
-                if flag.0 == message.message_uid {
-                    Some(flag.1.clone())
-                } else {
-                    None
-                }
-            })
-            .collect();
+    if changes.changed.is_empty() {
+        return Ok((changes, vec![]));
+    }
 
-        message.flags.push(flags);
-    });
+    let database_request = DatabaseRequest {
+        username: client.username.clone(),
+        address: client.address.clone(),
+        mailbox_path: mailbox_path.to_string(),
+        return_data: MessageReturnData::Summary,
+        id_type: MessageIdType::MessageUids,
+        sort: Some(Sort::received_desc()),
+        start: None,
+        end: None,
+        limit: None,
+        after: None,
+        id_rarray: Some(changes.changed.clone()),
+        flag: None,
+        not_flag: None,
+    };
 
-    return Ok(messages);
+    match database::messages::get(database_conn, database_request).await {
+        Ok(messages) => return Ok((changes, messages)),
+        Err(e) => return Err(e),
+    }
 }
 
 pub async fn get_imap_with_uids(
-    sessions: Arc<Mutex<Vec<Session>>>,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     session_id: usize,
-    client: &Client,
     mailbox_path: &str,
-    message_uids: &Vec<u32>,
+    message_uids: &[u32],
     fetch_mode: fetch_mode::FetchMode,
 ) -> Result<Vec<Message>, MyError> {
-    let sessions_2 = Arc::clone(&sessions);
-
-    let mut locked_sessions = sessions.lock().await;
-
-    let session = &mut locked_sessions[session_id];
-
-    match session.select(mailbox_path).await {
-        Ok(m) => m,
-        Err(e) => {
-            drop(locked_sessions);
-
-            match inbox_client::connect::handle_disconnect(sessions, session_id, client, e).await {
-                Ok(_) => {
-                    return Box::pin(get_imap_with_uids(
-                        sessions_2,
-                        session_id,
-                        client,
-                        mailbox_path,
-                        message_uids,
-                        fetch_mode,
-                    ))
-                    .await;
-                }
-                Err(e) => {
-                    return Err(e);
-                }
-            }
-        }
+    let mut session = match pool::acquire_mailbox(&sessions, session_id, mailbox_path).await {
+        Ok(s) => s,
+        Err(e) => return Err(e),
     };
 
     let uid_set = message_uids
@@ -164,20 +546,22 @@ pub async fn get_imap_with_uids(
         .collect::<Vec<String>>()
         .join(",");
 
-    let fetches: Vec<Result<Fetch, ImapError>> = match session
+    let fetches = match session
+        .session
         .uid_fetch(&uid_set, fetch_mode::string(fetch_mode))
         .await
     {
-        Ok(e) => e.collect().await,
-        Err(e) => {
-            let err = MyError::Imap(e, String::from("Error fetching messages"));
-            err.log_error();
+        Ok(e) => Ok(e.collect::<Vec<_>>().await),
+        Err(e) => Err(e),
+    };
 
-            return Err(err);
-        }
+    let fetches: Vec<Result<Fetch, ImapError>> = match fetches {
+        Ok(f) => f,
+        Err(e) => return Err(session.command_failed(e, "Error fetching messages")),
     };
 
-    drop(locked_sessions);
+    session.record_success();
+    drop(session);
 
     let fetches = fetches
         .iter()
@@ -199,41 +583,15 @@ pub async fn get_imap_with_uids(
 }
 
 pub async fn get_imap_with_seq(
-    sessions: Arc<Mutex<Vec<Session>>>,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     session_id: usize,
-    client: &Client,
     mailbox_path: &str,
     sequence_set: &SequenceSet,
     fetch_mode: fetch_mode::FetchMode,
 ) -> Result<Vec<Message>, MyError> {
-    let sessions_2 = Arc::clone(&sessions);
-
-    let mut locked_sessions = sessions.lock().await;
-
-    let session = &mut locked_sessions[session_id];
-
-    match session.select(mailbox_path).await {
-        Ok(m) => m,
-        Err(e) => {
-            drop(locked_sessions);
-
-            match inbox_client::connect::handle_disconnect(sessions, session_id, client, e).await {
-                Ok(_) => {
-                    return Box::pin(get_imap_with_seq(
-                        sessions_2,
-                        session_id,
-                        client,
-                        mailbox_path,
-                        sequence_set,
-                        fetch_mode,
-                    ))
-                    .await;
-                }
-                Err(e) => {
-                    return Err(e);
-                }
-            }
-        }
+    let mut session = match pool::acquire_mailbox(&sessions, session_id, mailbox_path).await {
+        Ok(s) => s,
+        Err(e) => return Err(e),
     };
 
     let sequence_set_str = match sequence_set.to_string(0, false) {
@@ -241,20 +599,22 @@ pub async fn get_imap_with_seq(
         Err(e) => return Err(e),
     };
 
-    let fetches: Vec<Result<Fetch, ImapError>> = match session
+    let fetches = match session
+        .session
         .fetch(&sequence_set_str, fetch_mode::string(fetch_mode))
         .await
     {
-        Ok(e) => e.collect().await,
-        Err(e) => {
-            let err = MyError::Imap(e, String::from("Error fetching messages"));
-            err.log_error();
+        Ok(e) => Ok(e.collect::<Vec<_>>().await),
+        Err(e) => Err(e),
+    };
 
-            return Err(err);
-        }
+    let fetches: Vec<Result<Fetch, ImapError>> = match fetches {
+        Ok(f) => f,
+        Err(e) => return Err(session.command_failed(e, "Error fetching messages")),
     };
 
-    drop(locked_sessions);
+    session.record_success();
+    drop(session);
 
     let fetches = fetches
         .iter()
@@ -0,0 +1,445 @@
+use async_imap::Authenticator;
+use async_std::sync::{Arc, Mutex};
+use async_std::task;
+use std::sync::Mutex as SyncMutex;
+
+use crate::database;
+use crate::database::credentials::CredentialStore;
+use crate::inbox_client::supervisor;
+use crate::my_error::MyError;
+use crate::types::error_code::ErrorCode;
+use crate::types::session::{AuthMechanism, Client, OAuth};
+
+/// Tokens are refreshed this long before they expire, so a connection is never
+/// opened with a token that runs out during the handshake.
+const EXPIRY_MARGIN_MS: i64 = 60 * 1000;
+
+#[derive(Debug, Clone, Default)]
+pub struct TokenState {
+    pub access_token: Option<String>,
+    pub expires_at: i64,
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub expires_in: Option<i64>,
+    pub refresh_token: Option<String>,
+}
+
+pub struct OAuthAuthenticator {
+    response: String,
+    error_response: String,
+    sent: bool,
+}
+
+impl OAuthAuthenticator {
+    pub fn new(client: &Client, access_token: &str) -> OAuthAuthenticator {
+        let (response, error_response) = match client.auth_mechanism {
+            AuthMechanism::OAuthBearer => (
+                format!(
+                    "n,a={},\x01host={}\x01port={}\x01auth=Bearer {}\x01\x01",
+                    client.username, client.address, client.port, access_token
+                ),
+                String::from("\x01"),
+            ),
+            _ => (
+                format!(
+                    "user={}\x01auth=Bearer {}\x01\x01",
+                    client.username, access_token
+                ),
+                String::new(),
+            ),
+        };
+
+        return OAuthAuthenticator {
+            response,
+            error_response,
+            sent: false,
+        };
+    }
+}
+
+impl Authenticator for OAuthAuthenticator {
+    type Response = String;
+
+    /// The first challenge is answered with the credentials, a second challenge
+    /// carries the error details and is acknowledged so the server sends its NO.
+    fn process(&mut self, _challenge: &[u8]) -> String {
+        if self.sent {
+            return self.error_response.clone();
+        }
+
+        self.sent = true;
+
+        return self.response.clone();
+    }
+}
+
+pub fn mechanism_name(auth_mechanism: &AuthMechanism) -> &str {
+    match auth_mechanism {
+        AuthMechanism::OAuthBearer => "OAUTHBEARER",
+        _ => "XOAUTH2",
+    }
+}
+
+/// Returns a valid access token for the client, refreshing it at the token
+/// endpoint when the cached one is missing or about to expire. A rotated
+/// refresh token is stored in the database.
+pub async fn access_token(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+    token_state: &SyncMutex<TokenState>,
+) -> Result<String, MyError> {
+    let oauth = match &client.oauth {
+        Some(oauth) => oauth,
+        None => {
+            let err = MyError::OAuth(
+                String::from("No OAuth credentials stored"),
+                String::from("Error refreshing access token"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    let refresh_token = {
+        let state = token_state.lock().unwrap();
+
+        if let Some(access_token) = &state.access_token {
+            if state.expires_at - EXPIRY_MARGIN_MS > supervisor::now() {
+                return Ok(access_token.clone());
+            }
+        }
+
+        state
+            .refresh_token
+            .clone()
+            .unwrap_or(oauth.refresh_token.clone())
+    };
+
+    let response = match refresh(oauth, &refresh_token).await {
+        Ok(r) => r,
+        Err(e) => return Err(e),
+    };
+
+    let rotated_refresh_token = {
+        let mut state = token_state.lock().unwrap();
+
+        state.access_token = Some(response.access_token.clone());
+        state.expires_at = supervisor::now() + response.expires_in.unwrap_or(3600) * 1000;
+
+        match &response.refresh_token {
+            Some(new_refresh_token) if *new_refresh_token != refresh_token => {
+                state.refresh_token = Some(new_refresh_token.clone());
+
+                Some(new_refresh_token.clone())
+            }
+            _ => None,
+        }
+    };
+
+    if let Some(new_refresh_token) = rotated_refresh_token {
+        match database::connections::update_refresh_token(
+            database_conn,
+            credentials,
+            client,
+            &new_refresh_token,
+        )
+        .await
+        {
+            Ok(_) => {}
+            Err(e) => e.log_error(),
+        }
+    }
+
+    return Ok(response.access_token);
+}
+
+pub fn invalidate(token_state: &SyncMutex<TokenState>) {
+    let mut state = token_state.lock().unwrap();
+
+    state.access_token = None;
+}
+
+pub async fn refresh(oauth: &OAuth, refresh_token: &str) -> Result<TokenResponse, MyError> {
+    let token_endpoint = oauth.token_endpoint.clone();
+
+    let mut form: Vec<(&'static str, String)> = vec![
+        ("grant_type", String::from("refresh_token")),
+        ("refresh_token", refresh_token.to_string()),
+        ("client_id", oauth.client_id.clone()),
+    ];
+
+    if let Some(client_secret) = &oauth.client_secret {
+        form.push(("client_secret", client_secret.clone()));
+    }
+
+    let result = task::spawn_blocking(move || {
+        let form: Vec<(&str, &str)> = form.iter().map(|(k, v)| (*k, v.as_str())).collect();
+
+        match ureq::post(&token_endpoint).send_form(&form) {
+            Ok(response) => response.into_string().map(|body| (200, body)),
+            Err(ureq::Error::Status(status, response)) => {
+                response.into_string().map(|body| (status, body))
+            }
+            Err(ureq::Error::Transport(e)) => Err(std::io::Error::other(e.to_string())),
+        }
+    })
+    .await;
+
+    let (status, body) = match result {
+        Ok(r) => r,
+        Err(e) => {
+            let err = MyError::Io(e, String::from("Error contacting token endpoint"));
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    return parse_token_response(status, &body);
+}
+
+pub fn parse_token_response(status: u16, body: &str) -> Result<TokenResponse, MyError> {
+    if status >= 500 {
+        let err = MyError::Code(
+            ErrorCode::ServerError,
+            body.to_string(),
+            format!("Token endpoint unavailable (status {})", status),
+        );
+        err.log_error();
+
+        return Err(err);
+    }
+
+    let json: serde_json::Value = match serde_json::from_str(body) {
+        Ok(json) => json,
+        Err(e) => {
+            let err = MyError::String(
+                e.to_string(),
+                format!("Invalid response from token endpoint (status {})", status),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    if status >= 400 || json.get("error").is_some() {
+        let error = json["error"].as_str().unwrap_or("unknown_error");
+        let description = json["error_description"].as_str().unwrap_or("");
+
+        let err = MyError::OAuth(
+            format!("{} {}", error, description).trim().to_string(),
+            format!(
+                "Token endpoint rejected the refresh token (status {})",
+                status
+            ),
+        );
+        err.log_error();
+
+        return Err(err);
+    }
+
+    let access_token = match json["access_token"].as_str() {
+        Some(t) => t.to_string(),
+        None => {
+            let err = MyError::String(
+                String::from("Missing access_token"),
+                String::from("Invalid response from token endpoint"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    return Ok(TokenResponse {
+        access_token,
+        expires_in: json["expires_in"].as_i64(),
+        refresh_token: json["refresh_token"].as_str().map(|t| t.to_string()),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::io::{BufReader, ReadExt, WriteExt};
+    use async_std::net::TcpListener;
+    use async_std::prelude::*;
+
+    use crate::types::session::Security;
+
+    fn get_client(auth_mechanism: AuthMechanism) -> Client {
+        Client {
+            address: String::from("imap.example.com"),
+            port: 993,
+            username: String::from("user@example.com"),
+            password: String::new(),
+            security: Security::Tls,
+            cert_fingerprint: None,
+            auth_mechanism,
+            oauth: None,
+        }
+    }
+
+    /// Answers a single token request with the given status and body and
+    /// returns the form that was posted.
+    async fn token_endpoint(status: u16, body: &'static str) -> (String, task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/token", listener.local_addr().unwrap());
+
+        let handle = task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(stream);
+
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).await.unwrap();
+
+                if line.trim().is_empty() {
+                    break;
+                }
+
+                if let Some((key, value)) = line.split_once(':') {
+                    if key.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+
+            let mut form = vec![0; content_length];
+            reader.read_exact(&mut form).await.unwrap();
+
+            let response = format!(
+                "HTTP/1.1 {} Status\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            let stream = reader.get_mut();
+            stream.write_all(response.as_bytes()).await.unwrap();
+            stream.flush().await.unwrap();
+
+            String::from_utf8(form).unwrap()
+        });
+
+        return (url, handle);
+    }
+
+    #[test]
+    fn xoauth2_response() {
+        let client = get_client(AuthMechanism::XOAuth2);
+        let mut authenticator = OAuthAuthenticator::new(&client, "token");
+
+        assert_eq!(
+            authenticator.process(b""),
+            "user=user@example.com\x01auth=Bearer token\x01\x01"
+        );
+        assert_eq!(authenticator.process(b"{\"status\":\"401\"}"), "");
+    }
+
+    #[test]
+    fn oauthbearer_response() {
+        let client = get_client(AuthMechanism::OAuthBearer);
+        let mut authenticator = OAuthAuthenticator::new(&client, "token");
+
+        assert_eq!(
+            authenticator.process(b""),
+            "n,a=user@example.com,\x01host=imap.example.com\x01port=993\x01auth=Bearer token\x01\x01"
+        );
+        assert_eq!(
+            authenticator.process(b"{\"status\":\"invalid_token\"}"),
+            "\x01"
+        );
+    }
+
+    #[test]
+    fn token_response() {
+        let response = parse_token_response(
+            200,
+            r#"{"access_token":"abc","expires_in":3599,"token_type":"Bearer"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            response,
+            TokenResponse {
+                access_token: String::from("abc"),
+                expires_in: Some(3599),
+                refresh_token: None,
+            }
+        );
+    }
+
+    #[test]
+    fn token_response_invalid_grant() {
+        let result = parse_token_response(
+            400,
+            r#"{"error":"invalid_grant","error_description":"Token has been revoked."}"#,
+        );
+
+        match result {
+            Err(MyError::OAuth(err, _)) => assert_eq!(err, "invalid_grant Token has been revoked."),
+            _ => panic!("expected OAuth error"),
+        }
+    }
+
+    #[test]
+    fn token_response_server_error() {
+        let result = parse_token_response(503, "<html>Service Unavailable</html>");
+
+        match result {
+            Err(e) => assert_eq!(e.code(), ErrorCode::ServerError),
+            Ok(_) => panic!("expected error"),
+        }
+    }
+
+    #[async_std::test]
+    async fn refresh_against_token_endpoint() {
+        let (url, handle) = token_endpoint(
+            200,
+            r#"{"access_token":"new-access","expires_in":3600,"refresh_token":"new-refresh"}"#,
+        )
+        .await;
+
+        let oauth = OAuth {
+            token_endpoint: url,
+            client_id: String::from("client"),
+            client_secret: Some(String::from("secret")),
+            refresh_token: String::from("old-refresh"),
+        };
+
+        let response = refresh(&oauth, "old-refresh").await.unwrap();
+        let form = handle.await;
+
+        assert_eq!(response.access_token, "new-access");
+        assert_eq!(response.refresh_token, Some(String::from("new-refresh")));
+        assert_eq!(
+            form,
+            "grant_type=refresh_token&refresh_token=old-refresh&client_id=client&client_secret=secret"
+        );
+    }
+
+    #[async_std::test]
+    async fn refresh_rejected_by_token_endpoint() {
+        let (url, _handle) = token_endpoint(400, r#"{"error":"invalid_grant"}"#).await;
+
+        let oauth = OAuth {
+            token_endpoint: url,
+            client_id: String::from("client"),
+            client_secret: None,
+            refresh_token: String::from("revoked"),
+        };
+
+        assert!(matches!(
+            refresh(&oauth, "revoked").await,
+            Err(MyError::OAuth(_, _))
+        ));
+    }
+}
@@ -0,0 +1,338 @@
+use async_std::sync::{Arc, Mutex};
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::database;
+use crate::database::credentials::CredentialStore;
+use crate::database::openpgp_keys::StoredKey;
+use crate::inbox_client;
+use crate::mime_parser::autocrypt::{self, AutocryptHeader, PreferEncrypt};
+use crate::mime_parser::{headers, parse_address, pgp_mime};
+use crate::my_error::MyError;
+use crate::openpgp::key::{self, Cert};
+use crate::types::error_code::ErrorCode;
+use crate::types::openpgp_key::OpenPgpKey;
+use crate::types::session::Client;
+
+/// Message signed or encrypted for sending.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProtectedMessage {
+    /// RFC 5322 message, multipart/signed or multipart/encrypted.
+    pub source: String,
+}
+
+/// Imports the keys of an armored key block. Protected secret keys are
+/// unlocked with `passphrase` first, so a wrong one is refused here rather
+/// than when a message is read.
+pub async fn import_keys(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+    armored: &str,
+    passphrase: Option<&str>,
+) -> Result<Vec<OpenPgpKey>, MyError> {
+    let certs = match key::read_armored(armored) {
+        Ok(certs) => certs,
+        Err(MyError::String(detail, _)) => return Err(invalid(&detail)),
+        Err(e) => return Err(e),
+    };
+
+    let now = Utc::now().timestamp();
+    let mut imported: Vec<OpenPgpKey> = vec![];
+
+    for cert in certs {
+        let mut stored_passphrase: Option<String> = None;
+
+        if cert.is_locked() {
+            let passphrase = match passphrase {
+                Some(passphrase) => passphrase,
+                None => return Err(invalid("The secret key is protected by a passphrase")),
+            };
+
+            if cert.clone().unlock(passphrase).is_err() {
+                return Err(invalid("Wrong passphrase for the secret key"));
+            }
+
+            stored_passphrase = Some(passphrase.to_string());
+        }
+
+        let key = StoredKey {
+            fingerprint: cert.fingerprint(),
+            public_key: cert.public_armor(),
+            secret_key: match cert.has_secret() {
+                true => Some(armored.to_string()),
+                false => None,
+            },
+            passphrase: stored_passphrase,
+        };
+
+        match database::openpgp_keys::insert(
+            Arc::clone(&database_conn),
+            credentials,
+            client,
+            &key,
+            &cert.user_ids,
+        )
+        .await
+        {
+            Ok(_) => {}
+            Err(e) => return Err(e),
+        }
+
+        imported.push(OpenPgpKey::from_cert(&cert, now));
+    }
+
+    return Ok(imported);
+}
+
+/// Keys of the keyring of an account.
+pub async fn get_keys(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+) -> Result<Vec<OpenPgpKey>, MyError> {
+    let certs = match keyring(database_conn, credentials, client).await {
+        Ok(certs) => certs,
+        Err(e) => return Err(e),
+    };
+
+    let now = Utc::now().timestamp();
+
+    return Ok(certs
+        .iter()
+        .map(|cert| OpenPgpKey::from_cert(cert, now))
+        .collect());
+}
+
+pub async fn delete_key(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    client: &Client,
+    fingerprint: &str,
+) -> Result<(), MyError> {
+    let fingerprint: String = fingerprint
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase();
+
+    match database::openpgp_keys::remove(database_conn, client, &fingerprint).await {
+        Ok(true) => return Ok(()),
+        Ok(false) => {
+            let err = MyError::Code(
+                ErrorCode::Nonexistent,
+                String::from("No key with this fingerprint"),
+                String::from("Error deleting OpenPGP key"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+        Err(e) => return Err(e),
+    }
+}
+
+/// Keys of an account with their secret keys unlocked, for decrypting and
+/// signing. Stored keys that no longer parse are left out.
+pub async fn keyring(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+) -> Result<Vec<Cert>, MyError> {
+    let stored = match database::openpgp_keys::get(database_conn, credentials, client).await {
+        Ok(stored) => stored,
+        Err(e) => return Err(e),
+    };
+
+    let mut certs: Vec<Cert> = vec![];
+
+    for key in stored {
+        let armored = key.secret_key.as_ref().unwrap_or(&key.public_key);
+
+        let mut cert = match key::read_armored(armored) {
+            Ok(certs) => match certs
+                .into_iter()
+                .find(|cert| cert.fingerprint() == key.fingerprint)
+            {
+                Some(cert) => cert,
+                None => continue,
+            },
+            // Already logged.
+            Err(_) => continue,
+        };
+
+        if let Some(passphrase) = &key.passphrase {
+            if let Err(e) = cert.unlock(passphrase) {
+                e.log_error();
+            }
+        }
+
+        certs.push(cert);
+    }
+
+    return Ok(certs);
+}
+
+/// `source` signed with the key of its From address and, when `encrypt` is
+/// set, encrypted to the keys of its recipients and the sender. Recipients
+/// without a key in the keyring are encrypted to with their Autocrypt key.
+/// Fails when a key is missing rather than sending the message unprotected.
+///
+/// The Autocrypt header of the sender is added, and a message encrypted to
+/// several recipients gossips their keys to each other inside the
+/// encryption (Autocrypt Level 1 section 2.7).
+pub async fn protect_message(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+    source: &str,
+    sign: bool,
+    encrypt: bool,
+) -> Result<ProtectedMessage, MyError> {
+    if !sign && !encrypt {
+        return Err(invalid("Nothing to do, sign or encrypt must be set"));
+    }
+
+    let certs = match keyring(Arc::clone(&database_conn), credentials, client).await {
+        Ok(certs) => certs,
+        Err(e) => return Err(e),
+    };
+
+    let message_headers = headers::parse(source);
+    let addresses = |name: &str| -> Vec<String> {
+        return headers::first(&message_headers, name)
+            .map(parse_address::parse_list)
+            .unwrap_or_default()
+            .iter()
+            .map(|address| address.address())
+            .filter(|address| !address.is_empty())
+            .collect();
+    };
+
+    let from = match addresses("From").first() {
+        Some(from) => from.clone(),
+        None => return Err(invalid("The message has no From address")),
+    };
+
+    let now = Utc::now().timestamp();
+
+    let signer = match sign {
+        true => match certs
+            .iter()
+            .find(|cert| cert.has_address(&from) && cert.signing_key(now).is_some())
+        {
+            Some(signer) => Some(signer),
+            None => return Err(invalid(&format!("No secret key to sign as {}", from))),
+        },
+        false => None,
+    };
+
+    let prefer_encrypt = match database::autocrypt::get_preference(
+        Arc::clone(&database_conn),
+        &client.username,
+        &client.address,
+    )
+    .await
+    {
+        Ok(prefer_encrypt) => prefer_encrypt,
+        Err(e) => return Err(e),
+    };
+
+    let mut source = source.to_string();
+    if headers::first(&message_headers, autocrypt::HEADER).is_none() {
+        if let Some(header) = inbox_client::autocrypt::own_header(&certs, &from, prefer_encrypt) {
+            source = autocrypt::add_headers(&source, &header);
+        }
+    }
+
+    let result = if encrypt {
+        let visible = [addresses("To"), addresses("Cc")].concat();
+        let mut keys: Vec<(String, Cert)> = vec![];
+        let mut missing: Vec<String> = vec![];
+
+        for address in [visible.clone(), addresses("Bcc")].concat() {
+            if let Some(cert) = encryption_cert(&certs, &address, now) {
+                keys.push((address, cert.clone()));
+                continue;
+            }
+
+            match database::autocrypt::get(
+                Arc::clone(&database_conn),
+                &client.username,
+                &client.address,
+                &address,
+            )
+            .await
+            {
+                Ok(peer) => match peer.and_then(|peer| peer.cert(now * 1000)) {
+                    Some(cert) => keys.push((address, cert)),
+                    None => missing.push(address),
+                },
+                Err(e) => return Err(e),
+            }
+        }
+
+        if !missing.is_empty() {
+            return Err(invalid(&format!("No key for {}", missing.join(", "))));
+        }
+
+        if keys.is_empty() {
+            return Err(invalid("The message has no recipients"));
+        }
+
+        // Bcc recipients stay hidden from the others.
+        if visible.len() > 1 {
+            let mut gossip = String::new();
+            for (address, cert) in keys.iter().filter(|(address, _)| visible.contains(address)) {
+                let header = AutocryptHeader {
+                    addr: address.to_lowercase(),
+                    prefer_encrypt: PreferEncrypt::NoPreference,
+                    keydata: cert.public_bytes(),
+                };
+                gossip.push_str(&autocrypt::format(autocrypt::GOSSIP_HEADER, &header));
+            }
+            source = autocrypt::add_headers(&source, &gossip);
+        }
+
+        let mut recipients: Vec<&Cert> = vec![];
+        for (_, cert) in &keys {
+            if !recipients.contains(&cert) {
+                recipients.push(cert);
+            }
+        }
+
+        // The sender reads their sent message with their own key.
+        if let Some(own) = encryption_cert(&certs, &from, now) {
+            if !recipients.contains(&own) {
+                recipients.push(own);
+            }
+        }
+
+        pgp_mime::encrypt(&source, &recipients, signer, now)
+    } else {
+        pgp_mime::sign(&source, signer.unwrap(), now)
+    };
+
+    match result {
+        Ok(source) => return Ok(ProtectedMessage { source }),
+        Err(e) => return Err(e),
+    }
+}
+
+/// Newest key of `address` that can encrypt.
+pub fn encryption_cert<'a>(certs: &'a [Cert], address: &str, now: i64) -> Option<&'a Cert> {
+    return certs
+        .iter()
+        .filter(|cert| cert.has_address(address) && !cert.encryption_keys(now).is_empty())
+        .max_by_key(|cert| cert.primary.public.created);
+}
+
+fn invalid(message: &str) -> MyError {
+    let err = MyError::Code(
+        ErrorCode::InvalidRequest,
+        message.to_string(),
+        String::from("Error processing OpenPGP request"),
+    );
+    err.log_error();
+
+    return err;
+}
@@ -0,0 +1,585 @@
+use async_imap::error::Error as ImapError;
+use async_std::channel::{self, Receiver, Sender};
+use async_std::sync::{Arc, Mutex};
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex as SyncMutex;
+use std::time::{Duration, Instant};
+
+use crate::database::credentials::CredentialStore;
+use crate::inbox_client;
+use crate::inbox_client::oauth::{self, TokenState};
+use crate::inbox_client::supervisor;
+use crate::my_error::MyError;
+use crate::types::account_state::AccountHealth;
+use crate::types::error_code::ErrorCode;
+use crate::types::session::{AuthMechanism, Client, Session};
+
+pub const DEFAULT_POOL_SIZE: usize = 4;
+
+/// How long a connection may sit in the pool before it is checked with a
+/// NOOP, servers drop idle connections without telling the client.
+const IDLE_CHECK: Duration = Duration::from_secs(60);
+
+pub struct PoolConnection {
+    pub session: Session,
+    pub selected: Option<String>,
+    idle_since: Instant,
+}
+
+impl PoolConnection {
+    pub async fn select(&mut self, mailbox_path: &str) -> Result<(), async_imap::error::Error> {
+        if self.selected.as_deref() == Some(mailbox_path) {
+            return Ok(());
+        }
+
+        self.selected = None;
+        match self.session.select(mailbox_path).await {
+            Ok(_) => {}
+            Err(e) => return Err(e),
+        };
+        self.selected = Some(mailbox_path.to_string());
+
+        return Ok(());
+    }
+}
+
+pub struct SessionPool {
+    client: Client,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: Arc<CredentialStore>,
+    max_size: usize,
+    idle_check: Duration,
+    token_state: SyncMutex<TokenState>,
+    health: Arc<SyncMutex<AccountHealth>>,
+    slots_tx: Sender<Option<PoolConnection>>,
+    slots_rx: Receiver<Option<PoolConnection>>,
+}
+
+pub struct PooledSession {
+    connection: Option<PoolConnection>,
+    /// Whether the connection was taken from the pool rather than opened for
+    /// this session.
+    reused: bool,
+    health: Arc<SyncMutex<AccountHealth>>,
+    slots_tx: Sender<Option<PoolConnection>>,
+}
+
+impl SessionPool {
+    pub fn new(
+        client: &Client,
+        database_conn: Arc<Mutex<rusqlite::Connection>>,
+        credentials: Arc<CredentialStore>,
+        max_size: usize,
+    ) -> SessionPool {
+        let max_size = max_size.max(1);
+        let (slots_tx, slots_rx) = channel::bounded(max_size);
+
+        // Every slot starts out empty, a connection is only opened once a slot is acquired.
+        for _ in 0..max_size {
+            let _ = slots_tx.try_send(None);
+        }
+
+        return SessionPool {
+            client: client.clone(),
+            database_conn,
+            credentials,
+            max_size,
+            idle_check: IDLE_CHECK,
+            token_state: SyncMutex::new(TokenState::default()),
+            health: Arc::new(SyncMutex::new(AccountHealth::new())),
+            slots_tx,
+            slots_rx,
+        };
+    }
+
+    pub fn client(&self) -> &Client {
+        return &self.client;
+    }
+
+    pub fn health(&self) -> &SyncMutex<AccountHealth> {
+        return &self.health;
+    }
+
+    pub fn credentials(&self) -> &CredentialStore {
+        return &self.credentials;
+    }
+
+    pub async fn acquire(&self) -> Result<PooledSession, MyError> {
+        let mut slot = match self.slots_rx.recv().await {
+            Ok(slot) => slot,
+            Err(e) => {
+                let err = MyError::Code(
+                    ErrorCode::InvalidSession,
+                    e.to_string(),
+                    String::from("Session pool closed"),
+                );
+                err.log_error();
+
+                return Err(err);
+            }
+        };
+
+        // A connection that was idle for a while is checked before it is
+        // handed out, the cached mailbox would otherwise skip every command
+        // until the first fetch fails on a connection the server closed.
+        if let Some(connection) = slot.as_mut() {
+            if connection.idle_since.elapsed() >= self.idle_check {
+                match connection.session.run_command_and_check_ok("NOOP").await {
+                    Ok(_) => {}
+                    Err(_) => slot = None,
+                };
+            }
+        }
+
+        let mut pooled_session = PooledSession {
+            reused: slot.is_some(),
+            connection: slot,
+            health: Arc::clone(&self.health),
+            slots_tx: self.slots_tx.clone(),
+        };
+
+        if pooled_session.connection.is_none() {
+            match supervisor::begin_connect(&self.health, supervisor::now()) {
+                Ok(_) => {}
+                Err(e) => return Err(e),
+            };
+
+            let access_token = match self.client.auth_mechanism {
+                AuthMechanism::Password => None,
+                _ => match oauth::access_token(
+                    Arc::clone(&self.database_conn),
+                    &self.credentials,
+                    &self.client,
+                    &self.token_state,
+                )
+                .await
+                {
+                    Ok(access_token) => Some(access_token),
+                    Err(e) => {
+                        supervisor::record_failure(&self.health, &e, supervisor::now());
+
+                        return Err(e);
+                    }
+                },
+            };
+
+            let session =
+                match inbox_client::connect::imap(&self.client, access_token.as_deref()).await {
+                    Ok(session) => session,
+                    Err(e) => {
+                        // A rejected token is refreshed before the next attempt.
+                        oauth::invalidate(&self.token_state);
+                        supervisor::record_failure(&self.health, &e, supervisor::now());
+
+                        return Err(e);
+                    }
+                };
+
+            supervisor::record_connected(&self.health);
+
+            pooled_session.connection = Some(PoolConnection {
+                session,
+                selected: None,
+                idle_since: Instant::now(),
+            });
+        }
+
+        return Ok(pooled_session);
+    }
+
+    /// A session with `mailbox_path` selected. A connection from the pool may
+    /// have been dropped by the server while idle, it is replaced once; a new
+    /// connection that fails is left to the supervisor's backoff.
+    pub async fn acquire_mailbox(&self, mailbox_path: &str) -> Result<PooledSession, MyError> {
+        let mut retried = false;
+
+        loop {
+            let mut session = match self.acquire().await {
+                Ok(session) => session,
+                Err(e) => return Err(e),
+            };
+
+            match session.select(mailbox_path).await {
+                Ok(_) => return Ok(session),
+                Err(e) => {
+                    let lost = is_connection_lost(&e);
+                    let err = session.command_failed(e, "Error selecting mailbox");
+
+                    if !lost || !session.reused || retried {
+                        return Err(err);
+                    }
+
+                    retried = true;
+                }
+            }
+        }
+    }
+
+    pub async fn close(&self) -> Result<(), MyError> {
+        let mut result = Ok(());
+
+        for _ in 0..self.max_size {
+            let slot = match self.slots_rx.recv().await {
+                Ok(slot) => slot,
+                Err(_) => break,
+            };
+
+            if let Some(mut connection) = slot {
+                match connection.session.logout().await {
+                    Ok(_) => {}
+                    Err(e) => {
+                        let err = MyError::Imap(e, String::from("Error logging out"));
+                        err.log_error();
+
+                        result = Err(err);
+                    }
+                }
+            }
+        }
+
+        self.slots_rx.close();
+
+        return result;
+    }
+}
+
+impl PooledSession {
+    /// Drops the underlying connection instead of returning it to the pool,
+    /// the freed slot will open a new connection on its next use.
+    pub fn discard(&mut self) {
+        self.connection = None;
+    }
+
+    pub fn health(&self) -> &SyncMutex<AccountHealth> {
+        return &self.health;
+    }
+
+    /// Called once a command got its response, the server is reachable again.
+    pub fn record_success(&self) {
+        supervisor::record_success(&self.health);
+    }
+
+    /// Error for a failed command. A connection that was lost, or may be out
+    /// of step with the server, is discarded and a lost one counts towards
+    /// the account's backoff.
+    pub fn command_failed(&mut self, e: ImapError, context: &str) -> MyError {
+        let lost = is_connection_lost(&e);
+
+        if !matches!(e, ImapError::No(_) | ImapError::Bad(_)) {
+            self.discard();
+        }
+
+        let err = match lost {
+            true => MyError::Imap(e, String::from("Connection to IMAP server lost")),
+            false => MyError::Imap(e, context.to_string()),
+        };
+        err.log_error();
+
+        if lost {
+            supervisor::record_failure(&self.health, &err, supervisor::now());
+        }
+
+        return err;
+    }
+}
+
+impl Deref for PooledSession {
+    type Target = PoolConnection;
+
+    fn deref(&self) -> &PoolConnection {
+        return self.connection.as_ref().unwrap();
+    }
+}
+
+impl DerefMut for PooledSession {
+    fn deref_mut(&mut self) -> &mut PoolConnection {
+        return self.connection.as_mut().unwrap();
+    }
+}
+
+impl Drop for PooledSession {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.as_mut() {
+            connection.idle_since = Instant::now();
+        }
+
+        let _ = self.slots_tx.try_send(self.connection.take());
+    }
+}
+
+fn is_connection_lost(e: &ImapError) -> bool {
+    return matches!(e, ImapError::ConnectionLost | ImapError::Io(_));
+}
+
+pub async fn get(
+    sessions: &Arc<Mutex<Vec<Arc<SessionPool>>>>,
+    session_id: usize,
+) -> Result<Arc<SessionPool>, MyError> {
+    let locked_sessions = sessions.lock().await;
+
+    match locked_sessions.get(session_id) {
+        Some(pool) => return Ok(Arc::clone(pool)),
+        None => {
+            let err = MyError::Code(
+                ErrorCode::InvalidSession,
+                String::from("Out of bounds array access"),
+                String::from("Invalid session ID"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+pub async fn acquire(
+    sessions: &Arc<Mutex<Vec<Arc<SessionPool>>>>,
+    session_id: usize,
+) -> Result<PooledSession, MyError> {
+    let pool = match get(sessions, session_id).await {
+        Ok(pool) => pool,
+        Err(e) => return Err(e),
+    };
+
+    return pool.acquire().await;
+}
+
+pub async fn acquire_mailbox(
+    sessions: &Arc<Mutex<Vec<Arc<SessionPool>>>>,
+    session_id: usize,
+    mailbox_path: &str,
+) -> Result<PooledSession, MyError> {
+    let pool = match get(sessions, session_id).await {
+        Ok(pool) => pool,
+        Err(e) => return Err(e),
+    };
+
+    return pool.acquire_mailbox(mailbox_path).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_native_tls::{Identity, TlsAcceptor};
+    use async_std::future;
+    use async_std::io::{BufReader, Read, Write};
+    use async_std::net::TcpListener;
+    use async_std::prelude::*;
+    use async_std::task;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::database;
+    use crate::database::credentials::KeySource;
+    use crate::inbox_client::connect;
+    use crate::types::account_state::AccountState;
+    use crate::types::session::Security;
+
+    struct StandIn {
+        port: u16,
+        fingerprint: String,
+        connections: Arc<AtomicUsize>,
+    }
+
+    /// Answers commands until the client logs out, a NOOP or a SELECT of
+    /// `Broken` breaks off the connection in the middle of a response instead
+    /// and a SELECT of `Idle` closes it once answered.
+    async fn reply_to_commands<S: Read + Write + Unpin>(stream: S) {
+        let mut reader = BufReader::new(stream);
+
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).await.unwrap_or(0) == 0 {
+                return;
+            }
+
+            let mut words = line.split_whitespace();
+            let tag = words.next().unwrap_or("").to_string();
+            let command = words.next().unwrap_or("").to_uppercase();
+            let argument = words.next().unwrap_or("").trim_matches('"').to_string();
+
+            if command == "NOOP" || argument == "Broken" {
+                let _ = reader.get_mut().write_all(b"* 1 EXI").await;
+                return;
+            }
+
+            let response = match command.as_str() {
+                "SELECT" => format!(
+                    "* 0 EXISTS\r\n* 0 RECENT\r\n{} OK [READ-WRITE] SELECT completed\r\n",
+                    tag
+                ),
+                "LOGOUT" => format!("* BYE\r\n{} OK LOGOUT completed\r\n", tag),
+                _ => format!("{} OK {} completed\r\n", tag, command),
+            };
+
+            let stream = reader.get_mut();
+            if stream.write_all(response.as_bytes()).await.is_err() {
+                return;
+            }
+            let _ = stream.flush().await;
+
+            if command == "LOGOUT" || argument == "Idle" {
+                return;
+            }
+        }
+    }
+
+    async fn stand_in() -> StandIn {
+        let certified_key =
+            rcgen::generate_simple_self_signed(vec![String::from("localhost")]).unwrap();
+        let identity = Identity::from_pkcs8(
+            certified_key.cert.pem().as_bytes(),
+            certified_key.key_pair.serialize_pem().as_bytes(),
+        )
+        .unwrap();
+        let acceptor = TlsAcceptor::from(native_tls::TlsAcceptor::new(identity).unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = Arc::clone(&connections);
+
+        task::spawn(async move {
+            loop {
+                let (tcp_stream, _) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(_) => return,
+                };
+                accepted.fetch_add(1, Ordering::SeqCst);
+
+                let acceptor = acceptor.clone();
+                task::spawn(async move {
+                    let mut tls_stream = match acceptor.accept(tcp_stream).await {
+                        Ok(s) => s,
+                        Err(_) => return,
+                    };
+
+                    if tls_stream.write_all(b"* OK ready\r\n").await.is_err() {
+                        return;
+                    }
+
+                    reply_to_commands(tls_stream).await;
+                });
+            }
+        });
+
+        return StandIn {
+            port,
+            fingerprint: connect::certificate_fingerprint(certified_key.cert.der()),
+            connections,
+        };
+    }
+
+    async fn get_pool(stand_in: &StandIn, max_size: usize) -> SessionPool {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        database::initialise(&conn).await.unwrap();
+
+        let source = KeySource::Passphrase(String::from("test"));
+        let credentials = Arc::new(CredentialStore::open(&conn, &source).unwrap());
+
+        let client = Client {
+            address: String::from("localhost"),
+            port: stand_in.port,
+            username: String::from("username"),
+            password: String::from("password"),
+            security: Security::Tls,
+            cert_fingerprint: Some(stand_in.fingerprint.clone()),
+            auth_mechanism: AuthMechanism::Password,
+            oauth: None,
+        };
+
+        return SessionPool::new(&client, Arc::new(Mutex::new(conn)), credentials, max_size);
+    }
+
+    #[async_std::test]
+    async fn exhausted_pool_waits_for_slot() {
+        let stand_in = stand_in().await;
+        let pool = get_pool(&stand_in, 1).await;
+
+        let session = pool.acquire().await.unwrap();
+
+        // The only slot is taken, a second caller waits for it.
+        assert!(future::timeout(Duration::from_millis(200), pool.acquire())
+            .await
+            .is_err());
+
+        task::spawn(async move {
+            task::sleep(Duration::from_millis(50)).await;
+            drop(session);
+        });
+
+        assert!(pool.acquire().await.is_ok());
+        assert_eq!(stand_in.connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[async_std::test]
+    async fn slot_returned_on_drop() {
+        let stand_in = stand_in().await;
+        let pool = get_pool(&stand_in, 1).await;
+
+        let mut session = pool.acquire().await.unwrap();
+        session.select("INBOX").await.unwrap();
+        drop(session);
+
+        // The connection is reused with its mailbox still selected.
+        let session = pool.acquire().await.unwrap();
+        assert_eq!(session.selected.as_deref(), Some("INBOX"));
+        assert_eq!(stand_in.connections.load(Ordering::SeqCst), 1);
+        drop(session);
+
+        pool.close().await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn broken_session_discarded() {
+        let stand_in = stand_in().await;
+        let pool = get_pool(&stand_in, 1).await;
+
+        let mut session = pool.acquire().await.unwrap();
+        let e = session.session.noop().await.unwrap_err();
+        session.command_failed(e, "Error checking connection");
+        drop(session);
+
+        assert_eq!(pool.health().lock().unwrap().state, AccountState::Offline);
+
+        // The freed slot opens a new connection.
+        let mut session = pool.acquire().await.unwrap();
+        assert_eq!(session.selected, None);
+        session.select("INBOX").await.unwrap();
+        assert_eq!(stand_in.connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[async_std::test]
+    async fn lost_connection_replaced_once() {
+        let stand_in = stand_in().await;
+        let pool = get_pool(&stand_in, 1).await;
+
+        let session = pool.acquire_mailbox("INBOX").await.unwrap();
+        session.record_success();
+        drop(session);
+
+        // The pooled connection is replaced once, then the error is returned
+        // and the account backs off.
+        assert!(pool.acquire_mailbox("Broken").await.is_err());
+        assert_eq!(stand_in.connections.load(Ordering::SeqCst), 2);
+
+        let health = pool.health().lock().unwrap();
+        assert_eq!(health.attempts, 2);
+        assert!(matches!(health.state, AccountState::Backoff(_)));
+    }
+
+    #[async_std::test]
+    async fn idle_connection_checked() {
+        let stand_in = stand_in().await;
+        let mut pool = get_pool(&stand_in, 1).await;
+        pool.idle_check = Duration::ZERO;
+
+        // The server closes the connection while it sits in the pool.
+        drop(pool.acquire_mailbox("Idle").await.unwrap());
+
+        // The failed NOOP replaces it before the cached mailbox is trusted.
+        let session = pool.acquire_mailbox("Idle").await.unwrap();
+        assert!(!session.reused);
+        assert_eq!(stand_in.connections.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.health().lock().unwrap().state, AccountState::Connected);
+    }
+}
@@ -0,0 +1,85 @@
+use async_std::sync::{Arc, Mutex};
+
+use crate::config::Prefetch;
+use crate::inbox_client::pool::SessionPool;
+use crate::inbox_client::{mailboxes, update_mailbox};
+use crate::my_error::MyError;
+use crate::types::session::Client;
+
+/// Syncs the mailboxes selected by the prefetch policy, meant to run in the
+/// background after an account connected.
+pub async fn prefetch(
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    session_id: usize,
+    client: &Client,
+    prefetch: Prefetch,
+    step_size: u32,
+) -> Result<(), MyError> {
+    if prefetch == Prefetch::None {
+        return Ok(());
+    }
+
+    let mailbox_paths = match mailboxes::update(
+        Arc::clone(&sessions),
+        Arc::clone(&database_conn),
+        session_id,
+        client,
+    )
+    .await
+    {
+        Ok(mailbox_paths) => mailbox_paths,
+        Err(e) => return Err(e),
+    };
+
+    for mailbox_path in selected(&mailbox_paths, prefetch) {
+        match update_mailbox::update_mailbox(
+            Arc::clone(&sessions),
+            Arc::clone(&database_conn),
+            session_id,
+            client,
+            mailbox_path,
+            false,
+            step_size,
+        )
+        .await
+        {
+            Ok(_) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    return Ok(());
+}
+
+/// INBOX is case-insensitive, RFC 3501 section 5.1.
+fn selected(mailbox_paths: &[String], prefetch: Prefetch) -> Vec<&String> {
+    match prefetch {
+        Prefetch::None => return vec![],
+        Prefetch::Inbox => {
+            return mailbox_paths
+                .iter()
+                .filter(|path| path.eq_ignore_ascii_case("INBOX"))
+                .collect()
+        }
+        Prefetch::All => return mailbox_paths.iter().collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selected_mailboxes() {
+        let mailbox_paths = vec![
+            String::from("Inbox"),
+            String::from("INBOX/Sub"),
+            String::from("Sent"),
+        ];
+
+        assert!(selected(&mailbox_paths, Prefetch::None).is_empty());
+        assert_eq!(selected(&mailbox_paths, Prefetch::Inbox), vec!["Inbox"]);
+        assert_eq!(selected(&mailbox_paths, Prefetch::All).len(), 3);
+    }
+}
@@ -0,0 +1,211 @@
+use async_std::sync::{Arc, Mutex};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::Utc;
+use openssl::x509::store::X509Store;
+
+use crate::config;
+use crate::database;
+use crate::database::credentials::CredentialStore;
+use crate::database::smime_identities::StoredIdentity;
+use crate::inbox_client;
+use crate::inbox_client::openpgp::ProtectedMessage;
+use crate::mime_parser::smime::{self, Identity};
+use crate::mime_parser::{headers, parse_address};
+use crate::my_error::MyError;
+use crate::types::error_code::ErrorCode;
+use crate::types::session::Client;
+use crate::types::smime_identity::SmimeIdentity;
+
+/// Imports the certificate and private key of a base64 encoded PKCS#12
+/// file.
+pub async fn import_identity(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+    pkcs12: &str,
+    password: &str,
+) -> Result<SmimeIdentity, MyError> {
+    let encoded: String = pkcs12.chars().filter(|c| !c.is_whitespace()).collect();
+    let der = match BASE64_STANDARD.decode(encoded) {
+        Ok(der) => der,
+        Err(_) => return Err(invalid("The PKCS#12 file is not base64 encoded")),
+    };
+
+    let identity = match Identity::from_pkcs12(&der, password) {
+        Ok(identity) => identity,
+        Err(MyError::String(detail, _)) => return Err(invalid(&detail)),
+        Err(e) => return Err(e),
+    };
+
+    let stored = StoredIdentity {
+        fingerprint: smime::fingerprint(&identity.certificate),
+        certificates: match identity.certificates_pem() {
+            Ok(certificates) => certificates,
+            Err(e) => return Err(e),
+        },
+        private_key: match identity.private_key_pem() {
+            Ok(private_key) => private_key,
+            Err(e) => return Err(e),
+        },
+    };
+
+    match database::smime_identities::insert(database_conn, credentials, client, &stored).await {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    }
+
+    let trust_store = match trust_store() {
+        Ok(trust_store) => trust_store,
+        Err(e) => return Err(e),
+    };
+
+    return Ok(SmimeIdentity::from_identity(&identity, &trust_store));
+}
+
+/// Identities of an account.
+pub async fn get_identities(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+) -> Result<Vec<SmimeIdentity>, MyError> {
+    let identities = match identities(database_conn, credentials, client).await {
+        Ok(identities) => identities,
+        Err(e) => return Err(e),
+    };
+
+    let trust_store = match trust_store() {
+        Ok(trust_store) => trust_store,
+        Err(e) => return Err(e),
+    };
+
+    return Ok(identities
+        .iter()
+        .map(|identity| SmimeIdentity::from_identity(identity, &trust_store))
+        .collect());
+}
+
+pub async fn delete_identity(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    client: &Client,
+    fingerprint: &str,
+) -> Result<(), MyError> {
+    // Fingerprints are often written with colons between the bytes.
+    let fingerprint: String = fingerprint
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':')
+        .collect::<String>()
+        .to_uppercase();
+
+    match database::smime_identities::remove(database_conn, client, &fingerprint).await {
+        Ok(true) => return Ok(()),
+        Ok(false) => {
+            let err = MyError::Code(
+                ErrorCode::Nonexistent,
+                String::from("No identity with this fingerprint"),
+                String::from("Error deleting S/MIME identity"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+        Err(e) => return Err(e),
+    }
+}
+
+/// Identities of an account for decrypting and signing. Stored identities
+/// that no longer parse are left out.
+pub async fn identities(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+) -> Result<Vec<Identity>, MyError> {
+    let stored = match database::smime_identities::get(database_conn, credentials, client).await {
+        Ok(stored) => stored,
+        Err(e) => return Err(e),
+    };
+
+    return Ok(stored
+        .iter()
+        // Already logged.
+        .filter_map(|identity| {
+            return Identity::from_pem(&identity.certificates, &identity.private_key).ok();
+        })
+        .collect());
+}
+
+/// CA certificates of the configured trust store.
+pub fn trust_store() -> Result<X509Store, MyError> {
+    return smime::trust_store(config::smime_trust_store());
+}
+
+/// `source` signed with the identity of its From address, with the Autocrypt
+/// header of the address when it has an OpenPGP key. S/MIME encryption
+/// needs the certificates of the recipients, which are not kept, so only
+/// signing is offered.
+pub async fn protect_message(
+    database_conn: Arc<Mutex<rusqlite::Connection>>,
+    credentials: &CredentialStore,
+    client: &Client,
+    source: &str,
+    sign: bool,
+    encrypt: bool,
+) -> Result<ProtectedMessage, MyError> {
+    if encrypt {
+        return Err(invalid("S/MIME encryption is not supported, only signing"));
+    }
+
+    if !sign {
+        return Err(invalid("Nothing to do, sign must be set"));
+    }
+
+    let message_headers = headers::parse(source);
+    let from = match headers::first(&message_headers, "From")
+        .map(parse_address::parse_list)
+        .unwrap_or_default()
+        .first()
+    {
+        Some(from) if !from.address().is_empty() => from.address().to_lowercase(),
+        _ => return Err(invalid("The message has no From address")),
+    };
+
+    let identities = match identities(Arc::clone(&database_conn), credentials, client).await {
+        Ok(identities) => identities,
+        Err(e) => return Err(e),
+    };
+
+    let now = Utc::now().timestamp();
+
+    let identity = identities.iter().find(|identity| {
+        return smime::addresses(&identity.certificate).contains(&from)
+            && smime::timestamp(identity.certificate.not_after()) > now;
+    });
+
+    let identity = match identity {
+        Some(identity) => identity,
+        None => return Err(invalid(&format!("No S/MIME identity to sign as {}", from))),
+    };
+
+    let source =
+        match inbox_client::autocrypt::add_own_header(database_conn, credentials, client, source)
+            .await
+        {
+            Ok(source) => source,
+            Err(e) => return Err(e),
+        };
+
+    match smime::sign(&source, identity) {
+        Ok(source) => return Ok(ProtectedMessage { source }),
+        Err(e) => return Err(e),
+    }
+}
+
+fn invalid(message: &str) -> MyError {
+    let err = MyError::Code(
+        ErrorCode::InvalidRequest,
+        message.to_string(),
+        String::from("Error processing S/MIME request"),
+    );
+    err.log_error();
+
+    return err;
+}
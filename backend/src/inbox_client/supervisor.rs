@@ -0,0 +1,299 @@
+use async_imap::error::Error as ImapError;
+use async_std::sync::{Arc, Mutex};
+use async_std::task;
+use rand::Rng;
+use std::sync::Mutex as SyncMutex;
+use std::time::Duration;
+
+use crate::inbox_client::pool::SessionPool;
+use crate::my_error::MyError;
+use crate::types::account_state::{AccountHealth, AccountState};
+use crate::types::error_code::ErrorCode;
+
+const BACKOFF_BASE_MS: i64 = 1000;
+const BACKOFF_MAX_MS: i64 = 5 * 60 * 1000;
+const SUPERVISOR_INTERVAL: Duration = Duration::from_secs(1);
+
+pub fn now() -> i64 {
+    return chrono::Utc::now().timestamp_millis();
+}
+
+/// Delay before the next connection attempt, `jitter` is expected in the range [0, 1).
+/// The first retry after a lost connection happens immediately.
+pub fn backoff_delay(attempts: u32, jitter: f64) -> i64 {
+    if attempts <= 1 {
+        return 0;
+    }
+
+    let exponent = (attempts - 2).min(20);
+    let delay = (BACKOFF_BASE_MS << exponent).min(BACKOFF_MAX_MS);
+
+    return delay / 2 + ((delay / 2) as f64 * jitter) as i64;
+}
+
+/// Called before opening a new connection, fails fast while the account is
+/// waiting for its next retry or its credentials were rejected.
+pub fn begin_connect(health: &SyncMutex<AccountHealth>, now: i64) -> Result<(), MyError> {
+    let mut health = health.lock().unwrap();
+
+    match health.state {
+        AccountState::AuthFailed => {
+            let err = MyError::Code(
+                ErrorCode::AuthenticationFailed,
+                health.last_error.clone().unwrap_or_default(),
+                String::from("Authentication failed, log in again to retry"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+        AccountState::Backoff(until) if until > now => {
+            let err = MyError::Backoff(
+                until,
+                format!("Next attempt in {} ms", until - now),
+                String::from("Server unreachable, waiting before reconnecting"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+        _ => {}
+    }
+
+    health.state = AccountState::Connecting;
+
+    return Ok(());
+}
+
+/// Called after logging in. The attempts are kept until a command succeeds,
+/// so a server that accepts connections and then drops them still backs off.
+pub fn record_connected(health: &SyncMutex<AccountHealth>) {
+    let mut health = health.lock().unwrap();
+
+    health.state = AccountState::Connected;
+    health.last_error = None;
+}
+
+pub fn record_success(health: &SyncMutex<AccountHealth>) {
+    let mut health = health.lock().unwrap();
+
+    health.state = AccountState::Connected;
+    health.attempts = 0;
+    health.last_error = None;
+}
+
+pub fn record_failure(health: &SyncMutex<AccountHealth>, err: &MyError, now: i64) {
+    let mut health = health.lock().unwrap();
+
+    health.last_error = Some(err.to_string());
+
+    if is_auth_error(err) {
+        health.state = AccountState::AuthFailed;
+        return;
+    }
+
+    health.attempts += 1;
+
+    let delay = backoff_delay(health.attempts, rand::thread_rng().gen::<f64>());
+
+    health.state = if delay == 0 {
+        AccountState::Offline
+    } else {
+        AccountState::Backoff(now + delay)
+    };
+}
+
+/// Marks a retry as started when the account is due for one, so a single
+/// background attempt is made at a time.
+pub fn claim_retry(health: &SyncMutex<AccountHealth>, now: i64) -> bool {
+    let mut health = health.lock().unwrap();
+
+    let due = match health.state {
+        AccountState::Offline => true,
+        AccountState::Backoff(until) => until <= now,
+        _ => false,
+    };
+
+    if due {
+        health.state = AccountState::Connecting;
+    }
+
+    return due;
+}
+
+/// Only rejected credentials stop the retries. Other NO and BAD responses,
+/// e.g. `[UNAVAILABLE]` or a proxy failing to reach the server, are transient.
+fn is_auth_error(err: &MyError) -> bool {
+    match err {
+        MyError::Imap(ImapError::No(text), _) | MyError::Imap(ImapError::Bad(text), _) => {
+            return matches!(
+                ErrorCode::from_imap_response(text),
+                Some(ErrorCode::AuthenticationFailed)
+                    | Some(ErrorCode::AuthorizationFailed)
+                    | Some(ErrorCode::Expired)
+            );
+        }
+        // A plain NO to LOGIN or AUTHENTICATE, see `connect::imap`.
+        MyError::Code(ErrorCode::AuthenticationFailed, _, _) => return true,
+        MyError::OAuth(_, _) => return true,
+        _ => return false,
+    }
+}
+
+pub async fn run(sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>) {
+    loop {
+        task::sleep(SUPERVISOR_INTERVAL).await;
+
+        let pools: Vec<Arc<SessionPool>> = sessions.lock().await.iter().cloned().collect();
+
+        for pool in pools {
+            if !claim_retry(pool.health(), now()) {
+                continue;
+            }
+
+            task::spawn(async move {
+                let mut session = match pool.acquire().await {
+                    Ok(session) => session,
+                    Err(e) => {
+                        e.log_error();
+                        return;
+                    }
+                };
+
+                match session.session.noop().await {
+                    Ok(_) => session.record_success(),
+                    Err(e) => {
+                        session.command_failed(e, "Error checking connection");
+                    }
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_health(state: AccountState, attempts: u32) -> SyncMutex<AccountHealth> {
+        SyncMutex::new(AccountHealth {
+            state,
+            attempts,
+            last_error: None,
+        })
+    }
+
+    #[test]
+    fn backoff_first_retry_immediate() {
+        assert_eq!(backoff_delay(0, 0.5), 0);
+        assert_eq!(backoff_delay(1, 0.5), 0);
+    }
+
+    #[test]
+    fn backoff_grows() {
+        assert_eq!(backoff_delay(2, 1.0), 1000);
+        assert_eq!(backoff_delay(3, 1.0), 2000);
+        assert_eq!(backoff_delay(4, 1.0), 4000);
+        assert_eq!(backoff_delay(4, 0.0), 2000);
+    }
+
+    #[test]
+    fn backoff_capped() {
+        assert_eq!(backoff_delay(40, 1.0), BACKOFF_MAX_MS);
+        assert_eq!(backoff_delay(u32::MAX, 0.0), BACKOFF_MAX_MS / 2);
+    }
+
+    #[test]
+    fn failure_then_backoff() {
+        let health = get_health(AccountState::Connected, 0);
+        let err = MyError::String(String::from("lost"), String::from("context"));
+
+        record_failure(&health, &err, 100);
+        assert_eq!(health.lock().unwrap().state, AccountState::Offline);
+
+        record_failure(&health, &err, 100);
+        match health.lock().unwrap().state {
+            AccountState::Backoff(until) => assert!(until > 100 && until <= 1100),
+            ref state => panic!("unexpected state {:?}", state),
+        }
+
+        assert!(begin_connect(&health, 100).is_err());
+        assert!(!claim_retry(&health, 100));
+        assert!(claim_retry(&health, 2000));
+        assert_eq!(health.lock().unwrap().state, AccountState::Connecting);
+    }
+
+    #[test]
+    fn auth_failure_stops_retrying() {
+        let health = get_health(AccountState::Connecting, 0);
+        let err = MyError::Imap(
+            ImapError::No(String::from("[AUTHENTICATIONFAILED] Invalid credentials")),
+            String::from("Error logging in"),
+        );
+
+        record_failure(&health, &err, 0);
+        assert_eq!(health.lock().unwrap().state, AccountState::AuthFailed);
+
+        assert!(begin_connect(&health, i64::MAX).is_err());
+        assert!(!claim_retry(&health, i64::MAX));
+    }
+
+    #[test]
+    fn transient_errors_keep_retrying() {
+        let transient = [
+            ImapError::No(String::from("[UNAVAILABLE] Try again later")),
+            ImapError::No(String::from("[INUSE] Mailbox in use")),
+            ImapError::No(String::from("[LIMIT] Too many connections")),
+            ImapError::Bad(String::from("Proxy error: backend unreachable")),
+            ImapError::No(String::from("SELECT failed")),
+        ];
+
+        for e in transient {
+            let health = get_health(AccountState::Connecting, 0);
+            let err = MyError::Imap(e, String::from("Error logging in"));
+
+            record_failure(&health, &err, 0);
+            assert_eq!(health.lock().unwrap().state, AccountState::Offline);
+        }
+
+        for text in [
+            "[AUTHORIZATIONFAILED] Not allowed",
+            "[EXPIRED] Password expired",
+        ] {
+            let err = MyError::Imap(
+                ImapError::No(String::from(text)),
+                String::from("Error logging in"),
+            );
+            assert!(is_auth_error(&err));
+        }
+
+        let plain_no = MyError::Code(
+            ErrorCode::AuthenticationFailed,
+            String::from("LOGIN failed"),
+            String::from("Error logging in"),
+        );
+        assert!(is_auth_error(&plain_no));
+    }
+
+    #[test]
+    fn connecting_keeps_attempts() {
+        let health = get_health(AccountState::Connecting, 3);
+
+        record_connected(&health);
+
+        let health = health.lock().unwrap();
+        assert_eq!(health.state, AccountState::Connected);
+        assert_eq!(health.attempts, 3);
+    }
+
+    #[test]
+    fn success_resets() {
+        let health = get_health(AccountState::Backoff(10), 5);
+
+        record_success(&health);
+
+        let health = health.lock().unwrap();
+        assert_eq!(health.state, AccountState::Connected);
+        assert_eq!(health.attempts, 0);
+    }
+}
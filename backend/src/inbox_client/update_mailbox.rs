@@ -3,24 +3,26 @@ use std::{collections::HashMap, u32, vec};
 
 use crate::database;
 use crate::inbox_client;
+use crate::inbox_client::pool::{self, SessionPool};
 use crate::my_error::MyError;
-use crate::types::database_request::{DatabaseRequest, MessageIdType, MessageReturnData};
+use crate::types::database_request::{DatabaseRequest, MessageIdType, MessageReturnData, Sort};
 use crate::types::fetch_mode::FetchMode;
 use crate::types::mailbox_changes::{ChangedSeqIdData, MailboxChanges};
 use crate::types::sequence_set::{SequenceSet, StartEnd};
-use crate::types::session::{Client, Session};
+use crate::types::session::Client;
 
 pub async fn update_mailbox(
-    sessions: Arc<Mutex<Vec<Session>>>,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
     session_id: usize,
     client: &Client,
     mailbox_path: &str,
     quick: bool,
+    step_size: u32,
 ) -> Result<MailboxChanges, MyError> {
     let sessions_2 = Arc::clone(&sessions);
     let (highest_seq, highest_seq_uid) =
-        match get_highest_seq_imap(sessions_2, session_id, client, mailbox_path).await {
+        match get_highest_seq_imap(sessions_2, session_id, mailbox_path).await {
             Ok(e) => e,
             Err(e) => return Err(e),
         };
@@ -40,7 +42,6 @@ pub async fn update_mailbox(
     }
 
     let mut end = 0;
-    let step_size = 20;
 
     loop {
         let mut start_end = StartEnd {
@@ -100,9 +101,8 @@ pub async fn update_mailbox(
 }
 
 async fn get_highest_seq_imap(
-    sessions: Arc<Mutex<Vec<Session>>>,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     session_id: usize,
-    client: &Client,
     mailbox_path: &str,
 ) -> Result<(u32, u32), MyError> {
     let sequence_set = SequenceSet {
@@ -117,7 +117,6 @@ async fn get_highest_seq_imap(
     let messages = match inbox_client::messages::get_imap_with_seq(
         sessions,
         session_id,
-        client,
         mailbox_path,
         &sequence_set,
         FetchMode::UID,
@@ -156,9 +155,11 @@ async fn get_highest_seq_db(
         mailbox_path: mailbox_path.to_string(),
         return_data: MessageReturnData::All,
         id_type: MessageIdType::MessageUids,
-        sorted: true,
+        sort: Some(Sort::received_desc()),
         start: None,
         end: None,
+        limit: None,
+        after: None,
         id_rarray: Some(vec![highest_seq_uid]),
         flag: None,
         not_flag: None,
@@ -186,7 +187,7 @@ async fn get_highest_seq_db(
 }
 
 async fn update_batch(
-    sessions: Arc<Mutex<Vec<Session>>>,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     session_id: usize,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
     client: &Client,
@@ -264,7 +265,7 @@ async fn update_batch(
 }
 
 async fn get_changes(
-    sessions: Arc<Mutex<Vec<Session>>>,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     session_id: usize,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
     client: &Client,
@@ -274,7 +275,6 @@ async fn get_changes(
     let fetches_imap = match inbox_client::messages::get_imap_with_seq(
         sessions,
         session_id,
-        client,
         mailbox_path,
         sequence_set,
         FetchMode::UID,
@@ -297,9 +297,11 @@ async fn get_changes(
         mailbox_path: mailbox_path.to_string(),
         return_data: MessageReturnData::All,
         id_type: MessageIdType::MessageUids,
-        sorted: true,
+        sort: Some(Sort::received_desc()),
         start: None,
         end: None,
+        limit: None,
+        after: None,
         id_rarray: Some(uids_imap.clone()),
         flag: None,
         not_flag: None,
@@ -332,9 +334,11 @@ async fn get_changes(
         mailbox_path: mailbox_path.to_string(),
         return_data: MessageReturnData::Uid,
         id_type: MessageIdType::SequenceIds,
-        sorted: false,
+        sort: None,
         start: None,
         end: None,
+        limit: None,
+        after: None,
         id_rarray: Some(seq_ids_to_remove),
         flag: None,
         not_flag: None,
@@ -371,17 +375,16 @@ async fn get_changes(
 }
 
 async fn get_new_messages(
-    sessions: Arc<Mutex<Vec<Session>>>,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     session_id: usize,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
     client: &Client,
     mailbox_path: &str,
-    new_message_uids: &Vec<u32>,
+    new_message_uids: &[u32],
 ) -> Result<(), MyError> {
     let messages = match inbox_client::messages::get_imap_with_uids(
-        sessions,
+        Arc::clone(&sessions),
         session_id,
-        client,
         mailbox_path,
         new_message_uids,
         FetchMode::ALL,
@@ -393,7 +396,7 @@ async fn get_new_messages(
     };
 
     match database::messages::insert(
-        database_conn,
+        Arc::clone(&database_conn),
         &client.username,
         &client.address,
         mailbox_path,
@@ -405,11 +408,38 @@ async fn get_new_messages(
         Err(e) => return Err(e),
     }
 
+    let protected_uids: Vec<u32> = messages
+        .iter()
+        .filter(|m| m.openpgp.is_some() || m.smime.is_some())
+        .map(|m| m.message_uid)
+        .collect();
+    if protected_uids.is_empty() {
+        return Ok(());
+    }
+
+    let pool = match pool::get(&sessions, session_id).await {
+        Ok(pool) => pool,
+        Err(e) => return Err(e),
+    };
+
+    match inbox_client::messages::store_security(
+        database_conn,
+        pool.credentials(),
+        client,
+        mailbox_path,
+        &protected_uids,
+    )
+    .await
+    {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    }
+
     return Ok(());
 }
 
 async fn update_flags(
-    sessions: Arc<Mutex<Vec<Session>>>,
+    sessions: Arc<Mutex<Vec<Arc<SessionPool>>>>,
     session_id: usize,
     database_conn: Arc<Mutex<rusqlite::Connection>>,
     client: &Client,
@@ -432,7 +462,6 @@ async fn update_flags(
     let messages = match inbox_client::messages::get_imap_with_uids(
         sessions,
         session_id,
-        client,
         mailbox_path,
         &message_uids_database,
         FetchMode::FLAGS,
@@ -447,7 +476,10 @@ async fn update_flags(
 
     for message in messages {
         let flags_database: Vec<String> =
-            flags_data.iter().map(|data| data.1.to_string()).collect();
+            match flags_data.iter().find(|data| data.0 == message.message_uid) {
+                Some(data) => data.1.clone(),
+                None => continue,
+            };
 
         let added_flags: Vec<String> = message
             .flags
@@ -498,7 +530,7 @@ async fn update_flags(
             };
         }
 
-        if !added_flags.is_empty() && !removed_flags.is_empty() {
+        if !added_flags.is_empty() || !removed_flags.is_empty() {
             flags_changed_uids.push(message.message_uid);
         }
     }
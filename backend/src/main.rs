@@ -1,34 +1,96 @@
 use async_std::sync::{Arc, Mutex};
+use async_std::task;
+use std::fs;
 
-use crate::types::session::Session;
+use crate::database::credentials::{CredentialStore, KeySource};
+use crate::inbox_client::pool::SessionPool;
 
+mod config;
 pub mod database;
 mod http_server {
+    pub mod api_token;
     mod handle_conn;
     pub mod http_server;
     mod params;
+    mod request;
+    mod response;
     pub mod to_display;
+    #[cfg(unix)]
+    mod unix_socket;
 }
 pub mod inbox_client;
 mod types {
+    pub mod account_state;
     pub mod database_request;
+    pub mod error_code;
     pub mod fetch_mode;
     pub mod mailbox_changes;
     pub mod message;
+    pub mod openpgp_key;
+    pub mod page_cursor;
     pub mod sequence_set;
     pub mod session;
+    pub mod smime_identity;
 }
 pub mod mime_parser {
+    pub mod autocrypt;
     pub mod decode;
+    pub mod entity;
+    pub mod headers;
+    pub mod html_to_text;
+    pub mod icalendar;
+    pub mod inline_parts;
+    pub mod itip;
     pub mod parse_address;
     pub mod parse_time;
     pub mod parser;
+    pub mod pgp_mime;
+    pub mod preview;
+    pub mod sanitize;
+    pub mod smime;
 }
 mod my_error;
+pub mod openpgp {
+    pub mod armor;
+    pub mod crypto;
+    pub mod key;
+    pub mod message;
+    pub mod packet;
+    pub mod signature;
+}
 
 #[async_std::main]
 async fn main() {
-    let database_conn = match database::new().await {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        print!("{}", config::USAGE);
+        return;
+    }
+
+    let config = match config::load(&args, |key| std::env::var(key).ok()) {
+        Ok(config) => Arc::new(config),
+        Err(errors) => {
+            for error in errors {
+                eprintln!("Invalid configuration: {}", error);
+            }
+            eprint!("\n{}", config::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    config::set_log_level(config.log_level);
+    if let Some(trust_store) = &config.smime_trust_store {
+        config::set_smime_trust_store(trust_store);
+    }
+
+    match fs::create_dir_all(&config.data_dir) {
+        Ok(_) => {}
+        Err(e) => panic!("Error creating data directory: {}", e),
+    };
+
+    let database_path = config.data_path(database::DEFAULT_DATABASE_FILE);
+    let database_conn = match database::new(&database_path).await {
         Ok(conn) => conn,
         Err(e) => panic!("Error opening database: {}", e),
     };
@@ -38,33 +100,95 @@ async fn main() {
         Err(e) => panic!("Error initialising database: {}", e),
     };
 
+    let key_source = KeySource::from_env(&config.data_dir);
+    let credentials = match CredentialStore::open(&database_conn, &key_source) {
+        Ok(credentials) => Arc::new(credentials),
+        Err(e) => panic!("Error opening credential store: {}", e),
+    };
+
     let database_conn = Arc::new(Mutex::new(database_conn));
 
+    let api_token = match http_server::api_token::read_or_create(
+        &http_server::api_token::path_from_env(&config.data_dir),
+    ) {
+        Ok(api_token) => Arc::new(api_token),
+        Err(e) => panic!("Error reading API token: {}", e),
+    };
+
+    // Persist secrets encrypted when the store was opened for the first time.
+    match database::backup(Arc::clone(&database_conn)).await {
+        Ok(_) => {}
+        Err(e) => panic!("Error backing up database: {}", e),
+    };
+
     let database_conn_2 = Arc::clone(&database_conn);
-    let clients = match database::connections::get(database_conn_2).await {
+    let clients = match database::connections::get(database_conn_2, &credentials).await {
         Ok(clients) => clients,
         Err(e) => panic!("Error getting connections: {}", e),
     };
 
     let nr_sessions = clients.len();
 
-    let sessions: Arc<Mutex<Vec<Session>>> = Arc::new(Mutex::new(Vec::new()));
+    let sessions: Arc<Mutex<Vec<Arc<SessionPool>>>> = Arc::new(Mutex::new(Vec::new()));
     let clients = Arc::new(Mutex::new(clients));
 
     for i in 0..nr_sessions {
         let sessions = Arc::clone(&sessions);
         let database_conn = Arc::clone(&database_conn);
-        let clients = Arc::clone(&clients);
+        let credentials = Arc::clone(&credentials);
 
         let locked_clients = clients.lock().await;
         let client = locked_clients[i].clone();
         drop(locked_clients);
 
-        match inbox_client::connect::connect(sessions, database_conn, clients, &client).await {
-            Ok(_) => {}
-            Err(e) => eprintln!("Error connecting to IMAP stored in local database: {:?}", e),
+        match inbox_client::connect::reconnect(
+            Arc::clone(&sessions),
+            Arc::clone(&database_conn),
+            credentials,
+            &client,
+            config.pool_size,
+        )
+        .await
+        {
+            Ok(_) => {
+                let config = Arc::clone(&config);
+
+                task::spawn(async move {
+                    match inbox_client::prefetch::prefetch(
+                        sessions,
+                        database_conn,
+                        i,
+                        &client,
+                        config.prefetch,
+                        config.sync_batch_size,
+                    )
+                    .await
+                    {
+                        Ok(_) => {}
+                        Err(e) => e.log_error(),
+                    }
+                });
+            }
+            Err(e) => eprintln!(
+                "Error connecting to IMAP stored in local database, retrying in background: {:?}",
+                e
+            ),
         }
     }
 
-    http_server::http_server::create_server(sessions, database_conn, clients).await;
+    task::spawn(inbox_client::supervisor::run(Arc::clone(&sessions)));
+
+    match http_server::http_server::create_server(
+        sessions,
+        database_conn,
+        credentials,
+        clients,
+        api_token,
+        config,
+    )
+    .await
+    {
+        Ok(_) => {}
+        Err(e) => panic!("Error starting HTTP server: {}", e),
+    };
 }
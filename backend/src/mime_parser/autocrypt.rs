@@ -0,0 +1,509 @@
+use base64::{prelude::BASE64_STANDARD, Engine};
+
+use crate::mime_parser::parse_address;
+use crate::openpgp::key::{self, Cert};
+use crate::types::message::Header;
+
+pub const HEADER: &str = "Autocrypt";
+pub const GOSSIP_HEADER: &str = "Autocrypt-Gossip";
+
+/// An Autocrypt key older than this many milliseconds before the last
+/// message of a peer is likely replaced by a client without Autocrypt,
+/// Level 1 section 2.4.1.
+const STALE: i64 = 35 * 24 * 60 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PreferEncrypt {
+    #[default]
+    NoPreference,
+    Mutual,
+}
+
+impl PreferEncrypt {
+    pub fn name(&self) -> &str {
+        match self {
+            PreferEncrypt::NoPreference => return "nopreference",
+            PreferEncrypt::Mutual => return "mutual",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<PreferEncrypt> {
+        match name {
+            "nopreference" => return Some(PreferEncrypt::NoPreference),
+            "mutual" => return Some(PreferEncrypt::Mutual),
+            _ => return None,
+        }
+    }
+}
+
+/// Attributes of a valid `Autocrypt` or `Autocrypt-Gossip` header.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AutocryptHeader {
+    /// Lowercase address the key belongs to.
+    pub addr: String,
+    pub prefer_encrypt: PreferEncrypt,
+    /// Binary OpenPGP public key.
+    pub keydata: Vec<u8>,
+}
+
+/// Autocrypt state of a peer, Level 1 section 2.3. Timestamps are
+/// milliseconds since the epoch, 0 when unset.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Peer {
+    /// Date of the newest message from the peer.
+    pub last_seen: i64,
+    /// Date of the newest message with an Autocrypt header from the peer.
+    pub autocrypt_timestamp: i64,
+    pub public_key: Option<Vec<u8>>,
+    pub prefer_encrypt: PreferEncrypt,
+    /// Date of the newest message gossiping a key of the peer.
+    pub gossip_timestamp: i64,
+    pub gossip_key: Option<Vec<u8>>,
+}
+
+impl Peer {
+    /// Updates the state with a message from the peer sent at `date`, with
+    /// its Autocrypt header if it has a valid one. Returns whether the state
+    /// changed.
+    pub fn update(&mut self, header: Option<&AutocryptHeader>, date: i64) -> bool {
+        if date < self.autocrypt_timestamp {
+            return false;
+        }
+
+        let before = self.clone();
+
+        if date > self.last_seen {
+            self.last_seen = date;
+        }
+
+        if let Some(header) = header {
+            self.autocrypt_timestamp = date;
+            self.public_key = Some(header.keydata.clone());
+            self.prefer_encrypt = header.prefer_encrypt;
+        }
+
+        return *self != before;
+    }
+
+    /// Updates the gossip key with an `Autocrypt-Gossip` header of a message
+    /// sent at `date`. Returns whether the state changed.
+    pub fn update_gossip(&mut self, header: &AutocryptHeader, date: i64) -> bool {
+        if date < self.gossip_timestamp {
+            return false;
+        }
+
+        let before = self.clone();
+
+        self.gossip_timestamp = date;
+        self.gossip_key = Some(header.keydata.clone());
+
+        return *self != before;
+    }
+
+    /// Key to encrypt to, the one of the peer's own header before a gossiped
+    /// one.
+    pub fn cert(&self, now: i64) -> Option<Cert> {
+        let keydata = self.public_key.as_ref().or(self.gossip_key.as_ref())?;
+
+        return key::parse(keydata)
+            .ok()?
+            .into_iter()
+            .next()
+            .filter(|cert| !cert.encryption_keys(now / 1000).is_empty());
+    }
+}
+
+/// Whether to encrypt to a recipient, from the weakest to the strongest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Recommendation {
+    /// No usable key.
+    Disable,
+    /// A key that might not be read anymore.
+    Discourage,
+    Available,
+    Encrypt,
+}
+
+impl Recommendation {
+    pub fn name(&self) -> &str {
+        match self {
+            Recommendation::Disable => return "disable",
+            Recommendation::Discourage => return "discourage",
+            Recommendation::Available => return "available",
+            Recommendation::Encrypt => return "encrypt",
+        }
+    }
+}
+
+/// Recommendation for encrypting to a peer, Level 1 section 2.4. `now` is in
+/// milliseconds since the epoch.
+pub fn recommend(
+    peer: &Peer,
+    own: PreferEncrypt,
+    reply_to_encrypted: bool,
+    now: i64,
+) -> Recommendation {
+    if peer.cert(now).is_none() {
+        return Recommendation::Disable;
+    }
+
+    let preliminary =
+        if peer.public_key.is_none() || peer.autocrypt_timestamp < peer.last_seen - STALE {
+            Recommendation::Discourage
+        } else {
+            Recommendation::Available
+        };
+
+    if reply_to_encrypted {
+        return Recommendation::Encrypt;
+    }
+
+    if preliminary == Recommendation::Available
+        && own == PreferEncrypt::Mutual
+        && peer.prefer_encrypt == PreferEncrypt::Mutual
+    {
+        return Recommendation::Encrypt;
+    }
+
+    return preliminary;
+}
+
+/// Recommendation for a message to all of `recipients`, the weakest one.
+pub fn combine(recipients: &[Recommendation]) -> Recommendation {
+    return recipients
+        .iter()
+        .copied()
+        .min()
+        .unwrap_or(Recommendation::Disable);
+}
+
+/// Attributes of a header value, None when it is not a valid Autocrypt
+/// header: `addr` or `keydata` missing or repeated, an unknown critical
+/// attribute (one not starting with `_`), or keydata that is not exactly one
+/// OpenPGP key. An unknown `prefer-encrypt` value means no preference.
+pub fn parse(value: &str) -> Option<AutocryptHeader> {
+    let mut addr: Option<String> = None;
+    let mut prefer_encrypt: Option<PreferEncrypt> = None;
+    let mut keydata: Option<Vec<u8>> = None;
+
+    for attribute in value.split(';') {
+        let (name, value) = attribute.split_once('=')?;
+        let name = name.trim().to_lowercase();
+
+        match name.as_str() {
+            "addr" if addr.is_none() => addr = Some(value.trim().to_lowercase()),
+            "prefer-encrypt" if prefer_encrypt.is_none() => {
+                prefer_encrypt = Some(PreferEncrypt::from_name(value.trim()).unwrap_or_default())
+            }
+            "keydata" if keydata.is_none() => {
+                let encoded: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+                keydata = Some(BASE64_STANDARD.decode(encoded).ok()?);
+            }
+            name if name.starts_with('_') => {}
+            _ => return None,
+        }
+    }
+
+    let addr = addr.filter(|addr| addr.contains('@'))?;
+    let keydata = keydata?;
+
+    match key::parse(&keydata) {
+        Ok(certs) if certs.len() == 1 => {}
+        _ => return None,
+    }
+
+    return Some(AutocryptHeader {
+        addr,
+        prefer_encrypt: prefer_encrypt.unwrap_or_default(),
+        keydata,
+    });
+}
+
+/// The Autocrypt header of a message from `from`. A message with several
+/// valid headers for the sender has none, Level 1 section 2.3.
+pub fn from_headers(headers: &[Header], from: &str) -> Option<AutocryptHeader> {
+    let from = from.to_lowercase();
+
+    let mut valid = headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case(HEADER))
+        .filter_map(|header| parse(&header.value))
+        .filter(|header| header.addr == from);
+
+    let header = valid.next()?;
+    if valid.next().is_some() {
+        return None;
+    }
+
+    return Some(header);
+}
+
+/// Valid `Autocrypt-Gossip` headers of a decrypted entity for addresses of
+/// `recipients`, the lowercase To and Cc addresses of the message.
+pub fn gossip(headers: &[Header], recipients: &[String]) -> Vec<AutocryptHeader> {
+    return headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case(GOSSIP_HEADER))
+        .filter_map(|header| parse(&header.value))
+        .filter(|header| recipients.contains(&header.addr))
+        .collect();
+}
+
+/// Whether a message with the Content-Type `content_type` updates the state
+/// of its sender, automated reports like bounces are left out.
+pub fn updates_peer(content_type: Option<&str>) -> bool {
+    return !content_type
+        .unwrap_or("")
+        .trim_start()
+        .to_lowercase()
+        .starts_with("multipart/report");
+}
+
+/// Lowercase address of a From header with exactly one address.
+pub fn sender(headers: &[Header]) -> Option<String> {
+    let from: Vec<String> = headers
+        .iter()
+        .filter(|header| header.name.eq_ignore_ascii_case("From"))
+        .flat_map(|header| parse_address::parse_list(&header.value))
+        .map(|address| address.address().to_lowercase())
+        .collect();
+
+    match from.as_slice() {
+        [from] if from.contains('@') => return Some(from.clone()),
+        _ => return None,
+    }
+}
+
+/// Header field `name` for `header`, folded with CRLF. Gossip headers leave
+/// out `prefer-encrypt`, Level 1 section 2.7.
+pub fn format(name: &str, header: &AutocryptHeader) -> String {
+    let mut field = format!("{}: addr={};", name, header.addr);
+
+    if name == HEADER && header.prefer_encrypt == PreferEncrypt::Mutual {
+        field.push_str(" prefer-encrypt=mutual;");
+    }
+
+    field.push_str(" keydata=");
+
+    let encoded = BASE64_STANDARD.encode(&header.keydata);
+    for line in encoded.as_bytes().chunks(76) {
+        field.push_str("\r\n ");
+        field.push_str(&String::from_utf8_lossy(line));
+    }
+    field.push_str("\r\n");
+
+    return field;
+}
+
+/// `source` with the CRLF terminated header fields `fields` added at the end
+/// of its header, with the line endings of the source.
+pub fn add_headers(source: &str, fields: &str) -> String {
+    let (fields, separator) = match source.contains("\r\n") {
+        true => (fields.to_string(), "\r\n\r\n"),
+        false => (fields.replace("\r\n", "\n"), "\n\n"),
+    };
+
+    match source.find(separator) {
+        Some(end) => {
+            let end = end + separator.len() / 2;
+            return format!("{}{}{}", &source[..end], fields, &source[end..]);
+        }
+        None => {
+            let newline = &separator[..separator.len() / 2];
+            let mut source = source.to_string();
+            if !source.is_empty() && !source.ends_with(newline) {
+                source.push_str(newline);
+            }
+
+            return format!("{}{}{}", source, fields, newline);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mime_parser::headers;
+
+    const ALICE_PUBLIC: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/openpgp/alice_public.asc"
+    ));
+    const BOB_PUBLIC: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/openpgp/bob_public.asc"
+    ));
+
+    const DAY: i64 = 24 * 60 * 60 * 1000;
+    const NOW: i64 = 1_900_000_000_000;
+
+    fn header(public: &str, addr: &str, prefer_encrypt: PreferEncrypt) -> AutocryptHeader {
+        return AutocryptHeader {
+            addr: addr.to_string(),
+            prefer_encrypt,
+            keydata: key::read_armored(public).unwrap()[0].public_bytes(),
+        };
+    }
+
+    #[test]
+    fn format_and_parse() {
+        let alice = header(ALICE_PUBLIC, "alice@example.com", PreferEncrypt::Mutual);
+        let field = format(HEADER, &alice);
+
+        assert!(field.starts_with(
+            "Autocrypt: addr=alice@example.com; prefer-encrypt=mutual; keydata=\r\n "
+        ));
+        assert!(field.lines().all(|line| line.len() <= 78));
+
+        let source = add_headers(
+            "From: Alice <Alice@Example.com>\r\nSubject: Hi\r\n\r\nBody\r\n",
+            &field,
+        );
+        assert!(source.ends_with("\r\n\r\nBody\r\n"));
+
+        let message_headers = headers::parse(&source);
+        assert_eq!(
+            sender(&message_headers),
+            Some(String::from("alice@example.com"))
+        );
+        assert_eq!(
+            from_headers(&message_headers, "alice@example.com"),
+            Some(alice.clone())
+        );
+        assert_eq!(from_headers(&message_headers, "bob@example.com"), None);
+
+        // Two headers for the sender cancel each other.
+        let twice = add_headers(&source, &field);
+        assert_eq!(
+            from_headers(&headers::parse(&twice), "alice@example.com"),
+            None
+        );
+
+        let gossip_field = format(GOSSIP_HEADER, &alice);
+        assert!(!gossip_field.contains("prefer-encrypt"));
+        let entity = add_headers("Content-Type: text/plain\n\nHi\n", &gossip_field);
+        assert!(!entity.contains('\r'));
+        let entity_headers = headers::parse(&entity);
+        assert_eq!(
+            gossip(&entity_headers, &[String::from("alice@example.com")])[0].prefer_encrypt,
+            PreferEncrypt::NoPreference
+        );
+        assert!(gossip(&entity_headers, &[String::from("bob@example.com")]).is_empty());
+    }
+
+    #[test]
+    fn invalid_headers() {
+        let keydata =
+            BASE64_STANDARD.encode(key::read_armored(BOB_PUBLIC).unwrap()[0].public_bytes());
+
+        assert!(parse(&format!("addr=bob@example.com; keydata={}", keydata)).is_some());
+        assert!(
+            parse(&format!(
+                "addr=bob@example.com; _note=hi; prefer-encrypt=maybe; keydata={}",
+                keydata
+            ))
+            .unwrap()
+            .prefer_encrypt
+                == PreferEncrypt::NoPreference
+        );
+        assert!(parse(&format!(
+            "addr=bob@example.com; critical=1; keydata={}",
+            keydata
+        ))
+        .is_none());
+        assert!(parse(&format!("keydata={}", keydata)).is_none());
+        assert!(parse("addr=bob@example.com; keydata=bm90IGEga2V5").is_none());
+        assert!(parse("addr=bob@example.com").is_none());
+
+        let report = "multipart/report; report-type=delivery-status; boundary=x";
+        assert!(!updates_peer(Some(report)));
+        assert!(updates_peer(Some("text/plain")));
+        assert!(updates_peer(None));
+
+        let two = headers::parse("From: a@example.com, b@example.com\r\n\r\n");
+        assert_eq!(sender(&two), None);
+    }
+
+    #[test]
+    fn peer_state() {
+        let bob = header(BOB_PUBLIC, "bob@example.com", PreferEncrypt::Mutual);
+        let mut peer = Peer::default();
+
+        assert_eq!(
+            recommend(&peer, PreferEncrypt::Mutual, false, NOW),
+            Recommendation::Disable
+        );
+
+        assert!(peer.update(None, NOW - 10 * DAY));
+        assert_eq!(peer.last_seen, NOW - 10 * DAY);
+        assert_eq!(peer.public_key, None);
+
+        assert!(peer.update(Some(&bob), NOW - 5 * DAY));
+        assert_eq!(peer.autocrypt_timestamp, NOW - 5 * DAY);
+        assert_eq!(peer.prefer_encrypt, PreferEncrypt::Mutual);
+        assert_eq!(
+            recommend(&peer, PreferEncrypt::Mutual, false, NOW),
+            Recommendation::Encrypt
+        );
+        assert_eq!(
+            recommend(&peer, PreferEncrypt::NoPreference, false, NOW),
+            Recommendation::Available
+        );
+
+        // Older messages change nothing.
+        assert!(!peer.update(None, NOW - 6 * DAY));
+        let nopreference = header(BOB_PUBLIC, "bob@example.com", PreferEncrypt::NoPreference);
+        assert!(!peer.update(Some(&nopreference), NOW - 6 * DAY));
+        assert_eq!(peer.prefer_encrypt, PreferEncrypt::Mutual);
+
+        // Mail without the header for long makes the key doubtful.
+        assert!(peer.update(None, NOW));
+        assert_eq!(peer.autocrypt_timestamp, NOW - 5 * DAY);
+        assert_eq!(
+            recommend(&peer, PreferEncrypt::Mutual, false, NOW + 40 * DAY),
+            Recommendation::Encrypt
+        );
+        assert!(peer.update(None, NOW + 40 * DAY));
+        assert_eq!(
+            recommend(&peer, PreferEncrypt::Mutual, false, NOW + 40 * DAY),
+            Recommendation::Discourage
+        );
+        assert_eq!(
+            recommend(&peer, PreferEncrypt::Mutual, true, NOW + 40 * DAY),
+            Recommendation::Encrypt
+        );
+    }
+
+    #[test]
+    fn gossip_state() {
+        let alice = header(
+            ALICE_PUBLIC,
+            "alice@example.com",
+            PreferEncrypt::NoPreference,
+        );
+        let mut peer = Peer::default();
+
+        assert!(peer.update_gossip(&alice, NOW));
+        assert!(!peer.update_gossip(&alice, NOW - DAY));
+        assert_eq!(peer.gossip_timestamp, NOW);
+        assert_eq!(
+            peer.cert(NOW).unwrap().user_id(),
+            "Alice <alice@example.com>"
+        );
+        assert_eq!(
+            recommend(&peer, PreferEncrypt::Mutual, false, NOW),
+            Recommendation::Discourage
+        );
+    }
+
+    #[test]
+    fn combined() {
+        use Recommendation::*;
+
+        assert_eq!(combine(&[Encrypt, Encrypt]), Encrypt);
+        assert_eq!(combine(&[Encrypt, Available]), Available);
+        assert_eq!(combine(&[Available, Discourage, Encrypt]), Discourage);
+        assert_eq!(combine(&[Encrypt, Disable]), Disable);
+        assert_eq!(combine(&[]), Disable);
+    }
+}
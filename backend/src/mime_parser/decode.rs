@@ -1,3 +1,9 @@
+use base64::{
+    prelude::{BASE64_STANDARD, BASE64_STANDARD_NO_PAD},
+    Engine,
+};
+use regex::Regex;
+
 pub fn to_u8(string: Option<&[u8]>) -> String {
     match string {
         Some(s) => match std::str::from_utf8(s) {
@@ -8,10 +14,120 @@ pub fn to_u8(string: Option<&[u8]>) -> String {
     }
 }
 
+/// Decodes the encoded words of a header value (RFC 2047). The whitespace
+/// between adjacent encoded words is dropped, words that cannot be decoded are
+/// left as they are.
+pub fn encoded_words(value: &str) -> String {
+    let re_word = Regex::new(r"=\?([^?\s]+)\?([bBqQ])\?([^?\s]*)\?=").unwrap();
+
+    let mut decoded = String::with_capacity(value.len());
+    let mut last_end = 0;
+    let mut last_was_word = false;
+
+    for caps in re_word.captures_iter(value) {
+        let word = caps.get(0).unwrap();
+        let between = &value[last_end..word.start()];
+
+        let text = match caps[2].to_ascii_lowercase().as_str() {
+            "b" => BASE64_STANDARD
+                .decode(&caps[3])
+                .or_else(|_| BASE64_STANDARD_NO_PAD.decode(caps[3].trim_end_matches('=')))
+                .ok(),
+            _ => Some(decode_q(&caps[3])),
+        };
+
+        match text {
+            Some(bytes) => {
+                if !(last_was_word && between.trim().is_empty()) {
+                    decoded.push_str(between);
+                }
+
+                decoded.push_str(&charset_to_string(&caps[1], &bytes));
+                last_was_word = true;
+            }
+            None => {
+                decoded.push_str(between);
+                decoded.push_str(word.as_str());
+                last_was_word = false;
+            }
+        }
+
+        last_end = word.end();
+    }
+
+    decoded.push_str(&value[last_end..]);
+
+    return decoded;
+}
+
+/// The Q encoding of RFC 2047 section 4.2, quoted-printable with `_` for a
+/// space.
+fn decode_q(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = match bytes[i] {
+            b'=' => bytes
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+
+        match (bytes[i], hex) {
+            (_, Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'_', None) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, None) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    return decoded;
+}
+
+/// Latin-1 maps bytes to the same code points, windows-1252 is taken as
+/// Latin-1 as well. Anything else is read as UTF-8.
+fn charset_to_string(charset: &str, bytes: &[u8]) -> String {
+    let charset = charset.split('*').next().unwrap_or("").to_lowercase();
+
+    match charset.as_str() {
+        "iso-8859-1" | "latin1" | "windows-1252" => {
+            return bytes.iter().map(|&byte| byte as char).collect()
+        }
+        _ => return String::from_utf8_lossy(bytes).to_string(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn encoded() {
+        assert_eq!(encoded_words("=?UTF-8?B?SsO2cmc=?= Doe"), "Jörg Doe");
+        assert_eq!(encoded_words("=?iso-8859-1?q?J=F6rg_Doe?="), "Jörg Doe");
+        assert_eq!(
+            encoded_words("=?utf-8?q?Hello?= \r\n =?utf-8?q?_World?= and =?utf-8?Q?more?="),
+            "Hello World and more"
+        );
+        assert_eq!(encoded_words("=?utf-8?B?SsO2cmc?="), "Jörg");
+        assert_eq!(
+            encoded_words("=?utf-8?B?!!!?= plain"),
+            "=?utf-8?B?!!!?= plain"
+        );
+        assert_eq!(encoded_words("plain = text?"), "plain = text?");
+    }
+
     #[test]
     fn u8() {
         assert_eq!(to_u8(Some(b"test")), String::from("test"));
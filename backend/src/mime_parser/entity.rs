@@ -0,0 +1,280 @@
+use base64::{prelude::BASE64_STANDARD, Engine};
+
+use crate::mime_parser::headers;
+use crate::types::message::Header;
+
+/// MIME entity of a message source. Parts keep their exact text, signatures
+/// of multipart/signed cover it byte for byte (RFC 1847).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entity<'a> {
+    pub raw: &'a str,
+    pub headers: Vec<Header>,
+    pub body: &'a str,
+}
+
+/// Headers and body of `raw`, split at the first empty line.
+pub fn parse(raw: &str) -> Entity<'_> {
+    let mut offset = 0;
+    let mut body_start = raw.len();
+
+    for line in raw.split_inclusive('\n') {
+        if line.trim_end_matches(['\r', '\n']).is_empty() {
+            body_start = offset + line.len();
+            break;
+        }
+
+        offset += line.len();
+    }
+
+    let header_end = body_start.min(raw.len());
+
+    return Entity {
+        raw,
+        headers: headers::parse(&raw[..header_end]),
+        body: &raw[header_end..],
+    };
+}
+
+impl<'a> Entity<'a> {
+    /// Media type in lowercase, text/plain when there is no Content-Type.
+    pub fn content_type(&self) -> String {
+        match headers::first(&self.headers, "Content-Type") {
+            Some(value) => {
+                let media_type = value.split(';').next().unwrap_or("").trim();
+
+                if media_type.is_empty() {
+                    return String::from("text/plain");
+                }
+
+                return media_type.to_lowercase();
+            }
+            None => return String::from("text/plain"),
+        }
+    }
+
+    /// Parameter of the Content-Type header, unquoted.
+    pub fn param(&self, name: &str) -> Option<String> {
+        let value = headers::first(&self.headers, "Content-Type")?;
+
+        return params(value)
+            .into_iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value);
+    }
+
+    /// Parts of a multipart entity, exactly as written between the
+    /// boundaries.
+    pub fn parts(&self) -> Vec<Entity<'a>> {
+        let boundary = match self.param("boundary") {
+            Some(boundary) if self.content_type().starts_with("multipart/") => boundary,
+            _ => return vec![],
+        };
+
+        let delimiter = format!("--{}", boundary);
+        let close = format!("--{}--", boundary);
+
+        let body: &'a str = self.body;
+        let mut parts: Vec<Entity<'a>> = vec![];
+        let mut start: Option<usize> = None;
+        let mut offset = 0;
+
+        for line in body.split_inclusive('\n') {
+            let content = line.trim_end();
+            let is_close = content == close;
+
+            if content == delimiter || is_close {
+                if let Some(start) = start {
+                    // The line break before a delimiter belongs to it.
+                    let mut end = offset;
+                    if body[..end].ends_with('\n') {
+                        end -= 1;
+                    }
+                    if body[..end].ends_with('\r') {
+                        end -= 1;
+                    }
+
+                    parts.push(parse(&body[start..end.max(start)]));
+                }
+
+                if is_close {
+                    break;
+                }
+
+                start = Some(offset + line.len());
+            }
+
+            offset += line.len();
+        }
+
+        return parts;
+    }
+
+    /// Body with its Content-Transfer-Encoding removed.
+    pub fn decoded(&self) -> Vec<u8> {
+        let encoding = headers::first(&self.headers, "Content-Transfer-Encoding")
+            .unwrap_or("")
+            .trim()
+            .to_lowercase();
+
+        match encoding.as_str() {
+            "base64" => {
+                let encoded: String = self.body.chars().filter(|c| !c.is_whitespace()).collect();
+
+                return BASE64_STANDARD
+                    .decode(encoded.trim_end_matches('='))
+                    .or_else(|_| BASE64_STANDARD.decode(&encoded))
+                    .unwrap_or_default();
+            }
+            "quoted-printable" => return quoted_printable(self.body),
+            _ => return self.body.as_bytes().to_vec(),
+        }
+    }
+
+    /// Decoded body as text, bytes that are not UTF-8 are taken as Latin-1.
+    pub fn text(&self) -> String {
+        let decoded = self.decoded();
+
+        match String::from_utf8(decoded) {
+            Ok(text) => return text,
+            Err(e) => return e.into_bytes().iter().map(|b| *b as char).collect(),
+        }
+    }
+}
+
+/// Parameters of a structured header value such as Content-Type.
+fn params(value: &str) -> Vec<(String, String)> {
+    let mut params: Vec<(String, String)> = vec![];
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut segments: Vec<String> = vec![];
+
+    for c in value.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            ';' if !quoted => segments.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    segments.push(current);
+
+    for segment in segments.iter().skip(1) {
+        if let Some((key, value)) = segment.split_once('=') {
+            let value = value.trim();
+            let value = match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+                Some(unquoted) => unquoted.replace("\\\"", "\"").replace("\\\\", "\\"),
+                None => value.to_string(),
+            };
+
+            params.push((key.trim().to_string(), value));
+        }
+    }
+
+    return params;
+}
+
+/// Decodes quoted-printable text, soft line breaks are removed.
+fn quoted_printable(body: &str) -> Vec<u8> {
+    let bytes = body.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'=' {
+            decoded.push(bytes[i]);
+            i += 1;
+
+            continue;
+        }
+
+        if bytes[i + 1..].starts_with(b"\r\n") {
+            i += 3;
+        } else if bytes[i + 1..].starts_with(b"\n") {
+            i += 2;
+        } else {
+            let hex = bytes
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+            match hex {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 3;
+                }
+                None => {
+                    decoded.push(b'=');
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    return decoded;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MULTIPART: &str = "From: a@example.com\r
+Content-Type: multipart/mixed;\r
+ boundary=\"outer; b\"\r
+\r
+Preamble\r
+--outer; b\r
+Content-Type: text/plain; charset=\"utf-8\"\r
+Content-Transfer-Encoding: quoted-printable\r
+\r
+caf=C3=A9 au =\r
+lait\r
+\r
+--outer; b\r
+Content-Type: application/octet-stream\r
+Content-Transfer-Encoding: base64\r
+\r
+aGVs\r
+bG8=\r
+--outer; b--\r
+Epilogue\r
+";
+
+    #[test]
+    fn multipart() {
+        let entity = parse(MULTIPART);
+
+        assert_eq!(entity.content_type(), "multipart/mixed");
+        assert_eq!(entity.param("Boundary").as_deref(), Some("outer; b"));
+
+        let parts = entity.parts();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(
+            parts[0].raw,
+            "Content-Type: text/plain; charset=\"utf-8\"\r\nContent-Transfer-Encoding: quoted-printable\r\n\r\ncaf=C3=A9 au =\r\nlait\r\n"
+        );
+        assert_eq!(parts[0].param("charset").as_deref(), Some("utf-8"));
+        assert_eq!(parts[0].text(), "café au lait\r\n");
+        assert_eq!(parts[1].decoded(), b"hello");
+    }
+
+    #[test]
+    fn single_part() {
+        let entity = parse("Subject: Hi\n\nBody\n");
+
+        assert_eq!(entity.content_type(), "text/plain");
+        assert_eq!(entity.body, "Body\n");
+        assert!(entity.parts().is_empty());
+
+        let entity = parse("Subject: no body");
+        assert_eq!(entity.body, "");
+        assert_eq!(entity.headers.len(), 1);
+
+        assert_eq!(parse("\r\nBody").body, "Body");
+        assert_eq!(
+            parse("Content-Type: text/plain\n\n=E9t=C3").text(),
+            "=E9t=C3"
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use base64::{prelude::BASE64_STANDARD, Engine};
+
+use crate::types::message::Header;
+
+/// Headers of a message source in their order, duplicates included. Folded
+/// lines are joined by removing the line break (RFC 5322 section 2.2.3), the
+/// values are not decoded.
+pub fn parse(source: &str) -> Vec<Header> {
+    let mut headers: Vec<Header> = vec![];
+
+    for line in source.lines() {
+        if line.is_empty() {
+            break;
+        }
+
+        if line.starts_with([' ', '\t']) {
+            if let Some(header) = headers.last_mut() {
+                header.value.push_str(line);
+            }
+
+            continue;
+        }
+
+        match line.split_once(':') {
+            Some((name, value)) if !name.is_empty() && !name.contains(' ') => {
+                headers.push(Header {
+                    name: name.to_string(),
+                    value: value.trim_start().to_string(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    for header in &mut headers {
+        header.value = header.value.trim_end().to_string();
+    }
+
+    return headers;
+}
+
+/// Value of the first header named `name`, the topmost one for trace headers
+/// like `Received`.
+pub fn first<'a>(headers: &'a [Header], name: &str) -> Option<&'a str> {
+    return headers
+        .iter()
+        .find(|header| header.name.eq_ignore_ascii_case(name))
+        .map(|header| header.value.as_str());
+}
+
+/// `text` with control characters as spaces, for a header value taken from
+/// elsewhere where a line break would start a header of its own.
+pub fn single_line(text: &str) -> String {
+    return text
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect();
+}
+
+/// Non-ASCII text as a single encoded word (RFC 2047), ASCII text as it is.
+pub fn encode_word(text: &str) -> String {
+    if text.is_ascii() {
+        return text.to_string();
+    }
+
+    return format!("=?utf-8?B?{}?=", BASE64_STANDARD.encode(text.as_bytes()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, value: &str) -> Header {
+        return Header {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+    }
+
+    #[test]
+    fn ordered_with_duplicates() {
+        let source = "Received: from mx2.example.com\r\n\tby mx1.example.net; Mon, 1 Jan 2024 10:00:01 +0000\r\nReceived: from client by mx2.example.com\r\nDKIM-Signature: v=1; a=rsa-sha256;\r\n b=abc\r\nSubject: Hi: there\r\nX-Empty:\r\n\r\nBody: not a header\r\n";
+
+        assert_eq!(
+            parse(source),
+            vec![
+                header(
+                    "Received",
+                    "from mx2.example.com\tby mx1.example.net; Mon, 1 Jan 2024 10:00:01 +0000"
+                ),
+                header("Received", "from client by mx2.example.com"),
+                header("DKIM-Signature", "v=1; a=rsa-sha256; b=abc"),
+                header("Subject", "Hi: there"),
+                header("X-Empty", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_lines() {
+        let source = "From line without colon\nTo: bob@example.com\n";
+
+        assert_eq!(parse(source), vec![header("To", "bob@example.com")]);
+    }
+
+    #[test]
+    fn encoded_words() {
+        assert_eq!(encode_word("Réunion"), "=?utf-8?B?UsOpdW5pb24=?=");
+        assert_eq!(encode_word("Meeting"), "Meeting");
+    }
+}
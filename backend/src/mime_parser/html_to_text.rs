@@ -0,0 +1,702 @@
+use html5ever::tendril::StrTendril;
+use html5ever::tokenizer::states::RawKind;
+use html5ever::tokenizer::{
+    BufferQueue, TagKind, Token, TokenSink, TokenSinkResult, Tokenizer, TokenizerOpts,
+};
+use std::cell::RefCell;
+
+/// Tables up to this width are laid out as columns, wider tables and tables
+/// used for layout get a paragraph per cell.
+const TABLE_WIDTH: usize = 100;
+
+/// Elements whose content is not shown.
+const HIDDEN_TAGS: [&str; 4] = ["script", "style", "title", "template"];
+
+/// Elements starting a new line.
+const LINE_TAGS: [&str; 12] = [
+    "div", "section", "article", "header", "footer", "nav", "aside", "center", "address", "dt",
+    "dd", "caption",
+];
+
+/// Elements separated from their surroundings by a blank line.
+const PARAGRAPH_TAGS: [&str; 10] = [
+    "p", "h1", "h2", "h3", "h4", "h5", "h6", "dl", "form", "figure",
+];
+
+/// Plain text rendering of the html of a message, for messages without a
+/// text part. Paragraphs and lists keep their layout, blockquotes are quoted
+/// with `>`, tables are laid out in columns and links are numbered with their
+/// targets listed at the end.
+pub fn render(html: &str) -> String {
+    let mut renderer = Renderer::new();
+
+    for event in tokenize(html) {
+        match event {
+            Event::Start(name, attributes) => renderer.start(&name, &attributes),
+            Event::End(name) => renderer.end(&name),
+            Event::Text(text) => renderer.text(&text),
+        }
+    }
+
+    return renderer.finish();
+}
+
+enum Event {
+    Start(String, Vec<(String, String)>),
+    End(String),
+    Text(String),
+}
+
+struct EventSink {
+    events: RefCell<Vec<Event>>,
+}
+
+impl TokenSink for EventSink {
+    type Handle = ();
+
+    fn process_token(&self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        let mut events = self.events.borrow_mut();
+
+        match token {
+            Token::TagToken(tag) => {
+                let name = tag.name.to_string();
+
+                if tag.kind == TagKind::EndTag {
+                    events.push(Event::End(name));
+
+                    return TokenSinkResult::Continue;
+                }
+
+                let attributes = tag
+                    .attrs
+                    .iter()
+                    .map(|attribute| {
+                        (
+                            attribute.name.local.to_string(),
+                            attribute.value.to_string(),
+                        )
+                    })
+                    .collect();
+                events.push(Event::Start(name.clone(), attributes));
+
+                if tag.self_closing {
+                    events.push(Event::End(name));
+
+                    return TokenSinkResult::Continue;
+                }
+
+                // The tokenizer needs to be told where raw text starts, which
+                // is otherwise the job of the tree builder.
+                match name.as_str() {
+                    "script" => return TokenSinkResult::RawData(RawKind::ScriptData),
+                    "style" | "xmp" | "iframe" | "noembed" | "noframes" => {
+                        return TokenSinkResult::RawData(RawKind::Rawtext)
+                    }
+                    "title" | "textarea" => return TokenSinkResult::RawData(RawKind::Rcdata),
+                    _ => {}
+                }
+            }
+            Token::CharacterTokens(text) => events.push(Event::Text(text.to_string())),
+            _ => {}
+        }
+
+        return TokenSinkResult::Continue;
+    }
+}
+
+fn tokenize(html: &str) -> Vec<Event> {
+    let input = BufferQueue::default();
+    input.push_back(StrTendril::from_slice(html));
+
+    let tokenizer = Tokenizer::new(
+        EventSink {
+            events: RefCell::new(vec![]),
+        },
+        TokenizerOpts::default(),
+    );
+    let _ = tokenizer.feed(&input);
+    tokenizer.end();
+
+    return tokenizer.sink.events.take();
+}
+
+#[derive(PartialEq)]
+enum FrameKind {
+    Root,
+    Quote,
+    /// Cell of the table at this index of the open tables.
+    Cell(usize),
+}
+
+/// Text rendered into a blockquote or table cell is kept apart until the
+/// element ends, it is then quoted or laid out with the rest of the table.
+struct Frame {
+    kind: FrameKind,
+    text: String,
+}
+
+struct Link {
+    href: String,
+    frame: usize,
+    start: usize,
+}
+
+struct Renderer {
+    frames: Vec<Frame>,
+    /// Rows of cells of the open tables.
+    tables: Vec<Vec<Vec<String>>>,
+    /// Open lists, with the number of the next item of an ordered list.
+    lists: Vec<Option<u32>>,
+    link: Option<Link>,
+    footnotes: Vec<String>,
+    hidden: usize,
+    pre: usize,
+    pending_space: bool,
+}
+
+impl Renderer {
+    fn new() -> Renderer {
+        return Renderer {
+            frames: vec![Frame {
+                kind: FrameKind::Root,
+                text: String::new(),
+            }],
+            tables: vec![],
+            lists: vec![],
+            link: None,
+            footnotes: vec![],
+            hidden: 0,
+            pre: 0,
+            pending_space: false,
+        };
+    }
+
+    fn buffer(&mut self) -> &mut String {
+        return &mut self.frames.last_mut().unwrap().text;
+    }
+
+    fn start(&mut self, name: &str, attributes: &[(String, String)]) {
+        if HIDDEN_TAGS.contains(&name) {
+            self.hidden += 1;
+
+            return;
+        }
+
+        if self.hidden > 0 {
+            return;
+        }
+
+        match name {
+            "br" => {
+                self.buffer().push('\n');
+                self.pending_space = false;
+            }
+            "hr" => self.push_block("----"),
+            "pre" => {
+                self.paragraph();
+                self.pre += 1;
+            }
+            "blockquote" => {
+                self.paragraph();
+                self.frames.push(Frame {
+                    kind: FrameKind::Quote,
+                    text: String::new(),
+                });
+            }
+            "ul" | "ol" => {
+                if self.lists.is_empty() {
+                    self.paragraph();
+                } else {
+                    self.line_break();
+                }
+
+                if name == "ol" {
+                    let start = attribute(attributes, "start")
+                        .and_then(|start| start.trim().parse::<u32>().ok())
+                        .unwrap_or(1);
+                    self.lists.push(Some(start));
+                } else {
+                    self.lists.push(None);
+                }
+            }
+            "li" => {
+                self.line_break();
+
+                let indent = "  ".repeat(self.lists.len().saturating_sub(1));
+                let marker = match self.lists.last_mut() {
+                    Some(Some(number)) => {
+                        *number += 1;
+
+                        format!("{}. ", *number - 1)
+                    }
+                    _ => String::from("- "),
+                };
+
+                self.buffer().push_str(&indent);
+                self.buffer().push_str(&marker);
+            }
+            "table" => {
+                self.paragraph();
+                self.tables.push(vec![]);
+            }
+            "tr" => {
+                self.close_cell();
+
+                if let Some(table) = self.tables.last_mut() {
+                    table.push(vec![]);
+                }
+            }
+            "td" | "th" => {
+                self.close_cell();
+
+                if self.tables.is_empty() {
+                    return;
+                }
+
+                let table = self.tables.len() - 1;
+                if self.tables[table].is_empty() {
+                    self.tables[table].push(vec![]);
+                }
+
+                self.frames.push(Frame {
+                    kind: FrameKind::Cell(table),
+                    text: String::new(),
+                });
+                self.pending_space = false;
+            }
+            "a" => {
+                let href = attribute(attributes, "href").unwrap_or_default();
+
+                if self.link.is_none() && is_footnote_target(&href) {
+                    let frame = self.frames.len() - 1;
+                    let start = self.frames[frame].text.len();
+
+                    self.link = Some(Link { href, frame, start });
+                }
+            }
+            "img" => {
+                let alt = attribute(attributes, "alt").unwrap_or_default();
+
+                if !alt.trim().is_empty() {
+                    self.text(&alt);
+                }
+            }
+            _ => {
+                if PARAGRAPH_TAGS.contains(&name) {
+                    self.paragraph();
+                } else if LINE_TAGS.contains(&name) {
+                    self.line_break();
+                }
+            }
+        }
+    }
+
+    fn end(&mut self, name: &str) {
+        if HIDDEN_TAGS.contains(&name) {
+            self.hidden = self.hidden.saturating_sub(1);
+
+            return;
+        }
+
+        if self.hidden > 0 {
+            return;
+        }
+
+        match name {
+            "pre" => {
+                self.pre = self.pre.saturating_sub(1);
+                self.paragraph();
+            }
+            "blockquote" => {
+                let quote = self
+                    .frames
+                    .iter()
+                    .rposition(|frame| frame.kind == FrameKind::Quote);
+                let cell = self
+                    .frames
+                    .iter()
+                    .rposition(|frame| matches!(frame.kind, FrameKind::Cell(_)));
+
+                if let Some(quote) = quote {
+                    if cell.is_none_or(|cell| cell < quote) {
+                        while self.frames.len() > quote {
+                            self.close_frame();
+                        }
+                    }
+                }
+            }
+            "ul" | "ol" => {
+                self.lists.pop();
+
+                if self.lists.is_empty() {
+                    self.paragraph();
+                } else {
+                    self.line_break();
+                }
+            }
+            "li" => self.line_break(),
+            "td" | "th" => self.close_cell(),
+            "table" => self.end_table(),
+            "a" => self.end_link(),
+            _ => {
+                if PARAGRAPH_TAGS.contains(&name) {
+                    self.paragraph();
+                } else if LINE_TAGS.contains(&name) {
+                    self.line_break();
+                }
+            }
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.hidden > 0 {
+            return;
+        }
+
+        if self.pre > 0 {
+            let text = text.replace("\r\n", "\n").replace('\u{a0}', " ");
+            self.buffer().push_str(&text);
+
+            return;
+        }
+
+        for c in text.chars() {
+            if c.is_whitespace() {
+                self.pending_space = true;
+
+                continue;
+            }
+
+            let pending_space = self.pending_space;
+            let buffer = self.buffer();
+            if pending_space && !buffer.is_empty() && !buffer.ends_with(['\n', ' ']) {
+                buffer.push(' ');
+            }
+            buffer.push(c);
+
+            self.pending_space = false;
+        }
+    }
+
+    fn line_break(&mut self) {
+        let buffer = self.buffer();
+        if !buffer.is_empty() && !buffer.ends_with('\n') {
+            buffer.push('\n');
+        }
+
+        self.pending_space = false;
+    }
+
+    fn paragraph(&mut self) {
+        self.line_break();
+
+        let buffer = self.buffer();
+        if !buffer.is_empty() && !buffer.ends_with("\n\n") {
+            buffer.push('\n');
+        }
+    }
+
+    fn push_block(&mut self, block: &str) {
+        if block.is_empty() {
+            return;
+        }
+
+        self.paragraph();
+        self.buffer().push_str(block);
+        self.paragraph();
+    }
+
+    /// Numbers the link with a footnote, unless its text already is the
+    /// address it points to.
+    fn end_link(&mut self) {
+        let link = match self.link.take() {
+            Some(link) => link,
+            None => return,
+        };
+
+        if link.frame != self.frames.len() - 1 {
+            return;
+        }
+
+        let text = self.frames[link.frame].text[link.start..]
+            .trim()
+            .to_lowercase();
+        if text.is_empty() || text == link.href.to_lowercase() || text == bare_address(&link.href) {
+            return;
+        }
+
+        let number = match self.footnotes.iter().position(|href| *href == link.href) {
+            Some(index) => index + 1,
+            None => {
+                self.footnotes.push(link.href);
+                self.footnotes.len()
+            }
+        };
+
+        let footnote = format!(" [{}]", number);
+        self.buffer().push_str(&footnote);
+    }
+
+    /// Ends the innermost blockquote or table cell and adds its text to the
+    /// element around it.
+    fn close_frame(&mut self) {
+        if self.frames.len() < 2 {
+            return;
+        }
+
+        if self
+            .link
+            .as_ref()
+            .is_some_and(|link| link.frame == self.frames.len() - 1)
+        {
+            self.link = None;
+        }
+
+        let frame = self.frames.pop().unwrap();
+        let text = tidy(&frame.text);
+
+        match frame.kind {
+            FrameKind::Quote => {
+                let quoted = text
+                    .lines()
+                    .map(|line| match line.is_empty() {
+                        true => String::from(">"),
+                        false => format!("> {}", line),
+                    })
+                    .collect::<Vec<String>>()
+                    .join("\n");
+
+                self.push_block(&quoted);
+            }
+            FrameKind::Cell(table) => {
+                if let Some(row) = self.tables.get_mut(table).and_then(|rows| rows.last_mut()) {
+                    row.push(text);
+                }
+            }
+            FrameKind::Root => {}
+        }
+    }
+
+    /// Ends the open cell of the innermost table, cells and rows are often
+    /// left unclosed.
+    fn close_cell(&mut self) {
+        let table = match self.tables.len() {
+            0 => return,
+            len => len - 1,
+        };
+
+        let cell = self
+            .frames
+            .iter()
+            .rposition(|frame| frame.kind == FrameKind::Cell(table));
+
+        if let Some(cell) = cell {
+            while self.frames.len() > cell {
+                self.close_frame();
+            }
+        }
+    }
+
+    fn end_table(&mut self) {
+        self.close_cell();
+
+        let rows = match self.tables.pop() {
+            Some(rows) => rows,
+            None => return,
+        };
+
+        let table = layout_table(rows);
+        self.push_block(&table);
+    }
+
+    fn finish(mut self) -> String {
+        while !self.tables.is_empty() || self.frames.len() > 1 {
+            if self.tables.is_empty() {
+                self.close_frame();
+            } else {
+                self.end_table();
+            }
+        }
+
+        let mut text = tidy(&self.frames[0].text);
+
+        if !self.footnotes.is_empty() {
+            text.push_str("\n\n");
+            text.push_str(
+                &self
+                    .footnotes
+                    .iter()
+                    .enumerate()
+                    .map(|(i, href)| format!("[{}] {}", i + 1, href))
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+            );
+        }
+
+        return text;
+    }
+}
+
+/// Columns padded to their widest cell and separated by `|` when every cell
+/// fits on one line, otherwise a paragraph per cell.
+fn layout_table(rows: Vec<Vec<String>>) -> String {
+    let rows: Vec<Vec<String>> = rows
+        .into_iter()
+        .filter(|row| row.iter().any(|cell| !cell.is_empty()))
+        .collect();
+
+    let columns = rows.iter().map(|row| row.len()).max().unwrap_or(0);
+    let mut widths = vec![0; columns];
+    for row in &rows {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = widths[i].max(cell.chars().count());
+        }
+    }
+
+    let is_grid = columns > 1
+        && rows.iter().flatten().all(|cell| !cell.contains('\n'))
+        && widths.iter().sum::<usize>() + 3 * (columns - 1) <= TABLE_WIDTH;
+
+    if !is_grid {
+        return rows
+            .iter()
+            .flatten()
+            .filter(|cell| !cell.is_empty())
+            .cloned()
+            .collect::<Vec<String>>()
+            .join("\n\n");
+    }
+
+    return rows
+        .iter()
+        .map(|row| {
+            row.iter()
+                .enumerate()
+                .map(|(i, cell)| format!("{:width$}", cell, width = widths[i]))
+                .collect::<Vec<String>>()
+                .join(" | ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+}
+
+/// Trailing whitespace removed from every line, at most one blank line in a
+/// row and none at the start or end.
+fn tidy(text: &str) -> String {
+    let mut lines: Vec<&str> = vec![];
+
+    for line in text.lines() {
+        let line = line.trim_end();
+
+        if line.is_empty() && lines.last().is_none_or(|last| last.is_empty()) {
+            continue;
+        }
+
+        lines.push(line);
+    }
+
+    while lines.last().is_some_and(|last| last.is_empty()) {
+        lines.pop();
+    }
+
+    return lines.join("\n");
+}
+
+fn attribute(attributes: &[(String, String)], name: &str) -> Option<String> {
+    return attributes
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.to_string());
+}
+
+fn is_footnote_target(href: &str) -> bool {
+    let href = href.trim().to_lowercase();
+
+    return href.starts_with("http://")
+        || href.starts_with("https://")
+        || href.starts_with("mailto:");
+}
+
+/// Address of a link as it would be written in text, without its scheme and
+/// trailing slash.
+fn bare_address(href: &str) -> String {
+    let href = href.trim().to_lowercase();
+    let href = href
+        .strip_prefix("mailto:")
+        .or(href.strip_prefix("https://"))
+        .or(href.strip_prefix("http://"))
+        .unwrap_or(&href);
+
+    return href.trim_end_matches('/').to_string();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paragraphs() {
+        let html = "<html><head><title>Title</title><style>p { color: red; }</style></head><body><p>First   paragraph\nwrapped.</p><p>Second&nbsp;one<br>with a break.</p><div>Line</div><div>Next line</div><script>if (a < b) {}</script></body></html>";
+
+        assert_eq!(
+            render(html),
+            "First paragraph wrapped.\n\nSecond one\nwith a break.\n\nLine\nNext line"
+        );
+    }
+
+    #[test]
+    fn lists() {
+        let html = "<p>Agenda:</p><ol start=\"3\"><li>Budget<li>Hiring<ul><li>Backend</li><li>Design</li></ul></li></ol><p>Thanks</p>";
+
+        assert_eq!(
+            render(html),
+            "Agenda:\n\n3. Budget\n4. Hiring\n  - Backend\n  - Design\n\nThanks"
+        );
+    }
+
+    #[test]
+    fn links() {
+        let html = r##"<p>Read the <a href="https://example.com/post">post</a>, <a href="https://example.com/">example.com</a> or <a href="mailto:bob@example.com">bob@example.com</a>. <a href="https://example.com/post">Again</a> <a href="#top">top</a></p>"##;
+
+        assert_eq!(
+            render(html),
+            "Read the post [1], example.com or bob@example.com. Again [1] top\n\n[1] https://example.com/post"
+        );
+    }
+
+    #[test]
+    fn tables() {
+        let html = "<table><tr><th>Item</th><th>Price</th></tr><tr><td>Coffee</td><td>3.50</td></tr><tr><td>Cake<td>4</table>";
+
+        assert_eq!(render(html), "Item   | Price\nCoffee | 3.50\nCake   | 4");
+    }
+
+    #[test]
+    fn layout_tables() {
+        let html = "<table><tr><td><img src=\"logo.png\" alt=\"Shop\"></td></tr><tr><td><table><tr><td><p>Big sale</p><p>Everything must go</p></td><td>Sidebar</td></tr></table></td></tr></table>";
+
+        assert_eq!(
+            render(html),
+            "Shop\n\nBig sale\n\nEverything must go\n\nSidebar"
+        );
+    }
+
+    #[test]
+    fn blockquotes() {
+        let html = "<p>Sounds good.</p><div>On Monday Bob wrote:</div><blockquote><p>Lunch?</p><blockquote>Earlier</blockquote></blockquote>";
+
+        assert_eq!(
+            render(html),
+            "Sounds good.\n\nOn Monday Bob wrote:\n\n> Lunch?\n>\n> > Earlier"
+        );
+    }
+
+    #[test]
+    fn preformatted() {
+        let html = "<p>Output:</p><pre>  a  b\n  c</pre>";
+
+        assert_eq!(render(html), "Output:\n\n  a  b\n  c");
+    }
+}
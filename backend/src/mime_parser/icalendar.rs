@@ -0,0 +1,617 @@
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+use serde::Serialize;
+
+/// Calendar object of a text/calendar part (RFC 5545) with its iTIP method
+/// (RFC 5546), REQUEST for an invitation.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Invitation {
+    pub method: String,
+    pub events: Vec<CalendarEvent>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CalendarEvent {
+    pub uid: String,
+    pub summary: String,
+    pub description: String,
+    pub location: String,
+    pub status: String,
+    pub sequence: u32,
+    pub organizer: Option<Participant>,
+    pub attendees: Vec<Participant>,
+    pub start: Option<EventTime>,
+    pub end: Option<EventTime>,
+    /// Occurrence of a recurring event this one overrides.
+    pub recurrence_id: Option<EventTime>,
+    /// RRULE as written, `FREQ=WEEKLY;BYDAY=MO`.
+    pub rrule: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Participant {
+    pub name: String,
+    pub address: String,
+    pub partstat: String,
+    pub role: String,
+    pub rsvp: bool,
+}
+
+/// Start or end of an event as written, and as a timestamp in milliseconds
+/// when its zone is known. All day events are at midnight UTC, floating times
+/// and zones the calendar does not define have no timestamp.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EventTime {
+    pub value: String,
+    pub tzid: Option<String>,
+    pub all_day: bool,
+    pub timestamp: Option<i64>,
+}
+
+/// Content line of a component, the value is not unescaped.
+#[derive(Debug, Clone)]
+pub struct Property {
+    pub name: String,
+    pub params: Vec<(String, String)>,
+    pub value: String,
+    /// The unfolded line.
+    pub raw: String,
+}
+
+impl Property {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        return self
+            .params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str());
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Component {
+    pub name: String,
+    pub properties: Vec<Property>,
+    pub components: Vec<Component>,
+}
+
+impl Component {
+    pub fn property(&self, name: &str) -> Option<&Property> {
+        return self.properties.iter().find(|p| p.name == name);
+    }
+
+    fn text(&self, name: &str) -> String {
+        return self
+            .property(name)
+            .map(|p| unescape(&p.value))
+            .unwrap_or_default();
+    }
+}
+
+/// The invitation of a calendar object, `None` when it has no event.
+pub fn parse(ics: &str) -> Option<Invitation> {
+    let calendar = components(ics)
+        .into_iter()
+        .find(|c| c.name == "VCALENDAR")?;
+
+    let timezones: Vec<&Component> = calendar
+        .components
+        .iter()
+        .filter(|c| c.name == "VTIMEZONE")
+        .collect();
+
+    let events: Vec<CalendarEvent> = calendar
+        .components
+        .iter()
+        .filter(|c| c.name == "VEVENT")
+        .map(|event| CalendarEvent {
+            uid: event.text("UID"),
+            summary: event.text("SUMMARY"),
+            description: event.text("DESCRIPTION"),
+            location: event.text("LOCATION"),
+            status: event.text("STATUS"),
+            sequence: event.text("SEQUENCE").trim().parse().unwrap_or(0),
+            organizer: event.property("ORGANIZER").map(participant),
+            attendees: event
+                .properties
+                .iter()
+                .filter(|p| p.name == "ATTENDEE")
+                .map(participant)
+                .collect(),
+            start: event
+                .property("DTSTART")
+                .and_then(|p| event_time(p, &timezones)),
+            end: event
+                .property("DTEND")
+                .and_then(|p| event_time(p, &timezones)),
+            recurrence_id: event
+                .property("RECURRENCE-ID")
+                .and_then(|p| event_time(p, &timezones)),
+            rrule: event.property("RRULE").map(|p| p.value.clone()),
+        })
+        .collect();
+
+    if events.is_empty() {
+        return None;
+    }
+
+    return Some(Invitation {
+        method: calendar.text("METHOD").to_uppercase(),
+        events,
+    });
+}
+
+/// Components of a calendar object, lines outside of one are ignored.
+pub fn components(ics: &str) -> Vec<Component> {
+    let mut stack: Vec<Component> = vec![];
+    let mut result: Vec<Component> = vec![];
+
+    for line in unfold(ics) {
+        let property = match parse_property(&line) {
+            Some(property) => property,
+            None => continue,
+        };
+
+        match property.name.as_str() {
+            "BEGIN" => stack.push(Component {
+                name: property.value.trim().to_uppercase(),
+                properties: vec![],
+                components: vec![],
+            }),
+            "END" => {
+                let component = match stack.pop() {
+                    Some(component) => component,
+                    None => continue,
+                };
+
+                match stack.last_mut() {
+                    Some(parent) => parent.components.push(component),
+                    None => result.push(component),
+                }
+            }
+            _ => {
+                if let Some(component) = stack.last_mut() {
+                    component.properties.push(property);
+                }
+            }
+        }
+    }
+
+    return result;
+}
+
+/// Folds a content line after 75 octets (RFC 5545 section 3.1).
+pub fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + line.len() / 74 * 3);
+    let mut length = 0;
+
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            length = 1;
+        }
+
+        folded.push(c);
+        length += c.len_utf8();
+    }
+
+    return folded;
+}
+
+/// Unescapes a TEXT value (RFC 5545 section 3.3.11).
+pub fn unescape(value: &str) -> String {
+    let mut text = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            text.push(c);
+
+            continue;
+        }
+
+        match chars.next() {
+            Some('n' | 'N') => text.push('\n'),
+            Some(c) => text.push(c),
+            None => {}
+        }
+    }
+
+    return text;
+}
+
+fn unfold(ics: &str) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+
+    for line in ics.lines() {
+        let line = line.trim_end_matches('\r');
+
+        if line.starts_with([' ', '\t']) {
+            if let Some(last) = lines.last_mut() {
+                last.push_str(&line[1..]);
+            }
+
+            continue;
+        }
+
+        if !line.trim().is_empty() {
+            lines.push(line.to_string());
+        }
+    }
+
+    return lines;
+}
+
+/// `NAME;PARAM=value;PARAM="quoted":value`, colons and semicolons inside
+/// quotes do not count.
+fn parse_property(line: &str) -> Option<Property> {
+    let mut in_quotes = false;
+    let mut separators: Vec<usize> = vec![];
+    let mut colon: Option<usize> = None;
+
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ';' if !in_quotes => separators.push(i),
+            ':' if !in_quotes => {
+                colon = Some(i);
+                break;
+            }
+            _ => {}
+        }
+    }
+
+    let colon = colon?;
+    let head = &line[..colon];
+    let name_end = separators.first().copied().unwrap_or(colon);
+
+    let mut params: Vec<(String, String)> = vec![];
+    for (j, start) in separators.iter().enumerate() {
+        let end = separators.get(j + 1).copied().unwrap_or(colon);
+
+        if let Some((key, value)) = head[start + 1..end].split_once('=') {
+            params.push((
+                key.trim().to_uppercase(),
+                value.trim_matches('"').to_string(),
+            ));
+        }
+    }
+
+    let name = head[..name_end].trim().to_uppercase();
+    if name.is_empty() {
+        return None;
+    }
+
+    return Some(Property {
+        name,
+        params,
+        value: line[colon + 1..].to_string(),
+        raw: line.to_string(),
+    });
+}
+
+fn participant(property: &Property) -> Participant {
+    let value = property.value.trim();
+    let address = match value.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("mailto:") => &value[7..],
+        _ => value,
+    };
+
+    return Participant {
+        name: property.param("CN").unwrap_or("").to_string(),
+        address: address.to_string(),
+        partstat: property
+            .param("PARTSTAT")
+            .unwrap_or("NEEDS-ACTION")
+            .to_uppercase(),
+        role: property
+            .param("ROLE")
+            .unwrap_or("REQ-PARTICIPANT")
+            .to_uppercase(),
+        rsvp: property
+            .param("RSVP")
+            .is_some_and(|rsvp| rsvp.eq_ignore_ascii_case("TRUE")),
+    };
+}
+
+fn event_time(property: &Property, timezones: &[&Component]) -> Option<EventTime> {
+    let value = property.value.trim();
+    let tzid = property.param("TZID").map(|tzid| tzid.to_string());
+
+    let all_day = value.len() == 8
+        || property
+            .param("VALUE")
+            .is_some_and(|kind| kind.eq_ignore_ascii_case("DATE"));
+
+    let timestamp = if all_day {
+        NaiveDate::parse_from_str(value, "%Y%m%d")
+            .ok()
+            .map(|date| date.and_time(NaiveTime::MIN).and_utc().timestamp_millis())
+    } else if let Some(utc) = value.strip_suffix(['Z', 'z']) {
+        local_time(utc).map(|time| time.and_utc().timestamp_millis())
+    } else {
+        match (&tzid, local_time(value)) {
+            (Some(tzid), Some(local)) => zone_offset(tzid, local, timezones).map(|offset| {
+                (local - Duration::seconds(offset as i64))
+                    .and_utc()
+                    .timestamp_millis()
+            }),
+            _ => None,
+        }
+    };
+
+    return Some(EventTime {
+        value: value.to_string(),
+        tzid,
+        all_day,
+        timestamp,
+    });
+}
+
+fn local_time(value: &str) -> Option<NaiveDateTime> {
+    return NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S").ok();
+}
+
+/// Offset in seconds of a zone at a local time, from the VTIMEZONE of the
+/// calendar with the same TZID. The observance with the latest onset before
+/// the time applies, onsets follow yearly rules on a weekday of a month, as
+/// every zone defines them.
+fn zone_offset(tzid: &str, local: NaiveDateTime, timezones: &[&Component]) -> Option<i32> {
+    let timezone = timezones
+        .iter()
+        .find(|tz| tz.property("TZID").is_some_and(|p| p.value.trim() == tzid));
+
+    let timezone = match timezone {
+        Some(timezone) => timezone,
+        None => match tzid.to_uppercase().as_str() {
+            "UTC" | "GMT" | "ETC/UTC" | "ETC/GMT" | "Z" => return Some(0),
+            _ => return None,
+        },
+    };
+
+    let mut latest: Option<(NaiveDateTime, i32)> = None;
+    let mut earliest: Option<(NaiveDateTime, i32)> = None;
+
+    for observance in &timezone.components {
+        let start = match observance
+            .property("DTSTART")
+            .and_then(|p| local_time(p.value.trim()))
+        {
+            Some(start) => start,
+            None => continue,
+        };
+        let offset_to = observance
+            .property("TZOFFSETTO")
+            .and_then(|p| utc_offset(&p.value));
+        let offset_from = observance
+            .property("TZOFFSETFROM")
+            .and_then(|p| utc_offset(&p.value));
+
+        if let Some(offset_from) = offset_from {
+            if earliest.is_none_or(|(time, _)| start < time) {
+                earliest = Some((start, offset_from));
+            }
+        }
+
+        let onset = match observance.property("RRULE") {
+            Some(rrule) => yearly_onset(&rrule.value, start, local),
+            None if start <= local => Some(start),
+            None => None,
+        };
+
+        if let (Some(onset), Some(offset_to)) = (onset, offset_to) {
+            if latest.is_none_or(|(time, _)| onset > time) {
+                latest = Some((onset, offset_to));
+            }
+        }
+    }
+
+    return latest.or(earliest).map(|(_, offset)| offset);
+}
+
+/// Latest onset before `local` of a `FREQ=YEARLY;BYMONTH=3;BYDAY=-1SU` rule.
+fn yearly_onset(rrule: &str, start: NaiveDateTime, local: NaiveDateTime) -> Option<NaiveDateTime> {
+    let mut month: Option<u32> = None;
+    let mut byday: Option<(i32, Weekday)> = None;
+    let mut until: Option<NaiveDateTime> = None;
+
+    for part in rrule.split(';') {
+        match part.split_once('=') {
+            Some(("FREQ", freq)) if freq != "YEARLY" => return None,
+            Some(("BYMONTH", value)) => month = value.parse().ok(),
+            Some(("BYDAY", value)) => {
+                let split = value.len().checked_sub(2)?;
+                let weekday = match &value[split..] {
+                    "MO" => Weekday::Mon,
+                    "TU" => Weekday::Tue,
+                    "WE" => Weekday::Wed,
+                    "TH" => Weekday::Thu,
+                    "FR" => Weekday::Fri,
+                    "SA" => Weekday::Sat,
+                    "SU" => Weekday::Sun,
+                    _ => return None,
+                };
+                let week = match &value[..split] {
+                    "" => 1,
+                    week => week.trim_start_matches('+').parse().ok()?,
+                };
+
+                byday = Some((week, weekday));
+            }
+            Some(("UNTIL", value)) => until = local_time(value.trim_end_matches('Z')),
+            _ => {}
+        }
+    }
+
+    let month = month?;
+    let (week, weekday) = byday?;
+
+    for year in [local.year(), local.year() - 1] {
+        let date = match week {
+            0 => continue,
+            1.. => NaiveDate::from_weekday_of_month_opt(year, month, weekday, week as u8),
+            _ => last_weekday_of_month(year, month, weekday)
+                .map(|date| date - Duration::weeks((-week - 1) as i64))
+                .filter(|date| date.month() == month),
+        };
+
+        let onset = match date {
+            Some(date) => date.and_time(start.time()),
+            None => continue,
+        };
+
+        if onset <= local && onset >= start && until.is_none_or(|until| onset <= until) {
+            return Some(onset);
+        }
+    }
+
+    return None;
+}
+
+fn last_weekday_of_month(year: i32, month: u32, weekday: Weekday) -> Option<NaiveDate> {
+    let next_month = match month {
+        12 => NaiveDate::from_ymd_opt(year + 1, 1, 1)?,
+        _ => NaiveDate::from_ymd_opt(year, month + 1, 1)?,
+    };
+
+    let mut date = next_month.pred_opt()?;
+    while date.weekday() != weekday {
+        date = date.pred_opt()?;
+    }
+
+    return Some(date);
+}
+
+/// `+hhmm`, `-hhmm` or `+hhmmss`.
+fn utc_offset(value: &str) -> Option<i32> {
+    let value = value.trim();
+    let sign = match value.get(..1)? {
+        "+" => 1,
+        "-" => -1,
+        _ => return None,
+    };
+    let digits = &value[1..];
+
+    if !(digits.len() == 4 || digits.len() == 6) || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let hours: i32 = digits[..2].parse().ok()?;
+    let minutes: i32 = digits[2..4].parse().ok()?;
+    let seconds: i32 = digits.get(4..).unwrap_or("0").parse().unwrap_or(0);
+
+    return Some(sign * (hours * 3600 + minutes * 60 + seconds));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GOOGLE: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/calendar/google_request.ics"
+    ));
+    const OUTLOOK: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/calendar/outlook_request.ics"
+    ));
+    const ALL_DAY: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/calendar/all_day_cancel.ics"
+    ));
+
+    #[test]
+    fn google_request() {
+        let invitation = parse(GOOGLE).unwrap();
+        assert_eq!(invitation.method, "REQUEST");
+        assert_eq!(invitation.events.len(), 1);
+
+        let event = &invitation.events[0];
+        assert_eq!(event.uid, "7kukuqrfedlm2f9t0vr42q7sgs@google.com");
+        assert_eq!(event.summary, "Weekly sync; planning");
+        assert_eq!(event.description, "Agenda:\n- Roadmap, budget\n- Hiring");
+        assert_eq!(event.location, "Room 4, Building B");
+        assert_eq!(event.sequence, 2);
+        assert_eq!(
+            event.rrule.as_deref(),
+            Some("FREQ=WEEKLY;BYDAY=MO;COUNT=10")
+        );
+        assert_eq!(
+            event.organizer,
+            Some(Participant {
+                name: String::from("Alice Doe"),
+                address: String::from("alice@example.com"),
+                partstat: String::from("NEEDS-ACTION"),
+                role: String::from("REQ-PARTICIPANT"),
+                rsvp: false,
+            })
+        );
+        assert_eq!(event.attendees.len(), 2);
+        assert_eq!(event.attendees[0].name, "Doe, Bob");
+        assert_eq!(event.attendees[0].address, "bob@example.com");
+        assert!(event.attendees[0].rsvp);
+        assert_eq!(event.attendees[1].partstat, "ACCEPTED");
+        assert_eq!(event.attendees[1].role, "CHAIR");
+
+        let start = event.start.as_ref().unwrap();
+        assert_eq!(start.value, "20240108T150000Z");
+        assert_eq!(start.timestamp, Some(1704726000000));
+        assert!(!start.all_day);
+        assert_eq!(event.end.as_ref().unwrap().timestamp, Some(1704729600000));
+    }
+
+    #[test]
+    fn outlook_time_zones() {
+        let invitation = parse(OUTLOOK).unwrap();
+        let event = &invitation.events[0];
+
+        assert_eq!(event.summary, "Quarterly review");
+        assert_eq!(
+            event.organizer.as_ref().unwrap().address,
+            "carol@example.org"
+        );
+
+        // 10:00 in winter is UTC+1, 10:00 in summer UTC+2.
+        let start = event.start.as_ref().unwrap();
+        assert_eq!(start.tzid.as_deref(), Some("W. Europe Standard Time"));
+        assert_eq!(start.timestamp, Some(1706605200000));
+
+        let end = event.end.as_ref().unwrap();
+        assert_eq!(end.value, "20240730T110000");
+        assert_eq!(end.timestamp, Some(1722330000000));
+    }
+
+    #[test]
+    fn all_day_cancel() {
+        let invitation = parse(ALL_DAY).unwrap();
+        assert_eq!(invitation.method, "CANCEL");
+
+        let event = &invitation.events[0];
+        assert_eq!(event.status, "CANCELLED");
+
+        let start = event.start.as_ref().unwrap();
+        assert!(start.all_day);
+        assert_eq!(start.timestamp, Some(1718928000000));
+
+        let recurrence_id = event.recurrence_id.as_ref().unwrap();
+        assert_eq!(recurrence_id.tzid.as_deref(), Some("America/New_York"));
+        assert_eq!(recurrence_id.timestamp, None);
+    }
+
+    #[test]
+    fn without_events() {
+        assert_eq!(parse(""), None);
+        assert_eq!(
+            parse("BEGIN:VCALENDAR\r\nMETHOD:PUBLISH\r\nEND:VCALENDAR\r\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn folding() {
+        let line = format!("DESCRIPTION:{}é", "a".repeat(100));
+        let folded = fold(&line);
+
+        assert!(folded.split("\r\n").all(|l| l.len() <= 75));
+        assert_eq!(unfold(&folded), vec![line]);
+    }
+}
@@ -0,0 +1,89 @@
+use regex::{Captures, Regex};
+
+use crate::types::message::InlinePart;
+
+/// Replaces the `cid:` references in the html of a message by data URIs of
+/// the inline parts they name. References to parts that are not stored are
+/// left as they are.
+pub fn resolve(html: &str, parts: &[InlinePart]) -> String {
+    if parts.is_empty() {
+        return html.to_string();
+    }
+
+    let re_cid = Regex::new(r#"(?i)cid:([^"'\s)>]+)"#).unwrap();
+
+    return re_cid
+        .replace_all(html, |caps: &Captures| {
+            let content_id = percent_decode(&caps[1]);
+
+            match parts
+                .iter()
+                .find(|part| part.content_id.eq_ignore_ascii_case(&content_id))
+            {
+                Some(part) => return format!("data:{};base64,{}", part.content_type, part.data),
+                None => return caps[0].to_string(),
+            }
+        })
+        .to_string();
+}
+
+/// Content-IDs in a `cid:` URL are percent encoded (RFC 2392).
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = match bytes[i] {
+            b'%' => bytes
+                .get(i + 1..i + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()),
+            _ => None,
+        };
+
+        match hex {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    return String::from_utf8_lossy(&decoded).to_string();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part(content_id: &str) -> InlinePart {
+        return InlinePart {
+            content_id: content_id.to_string(),
+            content_type: String::from("image/png"),
+            data: String::from("iVBORw0KGgo="),
+        };
+    }
+
+    #[test]
+    fn resolves_references() {
+        let parts = vec![part("logo@example.com"), part("bg 1@example.com")];
+        let html = r#"<img src="cid:logo@example.com"><td style="background: url(CID:bg%201@example.com)"><img src='cid:missing@example.com'>"#;
+
+        assert_eq!(
+            resolve(html, &parts),
+            r#"<img src="data:image/png;base64,iVBORw0KGgo="><td style="background: url(data:image/png;base64,iVBORw0KGgo=)"><img src='cid:missing@example.com'>"#
+        );
+    }
+
+    #[test]
+    fn without_parts() {
+        let html = r#"<img src="cid:logo@example.com">"#;
+
+        assert_eq!(resolve(html, &[]), html);
+    }
+}
@@ -0,0 +1,404 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::mime_parser::icalendar::{self, Component};
+use crate::mime_parser::{headers, parse_address};
+use crate::types::message::Address;
+
+/// Properties of an event copied into the reply, RFC 5546 section 3.2.3
+/// requires the UID, and the SEQUENCE and RECURRENCE-ID identify the version
+/// and occurrence answered.
+const COPIED_PROPERTIES: [&str; 8] = [
+    "UID",
+    "RECURRENCE-ID",
+    "SEQUENCE",
+    "DTSTART",
+    "DTEND",
+    "DURATION",
+    "SUMMARY",
+    "ORGANIZER",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PartStat {
+    Accepted,
+    Tentative,
+    Declined,
+}
+
+impl PartStat {
+    pub fn parse(value: &str) -> Option<PartStat> {
+        match value.to_lowercase().as_str() {
+            "accepted" | "accept" => return Some(PartStat::Accepted),
+            "tentative" => return Some(PartStat::Tentative),
+            "declined" | "decline" => return Some(PartStat::Declined),
+            _ => return None,
+        }
+    }
+
+    fn value(&self) -> &str {
+        match self {
+            PartStat::Accepted => return "ACCEPTED",
+            PartStat::Tentative => return "TENTATIVE",
+            PartStat::Declined => return "DECLINED",
+        }
+    }
+
+    fn subject_prefix(&self) -> &str {
+        match self {
+            PartStat::Accepted => return "Accepted",
+            PartStat::Tentative => return "Tentative",
+            PartStat::Declined => return "Declined",
+        }
+    }
+
+    fn verb(&self) -> &str {
+        match self {
+            PartStat::Accepted => return "accepted",
+            PartStat::Tentative => return "tentatively accepted",
+            PartStat::Declined => return "declined",
+        }
+    }
+}
+
+/// Reply to an invitation, ready to be sent to the organizer.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReplyMessage {
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    /// RFC 5322 message with the reply as text/calendar part.
+    pub source: String,
+}
+
+/// REPLY of `attendee` to a REQUEST (RFC 5546 section 3.2.3), for every event
+/// of the request they attend. `None` when the calendar object is no request
+/// or does not name them as attendee.
+pub fn reply(ics: &str, attendee: &str, partstat: PartStat, now: DateTime<Utc>) -> Option<String> {
+    let calendar = icalendar::components(ics)
+        .into_iter()
+        .find(|c| c.name == "VCALENDAR")?;
+
+    let method = calendar
+        .property("METHOD")
+        .map(|p| p.value.trim().to_uppercase());
+    if method.as_deref() != Some("REQUEST") {
+        return None;
+    }
+
+    let mut lines: Vec<String> = vec![
+        String::from("BEGIN:VCALENDAR"),
+        String::from("PRODID:-//mail_backend//iTIP reply//EN"),
+        String::from("VERSION:2.0"),
+        String::from("METHOD:REPLY"),
+    ];
+
+    for timezone in calendar.components.iter().filter(|c| c.name == "VTIMEZONE") {
+        raw_lines(timezone, &mut lines);
+    }
+
+    let mut replied = false;
+    for event in calendar.components.iter().filter(|c| c.name == "VEVENT") {
+        let attendee_property = event
+            .properties
+            .iter()
+            .find(|p| p.name == "ATTENDEE" && mailto(&p.value).eq_ignore_ascii_case(attendee));
+
+        let attendee_property = match attendee_property {
+            Some(property) => property,
+            None => continue,
+        };
+
+        lines.push(String::from("BEGIN:VEVENT"));
+
+        for name in COPIED_PROPERTIES {
+            if let Some(property) = event.property(name) {
+                lines.push(property.raw.clone());
+            }
+        }
+
+        lines.push(format!("DTSTAMP:{}", now.format("%Y%m%dT%H%M%SZ")));
+
+        let mut line = format!("ATTENDEE;PARTSTAT={}", partstat.value());
+        if let Some(name) = attendee_property.param("CN") {
+            line.push_str(&format!(";CN={}", param_value(name)));
+        }
+        line.push(':');
+        line.push_str(attendee_property.value.trim());
+        lines.push(line);
+
+        lines.push(String::from("END:VEVENT"));
+        replied = true;
+    }
+
+    if !replied {
+        return None;
+    }
+
+    lines.push(String::from("END:VCALENDAR"));
+
+    let folded: Vec<String> = lines.iter().map(|line| icalendar::fold(line)).collect();
+
+    return Some(folded.join("\r\n") + "\r\n");
+}
+
+/// The reply as a message to the organizer with a text part for clients that
+/// do not understand iTIP.
+pub fn reply_message(
+    ics: &str,
+    attendee: &str,
+    partstat: PartStat,
+    now: DateTime<Utc>,
+) -> Option<ReplyMessage> {
+    let invitation = icalendar::parse(ics)?;
+    let calendar = reply(ics, attendee, partstat, now)?;
+
+    let event = &invitation.events[0];
+    let organizer = event.organizer.as_ref()?;
+    let name = event
+        .attendees
+        .iter()
+        .find(|a| a.address.eq_ignore_ascii_case(attendee))
+        .map(|a| a.name.clone())
+        .unwrap_or_default();
+
+    let from = mailbox(&name, attendee);
+    let to = mailbox(&organizer.name, &organizer.address);
+    let summary = headers::single_line(&event.summary);
+    let subject = format!("{}: {}", partstat.subject_prefix(), summary);
+
+    let domain = match attendee.rsplit_once('@') {
+        Some((_, domain)) => headers::single_line(domain).replace(' ', ""),
+        None => String::from("localhost"),
+    };
+    let boundary = format!("itip-{:016x}", rand::random::<u64>());
+    let text = format!(
+        "{} has {} the invitation: {}\r\n",
+        if name.is_empty() { attendee } else { &name },
+        partstat.verb(),
+        summary
+    );
+
+    let source = format!(
+        "From: {}\r
+To: {}\r
+Subject: {}\r
+Date: {}\r
+Message-ID: <{:016x}@{}>\r
+MIME-Version: 1.0\r
+Content-Type: multipart/alternative; boundary=\"{}\"\r
+\r
+--{}\r
+Content-Type: text/plain; charset=utf-8\r
+Content-Transfer-Encoding: 8bit\r
+\r
+{}\r
+--{}\r
+Content-Type: text/calendar; charset=utf-8; method=REPLY\r
+Content-Transfer-Encoding: 8bit\r
+\r
+{}\r
+--{}--\r
+",
+        from,
+        to,
+        headers::encode_word(&subject),
+        now.to_rfc2822(),
+        rand::random::<u64>(),
+        domain,
+        boundary,
+        boundary,
+        text,
+        boundary,
+        calendar,
+        boundary
+    );
+
+    return Some(ReplyMessage {
+        from,
+        to,
+        subject,
+        source,
+    });
+}
+
+fn raw_lines(component: &Component, lines: &mut Vec<String>) {
+    lines.push(format!("BEGIN:{}", component.name));
+
+    for property in &component.properties {
+        lines.push(property.raw.clone());
+    }
+
+    for child in &component.components {
+        raw_lines(child, lines);
+    }
+
+    lines.push(format!("END:{}", component.name));
+}
+
+fn mailto(value: &str) -> &str {
+    let value = value.trim();
+
+    match value.get(..7) {
+        Some(scheme) if scheme.eq_ignore_ascii_case("mailto:") => return &value[7..],
+        _ => return value,
+    }
+}
+
+/// Parameter values with a colon, semicolon or comma are quoted, quotes
+/// cannot be escaped and are dropped.
+fn param_value(value: &str) -> String {
+    let value = value.replace('"', "");
+
+    if value.contains([':', ';', ',']) {
+        return format!("\"{}\"", value);
+    }
+
+    return value;
+}
+
+/// `Name <address>` of a calendar user, as written in a header.
+fn mailbox(name: &str, address: &str) -> String {
+    let (mailbox, host) = address.rsplit_once('@').unwrap_or((address, ""));
+
+    return parse_address::format(&Address {
+        name: name.to_string(),
+        mailbox: mailbox.to_string(),
+        host: host.to_string(),
+        group: None,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mime_parser::icalendar::Participant;
+    use chrono::TimeZone;
+
+    const GOOGLE: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/calendar/google_request.ics"
+    ));
+    const OUTLOOK: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/calendar/outlook_request.ics"
+    ));
+    const ALL_DAY: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/calendar/all_day_cancel.ics"
+    ));
+
+    fn now() -> DateTime<Utc> {
+        return Utc.with_ymd_and_hms(2024, 1, 3, 8, 30, 0).unwrap();
+    }
+
+    #[test]
+    fn reply_to_request() {
+        let ics = reply(GOOGLE, "Bob@example.com", PartStat::Accepted, now()).unwrap();
+        let invitation = icalendar::parse(&ics).unwrap();
+
+        assert_eq!(invitation.method, "REPLY");
+        assert!(ics.contains("DTSTAMP:20240103T083000Z\r\n"));
+
+        let event = &invitation.events[0];
+        assert_eq!(event.uid, "7kukuqrfedlm2f9t0vr42q7sgs@google.com");
+        assert_eq!(event.sequence, 2);
+        assert_eq!(
+            event.organizer.as_ref().unwrap().address,
+            "alice@example.com"
+        );
+        assert_eq!(
+            event.attendees,
+            vec![Participant {
+                name: String::from("Doe, Bob"),
+                address: String::from("bob@example.com"),
+                partstat: String::from("ACCEPTED"),
+                role: String::from("REQ-PARTICIPANT"),
+                rsvp: false,
+            }]
+        );
+        assert_eq!(event.rrule, None);
+    }
+
+    #[test]
+    fn reply_keeps_time_zones() {
+        let ics = reply(OUTLOOK, "bob@example.com", PartStat::Tentative, now()).unwrap();
+        let event = &icalendar::parse(&ics).unwrap().events[0];
+
+        assert!(ics.contains("BEGIN:VTIMEZONE\r\nTZID:W. Europe Standard Time\r\n"));
+        assert_eq!(event.start.as_ref().unwrap().timestamp, Some(1706605200000));
+        assert_eq!(event.attendees[0].partstat, "TENTATIVE");
+        assert!(event.uid.ends_with("F1E6F8B4C1E7C94F8C2F3E5D7A6B1C2D"));
+    }
+
+    #[test]
+    fn no_reply() {
+        assert_eq!(
+            reply(GOOGLE, "eve@example.com", PartStat::Declined, now()),
+            None
+        );
+        assert_eq!(
+            reply(ALL_DAY, "bob@example.com", PartStat::Declined, now()),
+            None
+        );
+    }
+
+    #[test]
+    fn message() {
+        let message = reply_message(GOOGLE, "bob@example.com", PartStat::Declined, now()).unwrap();
+
+        assert_eq!(message.from, "\"Doe, Bob\" <bob@example.com>");
+        assert_eq!(message.to, "Alice Doe <alice@example.com>");
+        assert_eq!(message.subject, "Declined: Weekly sync; planning");
+        assert!(message
+            .source
+            .contains("Subject: Declined: Weekly sync; planning\r\n"));
+        assert!(message
+            .source
+            .contains("Content-Type: text/calendar; charset=utf-8; method=REPLY\r\n"));
+        assert!(message
+            .source
+            .contains("Doe, Bob has declined the invitation: Weekly sync; planning\r\n"));
+        assert!(message
+            .source
+            .contains("ATTENDEE;PARTSTAT=DECLINED;CN=\"Doe, Bob\":mailto:bob@example.com\r\n"));
+    }
+
+    #[test]
+    fn encoded_names() {
+        assert_eq!(
+            mailbox("Jörg", "j@example.com"),
+            "=?utf-8?B?SsO2cmc=?= <j@example.com>"
+        );
+        assert_eq!(mailbox("", "j@example.com"), "j@example.com");
+    }
+
+    #[test]
+    fn no_header_injection() {
+        let ics = GOOGLE
+            .replace(
+                "SUMMARY:Weekly sync\\; planning",
+                "SUMMARY:Lunch\\nBcc: attacker@evil.example",
+            )
+            .replace(
+                "CN=Alice Doe:",
+                "CN=\"Alice\\nBcc: attacker@evil.example\":",
+            );
+        let message = reply_message(&ics, "bob@example.com", PartStat::Accepted, now()).unwrap();
+
+        assert_eq!(
+            message.subject,
+            "Accepted: Lunch Bcc: attacker@evil.example"
+        );
+        assert!(message
+            .source
+            .contains("Subject: Accepted: Lunch Bcc: attacker@evil.example\r\n"));
+        assert!(!message.source.contains("\nBcc:"));
+
+        assert_eq!(
+            mailbox("Eve\r\nBcc: attacker@evil.example", "eve@example.com"),
+            "\"Eve  Bcc: attacker@evil.example\" <eve@example.com>"
+        );
+        assert!(!mailbox("Eve", "eve@example.com\r\nBcc: attacker@evil.example").contains('\n'));
+    }
+}
@@ -1,37 +1,613 @@
-use async_imap::imap_proto::Address;
+use async_imap::imap_proto::Address as ImapAddress;
 
-use crate::mime_parser::decode;
+use crate::mime_parser::{decode, headers};
+use crate::types::message::Address;
 
-pub fn to_string(address: &Option<Vec<Address>>) -> String {
-    match address {
-        Some(a) => {
-            let mut result = String::from("[");
+enum Token {
+    /// Atom or quoted string.
+    Word(String),
+    Comment(String),
+    DomainLiteral(String),
+    Special(char),
+}
+
+/// Parses an address list header value (RFC 5322 section 3.4) with display
+/// names as atoms, quoted strings or encoded words, angle addresses with an
+/// obsolete route, groups and comments. A comment takes the place of a missing
+/// display name, `john@example.com (John Doe)`. Hosts in punycode are decoded.
+pub fn parse_list(value: &str) -> Vec<Address> {
+    let tokens = tokenize(value);
+    let mut addresses: Vec<Address> = vec![];
+    let mut group: Option<String> = None;
+
+    let mut i = 0;
+    while i < tokens.len() {
+        let phrase_start = i;
+        let mut phrase: Vec<&Token> = vec![];
+        let mut comment: Option<&str> = None;
+
+        while i < tokens.len() {
+            match &tokens[i] {
+                Token::Special('<' | ':' | ';' | ',' | '@') => break,
+                Token::Comment(text) => {
+                    comment.get_or_insert(text.as_str());
+                }
+                token => phrase.push(token),
+            }
+
+            i += 1;
+        }
+
+        match tokens.get(i) {
+            Some(Token::Special('<')) => {
+                i += 1;
+
+                // Only an obsolete route has a comma, an unclosed angle
+                // address ends at one.
+                let start = i;
+                let route = matches!(tokens.get(i), Some(Token::Special('@')));
+                while i < tokens.len() {
+                    match tokens[i] {
+                        Token::Special('>') => break,
+                        Token::Special(',') if !route => break,
+                        _ => i += 1,
+                    }
+                }
+
+                let mut spec = &tokens[start..i];
+                if let Some(colon) = spec.iter().position(|t| matches!(t, Token::Special(':'))) {
+                    spec = &spec[colon + 1..];
+                }
+
+                if matches!(tokens.get(i), Some(Token::Special('>'))) {
+                    i += 1;
+                }
+
+                let (mailbox, host) = addr_spec(spec);
+                let mut name = display_name(&phrase);
+
+                while let Some(Token::Comment(text)) = tokens.get(i) {
+                    comment.get_or_insert(text.as_str());
+                    i += 1;
+                }
+
+                if name.is_empty() {
+                    name = decode::encoded_words(comment.unwrap_or("").trim());
+                }
+
+                if !mailbox.is_empty() {
+                    addresses.push(address(name, mailbox, host, &group));
+                }
+            }
+            Some(Token::Special(':')) if group.is_none() => {
+                i += 1;
+                group = Some(display_name(&phrase));
+            }
+            Some(Token::Special(':')) => {
+                // Groups do not nest.
+                i += 1;
+                continue;
+            }
+            Some(Token::Special('@')) => {
+                i += 1;
+
+                while i < tokens.len() && !matches!(tokens[i], Token::Special(',' | ';')) {
+                    if let Token::Comment(text) = &tokens[i] {
+                        comment.get_or_insert(text.as_str());
+                    }
+
+                    i += 1;
+                }
+
+                let spec: Vec<&Token> = tokens[phrase_start..i]
+                    .iter()
+                    .filter(|t| !matches!(t, Token::Comment(_)))
+                    .collect();
+                let (mailbox, host) = addr_spec_refs(&spec);
+                let name = decode::encoded_words(comment.unwrap_or("").trim());
+
+                addresses.push(address(name, mailbox, host, &group));
+            }
+            _ => {
+                // A local part without a domain, as written by some mailers.
+                // Words without a dot between them are a stray display name.
+                let mailbox = local_part(&phrase);
+                let adjacent_words = phrase
+                    .windows(2)
+                    .any(|pair| matches!(pair, [Token::Word(_), Token::Word(_)]));
+
+                if !mailbox.is_empty() && !adjacent_words {
+                    let name = decode::encoded_words(comment.unwrap_or("").trim());
+                    addresses.push(address(name, mailbox, String::new(), &group));
+                }
+            }
+        }
+
+        match tokens.get(i) {
+            Some(Token::Special(';')) => group = None,
+            Some(Token::Special(',')) | None => {}
+            Some(_) => continue,
+        }
+
+        i += 1;
+    }
+
+    return addresses;
+}
+
+/// Addresses of an IMAP ENVELOPE. Groups are delimited by an address without
+/// host naming the group and one without mailbox ending it (RFC 3501 section
+/// 7.4.2).
+pub fn from_envelope(addresses: &Option<Vec<ImapAddress>>) -> Vec<Address> {
+    let mut result: Vec<Address> = vec![];
+    let mut group: Option<String> = None;
+
+    for imap_address in addresses.iter().flatten() {
+        let name = decode::encoded_words(&decode::to_u8(imap_address.name.as_deref()));
+        let mailbox = decode::to_u8(imap_address.mailbox.as_deref());
+
+        match (&imap_address.mailbox, &imap_address.host) {
+            (Some(_), None) => group = Some(mailbox),
+            (None, None) => group = None,
+            _ => {
+                let host = decode::to_u8(imap_address.host.as_deref());
+                result.push(address(name, mailbox, host, &group));
+            }
+        }
+    }
+
+    return result;
+}
+
+/// The addresses as they are stored in the database.
+pub fn to_json(addresses: &[Address]) -> String {
+    match serde_json::to_string(addresses) {
+        Ok(json) => return json,
+        Err(_) => return String::from("[]"),
+    }
+}
+
+pub fn from_json(value: &str) -> Vec<Address> {
+    return serde_json::from_str(value).unwrap_or_default();
+}
+
+/// An address as written in a header, `Name <mailbox@host>` or `mailbox@host`
+/// without a name. The name is quoted or encoded as needed and the host in
+/// punycode. Control characters are dropped, a line break would start a
+/// header of its own.
+pub fn format(address: &Address) -> String {
+    let name = headers::single_line(&address.name);
+
+    match name.trim() {
+        "" => return format_addr_spec(address),
+        name => return format!("{} <{}>", phrase(name), format_addr_spec(address)),
+    }
+}
+
+/// An address list header value, the members of a group listed after its
+/// name (RFC 5322 section 3.4).
+pub fn format_list(addresses: &[Address]) -> String {
+    return list_items(addresses).join(", ");
+}
+
+/// `name: addresses` folded to one item per line once it gets long.
+pub fn format_header(name: &str, addresses: &[Address]) -> String {
+    let items = list_items(addresses);
+    let line = format!("{}: {}", name, items.join(", "));
+
+    if line.len() <= 78 {
+        return line;
+    }
+
+    return format!("{}: {}", name, items.join(",\r\n "));
+}
+
+fn list_items(addresses: &[Address]) -> Vec<String> {
+    let mut items: Vec<String> = vec![];
+    let mut i = 0;
+
+    while i < addresses.len() {
+        let group = addresses[i]
+            .group
+            .as_deref()
+            .map(headers::single_line)
+            .unwrap_or_default();
+
+        if group.trim().is_empty() {
+            items.push(format(&addresses[i]));
+            i += 1;
+            continue;
+        }
+
+        let members: Vec<String> = addresses[i..]
+            .iter()
+            .take_while(|address| address.group == addresses[i].group)
+            .map(format)
+            .collect();
+        i += members.len();
+
+        items.push(format!("{}: {};", phrase(group.trim()), members.join(", ")));
+    }
+
+    return items;
+}
+
+/// A display name as an atom, a quoted string or an encoded word.
+fn phrase(name: &str) -> String {
+    if !name.is_ascii() {
+        return headers::encode_word(name);
+    }
+
+    if name.chars().all(|c| c.is_ascii_alphanumeric() || c == ' ') {
+        return name.to_string();
+    }
+
+    return format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""));
+}
+
+/// `mailbox@host` with a local part that is not a dot-atom quoted.
+fn format_addr_spec(address: &Address) -> String {
+    let mailbox: String = address
+        .mailbox
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect();
+    let host: String = address.host.chars().filter(|c| !c.is_control()).collect();
+
+    let is_atext = |c: char| c.is_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c);
+    let dot_atom = !mailbox.is_empty()
+        && mailbox
+            .split('.')
+            .all(|atom| !atom.is_empty() && atom.chars().all(is_atext));
+
+    let mailbox = match dot_atom {
+        true => mailbox,
+        false => format!("\"{}\"", mailbox.replace('\\', "\\\\").replace('"', "\\\"")),
+    };
+
+    if host.is_empty() {
+        return mailbox;
+    }
+
+    let host = match host.starts_with('[') {
+        true => host,
+        false => idna::domain_to_ascii(&host).unwrap_or(host),
+    };
+
+    return format!("{}@{}", mailbox, host);
+}
+
+/// An address with an IDNA host shown in its Unicode form.
+fn address(name: String, mailbox: String, host: String, group: &Option<String>) -> Address {
+    let host = match host
+        .split('.')
+        .any(|label| label.to_lowercase().starts_with("xn--"))
+    {
+        true => idna::domain_to_unicode(&host).0,
+        false => host,
+    };
+
+    return Address {
+        name,
+        mailbox,
+        host,
+        group: group.clone(),
+    };
+}
+
+fn addr_spec(tokens: &[Token]) -> (String, String) {
+    let refs: Vec<&Token> = tokens
+        .iter()
+        .filter(|t| !matches!(t, Token::Comment(_)))
+        .collect();
+
+    return addr_spec_refs(&refs);
+}
+
+fn addr_spec_refs(tokens: &[&Token]) -> (String, String) {
+    match tokens
+        .iter()
+        .rposition(|t| matches!(t, Token::Special('@')))
+    {
+        Some(at) => return (local_part(&tokens[..at]), local_part(&tokens[at + 1..])),
+        None => return (local_part(tokens), String::new()),
+    }
+}
+
+/// Local part or domain, the words and dots joined without the folding
+/// whitespace of the obsolete syntax.
+fn local_part(tokens: &[&Token]) -> String {
+    let mut text = String::new();
+
+    for token in tokens {
+        match token {
+            Token::Word(word) => text.push_str(word),
+            Token::DomainLiteral(literal) => text.push_str(literal),
+            Token::Special(c) => text.push(*c),
+            Token::Comment(_) => {}
+        }
+    }
+
+    return text;
+}
+
+fn display_name(tokens: &[&Token]) -> String {
+    let mut name = String::new();
+
+    for token in tokens {
+        match token {
+            Token::Word(word) => {
+                if !name.is_empty() {
+                    name.push(' ');
+                }
+
+                name.push_str(word);
+            }
+            Token::Special(c) => name.push(*c),
+            _ => {}
+        }
+    }
+
+    return decode::encoded_words(&name);
+}
 
-            for (i, address) in a.iter().enumerate() {
-                result.push_str("{");
-                result.push_str(&format!(
-                    "\"name\": \"{}\",",
-                    decode::to_u8(address.name.as_deref())
-                ));
-                result.push_str(&format!(
-                    "\"mailbox\": \"{}\",",
-                    decode::to_u8(address.mailbox.as_deref())
-                ));
-                result.push_str(&format!(
-                    "\"host\": \"{}\"",
-                    decode::to_u8(address.host.as_deref())
-                ));
-                result.push_str("}");
+fn tokenize(value: &str) -> Vec<Token> {
+    let mut tokens: Vec<Token> = vec![];
+    let mut chars = value.chars().peekable();
 
-                if i < a.len() - 1 {
-                    result.push_str(",");
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                let mut word = String::new();
+
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => word.extend(chars.next()),
+                        '\r' | '\n' => {}
+                        c => word.push(c),
+                    }
                 }
+
+                tokens.push(Token::Word(word));
             }
+            '(' => {
+                let mut comment = String::new();
+                let mut depth = 1;
 
-            result.push_str("]");
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => comment.extend(chars.next()),
+                        '(' => {
+                            depth += 1;
+                            comment.push(c);
+                        }
+                        ')' => {
+                            depth -= 1;
+
+                            if depth == 0 {
+                                break;
+                            }
+
+                            comment.push(c);
+                        }
+                        c => comment.push(c),
+                    }
+                }
+
+                tokens.push(Token::Comment(comment));
+            }
+            '[' => {
+                let mut literal = String::from("[");
 
-            return result;
+                for c in chars.by_ref() {
+                    literal.push(c);
+
+                    if c == ']' {
+                        break;
+                    }
+                }
+
+                tokens.push(Token::DomainLiteral(literal));
+            }
+            '<' | '>' | ':' | ';' | '@' | ',' | '.' => tokens.push(Token::Special(c)),
+            c if c.is_whitespace() => {}
+            c => {
+                let mut atom = String::from(c);
+
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || "\"()[]<>:;@,.".contains(next) {
+                        break;
+                    }
+
+                    atom.push(next);
+                    chars.next();
+                }
+
+                tokens.push(Token::Word(atom));
+            }
         }
-        None => return String::from("[]"),
+    }
+
+    return tokens;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+
+    fn address(name: &str, mailbox: &str, host: &str) -> Address {
+        return Address {
+            name: name.to_string(),
+            mailbox: mailbox.to_string(),
+            host: host.to_string(),
+            group: None,
+        };
+    }
+
+    #[test]
+    fn display_names() {
+        assert_eq!(
+            parse_list(
+                r#""Doe, John \"JD\"" <john@example.com>, Jane Q. Public <jane@example.com>"#
+            ),
+            vec![
+                address("Doe, John \"JD\"", "john", "example.com"),
+                address("Jane Q. Public", "jane", "example.com"),
+            ]
+        );
+        assert_eq!(
+            parse_list("=?UTF-8?B?SsO2cmc=?= <jorg@example.com>, \"=?utf-8?q?Ana_Mar=C3=ADa?=\" <ana@example.com>"),
+            vec![
+                address("Jörg", "jorg", "example.com"),
+                address("Ana María", "ana", "example.com"),
+            ]
+        );
+    }
+
+    #[test]
+    fn addr_specs_and_comments() {
+        assert_eq!(
+            parse_list("john@example.com (John Doe), <bob@example.com>, alice . smith @ example . com,postmaster"),
+            vec![
+                address("John Doe", "john", "example.com"),
+                address("", "bob", "example.com"),
+                address("", "alice.smith", "example.com"),
+                address("", "postmaster", ""),
+            ]
+        );
+        assert_eq!(
+            parse_list("Bob (the builder) <@relay.example.net:bob@[192.0.2.1]> (work)"),
+            vec![address("Bob", "bob", "[192.0.2.1]")]
+        );
+        assert_eq!(
+            parse_list("<\"john doe\"@example.com>"),
+            vec![address("", "john doe", "example.com")]
+        );
+    }
+
+    #[test]
+    fn groups() {
+        assert_eq!(parse_list("undisclosed-recipients:;"), vec![]);
+
+        let mut alice = address("Alice", "alice", "example.com");
+        alice.group = Some(String::from("Team"));
+        let mut bob = address("", "bob", "example.com");
+        bob.group = Some(String::from("Team"));
+
+        assert_eq!(
+            parse_list("Team: Alice <alice@example.com>, bob@example.com; carol@example.com"),
+            vec![alice, bob, address("", "carol", "example.com")]
+        );
+    }
+
+    #[test]
+    fn idn_hosts() {
+        assert_eq!(
+            parse_list("info@xn--bcher-kva.example, shop@bücher.example"),
+            vec![
+                address("", "info", "bücher.example"),
+                address("", "shop", "bücher.example"),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed() {
+        assert_eq!(parse_list(""), vec![]);
+        assert_eq!(parse_list(" , ,"), vec![]);
+        assert_eq!(parse_list("John Doe"), vec![]);
+        assert_eq!(
+            parse_list("John <john@example.com, bob@example.com"),
+            vec![
+                address("John", "john", "example.com"),
+                address("", "bob", "example.com"),
+            ]
+        );
+    }
+
+    #[test]
+    fn formatted() {
+        assert_eq!(
+            format(&address("Doe, John \"JD\"", "john", "example.com")),
+            r#""Doe, John \"JD\"" <john@example.com>"#
+        );
+        assert_eq!(
+            format(&address("Jörg", "jorg", "bücher.example")),
+            "=?utf-8?B?SsO2cmc=?= <jorg@xn--bcher-kva.example>"
+        );
+        assert_eq!(
+            format(&address("", "john doe", "example.com")),
+            r#""john doe"@example.com"#
+        );
+        assert_eq!(
+            format(&address(
+                "Eve\r\nBcc: x@evil.example",
+                "eve\r\n",
+                "example.com"
+            )),
+            "\"Eve  Bcc: x@evil.example\" <eve@example.com>"
+        );
+
+        let mut bob = address("", "bob", "example.com");
+        bob.group = Some(String::from("Team"));
+        let addresses = vec![bob, address("Carol", "carol", "example.com")];
+
+        assert_eq!(
+            format_list(&addresses),
+            "Team: bob@example.com;, Carol <carol@example.com>"
+        );
+        assert_eq!(parse_list(&format_list(&addresses)), addresses);
+
+        let many: Vec<Address> = (0..4)
+            .map(|i| address("Recipient", &format!("r{}", i), "example.com"))
+            .collect();
+        let header = format_header("To", &many);
+        assert!(header.starts_with("To: Recipient <r0@example.com>,\r\n Recipient <r1@"));
+        assert_eq!(parse_list(&header[4..].replace("\r\n", "")), many);
+    }
+
+    #[test]
+    fn envelope_addresses() {
+        let imap_address = |name: Option<&'static [u8]>,
+                            mailbox: Option<&'static [u8]>,
+                            host: Option<&'static [u8]>| ImapAddress {
+            name: name.map(Cow::Borrowed),
+            adl: None,
+            mailbox: mailbox.map(Cow::Borrowed),
+            host: host.map(Cow::Borrowed),
+        };
+
+        let addresses = from_envelope(&Some(vec![
+            imap_address(
+                Some(b"=?utf-8?q?J=C3=B6rg?="),
+                Some(b"jorg"),
+                Some(b"example.com"),
+            ),
+            imap_address(None, Some(b"Team"), None),
+            imap_address(None, Some(b"bob"), Some(b"example.com")),
+            imap_address(None, None, None),
+        ]));
+
+        let mut bob = address("", "bob", "example.com");
+        bob.group = Some(String::from("Team"));
+
+        assert_eq!(addresses, vec![address("Jörg", "jorg", "example.com"), bob]);
+        assert_eq!(from_envelope(&None), vec![]);
+    }
+
+    #[test]
+    fn json() {
+        let addresses = vec![address("\"Doe, John\" \\ Jr.", "john", "example.com")];
+        let json = to_json(&addresses);
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(parsed[0]["name"], "\"Doe, John\" \\ Jr.");
+        assert_eq!(parsed[0]["mailbox"], "john");
+        assert_eq!(parsed[0]["host"], "example.com");
+        assert_eq!(from_json(&json), addresses);
+        assert_eq!(to_json(&[]), "[]");
+        assert_eq!(from_json("not json"), vec![]);
     }
 }
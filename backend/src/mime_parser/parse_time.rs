@@ -1,47 +1,289 @@
-use chrono::{DateTime, FixedOffset};
-use regex::Regex;
-
-pub fn rfc2822(time_str: Option<&String>) -> DateTime<FixedOffset> {
-    let time_re =
-        Regex::new(r"(\w{1,3}, \d{1,2} \w{1,3} \d{4} \d{2}:\d{2}:\d{2} ([+-]\d{4})?(\w{3})?)")
-            .unwrap();
-    let binding = String::from("");
-
-    let date = match time_re.captures(time_str.unwrap_or(&binding)) {
-        Some(c) => c.get(1).unwrap().as_str(),
-        None => "Thu, 1 Jan 1970 00:00:00 +0000",
-    };
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveTime};
+
+use crate::mime_parser::headers;
+use crate::types::message::Header;
+
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Date and received time of a message in milliseconds. The date comes from
+/// the `Date` header, the received time from the topmost `Received` header.
+/// Either falls back to the IMAP INTERNALDATE, then to the other one, and is
+/// 0 when none of them can be parsed.
+pub fn message_dates(
+    headers: &[Header],
+    internal_date: Option<DateTime<FixedOffset>>,
+) -> (i64, i64) {
+    let date = headers::first(headers, "Date").and_then(rfc5322);
+    let received = headers::first(headers, "Received").and_then(received);
+
+    let date_ms = date
+        .or(internal_date)
+        .or(received)
+        .map(|d| d.timestamp_millis());
+    let received_ms = received
+        .or(internal_date)
+        .or(date)
+        .map(|d| d.timestamp_millis());
+
+    return (date_ms.unwrap_or(0), received_ms.unwrap_or(0));
+}
+
+/// Date of a `Received` header, which follows its last `;` outside of
+/// comments (RFC 5321 section 4.4).
+pub fn received(value: &str) -> Option<DateTime<FixedOffset>> {
+    match strip_comments(value).rsplit_once(';') {
+        Some((_, date)) => return rfc5322(date),
+        None => return None,
+    }
+}
+
+/// Parses a date-time of RFC 5322 section 3.3, accepting the obsolete forms of
+/// section 4.3: comments, the weekday left out, two and three digit years,
+/// missing seconds and zone names. Military zones and unknown names are taken
+/// as +0000, as the RFC advises.
+pub fn rfc5322(value: &str) -> Option<DateTime<FixedOffset>> {
+    let value = strip_comments(value).replace(',', " ");
+
+    let mut day: Option<u32> = None;
+    let mut month: Option<u32> = None;
+    let mut year: Option<i32> = None;
+    let mut time: Option<NaiveTime> = None;
+    let mut offset: Option<i32> = None;
+
+    for token in value.split_whitespace() {
+        if token.contains(':') && time.is_none() {
+            let (clock, zone) = match token.find(['+', '-']) {
+                Some(i) => (&token[..i], Some(&token[i..])),
+                None => (token, None),
+            };
+
+            time = Some(parse_clock(clock)?);
+
+            if let Some(zone) = zone {
+                offset = Some(numeric_zone(zone)?);
+            }
+        } else if token.starts_with(['+', '-']) {
+            offset = Some(numeric_zone(token)?);
+        } else if token.chars().all(|c| c.is_ascii_digit()) {
+            if day.is_none() && year.is_none() && token.len() <= 2 {
+                day = Some(token.parse().ok()?);
+            } else if year.is_none() {
+                year = Some(parse_year(token)?);
+            } else {
+                return None;
+            }
+        } else if token.chars().all(|c| c.is_ascii_alphabetic() || c == '.') {
+            let name = token.trim_end_matches('.').to_lowercase();
 
-    let date = match DateTime::parse_from_rfc2822(&date) {
-        Ok(date) => date,
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            DateTime::parse_from_rfc2822("Thu, 1 Jan 1970 00:00:00 +0000").unwrap()
+            if month.is_none() && name.len() >= 3 && MONTHS.contains(&&name[..3]) {
+                month = Some(MONTHS.iter().position(|m| *m == &name[..3]).unwrap() as u32 + 1);
+            } else if day.is_none() && name.len() >= 3 && WEEKDAYS.contains(&&name[..3]) {
+                continue;
+            } else if time.is_some() && offset.is_none() {
+                offset = Some(named_zone(&name));
+            }
+        } else {
+            return None;
         }
+    }
+
+    let date = NaiveDate::from_ymd_opt(year?, month?, day?)?;
+    let zone = FixedOffset::east_opt(offset.unwrap_or(0))?;
+
+    return date.and_time(time?).and_local_timezone(zone).single();
+}
+
+/// Removes the comments, which may be nested and contain quoted pairs.
+fn strip_comments(value: &str) -> String {
+    let mut stripped = String::with_capacity(value.len());
+    let mut depth = 0;
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if depth > 0 => {
+                chars.next();
+            }
+            '(' => depth += 1,
+            ')' if depth > 0 => {
+                depth -= 1;
+
+                if depth == 0 {
+                    stripped.push(' ');
+                }
+            }
+            _ if depth > 0 => {}
+            _ => stripped.push(c),
+        }
+    }
+
+    return stripped;
+}
+
+/// `hh:mm` or `hh:mm:ss`, where a leap second is taken as the second before
+/// and fractions of a second are ignored.
+fn parse_clock(clock: &str) -> Option<NaiveTime> {
+    let mut parts = clock.split(':');
+
+    let hour: u32 = parts.next()?.parse().ok()?;
+    let minute: u32 = parts.next()?.parse().ok()?;
+    let second: u32 = match parts.next() {
+        Some(second) => second.split('.').next()?.parse().ok()?,
+        None => 0,
+    };
+
+    if parts.next().is_some() {
+        return None;
+    }
+
+    return NaiveTime::from_hms_opt(hour, minute, second.min(59));
+}
+
+/// Two digit years are 1950 to 2049 and three digit years are counted from
+/// 1900 (RFC 5322 section 4.3).
+fn parse_year(year: &str) -> Option<i32> {
+    let value: i32 = year.parse().ok()?;
+
+    match year.len() {
+        2 if value < 50 => return Some(value + 2000),
+        2 => return Some(value + 1900),
+        3 => return Some(value + 1900),
+        4 => return Some(value),
+        _ => return None,
+    }
+}
+
+/// Offset in seconds of a `+hhmm` or `-hhmm` zone, a colon is tolerated.
+fn numeric_zone(zone: &str) -> Option<i32> {
+    let sign = if zone.starts_with('-') { -1 } else { 1 };
+    let digits = zone[1..].replace(':', "");
+
+    if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let hours: i32 = digits[..2].parse().ok()?;
+    let minutes: i32 = digits[2..].parse().ok()?;
+
+    if minutes > 59 {
+        return None;
+    }
+
+    return Some(sign * (hours * 3600 + minutes * 60));
+}
+
+/// Offset in seconds of an obsolete zone name.
+fn named_zone(name: &str) -> i32 {
+    let hours = match name {
+        "edt" => -4,
+        "est" | "cdt" => -5,
+        "cst" | "mdt" => -6,
+        "mst" | "pdt" => -7,
+        "pst" => -8,
+        _ => 0,
     };
 
-    return date;
+    return hours * 3600;
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn millis(value: &str) -> Option<i64> {
+        return rfc5322(value).map(|date| date.timestamp_millis());
+    }
+
+    #[test]
+    fn rfc5322_valid() {
+        assert_eq!(millis("Thu, 1 Jan 1970 00:00:10 +0000"), Some(10000));
+        assert_eq!(
+            millis("Mon, 01 Jan 2024 10:00:01 +0100"),
+            Some(1704099601000)
+        );
+        assert_eq!(
+            rfc5322("Mon, 1 Jan 2024 10:00:01 -0530")
+                .unwrap()
+                .offset()
+                .local_minus_utc(),
+            -19800
+        );
+    }
+
     #[test]
-    fn rfc2822_valid() {
-        let date = rfc2822(Some(&String::from("Thu, 1 Jan 1970 00:00:10 +0000")));
-        assert_eq!(date.timestamp_millis(), 10000);
+    fn rfc5322_obsolete_forms() {
+        let expected = millis("Mon, 1 Jan 2024 10:00:00 +0000");
+
+        assert_eq!(millis("1 Jan 2024 10:00:00 +0000"), expected);
+        assert_eq!(millis("1 Jan 24 10:00 GMT"), expected);
+        assert_eq!(
+            millis("Mon (Monday), 1 (first) Jan 2024 10:00:00 UT (comment (nested))"),
+            expected
+        );
+        assert_eq!(millis("Monday, 1 January 2024 05:00:00 EST"), expected);
+        assert_eq!(millis("Mon, 1 Jan 2024 02:00:00 PST"), expected);
+        assert_eq!(millis("Mon,1 Jan 2024 10:00:00 Z"), expected);
+        assert_eq!(millis("Mon, 1 Jan 2024 10:00:00"), expected);
+        assert_eq!(millis("Mon Jan  1 10:00:00 2024"), expected);
+        assert_eq!(millis("Mon, 1 Jan 2024 10:00:00.250 +0000"), expected);
+        assert_eq!(
+            millis("31 Dec 99 23:59:60 +0000"),
+            millis("31 Dec 1999 23:59:59 +0000")
+        );
+        assert_eq!(millis("1 Jan 124 10:00:00 +0000"), expected);
+    }
+
+    #[test]
+    fn rfc5322_invalid() {
+        assert_eq!(millis(""), None);
+        assert_eq!(millis("invalid"), None);
+        assert_eq!(millis("31 Feb 2024 10:00:00 +0000"), None);
+        assert_eq!(millis("1 Jan 2024 25:00:00 +0000"), None);
+        assert_eq!(millis("1 Jan 2024"), None);
     }
 
     #[test]
-    fn rfc2822_empty() {
-        let date = rfc2822(None);
-        assert_eq!(date.timestamp_millis(), 0);
+    fn received_date() {
+        assert_eq!(
+            received("from mx2.example.com (mx2 [10.0.0.1]; tls)\tby mx1.example.net with ESMTPS id abc; Mon, 1 Jan 2024 10:00:01 +0000 (UTC; relayed)")
+                .map(|date| date.timestamp_millis()),
+            Some(1704103201000)
+        );
+        assert_eq!(received("from client by mx2.example.com"), None);
     }
 
     #[test]
-    fn rfc2822_invalid() {
-        let date = rfc2822(Some(&String::from("invalid")));
-        assert_eq!(date.timestamp_millis(), 0);
+    fn dates_with_fallbacks() {
+        let header = |name: &str, value: &str| Header {
+            name: name.to_string(),
+            value: value.to_string(),
+        };
+        let internal_date = DateTime::parse_from_rfc3339("2024-01-03T00:00:00+00:00").ok();
+
+        let headers = vec![
+            header("Received", "by mx1.example.net; 2 Jan 2024 00:00:00 +0000"),
+            header("Received", "by mx2.example.com; 1 Jan 2024 12:00:00 +0000"),
+            header("Date", "1 Jan 2024 00:00:00 +0000"),
+        ];
+        assert_eq!(
+            message_dates(&headers, internal_date),
+            (1704067200000, 1704153600000)
+        );
+
+        let headers = vec![header("Date", "not a date")];
+        assert_eq!(
+            message_dates(&headers, internal_date),
+            (1704240000000, 1704240000000)
+        );
+
+        let headers = vec![header("Date", "1 Jan 2024 00:00:00 +0000")];
+        assert_eq!(
+            message_dates(&headers, None),
+            (1704067200000, 1704067200000)
+        );
+        assert_eq!(message_dates(&[], None), (0, 0));
     }
 }
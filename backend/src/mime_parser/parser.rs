@@ -1,13 +1,26 @@
-use async_imap::types::Fetch;
+use async_imap::imap_proto::Address as ImapAddress;
+use async_imap::types::{Fetch, Flag};
 use base64::{prelude::BASE64_STANDARD, Engine};
+use chrono::{DateTime, FixedOffset};
 use regex::Regex;
 use std::collections::HashMap;
 
 use crate::mime_parser::decode;
+use crate::mime_parser::entity;
+use crate::mime_parser::headers;
+use crate::mime_parser::html_to_text;
+use crate::mime_parser::icalendar;
 use crate::mime_parser::parse_address;
 use crate::mime_parser::parse_time;
+use crate::mime_parser::pgp_mime;
+use crate::mime_parser::preview;
+use crate::mime_parser::smime;
 use crate::my_error::MyError;
-use crate::types::message::Message;
+use crate::types::message::{Header, InlinePart, Message};
+
+/// Inline parts larger than this are left out, they would be loaded with
+/// every read of the message. The limit is on the base64 encoded size.
+const MAX_INLINE_PART_SIZE: usize = 2 * 1024 * 1024;
 
 enum MimeParserState {
     HeaderKey,
@@ -16,10 +29,14 @@ enum MimeParserState {
     Text,
     HtmlHeader,
     Html,
+    InlineHeader,
+    Inline,
+    CalendarHeader,
+    Calendar,
     BlankLine,
 }
 
-fn parse_message_body(body: &str) -> Message {
+fn parse_message_body(body: &str, internal_date: Option<DateTime<FixedOffset>>) -> Message {
     let mut state = MimeParserState::HeaderKey;
 
     let mut header_key = String::from("");
@@ -30,6 +47,19 @@ fn parse_message_body(body: &str) -> Message {
     let mut html_encoding = String::from("utf-8");
     let mut text_encoding = String::from("utf-8");
 
+    // Headers of the current part, Content-ID and the encoding can come
+    // before or after its Content-Type.
+    let mut part_content_id = String::from("");
+    let mut part_encoding = String::from("");
+    let mut inline_part = InlinePart {
+        content_id: String::from(""),
+        content_type: String::from(""),
+        data: String::from(""),
+    };
+    let mut inline_parts: Vec<InlinePart> = vec![];
+    let mut calendar = String::from("");
+    let mut calendar_encoding = String::from("");
+
     let lines = body.lines();
 
     let mut i = 0;
@@ -135,16 +165,140 @@ fn parse_message_body(body: &str) -> Message {
 
                 html.push_str(line);
             }
+            MimeParserState::InlineHeader => {
+                if line.is_empty() || (!line.contains(":") && line.starts_with(char::is_alphabetic))
+                {
+                    state = MimeParserState::Inline;
+
+                    continue;
+                }
+
+                let split = line.split_once(":").unwrap_or(("", ""));
+
+                match split.0.trim().to_lowercase().as_str() {
+                    "content-id" => part_content_id = content_id(split.1),
+                    "content-transfer-encoding" => part_encoding = split.1.trim().to_lowercase(),
+                    _ => {}
+                }
+            }
+            MimeParserState::Inline => {
+                if line.starts_with(&(String::from("--"))) {
+                    inline_part.content_id = part_content_id.clone();
+
+                    if !inline_part.content_id.is_empty()
+                        && part_encoding == "base64"
+                        && inline_part.data.len() <= MAX_INLINE_PART_SIZE
+                    {
+                        inline_parts.push(inline_part.clone());
+                    }
+
+                    part_content_id.clear();
+                    part_encoding.clear();
+                    state = MimeParserState::BlankLine;
+
+                    continue;
+                }
+
+                inline_part.data.push_str(line.trim());
+            }
+            MimeParserState::CalendarHeader => {
+                if line.is_empty() || (!line.contains(":") && line.starts_with(char::is_alphabetic))
+                {
+                    state = MimeParserState::Calendar;
+
+                    continue;
+                }
+
+                let split = line.split_once(":").unwrap_or(("", ""));
+
+                if split
+                    .0
+                    .trim()
+                    .eq_ignore_ascii_case("content-transfer-encoding")
+                {
+                    calendar_encoding = split.1.trim().to_lowercase();
+                }
+            }
+            MimeParserState::Calendar => {
+                if line.starts_with("--") {
+                    part_content_id.clear();
+                    part_encoding.clear();
+                    state = MimeParserState::BlankLine;
+
+                    continue;
+                }
+
+                // Content lines are kept apart, base64 is decoded as a whole.
+                if calendar_encoding == "base64" {
+                    calendar.push_str(line.trim());
+                } else {
+                    calendar.push_str(line);
+                    calendar.push('\n');
+                }
+            }
             MimeParserState::BlankLine => {
+                let lowercase = line.to_lowercase();
+
+                if line.starts_with("--") {
+                    part_content_id.clear();
+                    part_encoding.clear();
+                } else if lowercase.starts_with("content-id:") {
+                    part_content_id = content_id(&line[11..]);
+                } else if lowercase.starts_with("content-transfer-encoding:") {
+                    part_encoding = line[26..].trim().to_lowercase();
+                }
+
                 if line.starts_with("Content-Type: text/plain") && text.is_empty() {
+                    part_content_id.clear();
+                    part_encoding.clear();
                     state = MimeParserState::TextHeader;
                 } else if line.starts_with("Content-Type: text/html") && html.is_empty() {
+                    part_content_id.clear();
+                    part_encoding.clear();
                     state = MimeParserState::HtmlHeader;
+                } else if (lowercase.starts_with("content-type: text/calendar")
+                    || lowercase.starts_with("content-type: application/ics"))
+                    && calendar.is_empty()
+                {
+                    calendar_encoding = part_encoding.clone();
+                    state = MimeParserState::CalendarHeader;
+                } else if lowercase.starts_with("content-type: image/") {
+                    inline_part = InlinePart {
+                        content_id: String::from(""),
+                        content_type: lowercase[13..]
+                            .split(';')
+                            .next()
+                            .unwrap_or("")
+                            .trim()
+                            .to_string(),
+                        data: String::from(""),
+                    };
+                    state = MimeParserState::InlineHeader;
                 }
             }
         }
     }
 
+    let preview = preview::build(
+        &decode_part(&text, &text_encoding),
+        &decode_part(&html, &html_encoding),
+    );
+    let has_attachments = body.lines().any(is_attachment_header);
+
+    if text.trim().is_empty() && !html.trim().is_empty() {
+        text = html_to_text::render(&decode_part(&html, &html_encoding));
+        text_encoding = String::from("utf-8");
+    }
+
+    // Soft line breaks of quoted-printable end in `=`.
+    if calendar_encoding == "quoted-printable" {
+        calendar = calendar.replace("=\n", "");
+    }
+    calendar = decode_part(&calendar, &calendar_encoding);
+    if icalendar::parse(&calendar).is_none() {
+        calendar.clear();
+    }
+
     let re_encoding = Regex::new(r"=(..)").unwrap();
     html = re_encoding
         .replace_all(html.as_str(), |caps: &regex::Captures| {
@@ -171,13 +325,17 @@ fn parse_message_body(body: &str) -> Message {
         html = BASE64_STANDARD.encode(html.as_bytes());
     }
 
-    let date = parse_time::rfc2822(headers.get("Date"));
-    let received = parse_time::rfc2822(headers.get("Received"));
+    let message_headers = headers::parse(body);
+    let (date, received) = parse_time::message_dates(&message_headers, internal_date);
 
     let binding = String::from("");
-    let to = headers.get("To").unwrap_or(&binding);
-    let delivered_to = headers.get("Delivered-To").unwrap_or(&binding);
-    let from = headers.get("From").unwrap_or(&binding);
+    let delivered_to = headers::first(&message_headers, "Delivered-To")
+        .and_then(|value| {
+            parse_address::parse_list(value)
+                .first()
+                .map(|address| address.address())
+        })
+        .unwrap_or_default();
     let subject = headers.get("Subject").unwrap_or(&binding);
     let message_id = headers.get("Message-ID").unwrap_or(&binding);
 
@@ -186,22 +344,104 @@ fn parse_message_body(body: &str) -> Message {
         sequence_id: 0,
         message_id: message_id.to_string(),
         subject: subject.to_string(),
-        from: from.to_string(),
-        sender: String::from(""),
-        to: to.to_string(),
-        cc: String::from(""),
-        bcc: String::from(""),
-        reply_to: String::from(""),
+        from: addresses(&message_headers, "From", &None),
+        sender: addresses(&message_headers, "Sender", &None),
+        to: addresses(&message_headers, "To", &None),
+        cc: addresses(&message_headers, "Cc", &None),
+        bcc: addresses(&message_headers, "Bcc", &None),
+        reply_to: addresses(&message_headers, "Reply-To", &None),
         in_reply_to: String::from(""),
-        delivered_to: delivered_to.to_string(),
-        date: date.timestamp_millis(),
-        received: received.timestamp_millis(),
+        delivered_to,
+        date,
+        received,
+        size: 0,
+        preview,
+        has_attachments,
+        inline_parts,
+        calendar,
+        openpgp: pgp_mime::detect(body),
+        smime: smime::detect(body),
+        security: None,
+        headers: message_headers,
+        thread_size: 0,
         flags: vec![],
         text,
         html,
     };
 }
 
+/// Text, html, inline and calendar parts of a MIME entity that is not a
+/// whole message, such as the decrypted content of an encrypted one.
+pub fn parse_entity(source: &str) -> Message {
+    let part = entity::parse(source);
+    let content_type = part.content_type();
+
+    if content_type.starts_with("multipart/") {
+        return parse_message_body(source, None);
+    }
+
+    let mut message = parse_message_body("", None);
+
+    match content_type.as_str() {
+        "text/html" => {
+            let html = part.text();
+
+            message.text = BASE64_STANDARD.encode(html_to_text::render(&html).as_bytes());
+            message.html = BASE64_STANDARD.encode(html.as_bytes());
+        }
+        "text/plain" => message.text = BASE64_STANDARD.encode(part.text().as_bytes()),
+        _ => {}
+    }
+
+    return message;
+}
+
+/// Addresses of the first `name` header as JSON, or of the envelope when the
+/// message has no such header. Servers fill Sender and Reply-To of the
+/// envelope from From when they are missing.
+fn addresses(headers: &[Header], name: &str, envelope: &Option<Vec<ImapAddress>>) -> String {
+    match headers::first(headers, name) {
+        Some(value) => return parse_address::to_json(&parse_address::parse_list(value)),
+        None => return parse_address::to_json(&parse_address::from_envelope(envelope)),
+    }
+}
+
+/// System flags are stored without their backslash, keywords as they are.
+pub fn flag_name(flag: &Flag) -> String {
+    match flag {
+        Flag::Custom(keyword) => return keyword.to_string(),
+        _ => return format!("{:?}", flag),
+    }
+}
+
+/// Plain content of a text or html part for the preview.
+fn decode_part(part: &str, encoding: &str) -> String {
+    match encoding.to_lowercase().as_str() {
+        "base64" => match BASE64_STANDARD.decode(part) {
+            Ok(decoded) => return String::from_utf8_lossy(&decoded).to_string(),
+            Err(_) => return String::from(""),
+        },
+        "quoted-printable" => return preview::decode_quoted_printable(part),
+        _ => return part.to_string(),
+    }
+}
+
+/// Content-ID of a part without its angle brackets.
+fn content_id(value: &str) -> String {
+    return value
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .trim()
+        .to_string();
+}
+
+fn is_attachment_header(line: &str) -> bool {
+    let line = line.to_lowercase();
+
+    return line.starts_with("content-disposition:") && line.contains("attachment");
+}
+
 pub fn parse_fetch(fetch: &Fetch) -> Result<Message, MyError> {
     let envelope = fetch.envelope();
 
@@ -231,9 +471,9 @@ pub fn parse_fetch(fetch: &Fetch) -> Result<Message, MyError> {
         None => "",
     };
 
-    let flags: Vec<String> = fetch.flags().map(|f| format!("{:?}", f)).collect();
+    let flags: Vec<String> = fetch.flags().map(|f| flag_name(&f)).collect();
 
-    let body_data = parse_message_body(body_str);
+    let body_data = parse_message_body(body_str, fetch.internal_date());
 
     if envelope.is_some() {
         let envelope = envelope.unwrap();
@@ -243,16 +483,26 @@ pub fn parse_fetch(fetch: &Fetch) -> Result<Message, MyError> {
             sequence_id: fetch.message,
             message_id: decode::to_u8(envelope.message_id.as_deref()),
             subject: decode::to_u8(envelope.subject.as_deref()),
-            from: parse_address::to_string(&envelope.from),
-            sender: parse_address::to_string(&envelope.sender),
-            to: parse_address::to_string(&envelope.to),
-            cc: parse_address::to_string(&envelope.cc),
-            bcc: parse_address::to_string(&envelope.bcc),
-            reply_to: parse_address::to_string(&envelope.reply_to),
+            from: addresses(&body_data.headers, "From", &envelope.from),
+            sender: addresses(&body_data.headers, "Sender", &envelope.sender),
+            to: addresses(&body_data.headers, "To", &envelope.to),
+            cc: addresses(&body_data.headers, "Cc", &envelope.cc),
+            bcc: addresses(&body_data.headers, "Bcc", &envelope.bcc),
+            reply_to: addresses(&body_data.headers, "Reply-To", &envelope.reply_to),
             in_reply_to: decode::to_u8(envelope.in_reply_to.as_deref()),
             delivered_to: body_data.delivered_to,
             date: body_data.date,
             received: body_data.received,
+            size: body_str.len() as u32,
+            preview: body_data.preview,
+            has_attachments: body_data.has_attachments,
+            inline_parts: body_data.inline_parts,
+            calendar: body_data.calendar,
+            openpgp: body_data.openpgp,
+            smime: body_data.smime,
+            security: None,
+            headers: body_data.headers,
+            thread_size: 0,
             flags,
             text: body_data.text,
             html: body_data.html,
@@ -263,19 +513,195 @@ pub fn parse_fetch(fetch: &Fetch) -> Result<Message, MyError> {
             sequence_id: fetch.message,
             message_id: String::from(""),
             subject: String::from(""),
-            from: String::from(""),
-            sender: String::from(""),
-            to: String::from(""),
-            cc: String::from(""),
-            bcc: String::from(""),
-            reply_to: String::from(""),
+            from: body_data.from,
+            sender: body_data.sender,
+            to: body_data.to,
+            cc: body_data.cc,
+            bcc: body_data.bcc,
+            reply_to: body_data.reply_to,
             in_reply_to: String::from(""),
             delivered_to: body_data.delivered_to,
             date: body_data.date,
             received: body_data.received,
+            size: body_str.len() as u32,
+            preview: body_data.preview,
+            has_attachments: body_data.has_attachments,
+            inline_parts: body_data.inline_parts,
+            calendar: body_data.calendar,
+            openpgp: body_data.openpgp,
+            smime: body_data.smime,
+            security: None,
+            headers: body_data.headers,
+            thread_size: 0,
             flags,
             text: body_data.text,
             html: body_data.html,
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inline_parts() {
+        let body = "From: news@example.com\r
+Subject: News\r
+Content-Type: multipart/related; boundary=\"b1\"\r
+\r
+--b1\r
+Content-Type: text/html; charset=utf-8\r
+\r
+<p><img src=\"cid:logo@example.com\"></p>\r
+--b1\r
+Content-Type: image/png; name=\"logo.png\"\r
+Content-Transfer-Encoding: base64\r
+Content-ID: <logo@example.com>\r
+\r
+iVBORw0K\r
+GgoAAAAN\r
+--b1\r
+Content-ID: <photo@example.com>\r
+Content-Transfer-Encoding: base64\r
+Content-Type: image/JPEG\r
+\r
+/9j/4AAQ\r
+--b1\r
+Content-Type: image/gif\r
+Content-Transfer-Encoding: base64\r
+\r
+R0lGODlh\r
+--b1--\r
+";
+
+        let message = parse_message_body(body, None);
+
+        assert_eq!(
+            message.inline_parts,
+            vec![
+                InlinePart {
+                    content_id: String::from("logo@example.com"),
+                    content_type: String::from("image/png"),
+                    data: String::from("iVBORw0KGgoAAAAN"),
+                },
+                InlinePart {
+                    content_id: String::from("photo@example.com"),
+                    content_type: String::from("image/jpeg"),
+                    data: String::from("/9j/4AAQ"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn text_from_html() {
+        let body = "From: news@example.com\r
+Content-Type: multipart/alternative; boundary=\"b1\"\r
+\r
+--b1\r
+Content-Type: text/html; charset=utf-8\r
+Content-Transfer-Encoding: quoted-printable\r
+\r
+<p>Hello <a href=3D\"https://example.com\">there</a></p><ul><li>One</li></ul>\r
+--b1--\r
+";
+
+        let message = parse_message_body(body, None);
+        let text = BASE64_STANDARD.decode(message.text).unwrap();
+
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "Hello there [1]\n\n- One\n\n[1] https://example.com"
+        );
+    }
+
+    #[test]
+    fn addresses_from_headers() {
+        let body = "Delivered-To: me@example.com\r
+Delivered-To: alias@example.com\r
+From: =?utf-8?q?J=C3=B6rg?= <jorg@example.com>\r
+To: undisclosed-recipients:;\r
+Cc: \"Doe, Jane\" <jane@example.com>,\r
+ bob@example.com (Bob)\r
+Content-Type: text/plain\r
+\r
+Hi\r
+";
+
+        let message = parse_message_body(body, None);
+
+        assert_eq!(message.delivered_to, "me@example.com");
+        assert_eq!(
+            message.from,
+            r#"[{"name":"Jörg","mailbox":"jorg","host":"example.com"}]"#
+        );
+        assert_eq!(message.to, "[]");
+        assert_eq!(
+            message.cc,
+            r#"[{"name":"Doe, Jane","mailbox":"jane","host":"example.com"},{"name":"Bob","mailbox":"bob","host":"example.com"}]"#
+        );
+        assert_eq!(message.reply_to, "[]");
+    }
+
+    #[test]
+    fn entity() {
+        let text = |message: &Message| {
+            return String::from_utf8(BASE64_STANDARD.decode(&message.text).unwrap()).unwrap();
+        };
+
+        let message = parse_entity(
+            "Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: quoted-printable\r\n\r\ncaf=C3=A9\r\n",
+        );
+        assert_eq!(text(&message), "café\r\n");
+        assert_eq!(message.html, "");
+
+        let message = parse_entity("Content-Type: text/html\r\n\r\n<p>Hi</p>");
+        assert_eq!(text(&message), "Hi");
+        assert_eq!(message.html, BASE64_STANDARD.encode("<p>Hi</p>"));
+
+        let message = parse_entity(
+            "Content-Type: multipart/alternative; boundary=\"b\"\r\n\r\n--b\r\nContent-Type: text/plain\r\n\r\nHello\r\n--b--\r\n",
+        );
+        assert_eq!(text(&message), "Hello");
+    }
+
+    #[test]
+    fn calendar_part() {
+        let body = "From: alice@example.com\r
+Content-Type: multipart/mixed; boundary=\"b1\"\r
+\r
+--b1\r
+Content-Type: text/plain; charset=utf-8\r
+\r
+You are invited\r
+--b1\r
+Content-Type: text/calendar; charset=utf-8;\r
+ method=REQUEST\r
+Content-Transfer-Encoding: quoted-printable\r
+\r
+BEGIN:VCALENDAR\r
+METHOD:REQUEST\r
+BEGIN:VEVENT\r
+UID:1@example.com\r
+SUMMARY:R=C3=A9union d'=C3=A9quipe avec un titre bien trop long pour une seule lig=\r
+ne\r
+DTSTART:20240108T150000Z\r
+END:VEVENT\r
+END:VCALENDAR\r
+--b1--\r
+";
+
+        let message = parse_message_body(body, None);
+        let invitation = icalendar::parse(&message.calendar).unwrap();
+
+        assert_eq!(
+            invitation.events[0].summary,
+            "Réunion d'équipe avec un titre bien trop long pour une seule ligne"
+        );
+        assert_eq!(
+            parse_message_body("Subject: Hi\r\n\r\nHi\r\n", None).calendar,
+            ""
+        );
+    }
+}
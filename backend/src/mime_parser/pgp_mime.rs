@@ -0,0 +1,720 @@
+use crate::mime_parser::entity::{self, Entity};
+use crate::mime_parser::parse_address;
+use crate::my_error::MyError;
+use crate::openpgp::armor;
+use crate::openpgp::crypto;
+use crate::openpgp::key::Cert;
+use crate::openpgp::message::{self, Status, Verification};
+use crate::openpgp::signature::{self, hex};
+use crate::types::message::{MessageSecurity, Signer};
+
+/// Multiparts nest, deeper entities are not searched.
+const MAX_DEPTH: usize = 8;
+
+/// Written again from their parsed addresses when a message is protected.
+const ADDRESS_HEADERS: [&str; 6] = ["from", "sender", "reply-to", "to", "cc", "bcc"];
+
+/// How a message is protected with OpenPGP.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// multipart/encrypted (RFC 3156 section 4).
+    Encrypted,
+    /// multipart/signed (RFC 3156 section 5).
+    Signed,
+    /// Armored message or cleartext signature in a text/plain part.
+    Inline,
+}
+
+impl Kind {
+    pub fn name(&self) -> &str {
+        match self {
+            Kind::Encrypted => return "encrypted",
+            Kind::Signed => return "signed",
+            Kind::Inline => return "inline",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Kind> {
+        match name {
+            "encrypted" => return Some(Kind::Encrypted),
+            "signed" => return Some(Kind::Signed),
+            "inline" => return Some(Kind::Inline),
+            _ => return None,
+        }
+    }
+}
+
+/// OpenPGP data of a message, found when it is fetched and opened when it is
+/// read with the keys of the account.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PgpPart {
+    pub kind: Kind,
+    /// Armored message, detached signature or cleartext signed message.
+    pub data: String,
+    /// Exact text of the signed part of a multipart/signed, empty otherwise.
+    pub signed: String,
+}
+
+/// Readable content of an OpenPGP protected message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Opened {
+    /// Decrypted MIME entity, it replaces the parts of the message.
+    pub entity: Option<String>,
+    /// Decrypted or verified text of inline OpenPGP, it replaces the text
+    /// of the message.
+    pub text: Option<String>,
+    pub security: MessageSecurity,
+}
+
+/// First OpenPGP protected entity of a message source.
+pub fn detect(source: &str) -> Option<PgpPart> {
+    return detect_entity(&entity::parse(source), 0);
+}
+
+fn detect_entity(entity: &Entity, depth: usize) -> Option<PgpPart> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+
+    let content_type = entity.content_type();
+    let protocol = entity.param("protocol").unwrap_or_default().to_lowercase();
+
+    if content_type == "multipart/encrypted" && protocol == "application/pgp-encrypted" {
+        let parts = entity.parts();
+        let data = match parts.get(1) {
+            Some(part) => part.text(),
+            None => return None,
+        };
+
+        if !data.contains("-----BEGIN PGP MESSAGE-----") {
+            return None;
+        }
+
+        return Some(PgpPart {
+            kind: Kind::Encrypted,
+            data,
+            signed: String::new(),
+        });
+    }
+
+    if content_type == "multipart/signed" && protocol == "application/pgp-signature" {
+        let parts = entity.parts();
+
+        if parts.len() < 2 {
+            return None;
+        }
+
+        return Some(PgpPart {
+            kind: Kind::Signed,
+            data: parts[1].text(),
+            signed: parts[0].raw.to_string(),
+        });
+    }
+
+    if content_type.starts_with("multipart/") {
+        return entity
+            .parts()
+            .iter()
+            .find_map(|part| detect_entity(part, depth + 1));
+    }
+
+    if content_type == "text/plain" {
+        let text = entity.text();
+
+        let block = match armored_block(&text, "PGP MESSAGE", "PGP MESSAGE")
+            .or_else(|| armored_block(&text, "PGP SIGNED MESSAGE", "PGP SIGNATURE"))
+        {
+            Some(block) => block,
+            None => return None,
+        };
+
+        return Some(PgpPart {
+            kind: Kind::Inline,
+            data: block.to_string(),
+            signed: String::new(),
+        });
+    }
+
+    return None;
+}
+
+/// Text from the `begin` line up to the end of the `end` block.
+fn armored_block<'a>(text: &'a str, begin: &str, end: &str) -> Option<&'a str> {
+    let start = match text.find(&format!("-----BEGIN {}-----", begin)) {
+        Some(start) => start,
+        None => return None,
+    };
+    let end_line = format!("-----END {}-----", end);
+    let length = match text[start..].find(&end_line) {
+        Some(position) => position + end_line.len(),
+        None => return None,
+    };
+
+    return Some(&text[start..start + length]);
+}
+
+/// Decrypts and verifies `part` with `certs`, failures are reported in the
+/// security result rather than as errors. `senders` are the From and Sender
+/// addresses, a good signature by a key without any of them is a mismatch.
+pub fn open(part: &PgpPart, certs: &[Cert], senders: &[String]) -> Opened {
+    match part.kind {
+        Kind::Encrypted => return open_encrypted(part, certs, senders),
+        Kind::Signed => {
+            let verifications = verify_signed(&part.signed, &part.data, certs);
+
+            return Opened {
+                entity: None,
+                text: None,
+                security: security(false, verifications, certs, senders),
+            };
+        }
+        Kind::Inline if armor::is_cleartext(&part.data) => {
+            let cleartext = match armor::cleartext(&part.data) {
+                Ok(cleartext) => cleartext,
+                Err(e) => {
+                    return Opened {
+                        entity: None,
+                        text: None,
+                        security: security(false, Err(e), certs, senders),
+                    }
+                }
+            };
+
+            let verifications = message::verify_cleartext(&cleartext, certs);
+
+            return Opened {
+                entity: None,
+                text: Some(cleartext.text),
+                security: security(false, verifications, certs, senders),
+            };
+        }
+        Kind::Inline => match decrypt(&part.data, certs) {
+            Ok(opened) => {
+                return Opened {
+                    entity: None,
+                    text: Some(String::from_utf8_lossy(&opened.data).to_string()),
+                    security: decrypted(opened.encrypted, opened.signatures, certs, senders),
+                }
+            }
+            Err(e) => {
+                return Opened {
+                    entity: None,
+                    text: None,
+                    security: failed(&e),
+                }
+            }
+        },
+    }
+}
+
+fn open_encrypted(part: &PgpPart, certs: &[Cert], senders: &[String]) -> Opened {
+    let opened = match decrypt(&part.data, certs) {
+        Ok(opened) => opened,
+        Err(e) => {
+            return Opened {
+                entity: None,
+                text: None,
+                security: failed(&e),
+            }
+        }
+    };
+
+    let content = String::from_utf8_lossy(&opened.data).to_string();
+
+    // Signed and then encrypted as two layers (RFC 3156 section 6.1), an
+    // inner signature that can't be read is bad like an outer one.
+    let inner = entity::parse(&content);
+    if let Some(PgpPart {
+        kind: Kind::Signed,
+        data,
+        signed,
+    }) = detect_entity(&inner, MAX_DEPTH)
+    {
+        let verifications = match verify_signed(&signed, &data, certs) {
+            Ok(verifications) => {
+                let mut signatures = opened.signatures;
+                signatures.extend(verifications);
+
+                Ok(signatures)
+            }
+            Err(e) => Err(e),
+        };
+
+        return Opened {
+            entity: Some(signed),
+            text: None,
+            security: security(true, verifications, certs, senders),
+        };
+    }
+
+    return Opened {
+        entity: Some(content),
+        text: None,
+        security: decrypted(true, opened.signatures, certs, senders),
+    };
+}
+
+fn decrypt(armored: &str, certs: &[Cert]) -> Result<message::Opened, MyError> {
+    let armor = match armor::decode(armored) {
+        Ok(armor) => armor,
+        Err(e) => return Err(e),
+    };
+
+    return message::open(&armor.data, certs);
+}
+
+/// Signatures of a multipart/signed, made over its first part with
+/// canonical line endings.
+fn verify_signed(
+    signed: &str,
+    signature: &str,
+    certs: &[Cert],
+) -> Result<Vec<Verification>, MyError> {
+    let armor = match armor::decode(signature) {
+        Ok(armor) => armor,
+        Err(e) => return Err(e),
+    };
+
+    return message::verify_detached(
+        &signature::canonical_text(signed.as_bytes()),
+        &armor.data,
+        certs,
+    );
+}
+
+fn security(
+    encrypted: bool,
+    verifications: Result<Vec<Verification>, MyError>,
+    certs: &[Cert],
+    senders: &[String],
+) -> MessageSecurity {
+    match verifications {
+        Ok(verifications) => return decrypted(encrypted, verifications, certs, senders),
+        Err(e) => {
+            return MessageSecurity {
+                protocol: String::from("openpgp"),
+                encrypted,
+                decrypted: encrypted,
+                signature: String::from(Status::Bad.name()),
+                signer: None,
+                error: Some(detail(&e)),
+            }
+        }
+    }
+}
+
+/// The first good signature by a key of a sender stands for the message,
+/// otherwise the first good one, reported as a `mismatch`, or the first one.
+fn decrypted(
+    encrypted: bool,
+    verifications: Vec<Verification>,
+    certs: &[Cert],
+    senders: &[String],
+) -> MessageSecurity {
+    let by_sender = |verification: &Verification| -> bool {
+        return certs.iter().any(|cert| {
+            return cert.fingerprint() == verification.fingerprint
+                && senders.iter().any(|sender| cert.has_address(sender));
+        });
+    };
+
+    let verification = verifications
+        .iter()
+        .find(|verification| verification.status == Status::Good && by_sender(verification))
+        .or(verifications
+            .iter()
+            .find(|verification| verification.status == Status::Good))
+        .or(verifications.first());
+
+    return MessageSecurity {
+        protocol: String::from("openpgp"),
+        encrypted,
+        decrypted: encrypted,
+        signature: match verification {
+            Some(verification)
+                if verification.status == Status::Good && !by_sender(verification) =>
+            {
+                String::from("mismatch")
+            }
+            Some(verification) => verification.status.name().to_string(),
+            None => String::from("none"),
+        },
+        signer: verification.map(|verification| Signer {
+            fingerprint: verification.fingerprint.clone(),
+            identity: verification.user_id.clone(),
+            signed_at: verification.created * 1000,
+            expires: None,
+        }),
+        error: None,
+    };
+}
+
+fn failed(err: &MyError) -> MessageSecurity {
+    return MessageSecurity {
+        protocol: String::from("openpgp"),
+        encrypted: true,
+        decrypted: false,
+        signature: String::from("none"),
+        signer: None,
+        error: Some(detail(err)),
+    };
+}
+
+fn detail(err: &MyError) -> String {
+    match err {
+        MyError::String(detail, _) => return detail.clone(),
+        _ => return err.to_string(),
+    }
+}
+
+/// `source` as a multipart/signed by `signer` (RFC 3156 section 5).
+pub fn sign(source: &str, signer: &Cert, now: i64) -> Result<String, MyError> {
+    let (headers, content) = split_content(source);
+    let signature = match message::sign_detached(content.as_bytes(), signer, now) {
+        Ok(signature) => signature,
+        Err(e) => return Err(e),
+    };
+
+    let boundary = boundary();
+    let mut signed = headers;
+    signed.push_str(&format!(
+        "MIME-Version: 1.0\r\nContent-Type: multipart/signed; boundary=\"{}\";\r\n micalg=pgp-sha256; protocol=\"application/pgp-signature\"\r\n\r\n",
+        boundary
+    ));
+    signed.push_str("This is an OpenPGP/MIME signed message (RFC 4880 and 3156)\r\n");
+    signed.push_str(&format!("--{}\r\n{}\r\n", boundary, content));
+    signed.push_str(&format!(
+        "--{}\r\nContent-Type: application/pgp-signature; name=\"signature.asc\"\r\nContent-Description: OpenPGP digital signature\r\nContent-Disposition: attachment; filename=\"signature.asc\"\r\n\r\n",
+        boundary
+    ));
+    signed.push_str(&armor::encode(armor::SIGNATURE, &signature));
+    signed.push_str(&format!("--{}--\r\n", boundary));
+
+    return Ok(signed);
+}
+
+/// `source` as a multipart/encrypted to `recipients`, signed by `signer`
+/// inside the encryption when given (RFC 3156 section 6.2).
+pub fn encrypt(
+    source: &str,
+    recipients: &[&Cert],
+    signer: Option<&Cert>,
+    now: i64,
+) -> Result<String, MyError> {
+    let (headers, content) = split_content(source);
+    let encrypted = match message::encrypt(content.as_bytes(), recipients, signer, now) {
+        Ok(encrypted) => encrypted,
+        Err(e) => return Err(e),
+    };
+
+    let boundary = boundary();
+    let mut message = headers;
+    message.push_str(&format!(
+        "MIME-Version: 1.0\r\nContent-Type: multipart/encrypted; boundary=\"{}\";\r\n protocol=\"application/pgp-encrypted\"\r\n\r\n",
+        boundary
+    ));
+    message.push_str("This is an OpenPGP/MIME encrypted message (RFC 4880 and 3156)\r\n");
+    message.push_str(&format!(
+        "--{}\r\nContent-Type: application/pgp-encrypted\r\nContent-Description: PGP/MIME version identification\r\n\r\nVersion: 1\r\n\r\n",
+        boundary
+    ));
+    message.push_str(&format!(
+        "--{}\r\nContent-Type: application/octet-stream; name=\"encrypted.asc\"\r\nContent-Description: OpenPGP encrypted message\r\nContent-Disposition: inline; filename=\"encrypted.asc\"\r\n\r\n",
+        boundary
+    ));
+    message.push_str(&armor::encode(armor::MESSAGE, &encrypted));
+    message.push_str(&format!("--{}--\r\n", boundary));
+
+    return Ok(message);
+}
+
+/// Headers of `source` that stay outside the protected entity, with the
+/// address headers written in a single form, and the entity made of its
+/// Content and Autocrypt-Gossip headers and body with CRLF line endings.
+pub fn split_content(source: &str) -> (String, String) {
+    let source = String::from_utf8_lossy(&signature::canonical_text(source.as_bytes())).to_string();
+    let (header_block, body) = match source.split_once("\r\n\r\n") {
+        Some((headers, body)) => (headers.to_string(), body.to_string()),
+        None => (source.trim_end().to_string(), String::new()),
+    };
+
+    // Folded lines belong to the header before them.
+    let mut fields: Vec<String> = vec![];
+    for line in header_block.split("\r\n") {
+        match fields.last_mut() {
+            Some(field) if line.starts_with([' ', '\t']) => {
+                field.push_str("\r\n");
+                field.push_str(line);
+            }
+            _ => fields.push(line.to_string()),
+        }
+    }
+
+    let mut headers = String::new();
+    let mut content_headers = String::new();
+
+    for field in fields {
+        let name = field.split(':').next().unwrap_or("").trim().to_lowercase();
+
+        if name == "mime-version" {
+            continue;
+        }
+
+        let field = match ADDRESS_HEADERS.contains(&name.as_str()) {
+            true => address_field(field),
+            false => field,
+        };
+
+        // Gossiped keys are only for the recipients, Autocrypt Level 1
+        // section 2.7.
+        let target = match name.starts_with("content-") || name == "autocrypt-gossip" {
+            true => &mut content_headers,
+            false => &mut headers,
+        };
+        target.push_str(&field);
+        target.push_str("\r\n");
+    }
+
+    if content_headers.is_empty() {
+        content_headers.push_str("Content-Type: text/plain; charset=utf-8\r\n");
+    }
+
+    return (headers, format!("{}\r\n{}", content_headers, body));
+}
+
+/// An address header from the parsed addresses of its value, left as it is
+/// without any, e.g. `To: undisclosed-recipients:;`.
+fn address_field(field: String) -> String {
+    let (name, value) = match field.split_once(':') {
+        Some((name, value)) => (name.trim(), value.replace("\r\n", "")),
+        None => return field,
+    };
+
+    let addresses = parse_address::parse_list(&value);
+    if addresses.is_empty() {
+        return field;
+    }
+
+    return parse_address::format_header(name, &addresses);
+}
+
+fn boundary() -> String {
+    return format!("pgp-{}", hex(&crypto::random(12)).to_lowercase());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openpgp::key;
+
+    fn fixture(name: &str) -> String {
+        return std::fs::read_to_string(format!(
+            "{}/tests/fixtures/openpgp/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        ))
+        .unwrap();
+    }
+
+    fn from(address: &str) -> Vec<String> {
+        return vec![address.to_string()];
+    }
+
+    fn cert(name: &str) -> Cert {
+        let mut cert = key::read_armored(&fixture(name)).unwrap().remove(0);
+        cert.unlock("alice passphrase").unwrap();
+
+        return cert;
+    }
+
+    #[test]
+    fn encrypted_message() {
+        let part = detect(&fixture("pgp_mime_encrypted.eml")).unwrap();
+        assert_eq!(part.kind, Kind::Encrypted);
+
+        let opened = open(
+            &part,
+            &[cert("alice_secret.asc"), cert("bob_public.asc")],
+            &from("bob@example.com"),
+        );
+        assert!(opened
+            .entity
+            .unwrap()
+            .contains("The launch moves to Thursday, caf=C3=A9 at ten."));
+        assert_eq!(opened.security.signature, "good");
+        assert!(opened.security.decrypted);
+        assert_eq!(
+            opened.security.signer.unwrap().fingerprint,
+            "C1742C35F16FA02F540657BF4FF6F77928021E86"
+        );
+
+        // Without the secret key.
+        let opened = open(&part, &[cert("bob_public.asc")], &from("bob@example.com"));
+        assert_eq!(opened.entity, None);
+        assert!(opened.security.encrypted);
+        assert!(!opened.security.decrypted);
+        assert_eq!(
+            opened.security.error.as_deref(),
+            Some("No secret key for any recipient")
+        );
+    }
+
+    #[test]
+    fn signed_message() {
+        let source = fixture("pgp_mime_signed.eml");
+        let part = detect(&source).unwrap();
+        assert_eq!(part.kind, Kind::Signed);
+        assert_eq!(
+            part.signed,
+            "Content-Type: text/plain; charset=utf-8\r\n\r\nSigned by Alice.\r\n"
+        );
+
+        let opened = open(
+            &part,
+            &[cert("alice_public.asc")],
+            &from("alice@example.com"),
+        );
+        assert_eq!(opened.security.signature, "good");
+        assert!(!opened.security.encrypted);
+
+        assert_eq!(
+            open(&part, &[], &from("alice@example.com"))
+                .security
+                .signature,
+            "unknown_key"
+        );
+
+        // A good signature, but not by a key of the sender.
+        let opened = open(
+            &part,
+            &[cert("alice_public.asc")],
+            &from("mallory@example.com"),
+        );
+        assert_eq!(opened.security.signature, "mismatch");
+        assert_eq!(
+            opened.security.signer.unwrap().identity,
+            "Alice <alice@example.com>"
+        );
+
+        let tampered = detect(&source.replace("Signed by Alice.", "Signed by Mallory.")).unwrap();
+        assert_eq!(
+            open(
+                &tampered,
+                &[cert("alice_public.asc")],
+                &from("alice@example.com")
+            )
+            .security
+            .signature,
+            "bad"
+        );
+    }
+
+    #[test]
+    fn inline() {
+        let source = format!(
+            "Subject: Hi\r\nContent-Type: text/plain\r\n\r\nSee below.\r\n\r\n{}",
+            fixture("bob_clearsigned.asc")
+        );
+        let part = detect(&source).unwrap();
+        assert_eq!(part.kind, Kind::Inline);
+
+        let opened = open(&part, &[cert("bob_public.asc")], &from("bob@example.com"));
+        assert_eq!(opened.security.signature, "good");
+        assert!(opened.text.is_some());
+
+        let source = format!("Subject: Hi\r\n\r\n{}", fixture("bob_sha1_clearsigned.asc"));
+        let opened = open(
+            &detect(&source).unwrap(),
+            &[cert("bob_public.asc")],
+            &from("bob@example.com"),
+        );
+        assert_eq!(opened.security.signature, "weak_hash");
+
+        let source = format!("Subject: Hi\r\n\r\n{}", fixture("to_alice.asc"));
+        let opened = open(&detect(&source).unwrap(), &[cert("alice_secret.asc")], &[]);
+        assert!(opened.security.decrypted);
+        assert_eq!(opened.security.signature, "none");
+
+        assert_eq!(detect("Subject: Hi\r\n\r\nHello\r\n"), None);
+    }
+
+    #[test]
+    fn protect() {
+        let alice = cert("alice_secret.asc");
+        let bob = cert("bob_secret.asc");
+        let source = "From: Alice <alice@example.com>\r\nTo: bob@example.com\r\nSubject: Plans\r\nMIME-Version: 1.0\r\nContent-Type: text/plain;\r\n charset=utf-8\r\n\r\nSee you there.\r\n";
+
+        let signed = sign(source, &alice, 1_700_000_000).unwrap();
+        assert!(signed.starts_with("From: Alice <alice@example.com>\r\nTo: bob@example.com\r\nSubject: Plans\r\nMIME-Version: 1.0\r\nContent-Type: multipart/signed;"));
+
+        let part = detect(&signed).unwrap();
+        assert_eq!(
+            part.signed,
+            "Content-Type: text/plain;\r\n charset=utf-8\r\n\r\nSee you there.\r\n"
+        );
+        assert_eq!(
+            open(
+                &part,
+                std::slice::from_ref(&alice),
+                &from("alice@example.com")
+            )
+            .security
+            .signature,
+            "good"
+        );
+
+        let encrypted = encrypt(source, &[&bob], Some(&alice), 1_700_000_000).unwrap();
+        let opened = open(
+            &detect(&encrypted).unwrap(),
+            &[bob, alice],
+            &from("alice@example.com"),
+        );
+        assert_eq!(opened.security.signature, "good");
+        assert_eq!(
+            opened.entity.as_deref(),
+            Some("Content-Type: text/plain;\r\n charset=utf-8\r\n\r\nSee you there.\r\n")
+        );
+    }
+
+    #[test]
+    fn unreadable_inner_signature() {
+        let alice = cert("alice_secret.asc");
+        let bob = cert("bob_secret.asc");
+        let source = "From: alice@example.com\r\nSubject: Plans\r\n\r\nSee you there.\r\n";
+
+        let signed = sign(source, &alice, 1_700_000_000).unwrap();
+        let start = signed.find("-----BEGIN PGP SIGNATURE-----").unwrap();
+        let end = signed.find("-----END PGP SIGNATURE-----").unwrap();
+        let signed = format!(
+            "{}-----BEGIN PGP SIGNATURE-----\r\n\r\n!!!!\r\n{}",
+            &signed[..start],
+            &signed[end..]
+        );
+
+        let encrypted = encrypt(&signed, &[&bob], None, 1_700_000_000).unwrap();
+        let opened = open(
+            &detect(&encrypted).unwrap(),
+            &[bob, alice],
+            &from("alice@example.com"),
+        );
+
+        assert!(opened.security.decrypted);
+        assert_eq!(opened.security.signature, "bad");
+        assert!(opened.security.error.is_some());
+        assert_eq!(
+            opened.entity.as_deref(),
+            Some("Content-Type: text/plain; charset=utf-8\r\n\r\nSee you there.\r\n")
+        );
+    }
+
+    #[test]
+    fn address_headers() {
+        let (headers, _) = split_content(
+            "From: \"Alice\" <alice@example.com>\r\nTo: bob@example.com,\r\n Carol\r\n <carol@b\u{fc}cher.example>\r\nBcc: undisclosed-recipients:;\r\n\r\nHi\r\n",
+        );
+        assert_eq!(
+            headers,
+            "From: Alice <alice@example.com>\r\nTo: bob@example.com, Carol <carol@xn--bcher-kva.example>\r\nBcc: undisclosed-recipients:;\r\n"
+        );
+    }
+}
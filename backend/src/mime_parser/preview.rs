@@ -0,0 +1,122 @@
+use regex::Regex;
+
+/// Number of characters of a message shown in the message list.
+pub const PREVIEW_LENGTH: usize = 200;
+
+/// Plain text snippet of a message for the message list. The text part is
+/// used when there is one, otherwise the text of the html part. Quoted
+/// replies and the signature are left out.
+pub fn build(text: &str, html: &str) -> String {
+    let plain = if !text.trim().is_empty() {
+        text.to_string()
+    } else {
+        strip_html(html)
+    };
+
+    let mut lines: Vec<&str> = vec![];
+    for line in plain.lines() {
+        if line == "-- " {
+            break;
+        }
+
+        if line.trim_start().starts_with('>') {
+            continue;
+        }
+
+        lines.push(line);
+    }
+
+    let collapsed = lines
+        .iter()
+        .flat_map(|line| line.split_whitespace())
+        .collect::<Vec<&str>>()
+        .join(" ");
+
+    return collapsed.chars().take(PREVIEW_LENGTH).collect();
+}
+
+/// Decodes a quoted-printable body. The parser joins the lines of a part, so
+/// a soft line break is a lone `=` and is dropped.
+pub fn decode_quoted_printable(body: &str) -> String {
+    let bytes = body.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'=' {
+            decoded.push(bytes[i]);
+            i += 1;
+
+            continue;
+        }
+
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match hex {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => i += 1,
+        }
+    }
+
+    return String::from_utf8_lossy(&decoded).to_string();
+}
+
+fn strip_html(html: &str) -> String {
+    let re_hidden =
+        Regex::new(r"(?is)<head.*?</head>|<style.*?</style>|<script.*?</script>|<!--.*?-->")
+            .unwrap();
+    let re_break = Regex::new(r"(?i)<br\s*/?>|</(p|div|tr|li|h[1-6])>").unwrap();
+    let re_tag = Regex::new(r"(?s)<[^>]*>").unwrap();
+
+    let html = re_hidden.replace_all(html, " ");
+    let html = re_break.replace_all(&html, "\n");
+    let html = re_tag.replace_all(&html, " ");
+
+    return html
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_preview() {
+        let text = "Hi Bob,\n\n  see   you\ttomorrow.\n> earlier message\n-- \nAlice";
+
+        assert_eq!(build(text, "<p>ignored</p>"), "Hi Bob, see you tomorrow.");
+    }
+
+    #[test]
+    fn html_preview() {
+        let html = "<html><head><title>t</title><style>p{}</style></head><body><p>Hello&nbsp;&amp; welcome</p><div>Second</div></body></html>";
+
+        assert_eq!(build("", html), "Hello & welcome Second");
+    }
+
+    #[test]
+    fn preview_length() {
+        let text = "é".repeat(PREVIEW_LENGTH + 10);
+
+        assert_eq!(build(&text, "").chars().count(), PREVIEW_LENGTH);
+    }
+
+    #[test]
+    fn quoted_printable() {
+        assert_eq!(
+            decode_quoted_printable("caf=C3=A9 =3D long=line"),
+            "café = longline"
+        );
+    }
+}
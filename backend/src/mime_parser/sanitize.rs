@@ -0,0 +1,482 @@
+use ammonia::Builder;
+use regex::{Captures, Regex};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::sync::{Arc, Mutex};
+
+/// Remote content left out of a message body. Images and stylesheets are
+/// only blocked while remote content is not allowed for the sender, tracking
+/// pixels are always removed.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct RemoteContent {
+    pub images: u32,
+    pub styles: u32,
+    pub tracking_pixels: u32,
+    pub hosts: Vec<String>,
+}
+
+impl RemoteContent {
+    pub fn is_blocked(&self) -> bool {
+        return self.images > 0 || self.styles > 0;
+    }
+
+    fn add_host(&mut self, url: &str) {
+        let host = host(url);
+
+        if !host.is_empty() && !self.hosts.contains(&host) {
+            self.hosts.push(host);
+        }
+    }
+}
+
+/// Tags allowed on top of the ammonia defaults, for the table based layouts
+/// of html mail.
+const EXTRA_TAGS: [&str; 4] = ["style", "center", "font", "big"];
+
+const EXTRA_ATTRIBUTES: [&str; 13] = [
+    "style",
+    "class",
+    "align",
+    "valign",
+    "bgcolor",
+    "background",
+    "width",
+    "height",
+    "border",
+    "cellpadding",
+    "cellspacing",
+    "color",
+    "face",
+];
+
+/// Removes scripts, event handlers, forms, frames and dangerous CSS from the
+/// html of a message. Remote images and stylesheets are removed as well
+/// unless `allow_remote` is set, what was removed is reported so the client
+/// can offer to load it.
+pub fn sanitize(html: &str, allow_remote: bool) -> (String, RemoteContent) {
+    let mut remote = RemoteContent::default();
+
+    let html = remove_tracking_pixels(html, &mut remote);
+    let html = sanitize_style_elements(&html, allow_remote, &mut remote);
+
+    let shared = Arc::new(Mutex::new(remote));
+    let filter_remote = Arc::clone(&shared);
+
+    let clean = Builder::default()
+        .add_tags(&EXTRA_TAGS)
+        .rm_clean_content_tags(&["style"])
+        .add_generic_attributes(&EXTRA_ATTRIBUTES)
+        .add_tag_attributes("font", &["size"])
+        .add_url_schemes(&["cid", "data"])
+        .link_rel(Some("noopener noreferrer"))
+        .attribute_filter(move |element, attribute, value| {
+            let mut remote = filter_remote.lock().unwrap();
+
+            return filter_attribute(element, attribute, value, allow_remote, &mut remote);
+        })
+        .clean(&html)
+        .to_string();
+
+    let remote = shared.lock().unwrap().clone();
+
+    return (clean, remote);
+}
+
+fn filter_attribute<'u>(
+    element: &str,
+    attribute: &str,
+    value: &'u str,
+    allow_remote: bool,
+    remote: &mut RemoteContent,
+) -> Option<Cow<'u, str>> {
+    if attribute == "style" {
+        return Some(Cow::Owned(sanitize_css(value, allow_remote, remote)));
+    }
+
+    let is_image = (element == "img" && (attribute == "src" || attribute == "srcset"))
+        || attribute == "background";
+
+    let urls = match attribute {
+        "srcset" => srcset_urls(value),
+        _ => vec![value],
+    };
+
+    if urls
+        .iter()
+        .any(|url| is_data(url) && !(is_image && is_local_image(url)))
+    {
+        return None;
+    }
+
+    if is_image {
+        if allow_remote || urls.into_iter().all(is_local_image) {
+            return Some(Cow::Borrowed(value));
+        }
+
+        remote.images += 1;
+        remote.add_host(value);
+
+        return None;
+    }
+
+    let scheme = value.trim_start().to_lowercase();
+    if scheme.starts_with("cid:") {
+        return None;
+    }
+
+    return Some(Cow::Borrowed(value));
+}
+
+/// Stylesheets in `<style>` elements, ammonia only filters their markup.
+fn sanitize_style_elements(html: &str, allow_remote: bool, remote: &mut RemoteContent) -> String {
+    let re_style = Regex::new(r"(?is)(<style\b[^>]*>)(.*?)(</style\s*>)").unwrap();
+
+    return re_style
+        .replace_all(html, |caps: &Captures| {
+            format!(
+                "{}{}{}",
+                &caps[1],
+                sanitize_css(&caps[2], allow_remote, remote),
+                &caps[3]
+            )
+        })
+        .to_string();
+}
+
+/// Neutralises CSS able to run code, pull in other stylesheets or load remote
+/// resources. Escapes are removed first, they could hide any of these.
+fn sanitize_css(css: &str, allow_remote: bool, remote: &mut RemoteContent) -> String {
+    let re_import = Regex::new(r"(?i)@import[^;]*;?").unwrap();
+    let re_dangerous =
+        Regex::new(r"(?i)expression\s*\(|behavior\s*:|-moz-binding|javascript:|vbscript:").unwrap();
+    let re_url = Regex::new(r#"(?i)url\(\s*(?:"([^"]*)"|'([^']*)'|([^)]*?))\s*\)"#).unwrap();
+    // Image sets also take bare strings as URLs, the strings in `type()` are
+    // mime types. The arguments may nest two levels of functions, an unclosed
+    // set runs to the end like in a browser.
+    let re_image_set =
+        Regex::new(r"(?i)(?:-webkit-)?image-set\s*\(((?:[^()]|\((?:[^()]|\([^()]*\))*\))*)\)?")
+            .unwrap();
+    let re_set_url = Regex::new(
+        r#"(?i)type\([^)]*\)|url\(\s*(?:"([^"]*)"|'([^']*)'|([^)"']*?))\s*\)|"([^"]*)"|'([^']*)'"#,
+    )
+    .unwrap();
+
+    let css = css.replace('\\', "");
+    let css = re_import.replace_all(&css, "");
+    let css = re_dangerous.replace_all(&css, |caps: &Captures| {
+        if caps[0].ends_with('(') {
+            return String::from("blocked(");
+        } else if caps[0].ends_with(':') {
+            return String::from("blocked:");
+        }
+
+        return String::from("blocked");
+    });
+    let css = re_image_set.replace_all(&css, |caps: &Captures| {
+        let urls: Vec<&str> = re_set_url
+            .captures_iter(&caps[1])
+            .filter_map(|url| (1..=5).find_map(|i| url.get(i)))
+            .map(|url| url.as_str())
+            .collect();
+
+        let blocked: Vec<&str> = urls
+            .into_iter()
+            .filter(|url| !is_local_image(url) && (!allow_remote || is_data(url)))
+            .collect();
+        if blocked.is_empty() {
+            return caps[0].to_string();
+        }
+
+        if blocked.iter().any(|url| !is_data(url)) {
+            remote.styles += 1;
+        }
+        for url in blocked.iter().filter(|url| !is_data(url)) {
+            remote.add_host(url);
+        }
+
+        return String::from("none");
+    });
+
+    return re_url
+        .replace_all(&css, |caps: &Captures| {
+            let url = caps
+                .get(1)
+                .or(caps.get(2))
+                .or(caps.get(3))
+                .map(|url| url.as_str())
+                .unwrap_or("");
+
+            if is_local_image(url) || (allow_remote && !is_data(url)) {
+                return caps[0].to_string();
+            }
+
+            if is_data(url) {
+                return String::from("none");
+            }
+
+            remote.styles += 1;
+            remote.add_host(url);
+
+            return String::from("none");
+        })
+        .to_string();
+}
+
+/// Remote images of at most one pixel or hidden from view, used to report
+/// that and when a message was opened.
+fn remove_tracking_pixels(html: &str, remote: &mut RemoteContent) -> String {
+    let re_img = Regex::new(r#"(?is)<img\b(?:[^>"']|"[^"]*"|'[^']*')*>"#).unwrap();
+
+    return re_img
+        .replace_all(html, |caps: &Captures| {
+            let tag = &caps[0];
+
+            let src = attribute_value(tag, "src").unwrap_or_default();
+            if is_local_image(&src) || !is_tracking_pixel(tag) {
+                return tag.to_string();
+            }
+
+            remote.tracking_pixels += 1;
+            remote.add_host(&src);
+
+            return String::new();
+        })
+        .to_string();
+}
+
+fn is_tracking_pixel(tag: &str) -> bool {
+    let tiny = |value: Option<String>| match value {
+        Some(value) => match value.trim().trim_end_matches("px").trim().parse::<f32>() {
+            Ok(size) => size <= 1.0,
+            Err(_) => false,
+        },
+        None => false,
+    };
+
+    if tiny(attribute_value(tag, "width")) && tiny(attribute_value(tag, "height")) {
+        return true;
+    }
+
+    let style = attribute_value(tag, "style")
+        .unwrap_or_default()
+        .to_lowercase()
+        .replace(' ', "");
+
+    let re_size = Regex::new(r"(^|;)(width|height):0*[01](\.0*)?px").unwrap();
+
+    return style.contains("display:none")
+        || style.contains("visibility:hidden")
+        || re_size.find_iter(&style).count() >= 2;
+}
+
+fn attribute_value(tag: &str, name: &str) -> Option<String> {
+    let re_attribute = Regex::new(&format!(
+        r#"(?is)\s{}\s*=\s*(?:"([^"]*)"|'([^']*)'|([^\s>]+))"#,
+        name
+    ))
+    .unwrap();
+
+    return re_attribute.captures(tag).map(|caps| {
+        caps.get(1)
+            .or(caps.get(2))
+            .or(caps.get(3))
+            .map(|value| value.as_str().to_string())
+            .unwrap_or_default()
+    });
+}
+
+/// Images shown without a request: parts of the message and embedded images,
+/// svg left out as it can carry scripts. Browsers load anything else from
+/// somewhere, `https:\\host` and `https:host` included, so it counts as remote.
+fn is_local_image(url: &str) -> bool {
+    let url = url.trim().to_lowercase();
+
+    return url.starts_with("cid:")
+        || (url.starts_with("data:image/") && !url.starts_with("data:image/svg"));
+}
+
+fn is_data(url: &str) -> bool {
+    return url.trim_start().to_lowercase().starts_with("data:");
+}
+
+/// URLs of the image candidates of a `srcset`, each followed by optional
+/// descriptors. Data URLs may contain commas, a URL only ends at whitespace.
+fn srcset_urls(srcset: &str) -> Vec<&str> {
+    let mut urls = vec![];
+    let mut rest = srcset;
+
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        if rest.is_empty() {
+            break;
+        }
+
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let candidate = &rest[..end];
+        let url = candidate.trim_end_matches(',');
+        rest = &rest[end..];
+
+        urls.push(url);
+
+        // A URL ending in a comma has no descriptors.
+        if url.len() == candidate.len() {
+            match rest.find(',') {
+                Some(i) => rest = &rest[i + 1..],
+                None => break,
+            }
+        }
+    }
+
+    return urls;
+}
+
+/// Host of an absolute or protocol relative URL, empty for relative ones.
+fn host(url: &str) -> String {
+    let url = url.trim();
+    let (has_scheme, rest) = match url.split_once(':') {
+        Some((scheme, rest)) if is_scheme(scheme) => (true, rest),
+        _ => (false, url),
+    };
+
+    let authority = rest.trim_start_matches(['/', '\\']);
+    if !has_scheme && rest.len() - authority.len() < 2 {
+        return String::new();
+    }
+
+    let authority = authority.split(['/', '\\', '?', '#']).next().unwrap_or("");
+    let host = authority.rsplit('@').next().unwrap_or("");
+    let host = host.split(':').next().unwrap_or("");
+
+    return host.to_lowercase();
+}
+
+fn is_scheme(scheme: &str) -> bool {
+    return scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_active_content() {
+        let html = r#"<p onclick="steal()">Hi</p><script>alert(1)</script><form action="https://evil.example"><input name="password"></form><iframe src="https://evil.example"></iframe><a href="javascript:alert(1)">link</a>"#;
+
+        let (clean, remote) = sanitize(html, false);
+
+        assert_eq!(clean, r#"<p>Hi</p><a rel="noopener noreferrer">link</a>"#);
+        assert_eq!(remote, RemoteContent::default());
+    }
+
+    #[test]
+    fn blocks_remote_images() {
+        let html = r#"<img src="https://cdn.example.com/logo.png" alt="Logo"><img src="cid:logo@example"><img src="data:image/png;base64,AAAA"><img src="data:image/svg+xml;base64,AAAA"><table><tr><td background="http://img.example.org/bg.png"></td></tr></table>"#;
+
+        let (clean, remote) = sanitize(html, false);
+
+        assert_eq!(
+            clean,
+            r#"<img alt="Logo"><img src="cid:logo@example"><img src="data:image/png;base64,AAAA"><img><table><tbody><tr><td></td></tr></tbody></table>"#
+        );
+        assert_eq!(remote.images, 2);
+        assert_eq!(remote.hosts, vec!["cdn.example.com", "img.example.org"]);
+        assert!(remote.is_blocked());
+
+        let (clean, remote) = sanitize(html, true);
+
+        assert!(clean.contains(r#"<img src="https://cdn.example.com/logo.png" alt="Logo">"#));
+        assert!(!remote.is_blocked());
+    }
+
+    #[test]
+    fn blocks_malformed_remote_urls() {
+        let html = r#"<img src="https:\\evil.example/a.png"><img src="HTTPS:evil.example/b.png"><img src="images/c.png"><div style="background: url(https:\\evil.example/d.png)"></div>"#;
+
+        let (clean, remote) = sanitize(html, false);
+
+        assert_eq!(
+            clean,
+            r#"<img><img><img><div style="background: none"></div>"#
+        );
+        assert_eq!(remote.images, 3);
+        assert_eq!(remote.styles, 1);
+        assert_eq!(remote.hosts, vec!["evil.example"]);
+    }
+
+    #[test]
+    fn srcset_candidates() {
+        assert_eq!(
+            srcset_urls("a.png 1x, https://cdn.example.com/b.png 2x"),
+            vec!["a.png", "https://cdn.example.com/b.png"]
+        );
+        assert_eq!(
+            srcset_urls("data:image/png;base64,AA,AA 1x,cid:b"),
+            vec!["data:image/png;base64,AA,AA", "cid:b"]
+        );
+        assert_eq!(srcset_urls("cid:a,, cid:b"), vec!["cid:a", "cid:b"]);
+    }
+
+    #[test]
+    fn removes_tracking_pixels() {
+        let html = r#"<p>Hi</p><img src="https://t.example.net/open?id=1" width="1" height="1"><img style="display: none" src="https://t.example.net/o.gif"><img src="https://cdn.example.com/a.png" width="100" height="1">"#;
+
+        let (clean, remote) = sanitize(html, true);
+
+        assert_eq!(
+            clean,
+            r#"<p>Hi</p><img src="https://cdn.example.com/a.png" width="100" height="1">"#
+        );
+        assert_eq!(remote.tracking_pixels, 2);
+        assert_eq!(remote.hosts, vec!["t.example.net"]);
+    }
+
+    #[test]
+    fn sanitizes_css() {
+        let html = r#"<style>@import url(https://evil.example/a.css); p { background: url("https://cdn.example.com/bg.png") no-repeat; width: expression(alert(1)); }</style><div style="background-image: url('http://cdn.example.com/x.png'); color: red; -moz-binding: url(x.xml)">text</div>"#;
+
+        let (clean, remote) = sanitize(html, false);
+
+        assert_eq!(
+            clean,
+            r#"<style> p { background: none no-repeat; width: blocked(alert(1)); }</style><div style="background-image: none; color: red; blocked: none">text</div>"#
+        );
+        // The relative `x.xml` counts as remote as well.
+        assert_eq!(remote.styles, 3);
+        assert_eq!(remote.hosts, vec!["cdn.example.com"]);
+    }
+
+    #[test]
+    fn blocks_image_sets() {
+        let html = r#"<div style="background: image-set('https://a.example/x.png' 1x, url(cid:b@example) 2x)"></div><div style="background: -webkit-image-set(&quot;https://b.example/x.png&quot; 1x)"></div><div style="background: IMAGE-SET(url(https://c.example/x.png) 1x, type('image/png'))"></div><div style="background: image-set(&quot;https://d.example/x.png&quot; 1x"></div><div style="background: image-set(&quot;cid:e@example&quot; 1x, 'data:image/png;base64,AAAA' 2x type('image/png'))"></div>"#;
+
+        let (clean, remote) = sanitize(html, false);
+
+        assert_eq!(
+            clean,
+            r#"<div style="background: none"></div><div style="background: none"></div><div style="background: none"></div><div style="background: none"></div><div style="background: image-set(&quot;cid:e@example&quot; 1x, 'data:image/png;base64,AAAA' 2x type('image/png'))"></div>"#
+        );
+        assert_eq!(remote.styles, 4);
+        assert_eq!(
+            remote.hosts,
+            vec!["a.example", "b.example", "c.example", "d.example"]
+        );
+
+        let (clean, remote) = sanitize(html, true);
+
+        assert!(clean.contains("image-set('https://a.example/x.png' 1x"));
+        assert!(!remote.is_blocked());
+    }
+
+    #[test]
+    fn hosts() {
+        assert_eq!(host("https://user@Example.com:8080/path?q"), "example.com");
+        assert_eq!(host("//cdn.example.com/a.png"), "cdn.example.com");
+        assert_eq!(host("https:\\\\Evil.example\\a.png"), "evil.example");
+        assert_eq!(host("https:evil.example/a.png"), "evil.example");
+        assert_eq!(host("images/a.png"), "");
+        assert_eq!(host("/images/a.png"), "");
+    }
+}
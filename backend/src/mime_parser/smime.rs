@@ -0,0 +1,735 @@
+use std::fs;
+use std::path::Path;
+
+use chrono::Utc;
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkcs12::Pkcs12;
+use openssl::pkcs7::{Pkcs7, Pkcs7Flags};
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::x509::store::{X509Store, X509StoreBuilder};
+use openssl::x509::{X509Ref, X509};
+
+use crate::mime_parser::entity::{self, Entity};
+use crate::mime_parser::pgp_mime;
+use crate::my_error::MyError;
+use crate::openpgp::signature::hex;
+use crate::types::message::{MessageSecurity, Signer};
+
+/// Multiparts nest, deeper entities are not searched.
+const MAX_DEPTH: usize = 8;
+
+/// How a message is protected with S/MIME.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// multipart/signed with an application/pkcs7-signature part.
+    Signed,
+    /// application/pkcs7-mime signed-data, the content is inside the
+    /// signature.
+    Opaque,
+    /// application/pkcs7-mime enveloped-data.
+    Enveloped,
+}
+
+impl Kind {
+    pub fn name(&self) -> &str {
+        match self {
+            Kind::Signed => return "signed",
+            Kind::Opaque => return "opaque",
+            Kind::Enveloped => return "enveloped",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Kind> {
+        match name {
+            "signed" => return Some(Kind::Signed),
+            "opaque" => return Some(Kind::Opaque),
+            "enveloped" => return Some(Kind::Enveloped),
+            _ => return None,
+        }
+    }
+}
+
+/// S/MIME entity of a message, found when it is fetched and opened when it
+/// is read with the identities of the account.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SmimePart {
+    pub kind: Kind,
+    /// Exact text of the entity with its headers.
+    pub data: String,
+}
+
+/// Readable content of an S/MIME protected message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Opened {
+    /// Decrypted or unwrapped MIME entity, it replaces the parts of the
+    /// message.
+    pub entity: Option<String>,
+    pub security: MessageSecurity,
+}
+
+/// Certificate and private key for decrypting and signing, with the
+/// certificates that issued it.
+pub struct Identity {
+    pub certificate: X509,
+    pub chain: Vec<X509>,
+    pub key: PKey<Private>,
+}
+
+impl Identity {
+    /// Identity of a PKCS#12 file, such as those exported by browsers and
+    /// mail clients.
+    pub fn from_pkcs12(der: &[u8], password: &str) -> Result<Identity, MyError> {
+        let parsed = match Pkcs12::from_der(der).and_then(|pkcs12| pkcs12.parse2(password)) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                let err = MyError::String(
+                    format!("Cannot read the PKCS#12 file, wrong password? {}", e),
+                    String::from("Error reading S/MIME identity"),
+                );
+                err.log_error();
+
+                return Err(err);
+            }
+        };
+
+        match (parsed.cert, parsed.pkey) {
+            (Some(certificate), Some(key)) => {
+                return Ok(Identity {
+                    certificate,
+                    chain: parsed
+                        .ca
+                        .map(|ca| ca.into_iter().collect())
+                        .unwrap_or_default(),
+                    key,
+                })
+            }
+            _ => {
+                let err = MyError::String(
+                    String::from("The PKCS#12 file has no certificate and private key"),
+                    String::from("Error reading S/MIME identity"),
+                );
+                err.log_error();
+
+                return Err(err);
+            }
+        }
+    }
+
+    /// Identity as stored, the certificate first and then its chain.
+    pub fn from_pem(certificates: &str, private_key: &str) -> Result<Identity, MyError> {
+        let mut certificates = match X509::stack_from_pem(certificates.as_bytes()) {
+            Ok(certificates) if !certificates.is_empty() => certificates,
+            Ok(_) => {
+                let err = MyError::String(
+                    String::from("No certificate"),
+                    String::from("Error reading S/MIME identity"),
+                );
+                err.log_error();
+
+                return Err(err);
+            }
+            Err(e) => return Err(openssl_error(e)),
+        };
+
+        let key = match PKey::private_key_from_pem(private_key.as_bytes()) {
+            Ok(key) => key,
+            Err(e) => return Err(openssl_error(e)),
+        };
+
+        return Ok(Identity {
+            certificate: certificates.remove(0),
+            chain: certificates,
+            key,
+        });
+    }
+
+    pub fn certificates_pem(&self) -> Result<String, MyError> {
+        let mut pem: Vec<u8> = vec![];
+
+        for certificate in std::iter::once(&self.certificate).chain(self.chain.iter()) {
+            match certificate.to_pem() {
+                Ok(certificate) => pem.extend(certificate),
+                Err(e) => return Err(openssl_error(e)),
+            }
+        }
+
+        return Ok(String::from_utf8_lossy(&pem).to_string());
+    }
+
+    pub fn private_key_pem(&self) -> Result<String, MyError> {
+        match self.key.private_key_to_pem_pkcs8() {
+            Ok(pem) => return Ok(String::from_utf8_lossy(&pem).to_string()),
+            Err(e) => return Err(openssl_error(e)),
+        }
+    }
+}
+
+fn openssl_error(e: ErrorStack) -> MyError {
+    let err = MyError::String(e.to_string(), String::from("Error in S/MIME cryptography"));
+    err.log_error();
+
+    return err;
+}
+
+/// CA certificates that S/MIME signatures are checked against, those of the
+/// PEM file at `path` or the system certificates.
+pub fn trust_store(path: Option<&Path>) -> Result<X509Store, MyError> {
+    let mut builder = match X509StoreBuilder::new() {
+        Ok(builder) => builder,
+        Err(e) => return Err(openssl_error(e)),
+    };
+
+    match path {
+        Some(path) => {
+            let pem = match fs::read(path) {
+                Ok(pem) => pem,
+                Err(e) => {
+                    let err = MyError::Io(e, format!("Error reading {}", path.display()));
+                    err.log_error();
+
+                    return Err(err);
+                }
+            };
+
+            let certificates = match X509::stack_from_pem(&pem) {
+                Ok(certificates) => certificates,
+                Err(e) => return Err(openssl_error(e)),
+            };
+
+            for certificate in certificates {
+                if let Err(e) = builder.add_cert(certificate) {
+                    return Err(openssl_error(e));
+                }
+            }
+        }
+        None => {
+            if let Err(e) = builder.set_default_paths() {
+                return Err(openssl_error(e));
+            }
+        }
+    }
+
+    return Ok(builder.build());
+}
+
+/// SHA-256 fingerprint of a certificate.
+pub fn fingerprint(certificate: &X509Ref) -> String {
+    match certificate.digest(MessageDigest::sha256()) {
+        Ok(digest) => return hex(&digest),
+        Err(_) => return String::new(),
+    }
+}
+
+/// Email addresses of a certificate, from its subject alternative names and
+/// the emailAddress of its subject.
+pub fn addresses(certificate: &X509Ref) -> Vec<String> {
+    let mut addresses: Vec<String> = vec![];
+
+    if let Some(names) = certificate.subject_alt_names() {
+        for name in names.iter() {
+            if let Some(email) = name.email() {
+                addresses.push(email.to_lowercase());
+            }
+        }
+    }
+
+    for entry in certificate
+        .subject_name()
+        .entries_by_nid(Nid::PKCS9_EMAILADDRESS)
+    {
+        if let Ok(email) = entry.data().to_string() {
+            let email = email.to_lowercase();
+
+            if !addresses.contains(&email) {
+                addresses.push(email);
+            }
+        }
+    }
+
+    return addresses;
+}
+
+/// Common name of the subject of a certificate, or of its issuer.
+pub fn common_name(certificate: &X509Ref, issuer: bool) -> String {
+    let name = match issuer {
+        true => certificate.issuer_name(),
+        false => certificate.subject_name(),
+    };
+
+    return name
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().to_string().ok())
+        .unwrap_or_default();
+}
+
+/// Name and first address of a certificate written like a mailbox.
+pub fn identity(certificate: &X509Ref) -> String {
+    let name = common_name(certificate, false);
+
+    match addresses(certificate).first() {
+        Some(address) if !name.is_empty() => return format!("{} <{}>", name, address),
+        Some(address) => return address.clone(),
+        None => return name,
+    }
+}
+
+/// Seconds since the epoch.
+pub fn timestamp(time: &Asn1TimeRef) -> i64 {
+    let epoch = match Asn1Time::from_unix(0) {
+        Ok(epoch) => epoch,
+        Err(_) => return 0,
+    };
+
+    match epoch.diff(time) {
+        Ok(diff) => return diff.days as i64 * 86_400 + diff.secs as i64,
+        Err(_) => return 0,
+    }
+}
+
+/// First S/MIME protected entity of a message source.
+pub fn detect(source: &str) -> Option<SmimePart> {
+    return detect_entity(&entity::parse(source), 0);
+}
+
+fn detect_entity(entity: &Entity, depth: usize) -> Option<SmimePart> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+
+    let content_type = entity.content_type();
+    let protocol = entity.param("protocol").unwrap_or_default().to_lowercase();
+
+    if content_type == "multipart/signed"
+        && (protocol == "application/pkcs7-signature"
+            || protocol == "application/x-pkcs7-signature")
+    {
+        return Some(SmimePart {
+            kind: Kind::Signed,
+            data: entity.raw.to_string(),
+        });
+    }
+
+    if content_type == "application/pkcs7-mime" || content_type == "application/x-pkcs7-mime" {
+        let kind = match entity
+            .param("smime-type")
+            .unwrap_or_default()
+            .to_lowercase()
+            .as_str()
+        {
+            "signed-data" => Kind::Opaque,
+            // Older clients leave out the smime-type.
+            "enveloped-data" | "" => Kind::Enveloped,
+            _ => return None,
+        };
+
+        return Some(SmimePart {
+            kind,
+            data: entity.raw.to_string(),
+        });
+    }
+
+    if content_type.starts_with("multipart/") {
+        return entity
+            .parts()
+            .iter()
+            .find_map(|part| detect_entity(part, depth + 1));
+    }
+
+    return None;
+}
+
+/// Decrypts `part` with `identities` and checks its signature against
+/// `trust_store`, failures are reported in the security result rather than
+/// as errors. `senders` are the From and Sender addresses, a good signature
+/// by a certificate for none of them is a mismatch.
+pub fn open(
+    part: &SmimePart,
+    identities: &[Identity],
+    trust_store: &X509Store,
+    senders: &[String],
+) -> Opened {
+    let encrypted = part.kind == Kind::Enveloped;
+
+    let (pkcs7, content) = match Pkcs7::from_smime(part.data.as_bytes()) {
+        Ok(pkcs7) => pkcs7,
+        Err(e) => {
+            return Opened {
+                entity: None,
+                security: failed(encrypted, &e.to_string()),
+            }
+        }
+    };
+
+    if pkcs7.type_().map(|object| object.nid()) == Some(Nid::PKCS7_ENVELOPED) {
+        return open_enveloped(&pkcs7, identities, trust_store, senders);
+    }
+
+    let (security, entity) = verify(&pkcs7, content.as_deref(), trust_store, senders, false);
+
+    return Opened {
+        // The signed part of a multipart/signed is already the message.
+        entity: match part.kind {
+            Kind::Signed => None,
+            _ => entity,
+        },
+        security,
+    };
+}
+
+fn open_enveloped(
+    pkcs7: &Pkcs7,
+    identities: &[Identity],
+    trust_store: &X509Store,
+    senders: &[String],
+) -> Opened {
+    let content = identities.iter().find_map(|identity| {
+        return pkcs7
+            .decrypt(&identity.key, &identity.certificate, Pkcs7Flags::empty())
+            .ok();
+    });
+
+    let content = match content {
+        Some(content) => String::from_utf8_lossy(&content).to_string(),
+        None => {
+            return Opened {
+                entity: None,
+                security: failed(true, "No identity for any recipient"),
+            }
+        }
+    };
+
+    // Signed and then encrypted (RFC 8551 section 3.7).
+    if let Some(inner) = detect_entity(&entity::parse(&content), MAX_DEPTH) {
+        if inner.kind != Kind::Enveloped {
+            if let Ok((pkcs7, signed)) = Pkcs7::from_smime(inner.data.as_bytes()) {
+                let (security, entity) =
+                    verify(&pkcs7, signed.as_deref(), trust_store, senders, true);
+
+                return Opened {
+                    entity: entity.or(Some(content)),
+                    security,
+                };
+            }
+        }
+    }
+
+    return Opened {
+        entity: Some(content),
+        security: MessageSecurity {
+            protocol: String::from("smime"),
+            encrypted: true,
+            decrypted: true,
+            signature: String::from("none"),
+            signer: None,
+            error: None,
+        },
+    };
+}
+
+/// Checks a signed-data signature, its certificate chain and that the
+/// certificate is for one of `senders`, returns the signed content even when
+/// the signature does not hold.
+fn verify(
+    pkcs7: &Pkcs7,
+    content: Option<&[u8]>,
+    trust_store: &X509Store,
+    senders: &[String],
+    encrypted: bool,
+) -> (MessageSecurity, Option<String>) {
+    let no_certs = match Stack::<X509>::new() {
+        Ok(no_certs) => no_certs,
+        Err(e) => return (failed(encrypted, &e.to_string()), None),
+    };
+
+    let signer = pkcs7
+        .signers(&no_certs, Pkcs7Flags::empty())
+        .ok()
+        .and_then(|signers| signers.iter().next().map(|signer| signer.to_owned()));
+
+    let mut out: Vec<u8> = vec![];
+    let mut error: Option<String> = None;
+
+    let status = match pkcs7.verify(
+        &no_certs,
+        trust_store,
+        content,
+        Some(&mut out),
+        Pkcs7Flags::empty(),
+    ) {
+        Ok(_) => match &signer {
+            Some(signer)
+                if !senders
+                    .iter()
+                    .any(|sender| addresses(signer).contains(&sender.trim().to_lowercase())) =>
+            {
+                error = Some(String::from("The certificate is not issued for the sender"));
+                "mismatch"
+            }
+            _ => "good",
+        },
+        Err(e) => {
+            error = Some(e.to_string());
+            out.clear();
+
+            // The signature holds when the chain is not checked.
+            match pkcs7.verify(
+                &no_certs,
+                trust_store,
+                content,
+                Some(&mut out),
+                Pkcs7Flags::NOVERIFY,
+            ) {
+                Ok(_) => match &signer {
+                    Some(signer) if timestamp(signer.not_after()) < Utc::now().timestamp() => {
+                        "expired"
+                    }
+                    _ => "untrusted",
+                },
+                Err(_) => {
+                    out.clear();
+                    let _ = pkcs7.verify(
+                        &no_certs,
+                        trust_store,
+                        content,
+                        Some(&mut out),
+                        Pkcs7Flags::NOVERIFY | Pkcs7Flags::NOSIGS,
+                    );
+
+                    match signer {
+                        Some(_) => "bad",
+                        None => "unknown_key",
+                    }
+                }
+            }
+        }
+    };
+
+    let security = MessageSecurity {
+        protocol: String::from("smime"),
+        encrypted,
+        decrypted: encrypted,
+        signature: String::from(status),
+        signer: signer.map(|signer| Signer {
+            fingerprint: fingerprint(&signer),
+            identity: identity(&signer),
+            signed_at: 0,
+            expires: Some(timestamp(signer.not_after()) * 1000),
+        }),
+        error: match status {
+            "good" => None,
+            _ => error,
+        },
+    };
+
+    let entity = match out.is_empty() {
+        true => None,
+        false => Some(String::from_utf8_lossy(&out).to_string()),
+    };
+
+    return (security, entity);
+}
+
+fn failed(encrypted: bool, error: &str) -> MessageSecurity {
+    return MessageSecurity {
+        protocol: String::from("smime"),
+        encrypted,
+        decrypted: false,
+        signature: String::from("none"),
+        signer: None,
+        error: Some(error.to_string()),
+    };
+}
+
+/// `source` as a multipart/signed by `identity` (RFC 8551 section 3.5.3).
+pub fn sign(source: &str, identity: &Identity) -> Result<String, MyError> {
+    let (headers, content) = pgp_mime::split_content(source);
+
+    let mut chain = match Stack::<X509>::new() {
+        Ok(chain) => chain,
+        Err(e) => return Err(openssl_error(e)),
+    };
+    for certificate in &identity.chain {
+        if let Err(e) = chain.push(certificate.clone()) {
+            return Err(openssl_error(e));
+        }
+    }
+
+    let flags =
+        Pkcs7Flags::DETACHED | Pkcs7Flags::BINARY | Pkcs7Flags::CRLFEOL | Pkcs7Flags::NOOLDMIMETYPE;
+
+    let signed = Pkcs7::sign(
+        &identity.certificate,
+        &identity.key,
+        &chain,
+        content.as_bytes(),
+        flags,
+    )
+    .and_then(|pkcs7| pkcs7.to_smime(content.as_bytes(), flags));
+
+    match signed {
+        Ok(signed) => return Ok(format!("{}{}", headers, String::from_utf8_lossy(&signed))),
+        Err(e) => return Err(openssl_error(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> Vec<u8> {
+        return std::fs::read(format!(
+            "{}/tests/fixtures/smime/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        ))
+        .unwrap();
+    }
+
+    fn source(name: &str) -> String {
+        return String::from_utf8(fixture(name)).unwrap();
+    }
+
+    fn alice() -> Identity {
+        return Identity::from_pkcs12(&fixture("alice.p12"), "alice password").unwrap();
+    }
+
+    fn alice_address() -> Vec<String> {
+        return vec![String::from("Alice@Example.com")];
+    }
+
+    fn trusted() -> X509Store {
+        return trust_store(Some(
+            format!("{}/tests/fixtures/smime/ca.pem", env!("CARGO_MANIFEST_DIR")).as_ref(),
+        ))
+        .unwrap();
+    }
+
+    const FINGERPRINT: &str = "F79F7D7BBBDFCAE424308EB99AB89EF5E5CAC49243BDDAF9C19340B8BB9CF7FB";
+
+    #[test]
+    fn identity_pkcs12() {
+        let alice = alice();
+
+        assert_eq!(alice.chain.len(), 1);
+        assert_eq!(fingerprint(&alice.certificate), FINGERPRINT);
+        assert_eq!(identity(&alice.certificate), "Alice <alice@example.com>");
+        assert_eq!(common_name(&alice.certificate, true), "Example Mail CA");
+
+        let stored = Identity::from_pem(
+            &alice.certificates_pem().unwrap(),
+            &alice.private_key_pem().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(stored.certificate, alice.certificate);
+        assert_eq!(stored.chain, alice.chain);
+
+        assert!(Identity::from_pkcs12(&fixture("alice.p12"), "wrong").is_err());
+    }
+
+    #[test]
+    fn signed_message() {
+        let source = source("smime_signed.eml");
+        let part = detect(&source).unwrap();
+        assert_eq!(part.kind, Kind::Signed);
+
+        let opened = open(&part, &[], &trusted(), &alice_address());
+        assert_eq!(opened.entity, None);
+        assert_eq!(opened.security.signature, "good");
+        assert_eq!(opened.security.error, None);
+
+        let signer = opened.security.signer.unwrap();
+        assert_eq!(signer.fingerprint, FINGERPRINT);
+        assert_eq!(signer.identity, "Alice <alice@example.com>");
+        assert_eq!(signer.signed_at, 0);
+        assert!(signer.expires.unwrap() > 1_700_000_000_000);
+
+        // A good signature, but the certificate is not for the sender.
+        let opened = open(
+            &part,
+            &[],
+            &trusted(),
+            &[String::from("mallory@example.com")],
+        );
+        assert_eq!(opened.security.signature, "mismatch");
+        assert_eq!(
+            opened.security.error.as_deref(),
+            Some("The certificate is not issued for the sender")
+        );
+        assert_eq!(
+            opened.security.signer.unwrap().identity,
+            "Alice <alice@example.com>"
+        );
+
+        // Not issued by a trusted CA.
+        let untrusted = X509StoreBuilder::new().unwrap().build();
+        assert_eq!(
+            open(&part, &[], &untrusted, &alice_address())
+                .security
+                .signature,
+            "untrusted"
+        );
+
+        let tampered = detect(&source.replace("approved", "rejected")).unwrap();
+        assert_eq!(
+            open(&tampered, &[], &trusted(), &alice_address())
+                .security
+                .signature,
+            "bad"
+        );
+    }
+
+    #[test]
+    fn opaque_message() {
+        let part = detect(&source("smime_opaque.eml")).unwrap();
+        assert_eq!(part.kind, Kind::Opaque);
+
+        let opened = open(&part, &[], &trusted(), &alice_address());
+        assert_eq!(opened.security.signature, "good");
+        assert!(opened
+            .entity
+            .unwrap()
+            .contains("The budget is approved, caf=C3=A9 at ten."));
+    }
+
+    #[test]
+    fn encrypted_message() {
+        let part = detect(&source("smime_encrypted.eml")).unwrap();
+        assert_eq!(part.kind, Kind::Enveloped);
+
+        let opened = open(&part, &[alice()], &trusted(), &alice_address());
+        assert!(opened.security.decrypted);
+        assert_eq!(opened.security.signature, "good");
+        assert_eq!(
+            opened.entity.as_deref(),
+            Some("Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: quoted-printable\r\n\r\nThe budget is approved, caf=C3=A9 at ten.\r\n")
+        );
+
+        let opened = open(&part, &[], &trusted(), &alice_address());
+        assert_eq!(opened.entity, None);
+        assert!(opened.security.encrypted && !opened.security.decrypted);
+        assert_eq!(
+            opened.security.error.as_deref(),
+            Some("No identity for any recipient")
+        );
+
+        assert_eq!(detect("Subject: Hi\r\n\r\nHello\r\n"), None);
+    }
+
+    #[test]
+    fn sign_message() {
+        let source = "From: Alice <alice@example.com>\r\nTo: bob@example.com\r\nSubject: Plans\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\r\nSee you there.\r\n";
+
+        let signed = sign(source, &alice()).unwrap();
+        assert!(signed.starts_with("From: Alice <alice@example.com>\r\nTo: bob@example.com\r\nSubject: Plans\r\nMIME-Version: 1.0\r\nContent-Type: multipart/signed; protocol=\"application/pkcs7-signature\";"));
+
+        let part = detect(&signed).unwrap();
+        let opened = open(&part, &[], &trusted(), &alice_address());
+        assert_eq!(opened.security.signature, "good");
+        assert_eq!(opened.security.signer.unwrap().fingerprint, FINGERPRINT);
+    }
+}
@@ -4,11 +4,14 @@ use base64::DecodeError;
 use rusqlite::Error as SqliteError;
 use std::error::Error;
 use std::fmt;
-use std::io::Error as IoError;
+use std::io::{Error as IoError, ErrorKind};
 use std::num::ParseIntError;
 use std::str::{ParseBoolError, Utf8Error};
 use std::string::FromUtf8Error;
 
+use crate::config::{self, LogLevel};
+use crate::types::error_code::ErrorCode;
+
 #[derive(Debug)]
 pub enum MyError {
     String(String, String),
@@ -21,12 +24,62 @@ pub enum MyError {
     ParseBool(ParseBoolError, String),
     Tls(TlsError, String),
     Io(IoError, String),
+    OAuth(String, String),
+    Code(ErrorCode, String, String),
+    /// Retry time in milliseconds since the epoch, error and context.
+    Backoff(i64, String, String),
 }
 
 impl MyError {
     pub fn log_error(&self) {
+        if !config::log_enabled(LogLevel::Error) {
+            return;
+        }
+
         eprintln!("{}", format!("{}", self));
     }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            MyError::Code(code, _, _) => return *code,
+            MyError::Backoff(_, _, _) => return ErrorCode::Backoff,
+            MyError::Imap(err, _) => match err {
+                ImapError::No(text) => {
+                    return ErrorCode::from_imap_response(text).unwrap_or(ErrorCode::Rejected)
+                }
+                ImapError::Bad(text) => {
+                    return ErrorCode::from_imap_response(text).unwrap_or(ErrorCode::ServerError)
+                }
+                ImapError::Io(_) | ImapError::ConnectionLost => {
+                    return ErrorCode::ServerUnreachable
+                }
+                _ => return ErrorCode::ServerError,
+            },
+            MyError::Io(err, _) => match err.kind() {
+                ErrorKind::NotFound
+                | ErrorKind::PermissionDenied
+                | ErrorKind::AlreadyExists
+                | ErrorKind::InvalidData => return ErrorCode::Internal,
+                _ => return ErrorCode::ServerUnreachable,
+            },
+            MyError::Tls(_, _) => return ErrorCode::TlsError,
+            MyError::OAuth(_, _) => return ErrorCode::OAuthFailed,
+            MyError::Sqlite(_, _) => return ErrorCode::Database,
+            MyError::ParseInt(_, _) | MyError::ParseBool(_, _) => return ErrorCode::InvalidRequest,
+            MyError::String(_, _)
+            | MyError::FromUtf8(_, _)
+            | MyError::Utf8(_, _)
+            | MyError::Base64(_, _) => return ErrorCode::Internal,
+        }
+    }
+
+    /// When a retryable error can be retried, in milliseconds since the epoch.
+    pub fn retry_at(&self) -> Option<i64> {
+        match self {
+            MyError::Backoff(retry_at, _, _) => return Some(*retry_at),
+            _ => return None,
+        }
+    }
 }
 
 impl fmt::Display for MyError {
@@ -42,6 +95,9 @@ impl fmt::Display for MyError {
             MyError::ParseBool(err, context) => write!(f, "Error - {}: {}", context, err),
             MyError::Tls(err, context) => write!(f, "Error - {}: {}", context, err),
             MyError::Io(err, context) => write!(f, "Error - {}: {}", context, err),
+            MyError::OAuth(err, context) => write!(f, "Error - {}: {}", context, err),
+            MyError::Code(_, err, context) => write!(f, "Error - {}: {}", context, err),
+            MyError::Backoff(_, err, context) => write!(f, "Error - {}: {}", context, err),
         }
     }
 }
@@ -59,6 +115,9 @@ impl Error for MyError {
             MyError::ParseBool(_, _) => None,
             MyError::Tls(ref e, _) => Some(e),
             MyError::Io(ref e, _) => Some(e),
+            MyError::OAuth(_, _) => None,
+            MyError::Code(_, _, _) => None,
+            MyError::Backoff(_, _, _) => None,
         }
     }
 }
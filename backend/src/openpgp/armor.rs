@@ -0,0 +1,236 @@
+use base64::{prelude::BASE64_STANDARD, Engine};
+
+use crate::my_error::MyError;
+use crate::openpgp::packet;
+
+pub const MESSAGE: &str = "PGP MESSAGE";
+pub const PUBLIC_KEY_BLOCK: &str = "PGP PUBLIC KEY BLOCK";
+pub const PRIVATE_KEY_BLOCK: &str = "PGP PRIVATE KEY BLOCK";
+pub const SIGNATURE: &str = "PGP SIGNATURE";
+
+const SIGNED_MESSAGE: &str = "-----BEGIN PGP SIGNED MESSAGE-----";
+
+/// ASCII armored block (RFC 4880 section 6.2).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Armor {
+    pub kind: String,
+    pub data: Vec<u8>,
+}
+
+/// Text signed with a cleartext signature (RFC 4880 section 7), dash escapes
+/// removed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cleartext {
+    pub text: String,
+    pub signature: Vec<u8>,
+}
+
+/// First armored block of `text`, the checksum is checked when present.
+pub fn decode(text: &str) -> Result<Armor, MyError> {
+    let mut lines = text.lines().map(|line| line.trim_end());
+
+    let kind = loop {
+        match lines.next() {
+            Some(line) => {
+                if let Some(kind) = line
+                    .strip_prefix("-----BEGIN ")
+                    .and_then(|rest| rest.strip_suffix("-----"))
+                {
+                    break kind.to_string();
+                }
+            }
+            None => return Err(packet::error("No armored block found")),
+        }
+    };
+
+    let data = match body(&mut lines, &kind) {
+        Ok(data) => data,
+        Err(e) => return Err(e),
+    };
+
+    return Ok(Armor { kind, data });
+}
+
+/// Reads the headers, base64 body and checksum up to the end line of a block.
+fn body<'a, I>(lines: &mut I, kind: &str) -> Result<Vec<u8>, MyError>
+where
+    I: Iterator<Item = &'a str>,
+{
+    // Armor headers end with the first empty line.
+    for line in lines.by_ref() {
+        if line.trim().is_empty() {
+            break;
+        }
+    }
+
+    let end = format!("-----END {}-----", kind);
+    let mut encoded = String::new();
+    let mut checksum: Option<&str> = None;
+
+    loop {
+        match lines.next() {
+            Some(line) if line == end => break,
+            Some(line) => {
+                let line = line.trim();
+
+                if line.starts_with('=') && line.len() == 5 {
+                    checksum = Some(&line[1..]);
+                } else {
+                    encoded.push_str(line);
+                }
+            }
+            None => return Err(packet::error("Armored block is not terminated")),
+        }
+    }
+
+    let data = match BASE64_STANDARD.decode(encoded.as_bytes()) {
+        Ok(data) => data,
+        Err(_) => return Err(packet::error("Invalid base64 in armored block")),
+    };
+
+    if let Some(checksum) = checksum {
+        let expected = match BASE64_STANDARD.decode(checksum.as_bytes()) {
+            Ok(expected) if expected.len() == 3 => expected,
+            _ => return Err(packet::error("Invalid armor checksum")),
+        };
+
+        if crc24(&data).to_be_bytes()[1..] != expected[..] {
+            return Err(packet::error("Armor checksum mismatch"));
+        }
+    }
+
+    return Ok(data);
+}
+
+/// Armored block with a checksum, lines end in CRLF.
+pub fn encode(kind: &str, data: &[u8]) -> String {
+    let encoded = BASE64_STANDARD.encode(data);
+    let mut armored = format!("-----BEGIN {}-----\r\n\r\n", kind);
+
+    for chunk in encoded.as_bytes().chunks(64) {
+        armored.push_str(&String::from_utf8_lossy(chunk));
+        armored.push_str("\r\n");
+    }
+
+    armored.push('=');
+    armored.push_str(&BASE64_STANDARD.encode(&crc24(data).to_be_bytes()[1..]));
+    armored.push_str(&format!("\r\n-----END {}-----\r\n", kind));
+
+    return armored;
+}
+
+/// Whether `text` holds a cleartext signed message.
+pub fn is_cleartext(text: &str) -> bool {
+    return text.lines().any(|line| line.trim_end() == SIGNED_MESSAGE);
+}
+
+/// The signed text and signature of the first cleartext signed message of
+/// `text`.
+pub fn cleartext(text: &str) -> Result<Cleartext, MyError> {
+    let mut lines = text.lines().map(|line| line.trim_end_matches('\r'));
+
+    loop {
+        match lines.next() {
+            Some(line) if line.trim_end() == SIGNED_MESSAGE => break,
+            Some(_) => {}
+            None => return Err(packet::error("No cleartext signed message found")),
+        }
+    }
+
+    // Hash headers, the hash algorithm is also named by the signature.
+    for line in lines.by_ref() {
+        if line.trim().is_empty() {
+            break;
+        }
+    }
+
+    let begin = format!("-----BEGIN {}-----", SIGNATURE);
+    let mut signed: Vec<&str> = vec![];
+
+    loop {
+        match lines.next() {
+            Some(line) if line.trim_end() == begin => break,
+            Some(line) => signed.push(line.strip_prefix("- ").unwrap_or(line)),
+            None => return Err(packet::error("Cleartext signature is missing")),
+        }
+    }
+
+    let signature = match body(&mut lines.map(|line| line.trim_end()), SIGNATURE) {
+        Ok(signature) => signature,
+        Err(e) => return Err(e),
+    };
+
+    return Ok(Cleartext {
+        text: signed.join("\n"),
+        signature,
+    });
+}
+
+/// CRC-24 of the armor checksum (RFC 4880 section 6.1).
+fn crc24(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xb704ce;
+
+    for byte in data {
+        crc ^= (*byte as u32) << 16;
+
+        for _ in 0..8 {
+            crc <<= 1;
+
+            if crc & 0x1000000 != 0 {
+                crc ^= 0x1864cfb;
+            }
+        }
+    }
+
+    return crc & 0xffffff;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLEARSIGNED: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/openpgp/bob_clearsigned.asc"
+    ));
+
+    #[test]
+    fn round_trip() {
+        let data: Vec<u8> = (0..=255).collect();
+        let armored = encode(MESSAGE, &data);
+
+        assert!(armored.starts_with("-----BEGIN PGP MESSAGE-----\r\n\r\n"));
+        assert_eq!(
+            decode(&armored).unwrap(),
+            Armor {
+                kind: String::from(MESSAGE),
+                data
+            }
+        );
+    }
+
+    #[test]
+    fn checksum() {
+        // Example of RFC 4880 section 6.6 is too long, the checksum of
+        // nothing is the initial value.
+        assert_eq!(crc24(&[]), 0xb704ce);
+
+        let armored = encode(SIGNATURE, b"signature").replace("c2lnbmF0dXJl", "c2lnbmF0dXJm");
+        assert!(decode(&armored).is_err());
+        assert!(decode("-----BEGIN PGP MESSAGE-----\n\nAAAA\n").is_err());
+        assert!(decode("no armor").is_err());
+    }
+
+    #[test]
+    fn cleartext_message() {
+        assert!(is_cleartext(CLEARSIGNED));
+
+        let cleartext = cleartext(&format!("Quoted:\r\n{}", CLEARSIGNED)).unwrap();
+
+        assert_eq!(
+            cleartext.text,
+            "Hi there,  \nthis was signed by Bob.\n- dash line"
+        );
+        assert_eq!(packet::parse(&cleartext.signature).unwrap()[0].tag, 2);
+    }
+}
@@ -0,0 +1,659 @@
+use openssl::aes::{self, AesKey};
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::derive::Deriver;
+use openssl::error::ErrorStack;
+use openssl::hash::{Hasher, MessageDigest};
+use openssl::pkey::{Id, PKey, Private};
+use openssl::rsa::{Padding, Rsa};
+use openssl::sign::{Signer, Verifier};
+use openssl::symm::{self, Cipher};
+use rand::RngCore;
+
+use crate::my_error::MyError;
+use crate::openpgp::packet;
+
+// Public key algorithms (RFC 4880 section 9.1, RFC 6637, EdDSA as in
+// draft-koch-eddsa-for-openpgp).
+pub const RSA: u8 = 1;
+pub const RSA_ENCRYPT_ONLY: u8 = 2;
+pub const RSA_SIGN_ONLY: u8 = 3;
+pub const ECDH: u8 = 18;
+pub const EDDSA: u8 = 22;
+
+// Hash algorithms (RFC 4880 section 9.4).
+pub const SHA1: u8 = 2;
+pub const SHA256: u8 = 8;
+pub const SHA384: u8 = 9;
+pub const SHA512: u8 = 10;
+pub const SHA224: u8 = 11;
+
+// Symmetric algorithms (RFC 4880 section 9.2).
+pub const AES128: u8 = 7;
+pub const AES192: u8 = 8;
+pub const AES256: u8 = 9;
+
+/// ASN.1 DigestInfo prefixes of PKCS #1 signatures (RFC 4880 section 5.2.2).
+const SHA1_PREFIX: [u8; 15] = [
+    0x30, 0x21, 0x30, 0x09, 0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a, 0x05, 0x00, 0x04, 0x14,
+];
+const SHA224_PREFIX: [u8; 19] = [
+    0x30, 0x2d, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x04, 0x05,
+    0x00, 0x04, 0x1c,
+];
+const SHA256_PREFIX: [u8; 19] = [
+    0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05,
+    0x00, 0x04, 0x20,
+];
+const SHA384_PREFIX: [u8; 19] = [
+    0x30, 0x41, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02, 0x05,
+    0x00, 0x04, 0x30,
+];
+const SHA512_PREFIX: [u8; 19] = [
+    0x30, 0x51, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03, 0x05,
+    0x00, 0x04, 0x40,
+];
+
+fn openssl_error(e: ErrorStack) -> MyError {
+    let err = MyError::String(e.to_string(), String::from("Error in OpenPGP cryptography"));
+    err.log_error();
+
+    return err;
+}
+
+fn message_digest(hash_algorithm: u8) -> Result<MessageDigest, MyError> {
+    match hash_algorithm {
+        SHA1 => return Ok(MessageDigest::sha1()),
+        SHA256 => return Ok(MessageDigest::sha256()),
+        SHA384 => return Ok(MessageDigest::sha384()),
+        SHA512 => return Ok(MessageDigest::sha512()),
+        SHA224 => return Ok(MessageDigest::sha224()),
+        _ => return Err(packet::error("Unsupported hash algorithm")),
+    }
+}
+
+fn digest_info(hash_algorithm: u8) -> Result<&'static [u8], MyError> {
+    match hash_algorithm {
+        SHA1 => return Ok(&SHA1_PREFIX),
+        SHA256 => return Ok(&SHA256_PREFIX),
+        SHA384 => return Ok(&SHA384_PREFIX),
+        SHA512 => return Ok(&SHA512_PREFIX),
+        SHA224 => return Ok(&SHA224_PREFIX),
+        _ => return Err(packet::error("Unsupported hash algorithm")),
+    }
+}
+
+pub fn digest(hash_algorithm: u8, data: &[u8]) -> Result<Vec<u8>, MyError> {
+    let message_digest = match message_digest(hash_algorithm) {
+        Ok(message_digest) => message_digest,
+        Err(e) => return Err(e),
+    };
+
+    match openssl::hash::hash(message_digest, data) {
+        Ok(digest) => return Ok(digest.to_vec()),
+        Err(e) => return Err(openssl_error(e)),
+    }
+}
+
+pub fn random(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    return bytes;
+}
+
+/// Names the older algorithms still found in messages, RFC 4880 section 9.2.
+fn unsupported_symmetric(symmetric_algorithm: u8) -> MyError {
+    let name = match symmetric_algorithm {
+        1 => String::from("IDEA"),
+        2 => String::from("TripleDES"),
+        3 => String::from("CAST5"),
+        4 => String::from("Blowfish"),
+        10 => String::from("Twofish"),
+        11..=13 => String::from("Camellia"),
+        id => format!("{}", id),
+    };
+
+    return packet::error(&format!("Unsupported symmetric algorithm {}", name));
+}
+
+pub fn key_size(symmetric_algorithm: u8) -> Result<usize, MyError> {
+    match symmetric_algorithm {
+        AES128 => return Ok(16),
+        AES192 => return Ok(24),
+        AES256 => return Ok(32),
+        _ => return Err(unsupported_symmetric(symmetric_algorithm)),
+    }
+}
+
+fn cfb_cipher(symmetric_algorithm: u8) -> Result<Cipher, MyError> {
+    match symmetric_algorithm {
+        AES128 => return Ok(Cipher::aes_128_cfb128()),
+        AES192 => return Ok(Cipher::aes_192_cfb128()),
+        AES256 => return Ok(Cipher::aes_256_cfb128()),
+        _ => return Err(unsupported_symmetric(symmetric_algorithm)),
+    }
+}
+
+pub fn cfb_decrypt(
+    symmetric_algorithm: u8,
+    key: &[u8],
+    iv: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>, MyError> {
+    let cipher = match cfb_cipher(symmetric_algorithm) {
+        Ok(cipher) => cipher,
+        Err(e) => return Err(e),
+    };
+
+    match symm::decrypt(cipher, key, Some(iv), data) {
+        Ok(plaintext) => return Ok(plaintext),
+        Err(e) => return Err(openssl_error(e)),
+    }
+}
+
+pub fn cfb_encrypt(
+    symmetric_algorithm: u8,
+    key: &[u8],
+    iv: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>, MyError> {
+    let cipher = match cfb_cipher(symmetric_algorithm) {
+        Ok(cipher) => cipher,
+        Err(e) => return Err(e),
+    };
+
+    match symm::encrypt(cipher, key, Some(iv), data) {
+        Ok(ciphertext) => return Ok(ciphertext),
+        Err(e) => return Err(openssl_error(e)),
+    }
+}
+
+/// Key derived from a passphrase with a simple, salted or iterated and
+/// salted S2K (RFC 4880 section 3.7.1). Keys longer than the hash use
+/// further hash contexts preloaded with zero bytes.
+pub fn s2k(
+    hash_algorithm: u8,
+    salt: &[u8],
+    count: usize,
+    passphrase: &[u8],
+    key_len: usize,
+) -> Result<Vec<u8>, MyError> {
+    let mut input = salt.to_vec();
+    input.extend_from_slice(passphrase);
+
+    let count = count.max(input.len());
+    let mut key: Vec<u8> = vec![];
+    let mut preload = 0;
+
+    while key.len() < key_len {
+        let message_digest = match message_digest(hash_algorithm) {
+            Ok(message_digest) => message_digest,
+            Err(e) => return Err(e),
+        };
+        let mut hasher = match Hasher::new(message_digest) {
+            Ok(hasher) => hasher,
+            Err(e) => return Err(openssl_error(e)),
+        };
+
+        let mut data = vec![0u8; preload];
+        let mut remaining = count;
+        while remaining > 0 && !input.is_empty() {
+            let len = remaining.min(input.len());
+            data.extend_from_slice(&input[..len]);
+            remaining -= len;
+
+            if data.len() >= 65536 {
+                if let Err(e) = hasher.update(&data) {
+                    return Err(openssl_error(e));
+                }
+                data.clear();
+            }
+        }
+
+        if let Err(e) = hasher.update(&data) {
+            return Err(openssl_error(e));
+        }
+
+        match hasher.finish() {
+            Ok(digest) => key.extend_from_slice(&digest),
+            Err(e) => return Err(openssl_error(e)),
+        }
+
+        preload += 1;
+    }
+
+    key.truncate(key_len);
+
+    return Ok(key);
+}
+
+fn bignum(value: &[u8]) -> Result<BigNum, MyError> {
+    match BigNum::from_slice(value) {
+        Ok(value) => return Ok(value),
+        Err(e) => return Err(openssl_error(e)),
+    }
+}
+
+/// Left pads `value` with zeros to `len` bytes.
+pub fn pad(value: &[u8], len: usize) -> Vec<u8> {
+    if value.len() >= len {
+        return value.to_vec();
+    }
+
+    let mut padded = vec![0u8; len - value.len()];
+    padded.extend_from_slice(value);
+
+    return padded;
+}
+
+fn rsa_public(n: &[u8], e: &[u8]) -> Result<Rsa<openssl::pkey::Public>, MyError> {
+    let n = match bignum(n) {
+        Ok(n) => n,
+        Err(e) => return Err(e),
+    };
+    let e = match bignum(e) {
+        Ok(e) => e,
+        Err(e) => return Err(e),
+    };
+
+    match Rsa::from_public_components(n, e) {
+        Ok(rsa) => return Ok(rsa),
+        Err(e) => return Err(openssl_error(e)),
+    }
+}
+
+/// RSA private key from the public key and the secret d, p and q, OpenPGP
+/// stores p⁻¹ mod q while OpenSSL wants q⁻¹ mod p, the CRT parameters are
+/// computed.
+fn rsa_private(n: &[u8], e: &[u8], d: &[u8], p: &[u8], q: &[u8]) -> Result<Rsa<Private>, MyError> {
+    match rsa_private_components(n, e, d, p, q) {
+        Ok(rsa) => return Ok(rsa),
+        Err(e) => return Err(openssl_error(e)),
+    }
+}
+
+fn rsa_private_components(
+    n: &[u8],
+    e: &[u8],
+    d: &[u8],
+    p: &[u8],
+    q: &[u8],
+) -> Result<Rsa<Private>, ErrorStack> {
+    let mut ctx = match BigNumContext::new() {
+        Ok(ctx) => ctx,
+        Err(e) => return Err(e),
+    };
+    let d_value = match BigNum::from_slice(d) {
+        Ok(d_value) => d_value,
+        Err(e) => return Err(e),
+    };
+    let p_value = match BigNum::from_slice(p) {
+        Ok(p_value) => p_value,
+        Err(e) => return Err(e),
+    };
+    let q_value = match BigNum::from_slice(q) {
+        Ok(q_value) => q_value,
+        Err(e) => return Err(e),
+    };
+
+    let mut p_1 = match BigNum::from_slice(p) {
+        Ok(p_1) => p_1,
+        Err(e) => return Err(e),
+    };
+
+    match p_1.sub_word(1) {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    };
+
+    let mut q_1 = match BigNum::from_slice(q) {
+        Ok(q_1) => q_1,
+        Err(e) => return Err(e),
+    };
+
+    match q_1.sub_word(1) {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    };
+
+    let mut dmp1 = match BigNum::new() {
+        Ok(dmp1) => dmp1,
+        Err(e) => return Err(e),
+    };
+
+    match dmp1.nnmod(&d_value, &p_1, &mut ctx) {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    };
+
+    let mut dmq1 = match BigNum::new() {
+        Ok(dmq1) => dmq1,
+        Err(e) => return Err(e),
+    };
+
+    match dmq1.nnmod(&d_value, &q_1, &mut ctx) {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    };
+
+    let mut iqmp = match BigNum::new() {
+        Ok(iqmp) => iqmp,
+        Err(e) => return Err(e),
+    };
+
+    match iqmp.mod_inverse(&q_value, &p_value, &mut ctx) {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    };
+
+    let n_value = match BigNum::from_slice(n) {
+        Ok(n_value) => n_value,
+        Err(e) => return Err(e),
+    };
+    let e_value = match BigNum::from_slice(e) {
+        Ok(e_value) => e_value,
+        Err(e) => return Err(e),
+    };
+
+    return Rsa::from_private_components(
+        n_value, e_value, d_value, p_value, q_value, dmp1, dmq1, iqmp,
+    );
+}
+
+/// Checks a PKCS #1 v1.5 signature over `digest`.
+pub fn rsa_verify(n: &[u8], e: &[u8], hash_algorithm: u8, digest: &[u8], signature: &[u8]) -> bool {
+    let rsa = match rsa_public(n, e) {
+        Ok(rsa) => rsa,
+        Err(_) => return false,
+    };
+
+    let mut expected = match digest_info(hash_algorithm) {
+        Ok(prefix) => prefix.to_vec(),
+        Err(_) => return false,
+    };
+    expected.extend_from_slice(digest);
+
+    let size = rsa.size() as usize;
+    let mut decrypted = vec![0u8; size];
+
+    match rsa.public_decrypt(&pad(signature, size), &mut decrypted, Padding::PKCS1) {
+        Ok(len) => return decrypted[..len] == expected[..],
+        Err(_) => return false,
+    }
+}
+
+pub fn rsa_sign(
+    public: (&[u8], &[u8]),
+    secret: (&[u8], &[u8], &[u8]),
+    hash_algorithm: u8,
+    digest: &[u8],
+) -> Result<Vec<u8>, MyError> {
+    let rsa = match rsa_private(public.0, public.1, secret.0, secret.1, secret.2) {
+        Ok(rsa) => rsa,
+        Err(e) => return Err(e),
+    };
+
+    let mut data = match digest_info(hash_algorithm) {
+        Ok(prefix) => prefix.to_vec(),
+        Err(e) => return Err(e),
+    };
+    data.extend_from_slice(digest);
+
+    let mut signature = vec![0u8; rsa.size() as usize];
+    match rsa.private_encrypt(&data, &mut signature, Padding::PKCS1) {
+        Ok(len) => signature.truncate(len),
+        Err(e) => return Err(openssl_error(e)),
+    }
+
+    return Ok(signature);
+}
+
+pub fn rsa_decrypt(
+    public: (&[u8], &[u8]),
+    secret: (&[u8], &[u8], &[u8]),
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, MyError> {
+    let rsa = match rsa_private(public.0, public.1, secret.0, secret.1, secret.2) {
+        Ok(rsa) => rsa,
+        Err(e) => return Err(e),
+    };
+
+    let size = rsa.size() as usize;
+    let mut plaintext = vec![0u8; size];
+
+    match rsa.private_decrypt(&pad(ciphertext, size), &mut plaintext, Padding::PKCS1) {
+        Ok(len) => plaintext.truncate(len),
+        Err(e) => return Err(openssl_error(e)),
+    }
+
+    return Ok(plaintext);
+}
+
+pub fn rsa_encrypt(n: &[u8], e: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, MyError> {
+    let rsa = match rsa_public(n, e) {
+        Ok(rsa) => rsa,
+        Err(e) => return Err(e),
+    };
+
+    let mut ciphertext = vec![0u8; rsa.size() as usize];
+    match rsa.public_encrypt(plaintext, &mut ciphertext, Padding::PKCS1) {
+        Ok(len) => ciphertext.truncate(len),
+        Err(e) => return Err(openssl_error(e)),
+    }
+
+    return Ok(ciphertext);
+}
+
+/// Checks an Ed25519 signature, OpenPGP signs the digest instead of the
+/// data.
+pub fn ed25519_verify(public: &[u8], digest: &[u8], r: &[u8], s: &[u8]) -> bool {
+    let key = match PKey::public_key_from_raw_bytes(public, Id::ED25519) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+
+    let mut signature = pad(r, 32);
+    signature.extend_from_slice(&pad(s, 32));
+
+    let mut verifier = match Verifier::new_without_digest(&key) {
+        Ok(verifier) => verifier,
+        Err(_) => return false,
+    };
+
+    return verifier.verify_oneshot(&signature, digest).unwrap_or(false);
+}
+
+/// Signs `digest` with the Ed25519 key of `seed`, returns r and s.
+pub fn ed25519_sign(seed: &[u8], digest: &[u8]) -> Result<(Vec<u8>, Vec<u8>), MyError> {
+    let key = match PKey::private_key_from_raw_bytes(&pad(seed, 32), Id::ED25519) {
+        Ok(key) => key,
+        Err(e) => return Err(openssl_error(e)),
+    };
+
+    let signature = match Signer::new_without_digest(&key)
+        .and_then(|mut signer| signer.sign_oneshot_to_vec(digest))
+    {
+        Ok(signature) => signature,
+        Err(e) => return Err(openssl_error(e)),
+    };
+
+    if signature.len() != 64 {
+        return Err(packet::error("Invalid Ed25519 signature length"));
+    }
+
+    return Ok((signature[..32].to_vec(), signature[32..].to_vec()));
+}
+
+/// Shared secret of an X25519 key agreement, `secret` in native little
+/// endian order.
+pub fn x25519(secret: &[u8], public: &[u8]) -> Result<Vec<u8>, MyError> {
+    match x25519_derive(secret, public) {
+        Ok(shared) => return Ok(shared),
+        Err(e) => return Err(openssl_error(e)),
+    }
+}
+
+fn x25519_derive(secret: &[u8], public: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let secret = match PKey::private_key_from_raw_bytes(secret, Id::X25519) {
+        Ok(secret) => secret,
+        Err(e) => return Err(e),
+    };
+    let public = match PKey::public_key_from_raw_bytes(public, Id::X25519) {
+        Ok(public) => public,
+        Err(e) => return Err(e),
+    };
+
+    let mut deriver = match Deriver::new(&secret) {
+        Ok(deriver) => deriver,
+        Err(e) => return Err(e),
+    };
+    match deriver.set_peer(&public) {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    };
+
+    return deriver.derive_to_vec();
+}
+
+/// New X25519 key pair, the secret in native order and the public key.
+pub fn x25519_generate() -> Result<(Vec<u8>, Vec<u8>), MyError> {
+    let key = match PKey::generate_x25519() {
+        Ok(key) => key,
+        Err(e) => return Err(openssl_error(e)),
+    };
+
+    let secret = match key.raw_private_key() {
+        Ok(secret) => secret,
+        Err(e) => return Err(openssl_error(e)),
+    };
+
+    match key.raw_public_key() {
+        Ok(public) => return Ok((secret, public)),
+        Err(e) => return Err(openssl_error(e)),
+    }
+}
+
+/// Key encryption key of ECDH (RFC 6637 section 7), a single round of the
+/// NIST SP 800-56A KDF.
+pub fn ecdh_kdf(
+    hash_algorithm: u8,
+    shared: &[u8],
+    key_len: usize,
+    param: &[u8],
+) -> Result<Vec<u8>, MyError> {
+    let mut data = vec![0, 0, 0, 1];
+    data.extend_from_slice(shared);
+    data.extend_from_slice(param);
+
+    let mut key = match digest(hash_algorithm, &data) {
+        Ok(key) => key,
+        Err(e) => return Err(e),
+    };
+    if key.len() < key_len {
+        return Err(packet::error("KDF hash is too short for the key"));
+    }
+    key.truncate(key_len);
+
+    return Ok(key);
+}
+
+/// AES key wrap (RFC 3394).
+pub fn aes_wrap(kek: &[u8], data: &[u8]) -> Result<Vec<u8>, MyError> {
+    if !data.len().is_multiple_of(8) {
+        return Err(packet::error("Wrapped key is not a multiple of 8 bytes"));
+    }
+
+    let key = match AesKey::new_encrypt(kek) {
+        Ok(key) => key,
+        Err(_) => return Err(packet::error("Invalid key encryption key")),
+    };
+
+    let mut wrapped = vec![0u8; data.len() + 8];
+    match aes::wrap_key(&key, None, &mut wrapped, data) {
+        Ok(_) => return Ok(wrapped),
+        Err(_) => return Err(packet::error("Error wrapping session key")),
+    }
+}
+
+pub fn aes_unwrap(kek: &[u8], data: &[u8]) -> Result<Vec<u8>, MyError> {
+    if !data.len().is_multiple_of(8) || data.len() < 16 {
+        return Err(packet::error("Wrapped key is not a multiple of 8 bytes"));
+    }
+
+    let key = match AesKey::new_decrypt(kek) {
+        Ok(key) => key,
+        Err(_) => return Err(packet::error("Invalid key encryption key")),
+    };
+
+    let mut unwrapped = vec![0u8; data.len() - 8];
+    match aes::unwrap_key(&key, None, &mut unwrapped, data) {
+        Ok(_) => return Ok(unwrapped),
+        Err(_) => return Err(packet::error("Error unwrapping session key")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_wrap() {
+        // RFC 3394 section 4.1.
+        let kek: Vec<u8> = (0..16).collect();
+        let data: Vec<u8> = (0..16).map(|i| i * 0x11).collect();
+        let wrapped = aes_wrap(&kek, &data).unwrap();
+
+        assert_eq!(
+            wrapped,
+            vec![
+                0x1f, 0xa6, 0x8b, 0x0a, 0x81, 0x12, 0xb4, 0x47, 0xae, 0xf3, 0x4b, 0xd8, 0xfb, 0x5a,
+                0x7b, 0x82, 0x9d, 0x3e, 0x86, 0x23, 0x71, 0xd2, 0xcf, 0xe5
+            ]
+        );
+        assert_eq!(aes_unwrap(&kek, &wrapped).unwrap(), data);
+        assert!(aes_unwrap(&data, &wrapped).is_err());
+    }
+
+    #[test]
+    fn iterated_s2k() {
+        let simple = s2k(SHA1, &[], 0, b"passphrase", 16).unwrap();
+        assert_eq!(simple, digest(SHA1, b"passphrase").unwrap()[..16]);
+
+        // A longer key continues with a context preloaded with one zero.
+        let long = s2k(SHA1, b"saltsalt", 1024, b"pass", 32).unwrap();
+        let mut input = vec![0u8];
+        input.extend(b"saltsaltpass".iter().cycle().take(1024));
+        assert_eq!(long[20..], digest(SHA1, &input).unwrap()[..12]);
+    }
+
+    #[test]
+    fn signatures() {
+        let rsa = Rsa::generate(1024).unwrap();
+        let n = rsa.n().to_vec();
+        let e = rsa.e().to_vec();
+        let digest = digest(SHA256, b"data").unwrap();
+
+        let signature = rsa_sign(
+            (&n, &e),
+            (
+                &rsa.d().to_vec(),
+                &rsa.p().unwrap().to_vec(),
+                &rsa.q().unwrap().to_vec(),
+            ),
+            SHA256,
+            &digest,
+        )
+        .unwrap();
+
+        assert!(rsa_verify(&n, &e, SHA256, &digest, &signature));
+        assert!(!rsa_verify(&n, &e, SHA512, &digest, &signature));
+
+        let seed = random(32);
+        let key = PKey::private_key_from_raw_bytes(&seed, Id::ED25519).unwrap();
+        let public = key.raw_public_key().unwrap();
+        let (r, s) = ed25519_sign(&seed, &digest).unwrap();
+
+        assert!(ed25519_verify(&public, &digest, &r, &s));
+        assert!(!ed25519_verify(&public, &digest, &s, &r));
+    }
+}
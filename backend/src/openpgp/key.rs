@@ -0,0 +1,974 @@
+use crate::my_error::MyError;
+use crate::openpgp::armor;
+use crate::openpgp::crypto;
+use crate::openpgp::packet::{self, Packet, Reader};
+use crate::openpgp::signature::{self, Signature};
+
+/// Curve OIDs of Ed25519 and Curve25519 keys (RFC 4880bis section 9.2).
+const ED25519_OID: [u8; 9] = [0x2b, 0x06, 0x01, 0x04, 0x01, 0xda, 0x47, 0x0f, 0x01];
+const CURVE25519_OID: [u8; 10] = [0x2b, 0x06, 0x01, 0x04, 0x01, 0x97, 0x55, 0x01, 0x05, 0x01];
+
+// Key flags (RFC 4880 section 5.2.3.21).
+pub const CERTIFY: u8 = 0x01;
+pub const SIGN: u8 = 0x02;
+pub const ENCRYPT: u8 = 0x04 | 0x08;
+
+/// Secret keys protected with a S2K usage of 254 end in a SHA-1 hash, 255
+/// in a two octet checksum.
+const USAGE_SHA1: u8 = 254;
+const USAGE_CHECKSUM: u8 = 255;
+
+/// GnuPG extension for secret keys stored elsewhere, e.g. on a smartcard.
+const S2K_GNU: u8 = 101;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PublicParams {
+    Rsa {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+    /// Public point without the 0x40 prefix.
+    Ed25519 {
+        point: Vec<u8>,
+    },
+    /// Public point without the 0x40 prefix and the KDF parameters.
+    Cv25519 {
+        point: Vec<u8>,
+        hash: u8,
+        cipher: u8,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SecretParams {
+    Rsa {
+        d: Vec<u8>,
+        p: Vec<u8>,
+        q: Vec<u8>,
+    },
+    Ed25519 {
+        seed: Vec<u8>,
+    },
+    /// Scalar in native little endian order, OpenPGP stores it reversed.
+    Cv25519 {
+        scalar: Vec<u8>,
+    },
+}
+
+/// Version 4 public key (RFC 4880 section 5.5.2).
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublicKey {
+    pub algorithm: u8,
+    /// Seconds since the epoch.
+    pub created: i64,
+    pub params: PublicParams,
+    /// Body of the public key packet, hashed by fingerprints and key
+    /// signatures.
+    pub body: Vec<u8>,
+    pub fingerprint: Vec<u8>,
+}
+
+impl PublicKey {
+    /// Public key at the start of a public or secret key packet, and the
+    /// rest of the packet.
+    fn parse(body: &[u8]) -> Result<(PublicKey, &[u8]), MyError> {
+        let mut reader = Reader::new(body);
+
+        match reader.u8() {
+            Ok(4) => {}
+            Ok(_) => return Err(packet::error("Unsupported key version")),
+            Err(e) => return Err(e),
+        };
+
+        let created = match reader.u32() {
+            Ok(created) => created as i64,
+            Err(e) => return Err(e),
+        };
+        let algorithm = match reader.u8() {
+            Ok(algorithm) => algorithm,
+            Err(e) => return Err(e),
+        };
+
+        let params = match algorithm {
+            crypto::RSA | crypto::RSA_ENCRYPT_ONLY | crypto::RSA_SIGN_ONLY => {
+                let n = match reader.mpi() {
+                    Ok(n) => n.to_vec(),
+                    Err(e) => return Err(e),
+                };
+                let e = match reader.mpi() {
+                    Ok(e) => e.to_vec(),
+                    Err(e) => return Err(e),
+                };
+
+                PublicParams::Rsa { n, e }
+            }
+            crypto::EDDSA => {
+                let oid = match curve(&mut reader) {
+                    Ok(oid) => oid,
+                    Err(e) => return Err(e),
+                };
+                let point = match point(&mut reader) {
+                    Ok(point) => point,
+                    Err(e) => return Err(e),
+                };
+
+                if oid != ED25519_OID {
+                    return Err(packet::error("Unsupported EdDSA curve"));
+                }
+
+                PublicParams::Ed25519 { point }
+            }
+            crypto::ECDH => {
+                let oid = match curve(&mut reader) {
+                    Ok(oid) => oid,
+                    Err(e) => return Err(e),
+                };
+                let point = match point(&mut reader) {
+                    Ok(point) => point,
+                    Err(e) => return Err(e),
+                };
+
+                let len = match reader.u8() {
+                    Ok(len) => len as usize,
+                    Err(e) => return Err(e),
+                };
+                let kdf = match reader.take(len) {
+                    Ok(kdf) => kdf,
+                    Err(e) => return Err(e),
+                };
+                if oid != CURVE25519_OID || kdf.len() != 3 || kdf[0] != 1 {
+                    return Err(packet::error("Unsupported ECDH curve"));
+                }
+
+                PublicParams::Cv25519 {
+                    point,
+                    hash: kdf[1],
+                    cipher: kdf[2],
+                }
+            }
+            _ => return Err(packet::error("Unsupported public key algorithm")),
+        };
+
+        let (body, rest) = body.split_at(reader.position());
+        let body = body.to_vec();
+
+        let mut hashed = vec![0x99];
+        hashed.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        hashed.extend_from_slice(&body);
+        let fingerprint = match crypto::digest(crypto::SHA1, &hashed) {
+            Ok(fingerprint) => fingerprint,
+            Err(e) => return Err(e),
+        };
+
+        return Ok((
+            PublicKey {
+                algorithm,
+                created,
+                params,
+                body,
+                fingerprint,
+            },
+            rest,
+        ));
+    }
+
+    pub fn key_id(&self) -> &[u8] {
+        return &self.fingerprint[12..];
+    }
+
+    /// Key packet as hashed by key signatures.
+    pub fn hashed(&self) -> Vec<u8> {
+        let mut hashed = vec![0x99];
+        hashed.extend_from_slice(&(self.body.len() as u16).to_be_bytes());
+        hashed.extend_from_slice(&self.body);
+
+        return hashed;
+    }
+
+    /// Algorithm and size, e.g. `rsa3072` or `ed25519`.
+    pub fn description(&self) -> String {
+        match &self.params {
+            PublicParams::Rsa { n, .. } => {
+                let bits = match n.first() {
+                    Some(first) => n.len() * 8 - first.leading_zeros() as usize,
+                    None => 0,
+                };
+
+                return format!("rsa{}", bits);
+            }
+            PublicParams::Ed25519 { .. } => return String::from("ed25519"),
+            PublicParams::Cv25519 { .. } => return String::from("cv25519"),
+        }
+    }
+
+    /// Key flags of keys whose signature does not name them.
+    fn default_flags(&self) -> u8 {
+        match self.algorithm {
+            crypto::RSA => return CERTIFY | SIGN | ENCRYPT,
+            crypto::RSA_SIGN_ONLY | crypto::EDDSA => return CERTIFY | SIGN,
+            _ => return ENCRYPT,
+        }
+    }
+}
+
+fn curve(reader: &mut Reader) -> Result<Vec<u8>, MyError> {
+    let len = match reader.u8() {
+        Ok(len) => len as usize,
+        Err(e) => return Err(e),
+    };
+
+    match reader.take(len) {
+        Ok(oid) => return Ok(oid.to_vec()),
+        Err(e) => return Err(e),
+    }
+}
+
+fn point(reader: &mut Reader) -> Result<Vec<u8>, MyError> {
+    let point = match reader.mpi() {
+        Ok(point) => point,
+        Err(e) => return Err(e),
+    };
+
+    if point.len() != 33 || point[0] != 0x40 {
+        return Err(packet::error("Unsupported curve point encoding"));
+    }
+
+    return Ok(point[1..].to_vec());
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Secret {
+    Plain(SecretParams),
+    Encrypted {
+        usage: u8,
+        cipher: u8,
+        hash: u8,
+        salt: Vec<u8>,
+        count: usize,
+        iv: Vec<u8>,
+        data: Vec<u8>,
+    },
+}
+
+/// Primary key or subkey with what its self-signatures say about it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Key {
+    pub public: PublicKey,
+    secret: Option<Secret>,
+    pub flags: u8,
+    /// Seconds since the epoch.
+    pub expires: Option<i64>,
+    pub revoked: bool,
+}
+
+impl Key {
+    fn new(public: PublicKey, secret: Option<Secret>) -> Key {
+        return Key {
+            flags: public.default_flags(),
+            public,
+            secret,
+            expires: None,
+            revoked: false,
+        };
+    }
+
+    pub fn has_secret(&self) -> bool {
+        return self.secret.is_some();
+    }
+
+    pub fn is_locked(&self) -> bool {
+        return matches!(self.secret, Some(Secret::Encrypted { .. }));
+    }
+
+    /// Secret key material once the key is unlocked.
+    pub fn secret(&self) -> Option<&SecretParams> {
+        match &self.secret {
+            Some(Secret::Plain(params)) => return Some(params),
+            _ => return None,
+        }
+    }
+
+    /// Whether the key may be used for `flag` at `now`.
+    pub fn can(&self, flag: u8, now: i64) -> bool {
+        return !self.revoked
+            && self.flags & flag != 0
+            && self.expires.is_none_or(|expires| expires > now);
+    }
+
+    /// Decrypts the secret key material with `passphrase`.
+    pub fn unlock(&mut self, passphrase: &str) -> Result<(), MyError> {
+        let (usage, cipher, hash, salt, count, iv, data) = match &self.secret {
+            Some(Secret::Encrypted {
+                usage,
+                cipher,
+                hash,
+                salt,
+                count,
+                iv,
+                data,
+            }) => (*usage, *cipher, *hash, salt, *count, iv, data),
+            _ => return Ok(()),
+        };
+
+        let key_size = match crypto::key_size(cipher) {
+            Ok(key_size) => key_size,
+            Err(e) => return Err(e),
+        };
+        let key = match crypto::s2k(hash, salt, count, passphrase.as_bytes(), key_size) {
+            Ok(key) => key,
+            Err(e) => return Err(e),
+        };
+        let plaintext = match crypto::cfb_decrypt(cipher, &key, iv, data) {
+            Ok(plaintext) => plaintext,
+            Err(e) => return Err(e),
+        };
+
+        let check_len = if usage == USAGE_SHA1 { 20 } else { 2 };
+        if plaintext.len() < check_len {
+            return Err(wrong_passphrase());
+        }
+
+        let (params, check) = plaintext.split_at(plaintext.len() - check_len);
+        let valid = if usage == USAGE_SHA1 {
+            match crypto::digest(crypto::SHA1, params) {
+                Ok(digest) => digest == check,
+                Err(e) => return Err(e),
+            }
+        } else {
+            checksum(params).to_be_bytes() == check
+        };
+
+        if !valid {
+            return Err(wrong_passphrase());
+        }
+
+        let params = match secret_params(&self.public, &mut Reader::new(params)) {
+            Ok(params) => params,
+            Err(e) => return Err(e),
+        };
+        self.secret = Some(Secret::Plain(params));
+
+        return Ok(());
+    }
+}
+
+fn wrong_passphrase() -> MyError {
+    let err = MyError::String(
+        String::from("Secret key checksum mismatch"),
+        String::from("Wrong passphrase for OpenPGP key"),
+    );
+    err.log_error();
+
+    return err;
+}
+
+/// Sum of the bytes modulo 65536, the checksum of unprotected secret keys and
+/// session keys.
+pub fn checksum(data: &[u8]) -> u16 {
+    return data
+        .iter()
+        .fold(0u16, |sum, byte| sum.wrapping_add(*byte as u16));
+}
+
+fn secret_params(public: &PublicKey, reader: &mut Reader) -> Result<SecretParams, MyError> {
+    match public.params {
+        PublicParams::Rsa { .. } => {
+            let d = match reader.mpi() {
+                Ok(d) => d.to_vec(),
+                Err(e) => return Err(e),
+            };
+            let p = match reader.mpi() {
+                Ok(p) => p.to_vec(),
+                Err(e) => return Err(e),
+            };
+            let q = match reader.mpi() {
+                Ok(q) => q.to_vec(),
+                Err(e) => return Err(e),
+            };
+
+            return Ok(SecretParams::Rsa { d, p, q });
+        }
+        PublicParams::Ed25519 { .. } => match reader.mpi() {
+            Ok(seed) => {
+                return Ok(SecretParams::Ed25519 {
+                    seed: crypto::pad(seed, 32),
+                })
+            }
+            Err(e) => return Err(e),
+        },
+        PublicParams::Cv25519 { .. } => {
+            let mut scalar = match reader.mpi() {
+                Ok(scalar) => crypto::pad(scalar, 32),
+                Err(e) => return Err(e),
+            };
+            scalar.reverse();
+
+            return Ok(SecretParams::Cv25519 { scalar });
+        }
+    }
+}
+
+/// Public key and secret key material of a secret key packet (RFC 4880
+/// section 5.5.3). Keys without secret material, like GnuPG stubs of
+/// smartcard keys, have no secret.
+fn parse_secret(body: &[u8]) -> Result<(PublicKey, Option<Secret>), MyError> {
+    let (public, rest) = match PublicKey::parse(body) {
+        Ok(parsed) => parsed,
+        Err(e) => return Err(e),
+    };
+    let mut reader = Reader::new(rest);
+
+    let usage = match reader.u8() {
+        Ok(usage) => usage,
+        Err(e) => return Err(e),
+    };
+    if usage == 0 {
+        let params = match secret_params(&public, &mut reader) {
+            Ok(params) => params,
+            Err(e) => return Err(e),
+        };
+
+        return Ok((public, Some(Secret::Plain(params))));
+    }
+
+    if usage != USAGE_SHA1 && usage != USAGE_CHECKSUM {
+        return Err(packet::error("Unsupported secret key protection"));
+    }
+
+    let cipher = match reader.u8() {
+        Ok(cipher) => cipher,
+        Err(e) => return Err(e),
+    };
+    let s2k = match reader.u8() {
+        Ok(s2k) => s2k,
+        Err(e) => return Err(e),
+    };
+    let (hash, salt, count) = match s2k {
+        0 => match reader.u8() {
+            Ok(hash) => (hash, vec![], 0),
+            Err(e) => return Err(e),
+        },
+        1 => match (reader.u8(), reader.take(8)) {
+            (Ok(hash), Ok(salt)) => (hash, salt.to_vec(), 0),
+            (Err(e), _) | (_, Err(e)) => return Err(e),
+        },
+        3 => {
+            let hash = match reader.u8() {
+                Ok(hash) => hash,
+                Err(e) => return Err(e),
+            };
+            let salt = match reader.take(8) {
+                Ok(salt) => salt.to_vec(),
+                Err(e) => return Err(e),
+            };
+            let coded = match reader.u8() {
+                Ok(coded) => coded as usize,
+                Err(e) => return Err(e),
+            };
+
+            (hash, salt, (16 + (coded & 15)) << ((coded >> 4) + 6))
+        }
+        S2K_GNU => return Ok((public, None)),
+        _ => return Err(packet::error("Unsupported S2K specifier")),
+    };
+
+    match crypto::key_size(cipher) {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    };
+    let iv = match reader.take(16) {
+        Ok(iv) => iv.to_vec(),
+        Err(e) => return Err(e),
+    };
+    let data = reader.rest().to_vec();
+
+    return Ok((
+        public,
+        Some(Secret::Encrypted {
+            usage,
+            cipher,
+            hash,
+            salt,
+            count,
+            iv,
+            data,
+        }),
+    ));
+}
+
+/// Transferable public or secret key (RFC 4880 section 11.1), its primary
+/// key, user IDs and subkeys with valid self-signatures.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cert {
+    pub primary: Key,
+    pub user_ids: Vec<String>,
+    pub subkeys: Vec<Key>,
+    /// Packets of the public key as read, secret key packets replaced by
+    /// their public part.
+    packets: Vec<Packet>,
+}
+
+impl Cert {
+    pub fn fingerprint(&self) -> String {
+        return signature::hex(&self.primary.public.fingerprint);
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Key> {
+        return std::iter::once(&self.primary).chain(self.subkeys.iter());
+    }
+
+    /// Key with the key ID or fingerprint `id`.
+    pub fn find(&self, id: &[u8]) -> Option<&Key> {
+        return self.keys().find(|key| match id.len() {
+            8 => key.public.key_id() == id,
+            _ => key.public.fingerprint == id,
+        });
+    }
+
+    pub fn user_id(&self) -> &str {
+        return self.user_ids.first().map(|id| id.as_str()).unwrap_or("");
+    }
+
+    /// Whether a user ID is `address` or ends in `<address>`.
+    pub fn has_address(&self, address: &str) -> bool {
+        let address = address.trim().to_lowercase();
+
+        return self.user_ids.iter().any(|id| {
+            let id = id.trim().to_lowercase();
+
+            return id == address || id.ends_with(&format!("<{}>", address));
+        });
+    }
+
+    pub fn has_secret(&self) -> bool {
+        return self.keys().any(|key| key.has_secret());
+    }
+
+    /// Decrypts every protected secret key with `passphrase`.
+    pub fn unlock(&mut self, passphrase: &str) -> Result<(), MyError> {
+        match self.primary.unlock(passphrase) {
+            Ok(_) => {}
+            Err(e) => return Err(e),
+        };
+
+        for subkey in &mut self.subkeys {
+            match subkey.unlock(passphrase) {
+                Ok(_) => {}
+                Err(e) => return Err(e),
+            };
+        }
+
+        return Ok(());
+    }
+
+    pub fn is_locked(&self) -> bool {
+        return self.keys().any(|key| key.is_locked());
+    }
+
+    /// Valid encryption keys, all of them are used as a sender cannot know
+    /// which one the recipient reads with.
+    pub fn encryption_keys(&self, now: i64) -> Vec<&Key> {
+        if !self.is_usable(now) {
+            return vec![];
+        }
+
+        return self
+            .subkeys
+            .iter()
+            .chain(std::iter::once(&self.primary))
+            .filter(|key| {
+                key.can(ENCRYPT, now) && !matches!(key.public.params, PublicParams::Ed25519 { .. })
+            })
+            .collect();
+    }
+
+    /// Newest valid signing key with its secret material.
+    pub fn signing_key(&self, now: i64) -> Option<&Key> {
+        if !self.is_usable(now) {
+            return None;
+        }
+
+        return self
+            .keys()
+            .filter(|key| key.can(SIGN, now) && key.secret().is_some())
+            .filter(|key| !matches!(key.public.params, PublicParams::Cv25519 { .. }))
+            .max_by_key(|key| key.public.created);
+    }
+
+    /// Not revoked and not expired.
+    pub fn is_usable(&self, now: i64) -> bool {
+        return !self.primary.revoked && self.primary.expires.is_none_or(|e| e > now);
+    }
+
+    /// The public key in binary form.
+    pub fn public_bytes(&self) -> Vec<u8> {
+        let mut data: Vec<u8> = vec![];
+
+        for packet in &self.packets {
+            data.extend_from_slice(&packet::write(packet.tag, &packet.body));
+        }
+
+        return data;
+    }
+
+    /// The public key, armored.
+    pub fn public_armor(&self) -> String {
+        return armor::encode(armor::PUBLIC_KEY_BLOCK, &self.public_bytes());
+    }
+}
+
+/// Keys of an armored public or private key block.
+pub fn read_armored(text: &str) -> Result<Vec<Cert>, MyError> {
+    let armor = match armor::decode(text) {
+        Ok(armor) => armor,
+        Err(e) => return Err(e),
+    };
+
+    if armor.kind != armor::PUBLIC_KEY_BLOCK && armor.kind != armor::PRIVATE_KEY_BLOCK {
+        return Err(packet::error("Not an OpenPGP key block"));
+    }
+
+    return parse(&armor.data);
+}
+
+/// Keys of a keyring, keys without a valid user ID are left out.
+pub fn parse(data: &[u8]) -> Result<Vec<Cert>, MyError> {
+    let mut certs: Vec<Cert> = vec![];
+    let mut current: Option<Vec<Packet>> = None;
+
+    let packets = match packet::parse(data) {
+        Ok(packets) => packets,
+        Err(e) => return Err(e),
+    };
+
+    for packet in packets {
+        if packet.tag == packet::PUBLIC_KEY || packet.tag == packet::SECRET_KEY {
+            if let Some(packets) = current.take() {
+                match cert(packets) {
+                    Ok(cert) => certs.extend(cert),
+                    Err(e) => return Err(e),
+                };
+            }
+
+            current = Some(vec![]);
+        }
+
+        if let Some(packets) = &mut current {
+            packets.push(packet);
+        }
+    }
+
+    if let Some(packets) = current {
+        match cert(packets) {
+            Ok(cert) => certs.extend(cert),
+            Err(e) => return Err(e),
+        };
+    }
+
+    if certs.is_empty() {
+        return Err(packet::error("No usable OpenPGP key found"));
+    }
+
+    return Ok(certs);
+}
+
+/// The key of the packets of one transferable key, `None` when no user ID
+/// has a valid self-signature.
+fn cert(packets: Vec<Packet>) -> Result<Option<Cert>, MyError> {
+    let mut public_packets: Vec<Packet> = vec![];
+    let mut primary: Option<Key> = None;
+    let mut direct: Vec<Signature> = vec![];
+    let mut user_ids: Vec<(String, Vec<Signature>)> = vec![];
+    let mut subkeys: Vec<(Key, Vec<Signature>)> = vec![];
+
+    enum Context {
+        Primary,
+        UserId,
+        Attribute,
+        Subkey,
+    }
+    let mut context = Context::Primary;
+
+    for packet in packets {
+        match packet.tag {
+            packet::PUBLIC_KEY | packet::SECRET_KEY => {
+                let parsed = match packet.tag {
+                    packet::PUBLIC_KEY => match PublicKey::parse(&packet.body) {
+                        Ok((public, _)) => Ok((public, None)),
+                        Err(e) => Err(e),
+                    },
+                    _ => parse_secret(&packet.body),
+                };
+                let (public, secret) = match parsed {
+                    Ok(parsed) => parsed,
+                    Err(e) => return Err(e),
+                };
+
+                public_packets.push(Packet {
+                    tag: packet::PUBLIC_KEY,
+                    body: public.body.clone(),
+                });
+                primary = Some(Key::new(public, secret));
+                context = Context::Primary;
+            }
+            packet::USER_ID => {
+                public_packets.push(packet.clone());
+                user_ids.push((String::from_utf8_lossy(&packet.body).to_string(), vec![]));
+                context = Context::UserId;
+            }
+            packet::USER_ATTRIBUTE => {
+                public_packets.push(packet);
+                context = Context::Attribute;
+            }
+            packet::PUBLIC_SUBKEY | packet::SECRET_SUBKEY => {
+                // Subkeys of unsupported algorithms are skipped.
+                let parsed = match packet.tag {
+                    packet::PUBLIC_SUBKEY => {
+                        PublicKey::parse(&packet.body).map(|(key, _)| (key, None))
+                    }
+                    _ => parse_secret(&packet.body),
+                };
+
+                match parsed {
+                    Ok((public, secret)) => {
+                        public_packets.push(Packet {
+                            tag: packet::PUBLIC_SUBKEY,
+                            body: public.body.clone(),
+                        });
+                        subkeys.push((Key::new(public, secret), vec![]));
+                        context = Context::Subkey;
+                    }
+                    Err(_) => context = Context::Attribute,
+                }
+            }
+            packet::SIGNATURE => {
+                let signature = match Signature::parse(&packet.body) {
+                    Ok(signature) => signature,
+                    Err(_) => continue,
+                };
+
+                match context {
+                    Context::Primary => direct.push(signature),
+                    Context::UserId => user_ids.last_mut().unwrap().1.push(signature),
+                    Context::Subkey => subkeys.last_mut().unwrap().1.push(signature),
+                    Context::Attribute => {}
+                }
+
+                if !matches!(context, Context::Attribute) {
+                    public_packets.push(packet);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut primary = match primary {
+        Some(primary) => primary,
+        None => return Err(packet::error("Key block without a primary key")),
+    };
+    let primary_hashed = primary.public.hashed();
+
+    for signature in &direct {
+        if !signature.issued_by(&primary.public)
+            || !signature.verify(&primary.public, &primary_hashed)
+        {
+            continue;
+        }
+
+        match signature.signature_type {
+            signature::KEY_REVOCATION => primary.revoked = true,
+            signature::DIRECT_KEY => apply(&mut primary, signature),
+            _ => {}
+        }
+    }
+
+    // The newest self-signature of each user ID counts, the primary user ID
+    // decides the flags and expiry of the primary key.
+    let mut valid_ids: Vec<(String, Signature)> = vec![];
+    for (user_id, signatures) in user_ids {
+        let mut data = primary_hashed.clone();
+        data.push(0xb4);
+        data.extend_from_slice(&(user_id.len() as u32).to_be_bytes());
+        data.extend_from_slice(user_id.as_bytes());
+
+        let newest = signatures
+            .into_iter()
+            .filter(|signature| {
+                (0x10..=0x13).contains(&signature.signature_type)
+                    || signature.signature_type == signature::CERTIFICATION_REVOCATION
+            })
+            .filter(|signature| {
+                signature.issued_by(&primary.public) && signature.verify(&primary.public, &data)
+            })
+            .max_by_key(|signature| signature.created);
+
+        match newest {
+            Some(signature) if signature.signature_type != signature::CERTIFICATION_REVOCATION => {
+                valid_ids.push((user_id, signature))
+            }
+            _ => {}
+        }
+    }
+
+    valid_ids.sort_by_key(|(_, signature)| (!signature.primary_user_id, -signature.created));
+
+    let (_, primary_signature) = match valid_ids.first() {
+        Some(first) => first,
+        None => return Ok(None),
+    };
+    apply(&mut primary, primary_signature);
+
+    let mut valid_subkeys: Vec<Key> = vec![];
+    for (mut subkey, signatures) in subkeys {
+        let mut data = primary_hashed.clone();
+        data.extend_from_slice(&subkey.public.hashed());
+
+        let valid: Vec<&Signature> = signatures
+            .iter()
+            .filter(|signature| {
+                signature.issued_by(&primary.public) && signature.verify(&primary.public, &data)
+            })
+            .collect();
+
+        let binding = valid
+            .iter()
+            .filter(|signature| signature.signature_type == signature::SUBKEY_BINDING)
+            .max_by_key(|signature| signature.created);
+
+        let binding = match binding {
+            Some(binding) => binding,
+            None => continue,
+        };
+
+        apply(&mut subkey, binding);
+        subkey.revoked = valid
+            .iter()
+            .any(|signature| signature.signature_type == signature::SUBKEY_REVOCATION);
+
+        valid_subkeys.push(subkey);
+    }
+
+    return Ok(Some(Cert {
+        primary,
+        user_ids: valid_ids.into_iter().map(|(user_id, _)| user_id).collect(),
+        subkeys: valid_subkeys,
+        packets: public_packets,
+    }));
+}
+
+/// Takes flags and expiry of `key` from its self-signature.
+fn apply(key: &mut Key, signature: &Signature) {
+    if let Some(flags) = signature.key_flags {
+        key.flags = flags;
+    }
+
+    key.expires = match signature.key_expires {
+        Some(seconds) if seconds > 0 => Some(key.public.created + seconds as i64),
+        _ => None,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE_PUBLIC: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/openpgp/alice_public.asc"
+    ));
+    const ALICE_SECRET: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/openpgp/alice_secret.asc"
+    ));
+    const BOB_SECRET: &str = include_str!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/openpgp/bob_secret.asc"
+    ));
+
+    #[test]
+    fn public_key() {
+        let certs = read_armored(ALICE_PUBLIC).unwrap();
+        let alice = &certs[0];
+
+        assert_eq!(certs.len(), 1);
+        assert_eq!(
+            alice.fingerprint(),
+            "4FC52BAA2FDB1BBBE6571F1FC25A344C65AB30A0"
+        );
+        assert_eq!(alice.user_ids, vec!["Alice <alice@example.com>"]);
+        assert!(alice.has_address("ALICE@example.com"));
+        assert!(!alice.has_address("example.com"));
+        assert_eq!(alice.primary.public.description(), "rsa2048");
+        assert!(!alice.has_secret());
+
+        let now = alice.primary.public.created + 60;
+        let encryption = alice.encryption_keys(now);
+        assert_eq!(encryption.len(), 1);
+        assert_eq!(
+            signature::hex(encryption[0].public.key_id()),
+            "BB270CBB4727BA28"
+        );
+        assert!(alice.signing_key(now).is_none());
+
+        // Exported again without the secret parts.
+        assert_eq!(
+            read_armored(&alice.public_armor()).unwrap()[0].fingerprint(),
+            alice.fingerprint()
+        );
+    }
+
+    #[test]
+    fn protected_secret_key() {
+        let mut alice = read_armored(ALICE_SECRET).unwrap().remove(0);
+        let now = alice.primary.public.created + 60;
+
+        assert!(alice.has_secret());
+        assert!(alice.is_locked());
+        assert!(alice.signing_key(now).is_none());
+        assert!(alice.unlock("wrong").is_err());
+
+        alice.unlock("alice passphrase").unwrap();
+
+        assert!(!alice.is_locked());
+        assert_eq!(
+            alice.signing_key(now).unwrap().public.fingerprint,
+            alice.primary.public.fingerprint
+        );
+        assert_eq!(
+            read_armored(&alice.public_armor()).unwrap()[0],
+            read_armored(ALICE_PUBLIC).unwrap()[0]
+        );
+    }
+
+    #[test]
+    fn curve25519_secret_key() {
+        let bob = read_armored(BOB_SECRET).unwrap().remove(0);
+        let now = bob.primary.public.created + 60;
+
+        assert_eq!(
+            bob.fingerprint(),
+            "C1742C35F16FA02F540657BF4FF6F77928021E86"
+        );
+        assert!(!bob.is_locked());
+        assert_eq!(bob.primary.public.description(), "ed25519");
+        assert_eq!(bob.primary.flags, CERTIFY | SIGN);
+        assert_eq!(bob.encryption_keys(now)[0].public.description(), "cv25519");
+        assert!(matches!(
+            bob.subkeys[0].secret(),
+            Some(SecretParams::Cv25519 { .. })
+        ));
+        assert!(bob.signing_key(now).is_some());
+        assert!(bob.find(bob.subkeys[0].public.key_id()).is_some());
+    }
+
+    #[test]
+    fn invalid_keys() {
+        assert!(read_armored("not a key").is_err());
+
+        // A user ID whose self-signature does not match leaves no valid key.
+        let mut data = armor::decode(ALICE_PUBLIC).unwrap().data;
+        let at = data.windows(5).position(|w| w == b"Alice").unwrap();
+        data[at] = b'M';
+        assert!(parse(&data).is_err());
+    }
+}
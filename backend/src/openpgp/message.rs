@@ -0,0 +1,860 @@
+use flate2::read::{DeflateDecoder, ZlibDecoder};
+use std::io::Read;
+
+use crate::my_error::MyError;
+use crate::openpgp::armor;
+use crate::openpgp::crypto;
+use crate::openpgp::key::{self, Cert, Key, PublicParams, SecretParams};
+use crate::openpgp::packet::{self, Packet, Reader};
+use crate::openpgp::signature::{self, Signature};
+
+/// Compressed and signed messages nest packets, deeper nesting is refused.
+const MAX_DEPTH: usize = 8;
+
+/// Decompressed data larger than this is refused, protects against
+/// compression bombs.
+const MAX_DECOMPRESSED_SIZE: u64 = 64 * 1024 * 1024;
+
+/// ECDH parameters hash the sender as 20 bytes (RFC 6637 section 8).
+const ANONYMOUS_SENDER: &[u8; 20] = b"Anonymous Sender    ";
+
+const CURVE25519_OID: [u8; 10] = [0x2b, 0x06, 0x01, 0x04, 0x01, 0x97, 0x55, 0x01, 0x05, 0x01];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Good,
+    Bad,
+    UnknownKey,
+    Expired,
+    Revoked,
+    /// Made with SHA-1, which no longer protects against forgery.
+    WeakHash,
+}
+
+impl Status {
+    pub fn name(&self) -> &str {
+        match self {
+            Status::Good => return "good",
+            Status::Bad => return "bad",
+            Status::UnknownKey => return "unknown_key",
+            Status::Expired => return "expired",
+            Status::Revoked => return "revoked",
+            Status::WeakHash => return "weak_hash",
+        }
+    }
+}
+
+/// Result of checking one signature.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verification {
+    pub status: Status,
+    /// Fingerprint of the signing key's primary key, or the issuer named by
+    /// the signature when the key is unknown.
+    pub fingerprint: String,
+    pub user_id: String,
+    /// Seconds since the epoch.
+    pub created: i64,
+}
+
+/// Content of a message once decrypted and its signatures checked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Opened {
+    pub encrypted: bool,
+    pub data: Vec<u8>,
+    pub signatures: Vec<Verification>,
+}
+
+/// Decrypts `data` when it is encrypted and checks its signatures against
+/// `certs`.
+pub fn open(data: &[u8], certs: &[Cert]) -> Result<Opened, MyError> {
+    let packets = match packet::parse(data) {
+        Ok(packets) => packets,
+        Err(e) => return Err(e),
+    };
+
+    let encrypted = packets.iter().any(|packet| {
+        packet.tag == packet::SYM_ENCRYPTED_INTEGRITY_PROTECTED_DATA
+            || packet.tag == packet::SYMMETRICALLY_ENCRYPTED_DATA
+    });
+
+    let packets = if encrypted {
+        let decrypted = match decrypt(&packets, certs) {
+            Ok(decrypted) => decrypted,
+            Err(e) => return Err(e),
+        };
+
+        match packet::parse(&decrypted) {
+            Ok(packets) => packets,
+            Err(e) => return Err(e),
+        }
+    } else {
+        packets
+    };
+
+    let mut literal: Option<Vec<u8>> = None;
+    let mut signatures: Vec<Signature> = vec![];
+    match read(packets, 0, &mut literal, &mut signatures) {
+        Ok(_) => {}
+        Err(e) => return Err(e),
+    };
+
+    let data = match literal {
+        Some(data) => data,
+        None => return Err(packet::error("Message has no literal data")),
+    };
+
+    let signatures = signatures
+        .iter()
+        .map(|signature| verify(signature, &data, certs))
+        .collect();
+
+    return Ok(Opened {
+        encrypted,
+        data,
+        signatures,
+    });
+}
+
+/// Literal data and signatures of a decrypted or signed message.
+fn read(
+    packets: Vec<Packet>,
+    depth: usize,
+    literal: &mut Option<Vec<u8>>,
+    signatures: &mut Vec<Signature>,
+) -> Result<(), MyError> {
+    if depth > MAX_DEPTH {
+        return Err(packet::error("Message nests too deeply"));
+    }
+
+    for packet in packets {
+        match packet.tag {
+            packet::COMPRESSED_DATA => {
+                let data = match decompress(&packet.body) {
+                    Ok(data) => data,
+                    Err(e) => return Err(e),
+                };
+                let packets = match packet::parse(&data) {
+                    Ok(packets) => packets,
+                    Err(e) => return Err(e),
+                };
+                match read(packets, depth + 1, literal, signatures) {
+                    Ok(_) => {}
+                    Err(e) => return Err(e),
+                };
+            }
+            packet::LITERAL_DATA => {
+                if literal.is_some() {
+                    return Err(packet::error("Message has more than one literal packet"));
+                }
+
+                let mut reader = Reader::new(&packet.body);
+                match reader.u8() {
+                    Ok(_) => {}
+                    Err(e) => return Err(e),
+                };
+                let len = match reader.u8() {
+                    Ok(len) => len as usize,
+                    Err(e) => return Err(e),
+                };
+                match reader.take(len) {
+                    Ok(_) => {}
+                    Err(e) => return Err(e),
+                };
+                match reader.u32() {
+                    Ok(_) => {}
+                    Err(e) => return Err(e),
+                };
+
+                *literal = Some(reader.rest().to_vec());
+            }
+            packet::SIGNATURE => {
+                if let Ok(signature) = Signature::parse(&packet.body) {
+                    signatures.push(signature);
+                }
+            }
+            packet::ONE_PASS_SIGNATURE => {}
+            packet::SYM_ENCRYPTED_INTEGRITY_PROTECTED_DATA
+            | packet::SYMMETRICALLY_ENCRYPTED_DATA
+            | packet::PUBLIC_KEY_ENCRYPTED_SESSION_KEY => {
+                return Err(packet::error("Encrypted data inside a message"));
+            }
+            _ => {}
+        }
+    }
+
+    return Ok(());
+}
+
+fn decompress(body: &[u8]) -> Result<Vec<u8>, MyError> {
+    let (algorithm, data) = match body.split_first() {
+        Some(split) => split,
+        None => return Err(packet::error("Empty compressed packet")),
+    };
+
+    // One byte past the limit tells a message that is too large apart from
+    // one that fits exactly.
+    let mut decompressed: Vec<u8> = vec![];
+    let result = match algorithm {
+        0 => return Ok(data.to_vec()),
+        1 => DeflateDecoder::new(data)
+            .take(MAX_DECOMPRESSED_SIZE + 1)
+            .read_to_end(&mut decompressed),
+        2 => ZlibDecoder::new(data)
+            .take(MAX_DECOMPRESSED_SIZE + 1)
+            .read_to_end(&mut decompressed),
+        _ => return Err(packet::error("Unsupported compression algorithm")),
+    };
+
+    match result {
+        Ok(_) => {}
+        Err(_) => return Err(packet::error("Invalid compressed data")),
+    };
+
+    if decompressed.len() as u64 > MAX_DECOMPRESSED_SIZE {
+        return Err(packet::error("Compressed data too large"));
+    }
+
+    return Ok(decompressed);
+}
+
+/// Checks a detached signature over `data`, `signature` holds one or more
+/// signature packets.
+pub fn verify_detached(
+    data: &[u8],
+    signature: &[u8],
+    certs: &[Cert],
+) -> Result<Vec<Verification>, MyError> {
+    let packets = match packet::parse(signature) {
+        Ok(packets) => packets,
+        Err(e) => return Err(e),
+    };
+
+    let mut verifications: Vec<Verification> = vec![];
+    for packet in packets {
+        if packet.tag != packet::SIGNATURE {
+            continue;
+        }
+
+        let signature = match Signature::parse(&packet.body) {
+            Ok(signature) => signature,
+            Err(e) => return Err(e),
+        };
+        verifications.push(verify(&signature, data, certs));
+    }
+
+    if verifications.is_empty() {
+        return Err(packet::error("No signature found"));
+    }
+
+    return Ok(verifications);
+}
+
+/// Checks a cleartext signed message, its lines are signed without trailing
+/// whitespace (RFC 4880 section 7.1).
+pub fn verify_cleartext(
+    cleartext: &armor::Cleartext,
+    certs: &[Cert],
+) -> Result<Vec<Verification>, MyError> {
+    let lines: Vec<&str> = cleartext.text.lines().map(|line| line.trim_end()).collect();
+
+    return verify_detached(lines.join("\r\n").as_bytes(), &cleartext.signature, certs);
+}
+
+fn verify(signature: &Signature, data: &[u8], certs: &[Cert]) -> Verification {
+    let mut verification = Verification {
+        status: Status::UnknownKey,
+        fingerprint: signature.issuer_hex(),
+        user_id: String::new(),
+        created: signature.created,
+    };
+
+    let signer = certs.iter().find_map(|cert| {
+        return cert
+            .keys()
+            .find(|key| signature.issued_by(&key.public))
+            .map(|key| (cert, key));
+    });
+
+    let (cert, key) = match signer {
+        Some(signer) => signer,
+        None => return verification,
+    };
+
+    verification.fingerprint = cert.fingerprint();
+    verification.user_id = cert.user_id().to_string();
+
+    let document = signature.signature_type == signature::BINARY
+        || signature.signature_type == signature::TEXT;
+
+    verification.status =
+        if !document || key.flags & key::SIGN == 0 || !signature.verify(&key.public, data) {
+            Status::Bad
+        } else if signature.hash_algorithm == crypto::SHA1 {
+            Status::WeakHash
+        } else if cert.primary.revoked || key.revoked {
+            Status::Revoked
+        } else if [cert.primary.expires, key.expires]
+            .iter()
+            .flatten()
+            .any(|expires| *expires <= signature.created)
+        {
+            Status::Expired
+        } else {
+            Status::Good
+        };
+
+    return verification;
+}
+
+/// Plaintext of the encrypted data packet, decrypted with the session key
+/// of the first encrypted session key packet a secret key of `certs` opens.
+/// When a key matches but cannot open it, e.g. a session key for CAST5, that
+/// error is returned.
+fn decrypt(packets: &[Packet], certs: &[Cert]) -> Result<Vec<u8>, MyError> {
+    let mut session_key: Option<(u8, Vec<u8>)> = None;
+    let mut failure: Option<MyError> = None;
+
+    'packets: for packet in packets {
+        if packet.tag != packet::PUBLIC_KEY_ENCRYPTED_SESSION_KEY {
+            continue;
+        }
+
+        let mut reader = Reader::new(&packet.body);
+        match reader.u8() {
+            Ok(3) => {}
+            Ok(_) => continue,
+            Err(e) => return Err(e),
+        };
+
+        let key_id = match reader.take(8) {
+            Ok(key_id) => key_id,
+            Err(e) => return Err(e),
+        };
+        let algorithm = match reader.u8() {
+            Ok(algorithm) => algorithm,
+            Err(e) => return Err(e),
+        };
+        let encrypted = reader.rest();
+
+        for cert in certs {
+            for key in cert.keys() {
+                // A zero key ID hides the recipient, every key is tried.
+                let matches = key_id == key.public.key_id() || key_id == [0u8; 8];
+
+                if !matches || key.public.algorithm != algorithm || key.secret().is_none() {
+                    continue;
+                }
+
+                match session_key_of(key, encrypted) {
+                    Ok(decrypted) => {
+                        session_key = Some(decrypted);
+                        break 'packets;
+                    }
+                    Err(e) => failure = Some(e),
+                }
+            }
+        }
+    }
+
+    let (cipher, session_key) = match (session_key, failure) {
+        (Some(session_key), _) => session_key,
+        (None, Some(e)) => return Err(e),
+        (None, None) => {
+            let err = MyError::String(
+                String::from("No secret key for any recipient"),
+                String::from("Error decrypting OpenPGP message"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+    };
+
+    let body = match packets
+        .iter()
+        .find(|packet| packet.tag == packet::SYM_ENCRYPTED_INTEGRITY_PROTECTED_DATA)
+    {
+        Some(packet) => &packet.body,
+        None => return Err(packet::error("Encrypted data is not integrity protected")),
+    };
+
+    let ciphertext = match body.split_first() {
+        Some((1, ciphertext)) => ciphertext,
+        _ => return Err(packet::error("Unsupported encrypted data version")),
+    };
+
+    let plaintext = match crypto::cfb_decrypt(cipher, &session_key, &[0u8; 16], ciphertext) {
+        Ok(plaintext) => plaintext,
+        Err(e) => return Err(e),
+    };
+
+    // Random prefix of a block and its last two bytes repeated, then the
+    // data and a modification detection code packet.
+    if plaintext.len() < 18 + 22 || plaintext[14..16] != plaintext[16..18] {
+        return Err(packet::error("Invalid session key"));
+    }
+
+    let (content, mdc) = plaintext.split_at(plaintext.len() - 20);
+    let digest = match crypto::digest(crypto::SHA1, content) {
+        Ok(digest) => digest,
+        Err(e) => return Err(e),
+    };
+    if content[content.len() - 2..] != [0xd3, 0x14] || digest != mdc {
+        return Err(packet::error("Modification detected in encrypted data"));
+    }
+
+    return Ok(content[18..content.len() - 2].to_vec());
+}
+
+/// Symmetric algorithm and session key of an encrypted session key packet.
+fn session_key_of(key: &Key, encrypted: &[u8]) -> Result<(u8, Vec<u8>), MyError> {
+    let mut reader = Reader::new(encrypted);
+
+    let decrypted = match (&key.public.params, key.secret()) {
+        (PublicParams::Rsa { n, e }, Some(SecretParams::Rsa { d, p, q })) => {
+            let encrypted = match reader.mpi() {
+                Ok(encrypted) => encrypted,
+                Err(e) => return Err(e),
+            };
+
+            match crypto::rsa_decrypt((n, e), (d, p, q), encrypted) {
+                Ok(decrypted) => decrypted,
+                Err(e) => return Err(e),
+            }
+        }
+        (PublicParams::Cv25519 { hash, cipher, .. }, Some(SecretParams::Cv25519 { scalar })) => {
+            let ephemeral = match reader.mpi() {
+                Ok(ephemeral) => ephemeral,
+                Err(e) => return Err(e),
+            };
+            let len = match reader.u8() {
+                Ok(len) => len as usize,
+                Err(e) => return Err(e),
+            };
+            let wrapped = match reader.take(len) {
+                Ok(wrapped) => wrapped,
+                Err(e) => return Err(e),
+            };
+
+            if ephemeral.len() != 33 || ephemeral[0] != 0x40 {
+                return Err(packet::error("Invalid ephemeral key"));
+            }
+
+            let shared = match crypto::x25519(scalar, &ephemeral[1..]) {
+                Ok(shared) => shared,
+                Err(e) => return Err(e),
+            };
+            let key_size = match crypto::key_size(*cipher) {
+                Ok(key_size) => key_size,
+                Err(e) => return Err(e),
+            };
+            let param = ecdh_param(key, *hash, *cipher);
+            let kek = match crypto::ecdh_kdf(*hash, &shared, key_size, &param) {
+                Ok(kek) => kek,
+                Err(e) => return Err(e),
+            };
+            let unwrapped = match crypto::aes_unwrap(&kek, wrapped) {
+                Ok(unwrapped) => unwrapped,
+                Err(e) => return Err(e),
+            };
+
+            match unpad(unwrapped) {
+                Ok(decrypted) => decrypted,
+                Err(e) => return Err(e),
+            }
+        }
+        _ => return Err(packet::error("Key cannot decrypt")),
+    };
+
+    if decrypted.len() < 3 {
+        return Err(packet::error("Invalid session key"));
+    }
+
+    let (cipher, rest) = decrypted.split_first().unwrap();
+    let (session_key, check) = rest.split_at(rest.len() - 2);
+
+    let key_size = match crypto::key_size(*cipher) {
+        Ok(key_size) => key_size,
+        Err(e) => return Err(e),
+    };
+    if key_size != session_key.len() || key::checksum(session_key).to_be_bytes() != check {
+        return Err(packet::error("Invalid session key"));
+    }
+
+    return Ok((*cipher, session_key.to_vec()));
+}
+
+/// KDF parameters of ECDH (RFC 6637 section 8).
+fn ecdh_param(key: &Key, hash: u8, cipher: u8) -> Vec<u8> {
+    let mut param = vec![CURVE25519_OID.len() as u8];
+    param.extend_from_slice(&CURVE25519_OID);
+    param.extend_from_slice(&[crypto::ECDH, 3, 1, hash, cipher]);
+    param.extend_from_slice(ANONYMOUS_SENDER);
+    param.extend_from_slice(&key.public.fingerprint);
+
+    return param;
+}
+
+/// Removes the PKCS #5 padding of a wrapped session key.
+fn unpad(mut data: Vec<u8>) -> Result<Vec<u8>, MyError> {
+    let pad = match data.last() {
+        Some(pad) => *pad as usize,
+        None => return Err(packet::error("Invalid session key padding")),
+    };
+
+    if pad == 0 || pad > data.len() || data[data.len() - pad..].iter().any(|b| *b as usize != pad) {
+        return Err(packet::error("Invalid session key padding"));
+    }
+
+    data.truncate(data.len() - pad);
+
+    return Ok(data);
+}
+
+fn pad(mut data: Vec<u8>) -> Vec<u8> {
+    let pad = 8 - data.len() % 8;
+    data.extend(std::iter::repeat_n(pad as u8, pad));
+
+    return data;
+}
+
+/// Detached binary signature packet of `signer` over `data`.
+pub fn sign_detached(data: &[u8], signer: &Cert, now: i64) -> Result<Vec<u8>, MyError> {
+    let key = match signing_key(signer, now) {
+        Ok(key) => key,
+        Err(e) => return Err(e),
+    };
+    let signature = match Signature::create(signature::BINARY, key, data, now, &[]) {
+        Ok(signature) => signature,
+        Err(e) => return Err(e),
+    };
+
+    return Ok(packet::write(packet::SIGNATURE, &signature.to_bytes()));
+}
+
+fn signing_key(signer: &Cert, now: i64) -> Result<&Key, MyError> {
+    match signer.signing_key(now) {
+        Some(key) => return Ok(key),
+        None => {
+            let err = MyError::String(
+                format!("{} has no usable signing key", signer.fingerprint()),
+                String::from("Error signing OpenPGP message"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+    }
+}
+
+/// `data` encrypted to every recipient with AES-256, signed by `signer`
+/// first when given.
+pub fn encrypt(
+    data: &[u8],
+    recipients: &[&Cert],
+    signer: Option<&Cert>,
+    now: i64,
+) -> Result<Vec<u8>, MyError> {
+    let mut literal_body = vec![b'b', 0];
+    literal_body.extend_from_slice(&(now as u32).to_be_bytes());
+    literal_body.extend_from_slice(data);
+    let literal = packet::write(packet::LITERAL_DATA, &literal_body);
+
+    let content = match signer {
+        Some(signer) => {
+            let key = match signing_key(signer, now) {
+                Ok(key) => key,
+                Err(e) => return Err(e),
+            };
+            let signature = match Signature::create(signature::BINARY, key, data, now, &[]) {
+                Ok(signature) => signature,
+                Err(e) => return Err(e),
+            };
+
+            let mut one_pass = vec![3, signature::BINARY, signature.hash_algorithm];
+            one_pass.push(key.public.algorithm);
+            one_pass.extend_from_slice(key.public.key_id());
+            one_pass.push(1);
+
+            let mut content = packet::write(packet::ONE_PASS_SIGNATURE, &one_pass);
+            content.extend_from_slice(&literal);
+            content.extend_from_slice(&packet::write(packet::SIGNATURE, &signature.to_bytes()));
+            content
+        }
+        None => literal,
+    };
+
+    let cipher = crypto::AES256;
+    let session_key = match crypto::key_size(cipher) {
+        Ok(key_size) => crypto::random(key_size),
+        Err(e) => return Err(e),
+    };
+
+    let mut message: Vec<u8> = vec![];
+    for recipient in recipients {
+        let keys = recipient.encryption_keys(now);
+
+        if keys.is_empty() {
+            let err = MyError::String(
+                format!("{} has no usable encryption key", recipient.fingerprint()),
+                String::from("Error encrypting OpenPGP message"),
+            );
+            err.log_error();
+
+            return Err(err);
+        }
+
+        for key in keys {
+            let body = match encrypted_session_key(key, cipher, &session_key) {
+                Ok(body) => body,
+                Err(e) => return Err(e),
+            };
+            message.extend_from_slice(&packet::write(
+                packet::PUBLIC_KEY_ENCRYPTED_SESSION_KEY,
+                &body,
+            ));
+        }
+    }
+
+    let mut plaintext = crypto::random(16);
+    // The last two random bytes are repeated as a quick check.
+    let check = [plaintext[14], plaintext[15]];
+    plaintext.extend_from_slice(&check);
+    plaintext.extend_from_slice(&content);
+    plaintext.extend_from_slice(&[0xd3, 0x14]);
+    let mdc = match crypto::digest(crypto::SHA1, &plaintext) {
+        Ok(mdc) => mdc,
+        Err(e) => return Err(e),
+    };
+    plaintext.extend_from_slice(&mdc);
+
+    let mut body = vec![1];
+    match crypto::cfb_encrypt(cipher, &session_key, &[0u8; 16], &plaintext) {
+        Ok(ciphertext) => body.extend_from_slice(&ciphertext),
+        Err(e) => return Err(e),
+    };
+    message.extend_from_slice(&packet::write(
+        packet::SYM_ENCRYPTED_INTEGRITY_PROTECTED_DATA,
+        &body,
+    ));
+
+    return Ok(message);
+}
+
+/// Body of an encrypted session key packet for `key`.
+fn encrypted_session_key(key: &Key, cipher: u8, session_key: &[u8]) -> Result<Vec<u8>, MyError> {
+    let mut plaintext = vec![cipher];
+    plaintext.extend_from_slice(session_key);
+    plaintext.extend_from_slice(&key::checksum(session_key).to_be_bytes());
+
+    let mut body = vec![3];
+    body.extend_from_slice(key.public.key_id());
+    body.push(key.public.algorithm);
+
+    match &key.public.params {
+        PublicParams::Rsa { n, e } => {
+            match crypto::rsa_encrypt(n, e, &plaintext) {
+                Ok(encrypted) => body.extend_from_slice(&packet::mpi(&encrypted)),
+                Err(e) => return Err(e),
+            };
+        }
+        PublicParams::Cv25519 {
+            point,
+            hash,
+            cipher: kek_cipher,
+        } => {
+            let (ephemeral_secret, ephemeral_public) = match crypto::x25519_generate() {
+                Ok(pair) => pair,
+                Err(e) => return Err(e),
+            };
+            let shared = match crypto::x25519(&ephemeral_secret, point) {
+                Ok(shared) => shared,
+                Err(e) => return Err(e),
+            };
+            let key_size = match crypto::key_size(*kek_cipher) {
+                Ok(key_size) => key_size,
+                Err(e) => return Err(e),
+            };
+            let param = ecdh_param(key, *hash, *kek_cipher);
+            let kek = match crypto::ecdh_kdf(*hash, &shared, key_size, &param) {
+                Ok(kek) => kek,
+                Err(e) => return Err(e),
+            };
+            let wrapped = match crypto::aes_wrap(&kek, &pad(plaintext)) {
+                Ok(wrapped) => wrapped,
+                Err(e) => return Err(e),
+            };
+
+            let mut ephemeral = vec![0x40];
+            ephemeral.extend_from_slice(&ephemeral_public);
+            body.extend_from_slice(&packet::mpi(&ephemeral));
+            body.push(wrapped.len() as u8);
+            body.extend_from_slice(&wrapped);
+        }
+        PublicParams::Ed25519 { .. } => return Err(packet::error("Key cannot encrypt")),
+    }
+
+    return Ok(body);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openpgp::key;
+
+    fn fixture(name: &str) -> String {
+        return std::fs::read_to_string(format!(
+            "{}/tests/fixtures/openpgp/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        ))
+        .unwrap();
+    }
+
+    fn cert(name: &str) -> Cert {
+        let mut cert = key::read_armored(&fixture(name)).unwrap().remove(0);
+        cert.unlock("alice passphrase").unwrap();
+
+        return cert;
+    }
+
+    fn message(name: &str) -> Vec<u8> {
+        return armor::decode(&fixture(name)).unwrap().data;
+    }
+
+    #[test]
+    fn compression_bomb() {
+        let mut encoder = flate2::write::ZlibEncoder::new(vec![], flate2::Compression::best());
+        std::io::copy(
+            &mut std::io::repeat(0).take(MAX_DECOMPRESSED_SIZE + 1),
+            &mut encoder,
+        )
+        .unwrap();
+
+        let mut body = vec![2];
+        body.extend(encoder.finish().unwrap());
+
+        // A message of repeated zeros expands past the limit.
+        let err = decompress(&body).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Error - Error reading OpenPGP data: Compressed data too large"
+        );
+    }
+
+    #[test]
+    fn decrypt_and_verify() {
+        let bob = cert("bob_secret.asc");
+        let alice = cert("alice_public.asc");
+
+        // Signed, compressed and encrypted to an ECDH key.
+        let opened = open(
+            &message("to_bob_signed_by_alice.asc"),
+            &[bob.clone(), alice],
+        )
+        .unwrap();
+
+        assert!(opened.encrypted);
+        assert_eq!(opened.data, b"Hello Bob,\nthe figures are attached.\n");
+        assert_eq!(
+            opened.signatures,
+            vec![Verification {
+                status: Status::Good,
+                fingerprint: String::from("4FC52BAA2FDB1BBBE6571F1FC25A344C65AB30A0"),
+                user_id: String::from("Alice <alice@example.com>"),
+                created: opened.signatures[0].created,
+            }]
+        );
+
+        // The signer is not in the keyring.
+        let opened = open(&message("to_bob_signed_by_alice.asc"), &[bob]).unwrap();
+        assert_eq!(opened.signatures[0].status, Status::UnknownKey);
+        assert_eq!(
+            opened.signatures[0].fingerprint,
+            "4FC52BAA2FDB1BBBE6571F1FC25A344C65AB30A0"
+        );
+    }
+
+    #[test]
+    fn decrypt_with_protected_rsa_key() {
+        let opened = open(&message("to_alice.asc"), &[cert("alice_secret.asc")]).unwrap();
+
+        assert_eq!(opened.data, b"Hello Alice,\nno signature here.\n");
+        assert!(opened.signatures.is_empty());
+
+        // Without a matching secret key or with a locked one.
+        assert!(open(&message("to_alice.asc"), &[cert("bob_secret.asc")]).is_err());
+        let locked = key::read_armored(&fixture("alice_secret.asc")).unwrap();
+        assert!(open(&message("to_alice.asc"), &locked).is_err());
+    }
+
+    #[test]
+    fn unsupported_cipher() {
+        let err = open(&message("to_bob_cast5.asc"), &[cert("bob_secret.asc")]).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Error - Error reading OpenPGP data: Unsupported symmetric algorithm CAST5"
+        );
+    }
+
+    #[test]
+    fn signed_message() {
+        let opened = open(&message("carol_signed.asc"), &[cert("bob_public.asc")]).unwrap();
+
+        assert!(!opened.encrypted);
+        assert_eq!(opened.data, b"From Carol\n");
+        assert_eq!(opened.signatures[0].status, Status::UnknownKey);
+    }
+
+    #[test]
+    fn cleartext_signature() {
+        let cleartext = armor::cleartext(&fixture("bob_clearsigned.asc")).unwrap();
+        let bob = cert("bob_public.asc");
+
+        let verifications = verify_cleartext(&cleartext, std::slice::from_ref(&bob)).unwrap();
+        assert_eq!(verifications[0].status, Status::Good);
+        assert_eq!(verifications[0].user_id, "Bob <bob@example.com>");
+
+        let verifications = verify_detached(
+            b"Hi there,\r\nthis was signed by Eve.",
+            &cleartext.signature,
+            std::slice::from_ref(&bob),
+        )
+        .unwrap();
+        assert_eq!(verifications[0].status, Status::Bad);
+
+        let cleartext = armor::cleartext(&fixture("bob_sha1_clearsigned.asc")).unwrap();
+        let verifications = verify_cleartext(&cleartext, &[bob]).unwrap();
+        assert_eq!(verifications[0].status, Status::WeakHash);
+    }
+
+    #[test]
+    fn round_trip() {
+        let alice = cert("alice_secret.asc");
+        let bob = cert("bob_secret.asc");
+        let now = chrono::Utc::now().timestamp();
+
+        let encrypted = encrypt(b"Meet at noon", &[&alice, &bob], Some(&alice), now).unwrap();
+
+        for reader in [&alice, &bob] {
+            let opened = open(&encrypted, &[reader.clone(), alice.clone()]).unwrap();
+
+            assert_eq!(opened.data, b"Meet at noon");
+            assert_eq!(opened.signatures[0].status, Status::Good);
+        }
+
+        let signature = sign_detached(b"data", &bob, now).unwrap();
+        let verifications =
+            verify_detached(b"data", &signature, std::slice::from_ref(&bob)).unwrap();
+        assert_eq!(verifications[0].status, Status::Good);
+        assert_eq!(verifications[0].created, now);
+
+        // Tampered ciphertext fails the modification detection.
+        let mut tampered = encrypted.clone();
+        let last = tampered.len() - 30;
+        tampered[last] ^= 1;
+        assert!(open(&tampered, &[bob]).is_err());
+    }
+}
@@ -0,0 +1,297 @@
+use crate::my_error::MyError;
+
+pub const PUBLIC_KEY_ENCRYPTED_SESSION_KEY: u8 = 1;
+pub const SIGNATURE: u8 = 2;
+pub const SYMMETRIC_KEY_ENCRYPTED_SESSION_KEY: u8 = 3;
+pub const ONE_PASS_SIGNATURE: u8 = 4;
+pub const SECRET_KEY: u8 = 5;
+pub const PUBLIC_KEY: u8 = 6;
+pub const SECRET_SUBKEY: u8 = 7;
+pub const COMPRESSED_DATA: u8 = 8;
+pub const SYMMETRICALLY_ENCRYPTED_DATA: u8 = 9;
+pub const LITERAL_DATA: u8 = 11;
+pub const USER_ID: u8 = 13;
+pub const PUBLIC_SUBKEY: u8 = 14;
+pub const USER_ATTRIBUTE: u8 = 17;
+pub const SYM_ENCRYPTED_INTEGRITY_PROTECTED_DATA: u8 = 18;
+pub const MODIFICATION_DETECTION_CODE: u8 = 19;
+
+/// Packet of an OpenPGP message or key (RFC 4880 section 4), partial bodies
+/// joined.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub tag: u8,
+    pub body: Vec<u8>,
+}
+
+/// Error for malformed or unsupported OpenPGP data.
+pub fn error(detail: &str) -> MyError {
+    let err = MyError::String(
+        detail.to_string(),
+        String::from("Error reading OpenPGP data"),
+    );
+    err.log_error();
+
+    return err;
+}
+
+/// Splits `data` into packets, old and new format headers.
+pub fn parse(data: &[u8]) -> Result<Vec<Packet>, MyError> {
+    let mut packets: Vec<Packet> = vec![];
+    let mut reader = Reader::new(data);
+
+    while !reader.is_empty() {
+        let ctb = match reader.u8() {
+            Ok(ctb) => ctb,
+            Err(e) => return Err(e),
+        };
+
+        if ctb & 0x80 == 0 {
+            return Err(error("Invalid packet header"));
+        }
+
+        let packet = if ctb & 0x40 != 0 {
+            let tag = ctb & 0x3f;
+            let mut body: Vec<u8> = vec![];
+
+            loop {
+                let (len, last) = match new_length(&mut reader) {
+                    Ok(Length::Full(len)) => (len, true),
+                    Ok(Length::Partial(len)) => (len, false),
+                    Err(e) => return Err(e),
+                };
+
+                match reader.take(len) {
+                    Ok(part) => body.extend_from_slice(part),
+                    Err(e) => return Err(e),
+                };
+
+                if last {
+                    break;
+                }
+            }
+
+            Packet { tag, body }
+        } else {
+            let tag = (ctb >> 2) & 0x0f;
+            let len = match ctb & 0x03 {
+                0 => reader.u8().map(|len| len as usize),
+                1 => reader.u16().map(|len| len as usize),
+                2 => reader.u32().map(|len| len as usize),
+                _ => Ok(reader.remaining()),
+            };
+            let len = match len {
+                Ok(len) => len,
+                Err(e) => return Err(e),
+            };
+
+            match reader.take(len) {
+                Ok(body) => Packet {
+                    tag,
+                    body: body.to_vec(),
+                },
+                Err(e) => return Err(e),
+            }
+        };
+
+        packets.push(packet);
+    }
+
+    return Ok(packets);
+}
+
+enum Length {
+    Full(usize),
+    Partial(usize),
+}
+
+fn new_length(reader: &mut Reader) -> Result<Length, MyError> {
+    let first = match reader.u8() {
+        Ok(first) => first as usize,
+        Err(e) => return Err(e),
+    };
+
+    match first {
+        0..=191 => return Ok(Length::Full(first)),
+        192..=223 => {
+            let second = match reader.u8() {
+                Ok(second) => second as usize,
+                Err(e) => return Err(e),
+            };
+
+            return Ok(Length::Full(((first - 192) << 8) + second + 192));
+        }
+        224..=254 => return Ok(Length::Partial(1 << (first & 0x1f))),
+        _ => match reader.u32() {
+            Ok(len) => return Ok(Length::Full(len as usize)),
+            Err(e) => return Err(e),
+        },
+    }
+}
+
+/// Packet with a new format header and a definite length.
+pub fn write(tag: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0xc0 | tag];
+    let len = body.len();
+
+    if len < 192 {
+        packet.push(len as u8);
+    } else if len < 8384 {
+        let len = len - 192;
+        packet.push(((len >> 8) + 192) as u8);
+        packet.push((len & 0xff) as u8);
+    } else {
+        packet.push(0xff);
+        packet.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+
+    packet.extend_from_slice(body);
+
+    return packet;
+}
+
+/// Multiprecision integer, big endian without leading zeros.
+pub fn mpi(value: &[u8]) -> Vec<u8> {
+    let start = value.iter().position(|b| *b != 0).unwrap_or(value.len());
+    let value = &value[start..];
+
+    let bits = match value.first() {
+        Some(first) => (value.len() - 1) * 8 + (8 - first.leading_zeros() as usize),
+        None => 0,
+    };
+
+    let mut encoded = (bits as u16).to_be_bytes().to_vec();
+    encoded.extend_from_slice(value);
+
+    return encoded;
+}
+
+/// Cursor over the body of a packet.
+pub struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        return Reader { data, pos: 0 };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.pos >= self.data.len();
+    }
+
+    pub fn remaining(&self) -> usize {
+        return self.data.len() - self.pos;
+    }
+
+    pub fn position(&self) -> usize {
+        return self.pos;
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], MyError> {
+        if self.remaining() < len {
+            return Err(error("Truncated packet"));
+        }
+
+        let value = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+
+        return Ok(value);
+    }
+
+    pub fn rest(&mut self) -> &'a [u8] {
+        let value = &self.data[self.pos..];
+        self.pos = self.data.len();
+
+        return value;
+    }
+
+    pub fn u8(&mut self) -> Result<u8, MyError> {
+        match self.take(1) {
+            Ok(bytes) => return Ok(bytes[0]),
+            Err(e) => return Err(e),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, MyError> {
+        let bytes = match self.take(2) {
+            Ok(bytes) => bytes,
+            Err(e) => return Err(e),
+        };
+
+        return Ok(u16::from_be_bytes([bytes[0], bytes[1]]));
+    }
+
+    pub fn u32(&mut self) -> Result<u32, MyError> {
+        let bytes = match self.take(4) {
+            Ok(bytes) => bytes,
+            Err(e) => return Err(e),
+        };
+
+        return Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+    }
+
+    pub fn mpi(&mut self) -> Result<&'a [u8], MyError> {
+        let bits = match self.u16() {
+            Ok(bits) => bits as usize,
+            Err(e) => return Err(e),
+        };
+
+        return self.take(bits.div_ceil(8));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lengths() {
+        for len in [0, 191, 192, 8383, 8384, 70000] {
+            let body = vec![7u8; len];
+            let packets = parse(&write(LITERAL_DATA, &body)).unwrap();
+
+            assert_eq!(
+                packets,
+                vec![Packet {
+                    tag: LITERAL_DATA,
+                    body
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn old_format_and_partial_lengths() {
+        // Old format user id with a one byte length, then a new format
+        // literal packet split into a 2 byte partial body and a final one.
+        let data = [0xb4, 0x02, b'h', b'i', 0xcb, 0xe1, b'a', b'b', 0x01, b'c'];
+
+        assert_eq!(
+            parse(&data).unwrap(),
+            vec![
+                Packet {
+                    tag: USER_ID,
+                    body: b"hi".to_vec()
+                },
+                Packet {
+                    tag: LITERAL_DATA,
+                    body: b"abc".to_vec()
+                }
+            ]
+        );
+
+        assert!(parse(&[0xb4, 0x05, b'h']).is_err());
+        assert!(parse(&[0x34]).is_err());
+    }
+
+    #[test]
+    fn mpis() {
+        assert_eq!(mpi(&[0x00, 0x01, 0xff]), vec![0x00, 0x09, 0x01, 0xff]);
+        assert_eq!(mpi(&[0x40]), vec![0x00, 0x07, 0x40]);
+
+        let encoded = mpi(&[0x00, 0x80, 0x01]);
+        assert_eq!(Reader::new(&encoded).mpi().unwrap(), &[0x80, 0x01]);
+    }
+}
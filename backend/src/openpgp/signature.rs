@@ -0,0 +1,423 @@
+use crate::my_error::MyError;
+use crate::openpgp::crypto;
+use crate::openpgp::key::{Key, PublicKey, PublicParams, SecretParams};
+use crate::openpgp::packet::{self, Reader};
+
+// Signature types (RFC 4880 section 5.2.1).
+pub const BINARY: u8 = 0x00;
+pub const TEXT: u8 = 0x01;
+pub const POSITIVE_CERTIFICATION: u8 = 0x13;
+pub const SUBKEY_BINDING: u8 = 0x18;
+pub const DIRECT_KEY: u8 = 0x1f;
+pub const KEY_REVOCATION: u8 = 0x20;
+pub const SUBKEY_REVOCATION: u8 = 0x28;
+pub const CERTIFICATION_REVOCATION: u8 = 0x30;
+
+// Signature subpackets (RFC 4880 section 5.2.3.1).
+const CREATION_TIME: u8 = 2;
+const EXPIRATION_TIME: u8 = 3;
+const KEY_EXPIRATION_TIME: u8 = 9;
+const ISSUER: u8 = 16;
+const PRIMARY_USER_ID: u8 = 25;
+const KEY_FLAGS: u8 = 27;
+const ISSUER_FINGERPRINT: u8 = 33;
+
+/// Version 4 signature packet.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    pub signature_type: u8,
+    pub algorithm: u8,
+    pub hash_algorithm: u8,
+    hashed: Vec<u8>,
+    unhashed: Vec<u8>,
+    hash_prefix: [u8; 2],
+    values: Vec<Vec<u8>>,
+    /// Seconds since the epoch.
+    pub created: i64,
+    /// Seconds after creation, 0 or `None` when it does not expire.
+    pub expires: Option<u32>,
+    pub issuer: Option<Vec<u8>>,
+    pub issuer_fingerprint: Option<Vec<u8>>,
+    pub key_flags: Option<u8>,
+    /// Seconds after the creation of the key, 0 or `None` when it does not
+    /// expire.
+    pub key_expires: Option<u32>,
+    pub primary_user_id: bool,
+}
+
+impl Signature {
+    pub fn parse(body: &[u8]) -> Result<Signature, MyError> {
+        let mut reader = Reader::new(body);
+
+        match reader.u8() {
+            Ok(4) => {}
+            Ok(_) => return Err(packet::error("Unsupported signature version")),
+            Err(e) => return Err(e),
+        };
+
+        let signature_type = match reader.u8() {
+            Ok(signature_type) => signature_type,
+            Err(e) => return Err(e),
+        };
+        let algorithm = match reader.u8() {
+            Ok(algorithm) => algorithm,
+            Err(e) => return Err(e),
+        };
+        let hash_algorithm = match reader.u8() {
+            Ok(hash_algorithm) => hash_algorithm,
+            Err(e) => return Err(e),
+        };
+        let len = match reader.u16() {
+            Ok(len) => len as usize,
+            Err(e) => return Err(e),
+        };
+        let hashed = match reader.take(len) {
+            Ok(hashed) => hashed.to_vec(),
+            Err(e) => return Err(e),
+        };
+        let len = match reader.u16() {
+            Ok(len) => len as usize,
+            Err(e) => return Err(e),
+        };
+        let unhashed = match reader.take(len) {
+            Ok(unhashed) => unhashed.to_vec(),
+            Err(e) => return Err(e),
+        };
+        let prefix = match reader.take(2) {
+            Ok(prefix) => prefix,
+            Err(e) => return Err(e),
+        };
+
+        let mut values: Vec<Vec<u8>> = vec![];
+        while !reader.is_empty() {
+            match reader.mpi() {
+                Ok(value) => values.push(value.to_vec()),
+                Err(e) => return Err(e),
+            };
+        }
+
+        let mut signature = Signature {
+            signature_type,
+            algorithm,
+            hash_algorithm,
+            hashed,
+            unhashed,
+            hash_prefix: [prefix[0], prefix[1]],
+            values,
+            created: 0,
+            expires: None,
+            issuer: None,
+            issuer_fingerprint: None,
+            key_flags: None,
+            key_expires: None,
+            primary_user_id: false,
+        };
+
+        let subpackets = match signature.subpackets() {
+            Ok(subpackets) => subpackets,
+            Err(e) => return Err(e),
+        };
+
+        for (kind, value, hashed) in subpackets {
+            match (kind, value.len()) {
+                (CREATION_TIME, 4) if hashed => {
+                    signature.created =
+                        u32::from_be_bytes([value[0], value[1], value[2], value[3]]) as i64
+                }
+                (EXPIRATION_TIME, 4) if hashed => {
+                    signature.expires =
+                        Some(u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+                }
+                (KEY_EXPIRATION_TIME, 4) if hashed => {
+                    signature.key_expires =
+                        Some(u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+                }
+                (ISSUER, 8) => signature.issuer = Some(value),
+                (ISSUER_FINGERPRINT, 21) if value[0] == 4 => {
+                    signature.issuer_fingerprint = Some(value[1..].to_vec())
+                }
+                (PRIMARY_USER_ID, 1) if hashed => signature.primary_user_id = value[0] != 0,
+                (KEY_FLAGS, len) if hashed && len > 0 => signature.key_flags = Some(value[0]),
+                _ => {}
+            }
+        }
+
+        return Ok(signature);
+    }
+
+    /// Type, value and whether it is hashed of every subpacket.
+    fn subpackets(&self) -> Result<Vec<(u8, Vec<u8>, bool)>, MyError> {
+        let mut subpackets: Vec<(u8, Vec<u8>, bool)> = vec![];
+
+        for (area, hashed) in [(&self.hashed, true), (&self.unhashed, false)] {
+            let mut reader = Reader::new(area);
+
+            while !reader.is_empty() {
+                let first = match reader.u8() {
+                    Ok(first) => first as usize,
+                    Err(e) => return Err(e),
+                };
+                let len = match first {
+                    0..=191 => Ok(first),
+                    192..=254 => reader
+                        .u8()
+                        .map(|second| ((first - 192) << 8) + second as usize + 192),
+                    _ => reader.u32().map(|len| len as usize),
+                };
+                let len = match len {
+                    Ok(len) => len,
+                    Err(e) => return Err(e),
+                };
+
+                if len == 0 {
+                    return Err(packet::error("Empty signature subpacket"));
+                }
+
+                let kind = match reader.u8() {
+                    Ok(kind) => kind & 0x7f,
+                    Err(e) => return Err(e),
+                };
+                match reader.take(len - 1) {
+                    Ok(value) => subpackets.push((kind, value.to_vec(), hashed)),
+                    Err(e) => return Err(e),
+                };
+            }
+        }
+
+        return Ok(subpackets);
+    }
+
+    /// Data hashed after the signed data (RFC 4880 section 5.2.4).
+    fn trailer(&self) -> Vec<u8> {
+        let mut trailer = vec![4, self.signature_type, self.algorithm, self.hash_algorithm];
+        trailer.extend_from_slice(&(self.hashed.len() as u16).to_be_bytes());
+        trailer.extend_from_slice(&self.hashed);
+
+        let len = trailer.len() as u32;
+        trailer.extend_from_slice(&[4, 0xff]);
+        trailer.extend_from_slice(&len.to_be_bytes());
+
+        return trailer;
+    }
+
+    fn digest(&self, data: &[u8]) -> Result<Vec<u8>, MyError> {
+        let mut hashed = match self.signature_type {
+            TEXT => canonical_text(data),
+            _ => data.to_vec(),
+        };
+        hashed.extend_from_slice(&self.trailer());
+
+        return crypto::digest(self.hash_algorithm, &hashed);
+    }
+
+    /// Whether the signature names `key` as issuer, by fingerprint or key ID.
+    pub fn issued_by(&self, key: &PublicKey) -> bool {
+        if let Some(fingerprint) = &self.issuer_fingerprint {
+            return *fingerprint == key.fingerprint;
+        }
+
+        match &self.issuer {
+            Some(issuer) => return issuer[..] == *key.key_id(),
+            None => return false,
+        }
+    }
+
+    /// Key ID or fingerprint of the issuer in hex.
+    pub fn issuer_hex(&self) -> String {
+        match (&self.issuer_fingerprint, &self.issuer) {
+            (Some(fingerprint), _) => return hex(fingerprint),
+            (None, Some(issuer)) => return hex(issuer),
+            (None, None) => return String::new(),
+        }
+    }
+
+    /// Checks the signature of `key` over `data`, for key signatures the
+    /// framed key packets and user ID.
+    pub fn verify(&self, key: &PublicKey, data: &[u8]) -> bool {
+        if self.algorithm != key.algorithm {
+            return false;
+        }
+
+        let digest = match self.digest(data) {
+            Ok(digest) => digest,
+            Err(_) => return false,
+        };
+
+        if digest[..2] != self.hash_prefix {
+            return false;
+        }
+
+        match (&key.params, self.values.as_slice()) {
+            (PublicParams::Rsa { n, e }, [signature]) => {
+                return crypto::rsa_verify(n, e, self.hash_algorithm, &digest, signature)
+            }
+            (PublicParams::Ed25519 { point }, [r, s]) => {
+                return crypto::ed25519_verify(point, &digest, r, s)
+            }
+            _ => return false,
+        }
+    }
+
+    /// Signature of `key` over `data` made at `created`, `hashed` are extra
+    /// hashed subpackets as type and value.
+    pub fn create(
+        signature_type: u8,
+        key: &Key,
+        data: &[u8],
+        created: i64,
+        hashed: &[(u8, Vec<u8>)],
+    ) -> Result<Signature, MyError> {
+        let secret = match key.secret() {
+            Some(secret) => secret,
+            None => return Err(packet::error("Secret key is not available")),
+        };
+
+        let mut subpackets = vec![(CREATION_TIME, (created as u32).to_be_bytes().to_vec())];
+        let mut fingerprint = vec![4];
+        fingerprint.extend_from_slice(&key.public.fingerprint);
+        subpackets.push((ISSUER_FINGERPRINT, fingerprint));
+        subpackets.extend_from_slice(hashed);
+
+        let mut signature = Signature {
+            signature_type,
+            algorithm: key.public.algorithm,
+            hash_algorithm: crypto::SHA256,
+            hashed: encode_subpackets(&subpackets),
+            unhashed: encode_subpackets(&[(ISSUER, key.public.key_id().to_vec())]),
+            hash_prefix: [0, 0],
+            values: vec![],
+            created,
+            expires: None,
+            issuer: Some(key.public.key_id().to_vec()),
+            issuer_fingerprint: Some(key.public.fingerprint.clone()),
+            key_flags: None,
+            key_expires: None,
+            primary_user_id: false,
+        };
+
+        let digest = match signature.digest(data) {
+            Ok(digest) => digest,
+            Err(e) => return Err(e),
+        };
+        signature.hash_prefix = [digest[0], digest[1]];
+
+        signature.values = match (&key.public.params, secret) {
+            (PublicParams::Rsa { n, e }, SecretParams::Rsa { d, p, q }) => {
+                match crypto::rsa_sign((n, e), (d, p, q), signature.hash_algorithm, &digest) {
+                    Ok(value) => vec![value],
+                    Err(e) => return Err(e),
+                }
+            }
+            (PublicParams::Ed25519 { .. }, SecretParams::Ed25519 { seed }) => {
+                match crypto::ed25519_sign(seed, &digest) {
+                    Ok((r, s)) => vec![r, s],
+                    Err(e) => return Err(e),
+                }
+            }
+            _ => return Err(packet::error("Key cannot sign")),
+        };
+
+        return Ok(signature);
+    }
+
+    /// Body of the signature packet.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = vec![4, self.signature_type, self.algorithm, self.hash_algorithm];
+        body.extend_from_slice(&(self.hashed.len() as u16).to_be_bytes());
+        body.extend_from_slice(&self.hashed);
+        body.extend_from_slice(&(self.unhashed.len() as u16).to_be_bytes());
+        body.extend_from_slice(&self.unhashed);
+        body.extend_from_slice(&self.hash_prefix);
+
+        for value in &self.values {
+            body.extend_from_slice(&packet::mpi(value));
+        }
+
+        return body;
+    }
+}
+
+fn encode_subpackets(subpackets: &[(u8, Vec<u8>)]) -> Vec<u8> {
+    let mut encoded: Vec<u8> = vec![];
+
+    for (kind, value) in subpackets {
+        // Every subpacket written here is shorter than 191 bytes.
+        encoded.push(value.len() as u8 + 1);
+        encoded.push(*kind);
+        encoded.extend_from_slice(value);
+    }
+
+    return encoded;
+}
+
+/// Text with every line ending as CRLF, as hashed by text signatures.
+pub fn canonical_text(data: &[u8]) -> Vec<u8> {
+    let mut canonical: Vec<u8> = Vec::with_capacity(data.len());
+
+    let mut i = 0;
+    while i < data.len() {
+        match data[i] {
+            b'\r' if data.get(i + 1) == Some(&b'\n') => {
+                canonical.extend_from_slice(b"\r\n");
+                i += 1;
+            }
+            b'\r' | b'\n' => canonical.extend_from_slice(b"\r\n"),
+            byte => canonical.push(byte),
+        }
+        i += 1;
+    }
+
+    return canonical;
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    return bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_line_endings() {
+        assert_eq!(canonical_text(b"a\nb\r\nc\rd\r"), b"a\r\nb\r\nc\r\nd\r\n");
+        assert_eq!(canonical_text(b""), b"");
+    }
+
+    #[test]
+    fn subpacket_areas() {
+        let signature = Signature {
+            signature_type: BINARY,
+            algorithm: crypto::EDDSA,
+            hash_algorithm: crypto::SHA256,
+            hashed: encode_subpackets(&[
+                (CREATION_TIME, 1000u32.to_be_bytes().to_vec()),
+                (KEY_FLAGS, vec![0x03]),
+            ]),
+            unhashed: encode_subpackets(&[(ISSUER, vec![1, 2, 3, 4, 5, 6, 7, 8])]),
+            hash_prefix: [0xab, 0xcd],
+            values: vec![vec![1], vec![2]],
+            created: 0,
+            expires: None,
+            issuer: None,
+            issuer_fingerprint: None,
+            key_flags: None,
+            key_expires: None,
+            primary_user_id: false,
+        };
+
+        let parsed = Signature::parse(&signature.to_bytes()).unwrap();
+
+        assert_eq!(parsed.created, 1000);
+        assert_eq!(parsed.key_flags, Some(0x03));
+        assert_eq!(parsed.issuer, Some(vec![1, 2, 3, 4, 5, 6, 7, 8]));
+        assert_eq!(parsed.issuer_hex(), "0102030405060708");
+        assert_eq!(parsed.to_bytes(), signature.to_bytes());
+
+        // Only hashed subpackets are trusted for the creation time.
+        let mut moved = signature.clone();
+        moved.unhashed = moved.hashed.clone();
+        moved.hashed = vec![];
+        assert_eq!(Signature::parse(&moved.to_bytes()).unwrap().created, 0);
+    }
+}
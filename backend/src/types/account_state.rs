@@ -0,0 +1,37 @@
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccountState {
+    Connected,
+    Connecting,
+    AuthFailed,
+    Offline,
+    Backoff(i64),
+}
+
+impl AccountState {
+    pub fn name(&self) -> &str {
+        match self {
+            AccountState::Connected => "connected",
+            AccountState::Connecting => "connecting",
+            AccountState::AuthFailed => "auth_failed",
+            AccountState::Offline => "offline",
+            AccountState::Backoff(_) => "backoff",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AccountHealth {
+    pub state: AccountState,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+impl AccountHealth {
+    pub fn new() -> AccountHealth {
+        return AccountHealth {
+            state: AccountState::Offline,
+            attempts: 0,
+            last_error: None,
+        };
+    }
+}
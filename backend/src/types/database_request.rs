@@ -1,3 +1,7 @@
+use serde::{Deserialize, Serialize};
+
+use crate::types::page_cursor::PageCursor;
+
 #[derive(Debug, Clone)]
 pub enum MessageIdType {
     MessageUids,
@@ -9,9 +13,98 @@ pub enum MessageReturnData {
     All,
     AllWithFlags,
     Flags,
+    Summary,
     Uid,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortField {
+    Date,
+    Received,
+    Sender,
+    Subject,
+    Size,
+    Flagged,
+    Unread,
+}
+
+impl SortField {
+    pub fn from_str(sort: &str) -> Option<SortField> {
+        match sort.to_lowercase().as_str() {
+            "date" => Some(SortField::Date),
+            "received" => Some(SortField::Received),
+            "sender" => Some(SortField::Sender),
+            "subject" => Some(SortField::Subject),
+            "size" => Some(SortField::Size),
+            "flagged" => Some(SortField::Flagged),
+            "unread" => Some(SortField::Unread),
+            _ => None,
+        }
+    }
+
+    /// Expressions a list is ordered by before the message uid, which breaks
+    /// ties so every message has a unique position for keyset pagination.
+    /// Flagged and unread messages are ordered by time within their group.
+    pub fn key_columns(&self) -> Vec<&'static str> {
+        match self {
+            SortField::Date => return vec!["messages.date_"],
+            SortField::Received => return vec!["messages.received"],
+            SortField::Sender => return vec!["messages.sort_sender"],
+            SortField::Subject => return vec!["messages.sort_subject"],
+            SortField::Size => return vec!["messages.size"],
+            SortField::Flagged => return vec![
+                "EXISTS (SELECT 1 FROM flags f WHERE f.message_uid = messages.message_uid AND f.c_username = messages.c_username AND f.c_address = messages.c_address AND f.m_path = messages.m_path AND f.flag = 'Flagged')",
+                "messages.received",
+            ],
+            SortField::Unread => return vec![
+                "NOT EXISTS (SELECT 1 FROM flags f WHERE f.message_uid = messages.message_uid AND f.c_username = messages.c_username AND f.c_address = messages.c_address AND f.m_path = messages.m_path AND f.flag = 'Seen')",
+                "messages.received",
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl SortOrder {
+    pub fn from_str(order: &str) -> Option<SortOrder> {
+        match order.to_lowercase().as_str() {
+            "asc" => Some(SortOrder::Asc),
+            "desc" => Some(SortOrder::Desc),
+            _ => None,
+        }
+    }
+
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => return "ASC",
+            SortOrder::Desc => return "DESC",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sort {
+    pub field: SortField,
+    pub order: SortOrder,
+}
+
+impl Sort {
+    /// Newest first, the order of the mailbox view.
+    pub fn received_desc() -> Sort {
+        return Sort {
+            field: SortField::Received,
+            order: SortOrder::Desc,
+        };
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DatabaseRequest {
     pub username: String,
@@ -19,9 +112,11 @@ pub struct DatabaseRequest {
     pub mailbox_path: String,
     pub return_data: MessageReturnData,
     pub id_type: MessageIdType,
-    pub sorted: bool,
+    pub sort: Option<Sort>,
     pub start: Option<u32>,
     pub end: Option<u32>,
+    pub limit: Option<u32>,
+    pub after: Option<PageCursor>,
     pub id_rarray: Option<Vec<u32>>,
     pub flag: Option<String>,
     pub not_flag: Option<bool>,
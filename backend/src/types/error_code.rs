@@ -0,0 +1,191 @@
+use serde::Serialize;
+
+/// Machine readable reason for a failed request, returned next to the free
+/// text message so clients can react to a failure without parsing it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    // Request errors
+    InvalidRequest,
+    InvalidSession,
+    Unauthorized,
+    ForbiddenHost,
+    NotFound,
+    MethodNotAllowed,
+    RequestTimeout,
+    PayloadTooLarge,
+    NotImplemented,
+    VersionNotSupported,
+    WrongMasterKey,
+
+    // Connection errors
+    ServerUnreachable,
+    Backoff,
+    TlsError,
+    ServerError,
+
+    // Authentication errors
+    AuthenticationFailed,
+    AuthorizationFailed,
+    Expired,
+    #[serde(rename = "OAUTH_FAILED")]
+    OAuthFailed,
+
+    // IMAP response codes, RFC 5530
+    Nonexistent,
+    TryCreate,
+    AlreadyExists,
+    OverQuota,
+    Limit,
+    NoPerm,
+    InUse,
+    Unavailable,
+    ServerBug,
+    Cannot,
+    Rejected,
+
+    // Local errors
+    Database,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(&self) -> u16 {
+        match self {
+            ErrorCode::InvalidRequest => 400,
+            ErrorCode::InvalidSession => 404,
+            ErrorCode::Unauthorized => 401,
+            ErrorCode::ForbiddenHost => 403,
+            ErrorCode::NotFound => 404,
+            ErrorCode::MethodNotAllowed => 405,
+            ErrorCode::RequestTimeout => 408,
+            ErrorCode::PayloadTooLarge => 413,
+            ErrorCode::NotImplemented => 501,
+            ErrorCode::VersionNotSupported => 505,
+            ErrorCode::WrongMasterKey => 403,
+            ErrorCode::ServerUnreachable => 503,
+            ErrorCode::Backoff => 503,
+            ErrorCode::TlsError => 502,
+            ErrorCode::ServerError => 502,
+            ErrorCode::AuthenticationFailed => 401,
+            ErrorCode::AuthorizationFailed => 403,
+            ErrorCode::Expired => 401,
+            ErrorCode::OAuthFailed => 401,
+            ErrorCode::Nonexistent => 404,
+            ErrorCode::TryCreate => 404,
+            ErrorCode::AlreadyExists => 409,
+            ErrorCode::OverQuota => 507,
+            ErrorCode::Limit => 429,
+            ErrorCode::NoPerm => 403,
+            ErrorCode::InUse => 409,
+            ErrorCode::Unavailable => 503,
+            ErrorCode::ServerBug => 502,
+            ErrorCode::Cannot => 422,
+            ErrorCode::Rejected => 422,
+            ErrorCode::Database => 500,
+            ErrorCode::Internal => 500,
+        }
+    }
+
+    /// Whether sending the same request again later can succeed.
+    pub fn retryable(&self) -> bool {
+        return matches!(
+            self,
+            ErrorCode::RequestTimeout
+                | ErrorCode::ServerUnreachable
+                | ErrorCode::Backoff
+                | ErrorCode::ServerError
+                | ErrorCode::Limit
+                | ErrorCode::InUse
+                | ErrorCode::Unavailable
+        );
+    }
+
+    /// Code for the errors the HTTP layer produces before a handler runs.
+    pub fn from_status(status: u16) -> ErrorCode {
+        match status {
+            400 => ErrorCode::InvalidRequest,
+            401 => ErrorCode::Unauthorized,
+            403 => ErrorCode::ForbiddenHost,
+            404 => ErrorCode::NotFound,
+            405 => ErrorCode::MethodNotAllowed,
+            408 => ErrorCode::RequestTimeout,
+            413 => ErrorCode::PayloadTooLarge,
+            501 => ErrorCode::NotImplemented,
+            505 => ErrorCode::VersionNotSupported,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// Reads the response code in brackets at the start of the text of a NO
+    /// or BAD response, e.g. `[NONEXISTENT] Unknown Mailbox: Foo`.
+    pub fn from_imap_response(text: &str) -> Option<ErrorCode> {
+        let text = text.trim_start();
+
+        if !text.starts_with('[') {
+            return None;
+        }
+
+        let end = text.find([']', ' '])?;
+
+        match text[1..end].to_uppercase().as_str() {
+            "AUTHENTICATIONFAILED" => return Some(ErrorCode::AuthenticationFailed),
+            "AUTHORIZATIONFAILED" => return Some(ErrorCode::AuthorizationFailed),
+            "EXPIRED" => return Some(ErrorCode::Expired),
+            "NONEXISTENT" => return Some(ErrorCode::Nonexistent),
+            "TRYCREATE" => return Some(ErrorCode::TryCreate),
+            "ALREADYEXISTS" => return Some(ErrorCode::AlreadyExists),
+            "OVERQUOTA" => return Some(ErrorCode::OverQuota),
+            "LIMIT" => return Some(ErrorCode::Limit),
+            "NOPERM" | "PRIVACYREQUIRED" | "CONTACTADMIN" => return Some(ErrorCode::NoPerm),
+            "INUSE" => return Some(ErrorCode::InUse),
+            "UNAVAILABLE" => return Some(ErrorCode::Unavailable),
+            "SERVERBUG" => return Some(ErrorCode::ServerBug),
+            "CANNOT" => return Some(ErrorCode::Cannot),
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn imap_response_codes() {
+        assert_eq!(
+            ErrorCode::from_imap_response("[NONEXISTENT] Unknown Mailbox: Foo"),
+            Some(ErrorCode::Nonexistent)
+        );
+        assert_eq!(
+            ErrorCode::from_imap_response(" [overquota] Quota exceeded"),
+            Some(ErrorCode::OverQuota)
+        );
+        assert_eq!(
+            ErrorCode::from_imap_response("[TRYCREATE]"),
+            Some(ErrorCode::TryCreate)
+        );
+        assert_eq!(
+            ErrorCode::from_imap_response("[BADCHARSET (UTF-8)] Unsupported"),
+            None
+        );
+        assert_eq!(ErrorCode::from_imap_response("LOGIN failed"), None);
+        assert_eq!(ErrorCode::from_imap_response("[unterminated"), None);
+    }
+
+    #[test]
+    fn serialised_names() {
+        assert_eq!(
+            serde_json::to_string(&ErrorCode::AuthenticationFailed).unwrap(),
+            "\"AUTHENTICATION_FAILED\""
+        );
+        assert_eq!(
+            serde_json::to_string(&ErrorCode::OAuthFailed).unwrap(),
+            "\"OAUTH_FAILED\""
+        );
+        assert_eq!(
+            serde_json::to_string(&ErrorCode::TryCreate).unwrap(),
+            "\"TRY_CREATE\""
+        );
+    }
+}
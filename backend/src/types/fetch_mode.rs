@@ -4,14 +4,16 @@ pub enum FetchMode {
     BODY,
     UID,
     FLAGS,
+    SOURCE,
 }
 
 pub fn string(fetch_mode: FetchMode) -> String {
     match fetch_mode {
-        FetchMode::ALL => String::from("(UID FLAGS ENVELOPE BODY.PEEK[])"),
+        FetchMode::ALL => String::from("(UID FLAGS ENVELOPE INTERNALDATE BODY.PEEK[])"),
         FetchMode::ENVELOPE => String::from("(UID ENVELOPE)"),
         FetchMode::BODY => String::from("(UID BODY)"),
         FetchMode::UID => String::from("UID"),
         FetchMode::FLAGS => String::from("(UID FLAGS)"),
+        FetchMode::SOURCE => String::from("(UID BODY.PEEK[])"),
     }
 }
@@ -20,3 +20,12 @@ impl MailboxChanges {
         };
     }
 }
+
+/// Messages of a mailbox changed or removed after a cursor, and the cursor
+/// to pass on the next request.
+pub struct ChangesSince {
+    pub cursor: u32,
+    pub reset: bool,
+    pub changed: Vec<u32>,
+    pub removed: Vec<u32>,
+}
@@ -1,8 +1,11 @@
 use std::vec;
 
 use base64::{prelude::BASE64_STANDARD, Engine};
-use rusqlite::{Row, Error as SqlError};
+use rusqlite::Row;
+use serde::{Deserialize, Serialize};
 
+use crate::mime_parser::pgp_mime::PgpPart;
+use crate::mime_parser::smime::SmimePart;
 use crate::types::database_request::MessageReturnData;
 
 #[derive(Debug)]
@@ -21,17 +24,106 @@ pub struct Message {
     pub delivered_to: String,
     pub date: i64,
     pub received: i64,
+    pub size: u32,
+    pub preview: String,
+    pub has_attachments: bool,
+    pub inline_parts: Vec<InlinePart>,
+    /// Content of the text/calendar part of an invitation, empty when there
+    /// is none.
+    pub calendar: String,
+    /// OpenPGP data found when the message was fetched, opened when it is
+    /// read.
+    pub openpgp: Option<PgpPart>,
+    /// S/MIME entity found when the message was fetched, opened when it is
+    /// read.
+    pub smime: Option<SmimePart>,
+    /// Outcome of opening an encrypted or signed message.
+    pub security: Option<MessageSecurity>,
+    pub headers: Vec<Header>,
+    pub thread_size: u32,
     pub flags: Vec<String>,
     pub text: String,
     pub html: String,
 }
 
+/// Part of a message referenced from its html by Content-ID, mostly images
+/// of a multipart/related message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InlinePart {
+    pub content_id: String,
+    pub content_type: String,
+    /// Base64 encoded content.
+    pub data: String,
+}
+
+/// Address of a From, To, Cc, Bcc, Reply-To or Sender header, stored as a JSON
+/// array of these. Members of a group carry its display name.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Address {
+    pub name: String,
+    pub mailbox: String,
+    pub host: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+impl Address {
+    /// `mailbox@host`, or the mailbox alone when there is no host.
+    pub fn address(&self) -> String {
+        if self.host.is_empty() {
+            return self.mailbox.clone();
+        }
+
+        return format!("{}@{}", self.mailbox, self.host);
+    }
+}
+
+/// Result of decrypting a message and checking its signature, stored with
+/// the message when it is first read.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MessageSecurity {
+    /// `openpgp` or `smime`.
+    pub protocol: String,
+    pub encrypted: bool,
+    pub decrypted: bool,
+    /// `none`, `good`, `bad`, `unknown_key`, `expired` or `revoked`,
+    /// `mismatch` for a good signature by a key without the From or Sender
+    /// address, `weak_hash` for OpenPGP signatures made with SHA-1, and
+    /// `untrusted` for S/MIME certificates not issued by a trusted CA.
+    pub signature: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signer: Option<Signer>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Key or certificate that made a signature.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Signer {
+    pub fingerprint: String,
+    /// User ID of the key, or name and address of the certificate.
+    pub identity: String,
+    /// Milliseconds since the epoch, 0 when the signature has no time.
+    pub signed_at: i64,
+    /// End of the certificate's validity in milliseconds since the epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<i64>,
+}
+
+/// Header of a message as written in its source, folded lines joined.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Header {
+    pub name: String,
+    pub value: String,
+}
+
 impl Message {
     pub fn from_row(row: &Row, return_data: &MessageReturnData) -> Message {
         match return_data {
             MessageReturnData::All => Message::from_row_all(row),
             MessageReturnData::Flags => Message::from_row_flags(row),
             MessageReturnData::AllWithFlags => Message::from_row_all_with_flags(row),
+            MessageReturnData::Summary => Message::from_row_summary(row),
             MessageReturnData::Uid => Message::from_row_uid(row),
         }
     }
@@ -55,12 +147,56 @@ impl Message {
             delivered_to: row.get(14).unwrap(),
             date: row.get(15).unwrap(),
             received: row.get(16).unwrap(),
+            size: row.get(20).unwrap(),
+            preview: String::from(""),
+            has_attachments: false,
+            inline_parts: vec![],
+            calendar: String::new(),
+            openpgp: None,
+            smime: None,
+            security: None,
+            headers: vec![],
+            thread_size: 0,
             flags: vec![],
             html: BASE64_STANDARD.encode(html.as_bytes()),
             text: BASE64_STANDARD.encode(text.as_bytes()),
         }
     }
 
+    /// Envelope of a message for the message list, the body is replaced by
+    /// a preview.
+    fn from_row_summary(row: &Row) -> Message {
+        Message {
+            message_uid: row.get("message_uid").unwrap(),
+            sequence_id: row.get("sequence_id").unwrap(),
+            message_id: row.get("message_id").unwrap(),
+            subject: row.get("subject").unwrap(),
+            from: row.get("from_").unwrap(),
+            sender: row.get("sender").unwrap(),
+            to: row.get("to_").unwrap(),
+            cc: row.get("cc").unwrap(),
+            bcc: row.get("bcc").unwrap(),
+            reply_to: row.get("reply_to").unwrap(),
+            in_reply_to: row.get("in_reply_to").unwrap(),
+            delivered_to: row.get("delivered_to").unwrap(),
+            date: row.get("date_").unwrap(),
+            received: row.get("received").unwrap(),
+            size: row.get("size").unwrap(),
+            preview: row.get("preview").unwrap(),
+            has_attachments: row.get("has_attachments").unwrap(),
+            inline_parts: vec![],
+            calendar: String::new(),
+            openpgp: None,
+            smime: None,
+            security: None,
+            headers: vec![],
+            thread_size: row.get("thread_size").unwrap(),
+            flags: flags_from_row(row),
+            html: String::from(""),
+            text: String::from(""),
+        }
+    }
+
     fn from_row_uid(row: &Row) -> Message {
         Message {
             message_uid: row.get(0).unwrap(),
@@ -77,6 +213,16 @@ impl Message {
             delivered_to: String::from(""),
             date: 0,
             received: 0,
+            size: 0,
+            preview: String::from(""),
+            has_attachments: false,
+            inline_parts: vec![],
+            calendar: String::new(),
+            openpgp: None,
+            smime: None,
+            security: None,
+            headers: vec![],
+            thread_size: 0,
             flags: vec![],
             html: String::from(""),
             text: String::from(""),
@@ -84,12 +230,6 @@ impl Message {
     }
 
     fn from_row_flags(row: &Row) -> Message {
-        let mut flags = vec![];
-        let flag: Result<String, SqlError> = row.get(1);
-        if flag.is_ok() {
-            flags.push(flag.unwrap());
-        };
-
         Message {
             message_uid: row.get(0).unwrap(),
             sequence_id: 0,
@@ -105,7 +245,17 @@ impl Message {
             delivered_to: String::from(""),
             date: 0,
             received: 0,
-            flags,
+            size: 0,
+            preview: String::from(""),
+            has_attachments: false,
+            inline_parts: vec![],
+            calendar: String::new(),
+            openpgp: None,
+            smime: None,
+            security: None,
+            headers: vec![],
+            thread_size: 0,
+            flags: flags_from_row(row),
             html: String::from(""),
             text: String::from(""),
         }
@@ -115,11 +265,6 @@ impl Message {
         let html: String = row.get(17).unwrap();
         let text: String = row.get(18).unwrap();
 
-        let flags: Vec<String> = match row.get(20) {
-            Ok(flag) => vec![flag],
-            Err(_) => vec![],
-        };
-
         Message {
             message_uid: row.get(0).unwrap(),
             sequence_id: row.get(4).unwrap(),
@@ -135,9 +280,32 @@ impl Message {
             delivered_to: row.get(14).unwrap(),
             date: row.get(15).unwrap(),
             received: row.get(16).unwrap(),
-            flags,
+            size: row.get(20).unwrap(),
+            preview: String::from(""),
+            has_attachments: false,
+            inline_parts: vec![],
+            calendar: String::new(),
+            openpgp: None,
+            smime: None,
+            security: None,
+            headers: vec![],
+            thread_size: 0,
+            flags: flags_from_row(row),
             html: BASE64_STANDARD.encode(html.as_bytes()),
             text: BASE64_STANDARD.encode(text.as_bytes()),
         }
     }
 }
+
+/// Flags are aggregated into a JSON array per message.
+fn flags_from_row(row: &Row) -> Vec<String> {
+    let flags: String = match row.get("flags") {
+        Ok(flags) => flags,
+        Err(_) => return vec![],
+    };
+
+    match serde_json::from_str(&flags) {
+        Ok(flags) => return flags,
+        Err(_) => return vec![],
+    }
+}
@@ -0,0 +1,40 @@
+use serde::Serialize;
+
+use crate::openpgp::key::{self, Cert};
+
+/// Key of an account's keyring as returned to API clients.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OpenPgpKey {
+    pub fingerprint: String,
+    pub user_ids: Vec<String>,
+    /// Algorithm and size of the primary key, such as `rsa3072` or `ed25519`.
+    pub algorithm: String,
+    /// Milliseconds since the epoch.
+    pub created: i64,
+    /// Milliseconds since the epoch, `None` when the key does not expire.
+    pub expires: Option<i64>,
+    pub has_secret: bool,
+    pub can_sign: bool,
+    pub can_encrypt: bool,
+    pub revoked: bool,
+}
+
+impl OpenPgpKey {
+    /// What `cert` can be used for at `now`, in seconds since the epoch.
+    pub fn from_cert(cert: &Cert, now: i64) -> OpenPgpKey {
+        return OpenPgpKey {
+            fingerprint: cert.fingerprint(),
+            user_ids: cert.user_ids.clone(),
+            algorithm: cert.primary.public.description(),
+            created: cert.primary.public.created * 1000,
+            expires: cert.primary.expires.map(|expires| expires * 1000),
+            has_secret: cert.has_secret(),
+            can_sign: cert.has_secret()
+                && cert
+                    .keys()
+                    .any(|key| key.has_secret() && key.can(key::SIGN, now)),
+            can_encrypt: !cert.encryption_keys(now).is_empty(),
+            revoked: cert.primary.revoked,
+        };
+    }
+}
@@ -0,0 +1,117 @@
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use rusqlite::types::Value;
+use serde::{Deserialize, Serialize};
+
+use crate::types::database_request::{Sort, SortField, SortOrder};
+
+/// Position of the last message of a page, the next page starts after it.
+/// Handed to clients as an opaque string, so new mail arriving between two
+/// pages does not shift the rows the way an offset does.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PageCursor {
+    pub sort: SortField,
+    pub order: SortOrder,
+    pub key: Vec<serde_json::Value>,
+    pub uid: u32,
+}
+
+impl PageCursor {
+    pub fn new(sort: &Sort, key: Vec<Value>, uid: u32) -> PageCursor {
+        let key = key
+            .into_iter()
+            .map(|value| match value {
+                Value::Integer(i) => serde_json::Value::from(i),
+                Value::Real(f) => serde_json::Value::from(f),
+                Value::Text(s) => serde_json::Value::from(s),
+                Value::Null | Value::Blob(_) => serde_json::Value::Null,
+            })
+            .collect();
+
+        return PageCursor {
+            sort: sort.field,
+            order: sort.order,
+            key,
+            uid,
+        };
+    }
+
+    pub fn encode(&self) -> String {
+        let json = match serde_json::to_vec(self) {
+            Ok(json) => json,
+            Err(_) => return String::new(),
+        };
+
+        return BASE64_URL_SAFE_NO_PAD.encode(json);
+    }
+
+    pub fn decode(cursor: &str) -> Option<PageCursor> {
+        let json = BASE64_URL_SAFE_NO_PAD.decode(cursor).ok()?;
+
+        return serde_json::from_slice(&json).ok();
+    }
+
+    /// A cursor can only continue the listing it was created for.
+    pub fn matches(&self, sort: &Sort) -> bool {
+        return self.sort == sort.field
+            && self.order == sort.order
+            && self.key.len() == sort.field.key_columns().len();
+    }
+
+    /// The sort key followed by the uid, bound to the keyset comparison.
+    pub fn sql_values(&self) -> Vec<Value> {
+        let mut values: Vec<Value> = self
+            .key
+            .iter()
+            .map(|value| match value {
+                serde_json::Value::Number(n) => match n.as_i64() {
+                    Some(i) => Value::Integer(i),
+                    None => Value::Real(n.as_f64().unwrap_or(0.0)),
+                },
+                serde_json::Value::String(s) => Value::Text(s.clone()),
+                serde_json::Value::Bool(b) => Value::Integer(*b as i64),
+                _ => Value::Null,
+            })
+            .collect();
+
+        values.push(Value::Integer(self.uid as i64));
+
+        return values;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode() {
+        let sort = Sort {
+            field: SortField::Flagged,
+            order: SortOrder::Desc,
+        };
+        let cursor = PageCursor::new(
+            &sort,
+            vec![Value::Integer(1), Value::Integer(1722093350000)],
+            42,
+        );
+
+        let encoded = cursor.encode();
+        assert!(!encoded.contains(['+', '/', '=']));
+
+        let decoded = PageCursor::decode(&encoded).unwrap();
+        assert_eq!(decoded, cursor);
+        assert!(decoded.matches(&sort));
+        assert!(!decoded.matches(&Sort::received_desc()));
+        assert_eq!(
+            decoded.sql_values(),
+            vec![
+                Value::Integer(1),
+                Value::Integer(1722093350000),
+                Value::Integer(42)
+            ]
+        );
+
+        assert_eq!(PageCursor::decode("not a cursor"), None);
+        assert_eq!(PageCursor::decode(""), None);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::my_error::MyError;
+use crate::types::error_code::ErrorCode;
 
 #[derive(Debug)]
 pub struct StartEnd {
@@ -36,7 +37,8 @@ impl SequenceSet {
                 idx: None,
             } => {
                 if start > end {
-                    let err = MyError::String(
+                    let err = MyError::Code(
+                        ErrorCode::InvalidRequest,
                         String::from("Start must be less than or equal to end"),
                         String::from("Error converting SequenceSet to string representation"),
                     );
@@ -126,13 +128,10 @@ mod tests {
     ) -> SequenceSet {
         SequenceSet {
             nr_messages,
-            start_end: match start {
-                Some(start) => Some(StartEnd {
-                    start,
-                    end: end.unwrap(),
-                }),
-                None => None,
-            },
+            start_end: start.map(|start| StartEnd {
+                start,
+                end: end.unwrap(),
+            }),
             idx,
         }
     }
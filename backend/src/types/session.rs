@@ -1,12 +1,105 @@
 use async_native_tls::TlsStream;
 use async_std::net::TcpStream;
+use std::fmt;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Security {
+    Tls,
+    StartTls,
+}
+
+impl Security {
+    pub fn from_str(security: &str) -> Option<Security> {
+        match security.to_lowercase().as_str() {
+            "tls" => Some(Security::Tls),
+            "starttls" => Some(Security::StartTls),
+            _ => None,
+        }
+    }
+
+    pub fn default_for_port(port: u16) -> Security {
+        match port {
+            143 => Security::StartTls,
+            _ => Security::Tls,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Security::Tls => "tls",
+            Security::StartTls => "starttls",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthMechanism {
+    Password,
+    XOAuth2,
+    OAuthBearer,
+}
+
+impl AuthMechanism {
+    pub fn from_str(auth_mechanism: &str) -> Option<AuthMechanism> {
+        match auth_mechanism.to_lowercase().as_str() {
+            "password" => Some(AuthMechanism::Password),
+            "xoauth2" => Some(AuthMechanism::XOAuth2),
+            "oauthbearer" => Some(AuthMechanism::OAuthBearer),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            AuthMechanism::Password => "password",
+            AuthMechanism::XOAuth2 => "xoauth2",
+            AuthMechanism::OAuthBearer => "oauthbearer",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct OAuth {
+    pub token_endpoint: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub refresh_token: String,
+}
+
+#[derive(Clone)]
 pub struct Client {
     pub address: String,
     pub port: u16,
     pub username: String,
     pub password: String,
+    pub security: Security,
+    pub cert_fingerprint: Option<String>,
+    pub auth_mechanism: AuthMechanism,
+    pub oauth: Option<OAuth>,
+}
+
+// Secrets are left out so they never end up in logs.
+impl fmt::Debug for OAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OAuth")
+            .field("token_endpoint", &self.token_endpoint)
+            .field("client_id", &self.client_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("address", &self.address)
+            .field("port", &self.port)
+            .field("username", &self.username)
+            .field("security", &self.security)
+            .field("cert_fingerprint", &self.cert_fingerprint)
+            .field("auth_mechanism", &self.auth_mechanism)
+            .field("oauth", &self.oauth)
+            .finish_non_exhaustive()
+    }
 }
 
 pub type Session = async_imap::Session<TlsStream<TcpStream>>;
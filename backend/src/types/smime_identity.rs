@@ -0,0 +1,62 @@
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreRef;
+use openssl::x509::{X509StoreContext, X509};
+use serde::Serialize;
+
+use crate::mime_parser::smime::{self, Identity};
+
+/// S/MIME identity of an account as returned to API clients.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SmimeIdentity {
+    pub fingerprint: String,
+    /// Name and address of the certificate, such as `Alice <alice@example.com>`.
+    pub subject: String,
+    /// Common name of the issuing CA.
+    pub issuer: String,
+    pub addresses: Vec<String>,
+    /// Milliseconds since the epoch.
+    pub not_before: i64,
+    /// Milliseconds since the epoch.
+    pub not_after: i64,
+    /// Whether the certificate is issued by a CA of the trust store.
+    pub trusted: bool,
+}
+
+impl SmimeIdentity {
+    pub fn from_identity(identity: &Identity, trust_store: &X509StoreRef) -> SmimeIdentity {
+        let certificate = &identity.certificate;
+
+        return SmimeIdentity {
+            fingerprint: smime::fingerprint(certificate),
+            subject: smime::identity(certificate),
+            issuer: smime::common_name(certificate, true),
+            addresses: smime::addresses(certificate),
+            not_before: smime::timestamp(certificate.not_before()) * 1000,
+            not_after: smime::timestamp(certificate.not_after()) * 1000,
+            trusted: trusted(identity, trust_store),
+        };
+    }
+}
+
+fn trusted(identity: &Identity, trust_store: &X509StoreRef) -> bool {
+    let mut chain = match Stack::<X509>::new() {
+        Ok(chain) => chain,
+        Err(_) => return false,
+    };
+    for certificate in &identity.chain {
+        if chain.push(certificate.clone()).is_err() {
+            return false;
+        }
+    }
+
+    let mut context = match X509StoreContext::new() {
+        Ok(context) => context,
+        Err(_) => return false,
+    };
+
+    return context
+        .init(trust_store, &identity.certificate, &chain, |context| {
+            context.verify_cert()
+        })
+        .unwrap_or(false);
+}
@@ -117,6 +117,27 @@ class HomePageState extends State<HomePage> {
     setState(() {
       _activeID = idx;
     });
+
+    _loadMessageBody(idx);
+  }
+
+  Future<void> _loadMessageBody(int idx) async {
+    if (idx >= _messages.length || _messages[idx].hasBody) return;
+
+    final uid = _messages[idx].uid;
+    final messages = await InboxService().getMessagesWithUids(uids: [uid]);
+
+    if (messages.isEmpty ||
+        idx >= _messages.length ||
+        _messages[idx].uid != uid) {
+      return;
+    }
+
+    setState(() {
+      _messages[idx].text = messages.first.text;
+      _messages[idx].html = messages.first.html;
+      _messages[idx].remoteContent = messages.first.remoteContent;
+    });
   }
 
   void _loadMoreMessages() async {
@@ -311,7 +332,7 @@ class HomePageState extends State<HomePage> {
                 Expanded(
                   child: MessageContent(
                     key: ValueKey(
-                        "${_activeID != null && _messages.isNotEmpty ? _messages[_activeID!].uid : 0}${_activeID != null && _messages.isNotEmpty ? _messages[_activeID!].messageId : ''}"),
+                        "${_activeID != null && _messages.isNotEmpty ? _messages[_activeID!].uid : 0}${_activeID != null && _messages.isNotEmpty ? _messages[_activeID!].messageId : ''}${_activeID != null && _messages.isNotEmpty && _messages[_activeID!].hasBody}"),
                     message: _activeID != null && _messages.isNotEmpty
                         ? _messages[_activeID!]
                         : null,
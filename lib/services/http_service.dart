@@ -1,5 +1,9 @@
-import 'package:http/http.dart' as http show get;
-import 'dart:convert' as convert show jsonDecode;
+import 'dart:io'
+    show File, HttpClient, InternetAddress, InternetAddressType, Platform, Socket;
+
+import 'package:http/http.dart' as http show Client;
+import 'package:http/io_client.dart' show IOClient;
+import 'dart:convert' as convert show jsonDecode, jsonEncode;
 
 import '../types/http_request_path.dart';
 import '../types/message_request.dart';
@@ -7,17 +11,57 @@ import '../types/message_request.dart';
 class HttpService {
   final address = 'http://localhost:9001';
 
+  // Written by the backend on its first start, readable only by this user.
+  final tokenFile =
+      Platform.environment['MAIL_API_TOKEN_FILE'] ?? 'backend/api.token';
+
+  // Set when the backend serves the API on a Unix domain socket.
+  final socketPath = Platform.environment['MAIL_SOCKET'];
+
+  String? _token;
+  http.Client? _client;
+
+  http.Client _httpClient() {
+    _client ??= IOClient(_createHttpClient());
+
+    return _client!;
+  }
+
+  HttpClient _createHttpClient() {
+    final client = HttpClient();
+    final path = socketPath;
+
+    if (path != null && path.isNotEmpty) {
+      final socket = InternetAddress(path, type: InternetAddressType.unix);
+
+      client.connectionFactory =
+          (uri, proxyHost, proxyPort) => Socket.startConnect(socket, 0);
+    }
+
+    return client;
+  }
+
+  Future<String> _apiToken() async {
+    _token ??= (await File(tokenFile).readAsString()).trim();
+
+    return _token!;
+  }
+
   Future<MessageResponse> sendRequest(
     HttpRequestPath request,
     Map<String, String> params,
   ) async {
-    String query = params.entries.map((e) => '${e.key}=${e.value}').join('&');
-    if (query.isNotEmpty) query = '?$query';
-
-    final url = '$address/${request.name}$query';
+    final url = '$address/${request.name}';
 
     try {
-      final response = await http.get(Uri.parse(url));
+      final response = await _httpClient().post(
+        Uri.parse(url),
+        headers: {
+          'Content-Type': 'application/json',
+          'Authorization': 'Bearer ${await _apiToken()}',
+        },
+        body: convert.jsonEncode(params),
+      );
 
       return MessageResponse.fromJson(convert.jsonDecode(response.body));
     } catch (e) {
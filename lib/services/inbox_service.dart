@@ -220,6 +220,7 @@ class InboxService {
     int? session,
     String? mailbox,
     required List<int> uids,
+    bool loadRemote = false,
   }) async {
     if (session == null) {
       session = _activeSession;
@@ -235,7 +236,8 @@ class InboxService {
     final body = {
       'session_id': session.toString(),
       'mailbox_path': mailbox!,
-      'uids': uids.join(','),
+      'message_uids': uids.join(','),
+      'load_remote': loadRemote.toString(),
     };
 
     final messageData = await HttpService()
@@ -253,6 +255,29 @@ class InboxService {
     return messages;
   }
 
+  Future<bool> allowRemoteContent({
+    int? session,
+    required String sender,
+    required bool allow,
+  }) async {
+    if (session == null) {
+      session = _activeSession;
+
+      if (_activeSession == null) return false;
+    }
+
+    final body = {
+      'session_id': session.toString(),
+      'sender': sender,
+      'allow': allow.toString(),
+    };
+
+    final messageData = await HttpService()
+        .sendRequest(HttpRequestPath.allow_remote_content, body);
+
+    return messageData.success;
+  }
+
   Future<List<MessageFlag>> modifyFlags({
     int? session,
     String? mailbox,
@@ -7,8 +7,10 @@ enum HttpRequestPath {
   get_messages_with_uids,
   get_messages_sorted,
   get_mailboxes,
+  changes_since,
   update_mailbox,
   update_mailboxes,
   modify_flags,
   move_message,
+  allow_remote_content,
 }
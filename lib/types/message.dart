@@ -2,6 +2,7 @@ import 'dart:convert' as convert show base64Decode, utf8;
 
 import 'message_address.dart';
 import 'message_flag.dart';
+import 'remote_content.dart';
 
 class Message {
   late int uid;
@@ -17,9 +18,14 @@ class Message {
   late String deliveredTo;
   late int date;
   late int received;
+  late int size;
   late List<MessageFlag> flags;
+  late bool hasAttachments;
+  late int threadSize;
+  late String preview;
   late String text;
   late String html;
+  RemoteContent remoteContent = RemoteContent.none();
 
   Message(
     this.uid,
@@ -35,11 +41,18 @@ class Message {
     this.deliveredTo,
     this.date,
     this.received,
+    this.size,
     this.flags,
+    this.hasAttachments,
+    this.threadSize,
+    this.preview,
     this.text,
     this.html,
   );
 
+  /// Message lists only contain a preview, the body is loaded separately.
+  bool get hasBody => text.isNotEmpty || html.isNotEmpty;
+
   String decodedText() {
     return convert.utf8.decode(convert.base64Decode(text));
   }
@@ -62,9 +75,14 @@ class Message {
     final deliveredTo = data['delivered_to'];
     final date = data['date'] as int;
     final received = data['received'] as int;
+    final size = data['size'] as int? ?? 0;
     final flags = messageFlagsFromJsonList(data['flags']);
-    final text = data['text'] as String;
-    final html = data['html'] as String;
+    final hasAttachments = data['has_attachments'] as bool? ?? false;
+    final threadSize = data['thread_size'] as int? ?? 1;
+    final preview = data['preview'] as String? ?? '';
+    final text = data['text'] as String? ?? '';
+    final html = data['html'] as String? ?? '';
+    final remoteContent = RemoteContent.fromJson(data['remote_content']);
 
     return Message(
       uid,
@@ -80,9 +98,13 @@ class Message {
       deliveredTo,
       date,
       received,
+      size,
       flags,
+      hasAttachments,
+      threadSize,
+      preview,
       text,
       html,
-    );
+    )..remoteContent = remoteContent;
   }
 }
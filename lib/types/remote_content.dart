@@ -0,0 +1,25 @@
+/// Remote content the backend removed from the html of a message.
+class RemoteContent {
+  late int images;
+  late int styles;
+  late int trackingPixels;
+  late List<String> hosts;
+
+  RemoteContent(this.images, this.styles, this.trackingPixels, this.hosts);
+
+  RemoteContent.none() : this(0, 0, 0, []);
+
+  bool get isBlocked => images > 0 || styles > 0;
+
+  factory RemoteContent.fromJson(Map<String, dynamic>? data) {
+    if (data == null) return RemoteContent.none();
+
+    final images = data['images'] as int? ?? 0;
+    final styles = data['styles'] as int? ?? 0;
+    final trackingPixels = data['tracking_pixels'] as int? ?? 0;
+    final hosts =
+        (data['hosts'] as List<dynamic>? ?? []).map((e) => e as String).toList();
+
+    return RemoteContent(images, styles, trackingPixels, hosts);
+  }
+}
@@ -4,6 +4,7 @@ import 'package:flutter_widget_from_html_core/flutter_widget_from_html_core.dart
     as html_widget show BuildTree, ListViewMode, WidgetFactory, HtmlWidget;
 import 'package:html/dom.dart' as html_dom show Element;
 
+import '../../services/inbox_service.dart';
 import '../../types/message.dart';
 import '../../types/project_colors.dart';
 import '../../types/project_sizes.dart';
@@ -135,6 +136,56 @@ class MessageContentState extends State<MessageContent> {
     });
   }
 
+  /// Loads the message again with its remote content, and keeps loading it
+  /// for every message of the sender when [always] is set.
+  Future<void> _loadRemoteContent(bool always) async {
+    if (always) {
+      final allowed =
+          await InboxService().allowRemoteContent(sender: _from, allow: true);
+
+      if (!allowed) return;
+    }
+
+    final messages = await InboxService()
+        .getMessagesWithUids(uids: [_message!.uid], loadRemote: true);
+
+    if (messages.isEmpty || !mounted) return;
+
+    _message!.html = messages.first.html;
+    _message!.remoteContent = messages.first.remoteContent;
+
+    _loadHtml();
+  }
+
+  Widget _remoteContentBar() {
+    final textStyle = TextStyle(
+      color: ProjectColors.text(true),
+      fontSize: ProjectSizes.fontSize,
+    );
+
+    return Padding(
+      padding: const EdgeInsets.only(bottom: 15),
+      child: Row(
+        children: [
+          Expanded(
+            child: Text(
+              'Remote images were blocked to protect your privacy.',
+              style: textStyle,
+            ),
+          ),
+          TextButton(
+            onPressed: () => _loadRemoteContent(false),
+            child: const Text('Load images'),
+          ),
+          TextButton(
+            onPressed: () => _loadRemoteContent(true),
+            child: Text('Always load from $_from'),
+          ),
+        ],
+      ),
+    );
+  }
+
   bool openUrl(String url) {
     print('tapped $url');
 
@@ -177,6 +228,8 @@ class MessageContentState extends State<MessageContent> {
                                   _message!.received),
                             ),
                           ),
+                          if (_message!.remoteContent.isBlocked)
+                            _remoteContentBar(),
                           Opacity(
                             opacity: _showHtml ? 1.0 : 0,
                             child: _emailWidget,
@@ -55,9 +55,7 @@ class MessagePreviewState extends State<MessagePreview> {
   }
 
   String _textPreview() {
-    var decoded = _message.decodedText();
-
-    return decoded.replaceAll(RegExp(r"\n"), " ");
+    return _message.preview;
   }
 
   @override